@@ -0,0 +1,201 @@
+//! Example bot that repeats every message it receives back to the sender.
+//! Connects to a running tocks instance through the event server, so tocks
+//! must already be running with the account logged in
+
+use tocks::{AccountId, ChatHandle, EventClient, TocksEvent, TocksUiEvent, UserHandle};
+use toxcore::Message;
+
+use futures::prelude::*;
+use structopt::StructOpt;
+
+use std::collections::HashMap;
+
+#[derive(StructOpt)]
+struct Opts {
+    #[structopt(long)]
+    account: i64,
+}
+
+struct EchoBot {
+    account: AccountId,
+    // Friend on the other end of each chat. Our own messages show up as
+    // MessageInserted too, so anything not sent by the friend is skipped to
+    // avoid echoing our own replies forever
+    chat_friends: HashMap<ChatHandle, UserHandle>,
+}
+
+impl EchoBot {
+    fn new(account: AccountId) -> EchoBot {
+        EchoBot {
+            account,
+            chat_friends: HashMap::new(),
+        }
+    }
+
+    /// Returns the reply to send for event, if any
+    fn handle_event(&mut self, event: &TocksEvent) -> Option<TocksUiEvent> {
+        match event {
+            TocksEvent::FriendList(account, friends)
+            | TocksEvent::FriendsLoaded(account, friends)
+                if *account == self.account =>
+            {
+                self.chat_friends = friends
+                    .iter()
+                    .map(|friend| (*friend.chat_handle(), *friend.id()))
+                    .collect();
+                None
+            }
+            TocksEvent::FriendAdded(account, friend) if *account == self.account => {
+                self.chat_friends
+                    .insert(*friend.chat_handle(), *friend.id());
+                None
+            }
+            TocksEvent::FriendRemoved(account, user) if *account == self.account => {
+                self.chat_friends.retain(|_, friend| friend != user);
+                None
+            }
+            TocksEvent::MessageInserted(account, chat, entry) if *account == self.account => {
+                if self.chat_friends.get(chat) != Some(entry.sender()) {
+                    return None;
+                }
+
+                let reply = match entry.message() {
+                    Message::Normal(text) => {
+                        TocksUiEvent::MessageSent(self.account, *chat, text.clone())
+                    }
+                    Message::Action(text) => {
+                        TocksUiEvent::ActionSent(self.account, *chat, text.clone())
+                    }
+                };
+
+                Some(reply)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let options = Opts::from_args();
+
+    let mut client = EventClient::connect()
+        .await
+        .expect("Failed to connect to tocks");
+
+    let mut bot = EchoBot::new(options.account.into());
+
+    // Friends added before we connected won't show up as FriendAdded
+    client
+        .send(TocksUiEvent::QueryFriends(bot.account))
+        .await
+        .expect("Failed to query friends");
+
+    while let Some(item) = client.next().await {
+        let event = match item {
+            Ok(event) => event,
+            Err(e) => {
+                eprintln!("Skipping malformed event: {:#}", e);
+                continue;
+            }
+        };
+
+        match &event {
+            TocksEvent::Error(e) => eprintln!("{}", e),
+            TocksEvent::TypedError(e) => eprintln!("{}", e),
+            _ => (),
+        }
+
+        if let Some(reply) = bot.handle_event(&event) {
+            client.send(reply).await.expect("Failed to send reply");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tocks::{ChatLogEntry, Friend, Status};
+    use toxcore::PublicKey;
+
+    const FRIEND_ID: i64 = 1;
+    const CHAT_ID: i64 = 2;
+    const SELF_ID: i64 = 0;
+
+    fn friend() -> Friend {
+        Friend::new(
+            FRIEND_ID.into(),
+            CHAT_ID.into(),
+            PublicKey::from_bytes(vec![0; PublicKey::SIZE]).unwrap(),
+            "friend".to_string(),
+            Status::Online,
+        )
+    }
+
+    /// The event as the bot would see it, after a trip through the event
+    /// server's JSON
+    fn message_inserted(account: i64, sender: i64, message: Message) -> TocksEvent {
+        let timestamp = "2021-07-01T00:00:00Z".parse().unwrap();
+        let entry = ChatLogEntry::new(10.into(), sender.into(), message, timestamp, None);
+        let event = TocksEvent::MessageInserted(account.into(), CHAT_ID.into(), entry);
+
+        serde_json::from_str(&serde_json::to_string(&event).unwrap()).unwrap()
+    }
+
+    fn hello() -> Message {
+        Message::Normal("hello".to_string())
+    }
+
+    #[test]
+    fn test_echoes_friend_messages() {
+        let mut bot = EchoBot::new(0.into());
+        bot.handle_event(&TocksEvent::FriendList(0.into(), vec![friend()]));
+
+        let reply = bot.handle_event(&message_inserted(0, FRIEND_ID, hello()));
+        assert!(matches!(
+            reply,
+            Some(TocksUiEvent::MessageSent(account, chat, text))
+                if account == 0.into() && chat == CHAT_ID.into() && text == "hello"
+        ));
+
+        let reply = bot.handle_event(&message_inserted(
+            0,
+            FRIEND_ID,
+            Message::Action("waves".to_string()),
+        ));
+        assert!(matches!(
+            reply,
+            Some(TocksUiEvent::ActionSent(_, _, text)) if text == "waves"
+        ));
+    }
+
+    #[test]
+    fn test_does_not_echo_self() {
+        let mut bot = EchoBot::new(0.into());
+        bot.handle_event(&TocksEvent::FriendAdded(0.into(), friend()));
+
+        // Our own reply coming back through the event server
+        let reply = bot.handle_event(&message_inserted(0, SELF_ID, hello()));
+        assert!(reply.is_none());
+    }
+
+    #[test]
+    fn test_ignores_unknown_chats_and_accounts() {
+        let mut bot = EchoBot::new(0.into());
+
+        // Friend not known yet
+        let event = message_inserted(0, FRIEND_ID, hello());
+        assert!(bot.handle_event(&event).is_none());
+
+        bot.handle_event(&TocksEvent::FriendList(0.into(), vec![friend()]));
+
+        // Same ids, different account
+        let event = message_inserted(1, FRIEND_ID, hello());
+        assert!(bot.handle_event(&event).is_none());
+
+        bot.handle_event(&TocksEvent::FriendRemoved(0.into(), FRIEND_ID.into()));
+        let event = message_inserted(0, FRIEND_ID, hello());
+        assert!(bot.handle_event(&event).is_none());
+    }
+}
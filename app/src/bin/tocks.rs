@@ -1,6 +1,6 @@
 use futures::{channel::mpsc, prelude::*};
 use log::error;
-use tocks::{EventServer, Tocks};
+use tocks::{EventServer, TeeLogger, Tocks};
 use ui::QmlUi;
 
 #[tokio::main]
@@ -8,7 +8,12 @@ async fn main() {
     let env = env_logger::Env::default()
         .default_filter_or("INFO");
 
-    env_logger::init_from_env(env);
+    // Keep recent log lines around for the in app log viewer
+    let logger = env_logger::Builder::from_env(env).build();
+    let max_level = logger.filter();
+    log::set_boxed_logger(Box::new(TeeLogger::new(Box::new(logger))))
+        .expect("Failed to install logger");
+    log::set_max_level(max_level);
 
     let tocks_event_channel = mpsc::unbounded();
     let ui_event_channel = mpsc::unbounded();
@@ -17,12 +22,13 @@ async fn main() {
     let mut ui = QmlUi::new(ui_event_channel.0.clone(), event_server_channel.1)
         .expect("Failed to start QML UI");
 
+    // The UI receives its events through the event server, so it runs even if
+    // external clients can't connect
     let mut event_server = EventServer::new(
         tocks_event_channel.1,
         event_server_channel.0,
         ui_event_channel.0,
-    )
-    .expect("Failed to start event server");
+    );
 
     let mut tocks = Tocks::new(ui_event_channel.1, tocks_event_channel.0);
 
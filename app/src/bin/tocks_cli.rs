@@ -1,10 +1,13 @@
-use tocks::{EventClient, TocksUiEvent};
-use toxcore::ToxId;
+use tocks::{
+    audio::{AudioData, AudioFrame},
+    AccountId, AutoAcceptPolicy, EventClient, Friend, Status, TocksError, TocksEvent, TocksUiEvent,
+};
+use toxcore::ToxUri;
 
 use futures::prelude::*;
 use structopt::StructOpt;
 
-use std::str::FromStr;
+use std::{path::PathBuf, str::FromStr};
 
 #[derive(StructOpt)]
 enum WriteCommand {
@@ -13,12 +16,26 @@ enum WriteCommand {
         name: String,
         password: String,
     },
+    CreateAccountFromKey {
+        name: String,
+        password: String,
+        secret_key: String,
+    },
+    ImportToxSave {
+        name: String,
+        password: String,
+        path: PathBuf,
+        /// Password the save was encrypted with by the other client
+        #[structopt(long)]
+        save_password: Option<String>,
+    },
     AcceptPendingFriend {
         account: i64,
         user: i64,
     },
     RequestFriend {
         account: i64,
+        /// Hex tox ID or tox: link
         tox_id: String,
         message: String,
     },
@@ -26,6 +43,38 @@ enum WriteCommand {
         account: i64,
         user: i64,
     },
+    SetName {
+        account: i64,
+        name: String,
+    },
+    /// Show as online, away or busy to friends
+    SetStatus {
+        account: i64,
+        #[structopt(possible_values = &["online", "away", "busy"])]
+        status: String,
+    },
+    /// Disconnect from or reconnect to the tox network
+    SetOnline {
+        account: i64,
+        #[structopt(long)]
+        offline: bool,
+    },
+    /// Mark a friend's public key as confirmed out of band
+    VerifyFriend {
+        account: i64,
+        user: i64,
+        /// Clear the verified flag instead
+        #[structopt(long)]
+        revoke: bool,
+    },
+    /// Stop notifications for messages in a chat
+    MuteChat {
+        account: i64,
+        chat: i64,
+        /// Turn notifications back on instead
+        #[structopt(long)]
+        unmute: bool,
+    },
     PurgeUser {
         account: i64,
         user: i64,
@@ -38,64 +87,399 @@ enum WriteCommand {
         account: i64,
         chat: i64,
         message: String,
+        /// Send as an action (/me) instead of a normal message
+        #[structopt(long)]
+        action: bool,
+    },
+    ResendMessage {
+        account: i64,
+        chat: i64,
+        message: i64,
+    },
+    SendReply {
+        account: i64,
+        chat: i64,
+        reply_to: i64,
+        message: String,
+    },
+    SetAutoReply {
+        account: i64,
+        message: Option<String>,
+    },
+    /// Accept friend requests without asking. With no keys, no --all and no
+    /// --message-contains, requests go back to needing a manual accept
+    SetAutoAccept {
+        account: i64,
+        #[structopt(long)]
+        all: bool,
+        /// Accept requests whose message contains this text, ignoring case
+        #[structopt(long)]
+        message_contains: Option<String>,
+        /// Public keys to accept requests from
+        public_keys: Vec<String>,
+    },
+    SendReadMarker {
+        account: i64,
+        chat: i64,
     },
     LoadMessages {
         account: i64,
         chat: i64,
+        /// Number of the newest messages to load
+        #[structopt(long, default_value = "500")]
+        limit: usize,
     },
+    /// Accepts an incoming call, or calls the friend if there isn't one
+    #[structopt(alias = "start-call")]
     JoinCall {
         account: i64,
         chat: i64,
     },
+    #[structopt(alias = "end-call")]
     LeaveCall {
         account: i64,
         chat: i64,
     },
+    /// Puts an active call on hold
+    PauseCall {
+        account: i64,
+        chat: i64,
+        /// Take the call off hold instead
+        #[structopt(long)]
+        resume: bool,
+    },
+}
+
+enum OutputFormat {
+    /// Pretty printed JSON
+    Json,
+    /// One JSON event per line, easy to pipe into other tools
+    Ndjson,
+    /// Colored one line summaries for humans
+    Pretty,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputFormat, String> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "pretty" => Ok(OutputFormat::Pretty),
+            _ => Err(format!("Unknown format {}", s)),
+        }
+    }
 }
 
 #[derive(StructOpt)]
 enum Opts {
-    Read,
+    Read {
+        #[structopt(
+            long,
+            default_value = "ndjson",
+            possible_values = &["json", "ndjson", "pretty"]
+        )]
+        format: OutputFormat,
+        /// Don't color pretty output. Color is only used when stdout is a
+        /// terminal anyway
+        #[structopt(long)]
+        no_color: bool,
+    },
     Write {
         #[structopt(subcommand)]
         command: WriteCommand,
     },
     Raw {
         command: String,
+        /// Only check that the event is valid, don't send it
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    Metrics,
+    Friends {
+        #[structopt(long)]
+        account: i64,
+    },
+    /// Print connectivity details for an account
+    Diag {
+        #[structopt(long)]
+        account: i64,
     },
+    /// Print calls that are ringing or in progress
+    Calls,
+    /// Print recent log lines from the running tocks
+    Logs,
 }
 
 #[tokio::main]
 async fn main() {
-    let client = EventClient::connect().await.unwrap();
-
     let options = Opts::from_args();
 
     match options {
-        Opts::Read => print_events(client).await,
-        Opts::Write { command } => send_command(client, parse_command(command)).await,
-        Opts::Raw { command } => send_command(client, parse_raw(command)).await,
+        Opts::Read { format, no_color } => {
+            let use_color = !no_color && atty::is(atty::Stream::Stdout);
+            print_events(connect().await, format, use_color).await
+        }
+        Opts::Write { command } => send_command(connect().await, parse_command(command)).await,
+        Opts::Raw { command, dry_run } => {
+            let event = match parse_raw(&command) {
+                Ok(event) => event,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            if dry_run {
+                println!("{}", serde_json::to_string(&event).unwrap());
+            } else {
+                send_command(connect().await, event).await
+            }
+        }
+        Opts::Metrics => print_metrics(connect().await).await,
+        Opts::Friends { account } => print_friends(connect().await, account.into()).await,
+        Opts::Diag { account } => print_diagnostics(connect().await, account.into()).await,
+        Opts::Calls => print_calls(connect().await).await,
+        Opts::Logs => print_logs(connect().await).await,
     };
 }
 
-async fn print_events(mut client: EventClient) {
+async fn connect() -> EventClient {
+    EventClient::connect().await.unwrap()
+}
+
+async fn print_events(mut client: EventClient, format: OutputFormat, use_color: bool) {
     while let Some(item) = client.next().await {
         match item {
-            Ok(item) => {
-                println!("{}", serde_json::to_string(&item).unwrap());
+            Ok(item) => println!("{}", format_event(&item, &format, use_color)),
+            // Only this event was bad, the connection is still usable
+            Err(e) => eprintln!("Skipping malformed event: {:#}", e),
+        }
+    }
+
+    eprintln!("Event server closed the connection");
+}
+
+const COLOR_RED: &str = "\x1b[31m";
+const COLOR_GREEN: &str = "\x1b[32m";
+const COLOR_CYAN: &str = "\x1b[36m";
+const COLOR_DIM: &str = "\x1b[2m";
+const COLOR_RESET: &str = "\x1b[0m";
+
+fn format_event(event: &TocksEvent, format: &OutputFormat, use_color: bool) -> String {
+    match format {
+        OutputFormat::Json => serde_json::to_string_pretty(event).unwrap(),
+        OutputFormat::Ndjson => serde_json::to_string(event).unwrap(),
+        OutputFormat::Pretty => format_pretty(event, use_color),
+    }
+}
+
+/// Escape codes end up as garbage in files and pipes, so use_color should
+/// only be set when writing to a terminal
+fn format_pretty(event: &TocksEvent, use_color: bool) -> String {
+    let (color, reset) = if use_color {
+        (event_color(event), COLOR_RESET)
+    } else {
+        ("", "")
+    };
+
+    // Dumping PCM data makes the output unreadable
+    if let TocksEvent::AudioDataReceived(account, chat, frame) = event {
+        return format!(
+            "{}AudioDataReceived{} {} {} {}",
+            color,
+            reset,
+            account,
+            chat.id(),
+            summarize_audio_frame(frame)
+        );
+    }
+
+    // Events are externally tagged, so the serialized form is either the bare
+    // event name or a single entry object of name -> arguments
+    match serde_json::to_value(event).unwrap() {
+        serde_json::Value::Object(map) => map
+            .into_iter()
+            .map(|(name, args)| format!("{}{}{} {}", color, name, reset, args))
+            .collect(),
+        serde_json::Value::String(name) => format!("{}{}{}", color, name, reset),
+        other => other.to_string(),
+    }
+}
+
+fn event_color(event: &TocksEvent) -> &'static str {
+    match event {
+        TocksEvent::Error(_)
+        | TocksEvent::TypedError(_)
+        | TocksEvent::AccountInUse(_)
+        | TocksEvent::AccountRemoved(..) => COLOR_RED,
+        TocksEvent::MessageInserted(..)
+        | TocksEvent::MessagesLoaded(..)
+        | TocksEvent::OlderMessagesLoaded(..)
+        | TocksEvent::MessagesAroundLoaded(..)
+        | TocksEvent::MessageCompleted(..) => COLOR_GREEN,
+        TocksEvent::AudioDataReceived(..) => COLOR_DIM,
+        _ => COLOR_CYAN,
+    }
+}
+
+fn summarize_audio_frame(frame: &AudioFrame) -> String {
+    let (format, samples) = match &frame.data {
+        AudioData::Mono8(data) => ("mono8", data.len()),
+        AudioData::Mono16(data) => ("mono16", data.len()),
+        AudioData::Stereo8(data) => ("stereo8", data.len()),
+        AudioData::Stereo16(data) => ("stereo16", data.len()),
+    };
+
+    format!("[{} samples {} @ {}Hz]", samples, format, frame.sample_rate)
+}
+
+async fn print_metrics(mut client: EventClient) {
+    client
+        .send(TocksUiEvent::QueryMetrics)
+        .await
+        .expect("Failed to send event");
+
+    while let Some(item) = client.next().await {
+        if let Ok(TocksEvent::Metrics(metrics)) = item {
+            println!("{}", serde_json::to_string_pretty(&metrics).unwrap());
+            return;
+        }
+    }
+}
+
+async fn print_logs(mut client: EventClient) {
+    client
+        .send(TocksUiEvent::QueryLogs)
+        .await
+        .expect("Failed to send event");
+
+    while let Some(item) = client.next().await {
+        if let Ok(TocksEvent::Logs(entries)) = item {
+            for entry in &entries {
+                println!(
+                    "{} {} {}: {}",
+                    entry.timestamp.to_rfc3339(),
+                    entry.level,
+                    entry.target,
+                    entry.message
+                );
             }
-            Err(e) => {
-                if let Some(io_err) = e.downcast_ref::<serde_json::error::Error>() {
-                    println!("{:?}", io_err);
+            return;
+        }
+    }
+}
+
+async fn print_friends(mut client: EventClient, account: AccountId) {
+    client
+        .send(TocksUiEvent::QueryFriends(account))
+        .await
+        .expect("Failed to send event");
+
+    while let Some(item) = client.next().await {
+        match item {
+            Ok(TocksEvent::FriendList(list_account, friends)) if list_account == account => {
+                for friend in &friends {
+                    println!("{}", format_friend(friend));
                 }
-                println!("Failed to parse event: {:?}", e);
+                return;
+            }
+            // Other clients' errors are broadcast to us too
+            Ok(TocksEvent::TypedError(TocksError::UnknownAccount(error_account, e)))
+                if error_account == account =>
+            {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            _ => (),
+        }
+    }
+}
+
+async fn print_diagnostics(mut client: EventClient, account: AccountId) {
+    client
+        .send(TocksUiEvent::QueryDiagnostics(account))
+        .await
+        .expect("Failed to send event");
+
+    while let Some(item) = client.next().await {
+        match item {
+            Ok(TocksEvent::Diagnostics(diag_account, diagnostics)) if diag_account == account => {
+                println!("DHT ID:\t{}", diagnostics.dht_id);
+                println!("Connection:\t{}", diagnostics.connection);
+                println!("Online:\t{}", diagnostics.online);
+                println!(
+                    "Friends online:\t{}/{}",
+                    diagnostics.online_friends, diagnostics.friends
+                );
+                return;
+            }
+            // Other clients' errors are broadcast to us too
+            Ok(TocksEvent::TypedError(TocksError::UnknownAccount(error_account, e)))
+                if error_account == account =>
+            {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            _ => (),
+        }
+    }
+}
+
+async fn print_calls(mut client: EventClient) {
+    client
+        .send(TocksUiEvent::QueryCalls)
+        .await
+        .expect("Failed to send event");
+
+    while let Some(item) = client.next().await {
+        if let Ok(TocksEvent::ActiveCalls(calls)) = item {
+            for (account, chat, state) in &calls {
+                println!("{}\t{}\t{:?}", account, chat.id(), state);
             }
+            return;
         }
     }
 }
 
-fn parse_raw(command: String) -> TocksUiEvent {
-    serde_json::from_str::<TocksUiEvent>(&command).expect("Invalid tocks ui event")
+fn status_str(status: &Status) -> &'static str {
+    match status {
+        Status::Online => "online",
+        Status::Away => "away",
+        Status::Busy => "busy",
+        Status::Offline => "offline",
+        Status::Pending => "pending",
+    }
+}
+
+fn format_friend(friend: &Friend) -> String {
+    format!(
+        "{}\t{}\t{}\t{}",
+        friend.id().id(),
+        status_str(friend.status()),
+        friend.name(),
+        friend.public_key()
+    )
+}
+
+/// Parses a raw event before it goes anywhere near the server, which would
+/// otherwise just drop it with an opaque error
+fn parse_raw(command: &str) -> Result<TocksUiEvent, String> {
+    serde_json::from_str::<TocksUiEvent>(command).map_err(|e| {
+        // Generate the examples so they can't drift from the real encoding
+        let example = TocksUiEvent::MessageSent(1.into(), 2.into(), "hello".to_string());
+
+        format!(
+            "Invalid tocks ui event: {}\n\n\
+             Events are JSON objects keyed by the event name with the arguments \
+             as a list, events without arguments are just the name. e.g.\n  \
+             {}\n  {}",
+            e,
+            serde_json::to_string(&TocksUiEvent::Close).unwrap(),
+            serde_json::to_string(&example).unwrap()
+        )
+    })
 }
 
 fn parse_command(command: WriteCommand) -> TocksUiEvent {
@@ -107,42 +491,198 @@ fn parse_command(command: WriteCommand) -> TocksUiEvent {
         WriteCommand::BlockUser { account, user } => {
             TocksUiEvent::BlockUser(account.into(), user.into())
         }
+        WriteCommand::SetName { account, name } => TocksUiEvent::SetSelfName(account.into(), name),
+        WriteCommand::SetStatus { account, status } => {
+            let status = match status.as_str() {
+                "away" => Status::Away,
+                "busy" => Status::Busy,
+                _ => Status::Online,
+            };
+            TocksUiEvent::SetSelfStatus(account.into(), status)
+        }
+        WriteCommand::SetOnline { account, offline } => {
+            TocksUiEvent::SetOnline(account.into(), !offline)
+        }
+        WriteCommand::VerifyFriend {
+            account,
+            user,
+            revoke,
+        } => TocksUiEvent::SetFriendVerified(account.into(), user.into(), !revoke),
+        WriteCommand::MuteChat {
+            account,
+            chat,
+            unmute,
+        } => TocksUiEvent::SetChatMuted(account.into(), chat.into(), !unmute),
         WriteCommand::PurgeUser { account, user } => {
             TocksUiEvent::PurgeUser(account.into(), user.into())
         }
         WriteCommand::CreateAccount { name, password } => {
             TocksUiEvent::CreateAccount(name, password)
         }
+        WriteCommand::CreateAccountFromKey {
+            name,
+            password,
+            secret_key,
+        } => TocksUiEvent::CreateAccountFromKey(name, password, secret_key),
+        WriteCommand::ImportToxSave {
+            name,
+            password,
+            path,
+            save_password,
+        } => TocksUiEvent::ImportToxSave(name, password, path, save_password.unwrap_or_default()),
         WriteCommand::Login {
             account_name,
             password,
         } => TocksUiEvent::Login(account_name, password),
-        WriteCommand::LoadMessages { account, chat } => {
-            TocksUiEvent::LoadMessages(account.into(), chat.into())
+        WriteCommand::SetAutoReply { account, message } => {
+            TocksUiEvent::SetAutoReply(account.into(), message)
         }
+        WriteCommand::SetAutoAccept {
+            account,
+            all,
+            message_contains,
+            public_keys,
+        } => {
+            let policy = if all {
+                AutoAcceptPolicy::All
+            } else if let Some(pattern) = message_contains {
+                AutoAcceptPolicy::MessageContains(pattern)
+            } else if public_keys.is_empty() {
+                AutoAcceptPolicy::Manual
+            } else {
+                AutoAcceptPolicy::Allowlist(public_keys)
+            };
+
+            TocksUiEvent::SetAutoAccept(account.into(), policy)
+        }
+        WriteCommand::SendReadMarker { account, chat } => {
+            TocksUiEvent::SendReadMarker(account.into(), chat.into())
+        }
+        WriteCommand::LoadMessages {
+            account,
+            chat,
+            limit,
+        } => TocksUiEvent::LoadMessages(account.into(), chat.into(), limit),
         WriteCommand::RequestFriend {
             account,
             tox_id,
             message,
         } => TocksUiEvent::RequestFriend(
             account.into(),
-            ToxId::from_str(&tox_id).expect("Invalid tox id"),
+            ToxUri::from_str(&tox_id).expect("Invalid tox id").tox_id,
             message,
         ),
         WriteCommand::SendMessage {
             account,
             chat,
             message,
+            action: false,
         } => TocksUiEvent::MessageSent(account.into(), chat.into(), message),
+        WriteCommand::SendMessage {
+            account,
+            chat,
+            message,
+            action: true,
+        } => TocksUiEvent::ActionSent(account.into(), chat.into(), message),
+        WriteCommand::ResendMessage {
+            account,
+            chat,
+            message,
+        } => TocksUiEvent::ResendMessage(account.into(), chat.into(), message.into()),
+        WriteCommand::SendReply {
+            account,
+            chat,
+            reply_to,
+            message,
+        } => TocksUiEvent::ReplySent(account.into(), chat.into(), reply_to.into(), message),
         WriteCommand::JoinCall { account, chat } => {
             TocksUiEvent::JoinCall(account.into(), chat.into())
         }
         WriteCommand::LeaveCall { account, chat } => {
             TocksUiEvent::LeaveCall(account.into(), chat.into())
         }
+        WriteCommand::PauseCall {
+            account,
+            chat,
+            resume,
+        } => TocksUiEvent::SetCallPaused(account.into(), chat.into(), !resume),
     }
 }
 
 async fn send_command(mut client: EventClient, event: TocksUiEvent) {
     client.send(event).await.expect("Failed to send event");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tocks::{ChatHandle, UserHandle};
+    use toxcore::PublicKey;
+
+    #[test]
+    fn test_format_friend() {
+        let public_key = PublicKey::from_bytes(vec![0xab; PublicKey::SIZE]).unwrap();
+        let friend = Friend::new(
+            UserHandle::from(3),
+            ChatHandle::from(4),
+            public_key,
+            "friend name".to_string(),
+            Status::Away,
+        );
+
+        assert_eq!(
+            format_friend(&friend),
+            format!("3\taway\tfriend name\t{}", "ab".repeat(PublicKey::SIZE))
+        );
+    }
+
+    #[test]
+    fn test_summarize_audio_frame() {
+        let frame = AudioFrame {
+            data: AudioData::Stereo16(vec![0; 1920]),
+            sample_rate: 48000,
+        };
+        assert_eq!(
+            summarize_audio_frame(&frame),
+            "[1920 samples stereo16 @ 48000Hz]"
+        );
+
+        let event = TocksEvent::AudioDataReceived(1.into(), 2.into(), frame);
+        let pretty = format_pretty(&event, true);
+        assert!(pretty.contains("AudioDataReceived"));
+        assert!(pretty.ends_with("1 2 [1920 samples stereo16 @ 48000Hz]"));
+    }
+
+    #[test]
+    fn test_format_pretty() {
+        let event = TocksEvent::Error("oops".to_string());
+        assert_eq!(
+            format_pretty(&event, true),
+            format!("{}Error{} \"oops\"", COLOR_RED, COLOR_RESET)
+        );
+        assert_eq!(format_pretty(&event, false), "Error \"oops\"");
+    }
+
+    #[test]
+    fn test_parse_raw() {
+        let event = TocksUiEvent::MessageSent(1.into(), 2.into(), "hello".to_string());
+        let serialized = serde_json::to_string(&event).unwrap();
+
+        assert!(matches!(
+            parse_raw(&serialized),
+            Ok(TocksUiEvent::MessageSent(_, _, message)) if message == "hello"
+        ));
+        assert!(matches!(parse_raw("\"Close\""), Ok(TocksUiEvent::Close)));
+    }
+
+    #[test]
+    fn test_parse_raw_error_shows_schema() {
+        let err = parse_raw("{\"NotAnEvent\": []}").unwrap_err();
+        assert!(err.starts_with("Invalid tocks ui event"));
+        assert!(err.contains("\"Close\""));
+        assert!(err.contains("MessageSent"));
+
+        assert!(parse_raw("not json").is_err());
+    }
+}
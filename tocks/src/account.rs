@@ -1,16 +1,32 @@
 use crate::{
     audio::AudioFrame,
-    calls::{CallEvent, CallManager, CallState},
+    auto_accept::{AutoAccept, AutoAcceptPolicy},
+    auto_reply::AutoReply,
+    bootstrap::{self, BootstrapNode, BootstrapRetry},
+    calls::{initial_audio_bit_rate, CallEndReason, CallEvent, CallManager, CallState},
     contact::{Friend, Status, User, UserManager},
-    error::ExitError,
-    savemanager::SaveManager,
-    storage::{ChatHandle, ChatLogEntry, ChatMessageId, Storage, UserHandle},
+    error::{AccountInUseError, ExitError, PasswordRequiredError},
+    flood::{FloodGuard, FloodLimits},
+    metrics::COUNTERS,
+    read_marker::{PeerReadMessages, ReadMarker},
+    removal_hint::RemovalHints,
+    reply_marker::ReplyMarker,
+    savemanager::{self, SaveManager},
+    storage::{
+        ChatHandle, ChatLogEntry, ChatMessageId, SendState, Storage, UnsentMessage, UserHandle,
+    },
+    typing::TypingState,
     TocksEvent, APP_DIRS,
 };
 
-use toxcore::{Event as CoreEvent, Message, PublicKey, Receipt, Status as ToxStatus, Tox, ToxId};
+use toxcore::{
+    error::{ToxBuildError, ToxCreationError, ToxSendMessageError},
+    ConnectionStatus, Event as CoreEvent, Friend as ToxFriend, Message, PassKey, PublicKey,
+    Receipt, SaveData, SecretKey, Status as ToxStatus, Tox, ToxId,
+};
 
-use anyhow::{anyhow, Context, Error, Result};
+use anyhow::{anyhow, bail, Context, Error, Result};
+use chrono::{DateTime, TimeZone, Utc};
 use fslock::LockFile;
 use futures::{channel::mpsc, prelude::*};
 use lazy_static::lazy_static;
@@ -18,10 +34,21 @@ use log::*;
 use platform_dirs::AppDirs;
 use serde::{Deserialize, Serialize};
 
-use std::{collections::HashMap, fmt, fs, io::ErrorKind, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// How long we wait on a read receipt before giving up on it. Receipts can
+/// get lost without the friend ever disconnecting
+const RECEIPT_TIMEOUT: Duration = Duration::from_secs(120);
 
 lazy_static! {
     pub static ref TOX_SAVE_DIR: PathBuf = AppDirs::new(Some("tox"), false).unwrap().config_dir;
+    static ref BOOTSTRAP_NODES_PATH: PathBuf = APP_DIRS.config_dir.join("bootstrap_nodes.json");
 }
 
 #[derive(Debug)]
@@ -29,10 +56,16 @@ pub(crate) enum AccountEvent {
     FriendAdded(Friend),
     ChatMessageInserted(ChatHandle, ChatLogEntry),
     ChatMessageCompleted(ChatHandle, ChatMessageId),
+    ChatMessageSendStateChanged(ChatHandle, ChatMessageId, SendState),
     FriendStatusChanged(UserHandle, Status),
     UserNameChanged(UserHandle, String),
     CallStateChanged(ChatHandle, CallState),
+    CallEnded(ChatHandle, CallEndReason),
     AudioDataReceived(ChatHandle, AudioFrame),
+    PeerReadUpTo(ChatHandle, ChatMessageId),
+    UnreadCountChanged(ChatHandle, usize),
+    FriendPossiblyRemoved(UserHandle, bool),
+    UnableToConnect,
 }
 
 impl From<(AccountId, AccountEvent)> for TocksEvent {
@@ -45,6 +78,9 @@ impl From<(AccountId, AccountEvent)> for TocksEvent {
             AccountEvent::ChatMessageCompleted(chat, id) => {
                 TocksEvent::MessageCompleted(v.0, chat, id)
             }
+            AccountEvent::ChatMessageSendStateChanged(chat, id, state) => {
+                TocksEvent::MessageSendStateChanged(v.0, chat, id, state)
+            }
             AccountEvent::FriendStatusChanged(user, status) => {
                 TocksEvent::FriendStatusChanged(v.0, user, status)
             }
@@ -54,9 +90,20 @@ impl From<(AccountId, AccountEvent)> for TocksEvent {
             AccountEvent::CallStateChanged(chat, call_state) => {
                 TocksEvent::ChatCallStateChanged(v.0, chat, call_state)
             }
+            AccountEvent::CallEnded(chat, reason) => TocksEvent::CallEnded(v.0, chat, reason),
             AccountEvent::AudioDataReceived(chat, frame) => {
                 TocksEvent::AudioDataReceived(v.0, chat, frame)
             }
+            AccountEvent::PeerReadUpTo(chat, message_id) => {
+                TocksEvent::PeerReadUpTo(v.0, chat, message_id)
+            }
+            AccountEvent::UnreadCountChanged(chat, count) => {
+                TocksEvent::UnreadCountChanged(v.0, chat, count)
+            }
+            AccountEvent::FriendPossiblyRemoved(user, removed) => {
+                TocksEvent::FriendPossiblyRemoved(v.0, user, removed)
+            }
+            AccountEvent::UnableToConnect => TocksEvent::UnableToConnect(v.0),
         }
     }
 }
@@ -68,11 +115,28 @@ pub(crate) struct Account {
     user_manager: UserManager,
     call_manager: CallManager,
     storage: Storage,
-    outgoing_messages: HashMap<Receipt, (ChatHandle, ChatMessageId)>,
+    // Messages waiting on a read receipt, with when they were handed to tox
+    outgoing_messages: HashMap<Receipt, (ChatHandle, ChatMessageId, Instant)>,
+    auto_reply: AutoReply,
+    auto_accept: AutoAccept,
+    flood_guard: FloodGuard,
+    expand_shortcodes: bool,
+    peer_read_messages: PeerReadMessages,
+    // Reply marker for the next incoming message of each chat, with the
+    // message it quotes. None if the peer quoted something we don't have
+    pending_replies: HashMap<ChatHandle, (ReplyMarker, Option<ChatMessageId>)>,
+    // Whether tox is iterating. Offline accounts keep all their state
+    online: bool,
+    typing: TypingState,
+    removal_hints: RemovalHints,
+    bootstrap_nodes: Vec<BootstrapNode>,
+    bootstrap_retry: BootstrapRetry,
     user_handle: UserHandle,
     public_key: PublicKey,
     tox_id: ToxId,
     name: String,
+    // Our own status while connected, what friends see when we're away
+    self_status: ToxStatus,
     toxcore_callback_rx: mpsc::UnboundedReceiver<CoreEvent>,
     account_event_tx: mpsc::UnboundedSender<AccountEvent>,
 }
@@ -86,19 +150,153 @@ impl Account {
         let account_lock = lock_account(account_name.clone())?;
 
         let save_manager = create_save_manager(account_name.clone(), &password)?;
-        let (mut tox, toxcore_callback_rx) = create_tox(save_manager.load())?;
+        let savedata = match handle_savedata_failure(save_manager.load())? {
+            Some(d) => SaveData::ToxSave(d),
+            None => SaveData::None,
+        };
+        let (tox, toxcore_callback_rx) = match create_tox(savedata) {
+            Ok(v) => v,
+            Err(e) => {
+                // The save could be read but toxcore can't make sense of it,
+                // the backup is better than losing the identity
+                let backup = save_manager.load_backup().map_err(|_| e)?;
+                warn!("Tox save for {} is corrupt, using backup", account_name);
+                create_tox(SaveData::ToxSave(backup))?
+            }
+        };
 
-        let self_public_key = tox.self_public_key();
-        let tox_id = tox.self_address();
-        let mut name = tox.self_name();
+        Self::from_tox(
+            account_name,
+            account_lock,
+            save_manager,
+            tox,
+            toxcore_callback_rx,
+            account_event_tx,
+        )
+    }
 
-        if name.is_empty() {
+    /// Creates a new account with an identity migrated from another client
+    pub fn from_secret_key(
+        account_name: String,
+        password: String,
+        secret_key: SecretKey,
+        account_event_tx: mpsc::UnboundedSender<AccountEvent>,
+    ) -> Result<Account> {
+        Self::import(
+            account_name,
+            password,
+            SaveData::SecretKey(secret_key.as_bytes().to_vec()),
+            account_event_tx,
+        )
+    }
+
+    /// Creates a new account from a save file written by another tox client.
+    /// The save is decrypted with save_password, which is independent of the
+    /// password protecting the new account
+    pub fn from_tox_save_file(
+        account_name: String,
+        password: String,
+        path: &Path,
+        save_password: &str,
+        account_event_tx: mpsc::UnboundedSender<AccountEvent>,
+    ) -> Result<Account> {
+        let savedata = read_tox_save(path, save_password)?;
+
+        Self::import(
+            account_name,
+            password,
+            SaveData::ToxSave(savedata),
+            account_event_tx,
+        )
+    }
+
+    fn import(
+        account_name: String,
+        password: String,
+        savedata: SaveData,
+        account_event_tx: mpsc::UnboundedSender<AccountEvent>,
+    ) -> Result<Account> {
+        let account_lock = lock_account(account_name.clone())?;
+
+        if retrieve_account_list()
+            .unwrap_or_default()
+            .contains(&account_name)
+        {
+            return Err(anyhow!("Account {} already exists", account_name));
+        }
+
+        let save_manager = create_save_manager(account_name.clone(), &password)?;
+        let (tox, toxcore_callback_rx) =
+            create_tox(savedata).map_err(describe_tox_creation_error)?;
+
+        // Two accounts with one identity would fight over the same tox
+        // network presence, and only one of them would get each message
+        let existing_accounts = retrieve_account_list().unwrap_or_default();
+        if let Some(existing) =
+            account_with_identity(&existing_accounts, &tox.self_public_key(), storage_path)
+        {
+            bail!("Identity is already used by account {}", existing);
+        }
+
+        // create_storage falls back to a RAM DB if the on disk DB belongs to
+        // someone else. That's fine for existing accounts, but an imported
+        // identity should not silently take over someone else's history
+        Storage::open(
+            storage_path(&account_name),
+            &tox.self_public_key(),
+            &account_name,
+        )
+        .context("Existing account data does not match imported identity")?;
+
+        Self::from_tox(
+            account_name,
+            account_lock,
+            save_manager,
+            tox,
+            toxcore_callback_rx,
+            account_event_tx,
+        )
+    }
+
+    fn from_tox(
+        account_name: String,
+        account_lock: LockFile,
+        save_manager: SaveManager,
+        mut tox: Tox,
+        toxcore_callback_rx: mpsc::UnboundedReceiver<CoreEvent>,
+        account_event_tx: mpsc::UnboundedSender<AccountEvent>,
+    ) -> Result<Account> {
+        if tox.self_name().is_empty() {
             tox.self_set_name(&account_name)
                 .context("Failed to initialize account name")?;
-            name = tox.self_name();
         }
 
-        let mut storage = create_storage(&account_name, &tox.self_public_key(), &tox.self_name())?;
+        let storage = create_storage(&account_name, &tox.self_public_key(), &tox.self_name())?;
+
+        Self::with_storage(
+            account_lock,
+            save_manager,
+            tox,
+            toxcore_callback_rx,
+            storage,
+            account_event_tx,
+        )
+    }
+
+    /// Everything an account needs once the files backing it are settled.
+    /// Tests come in here with a RAM DB and a mocked tox
+    fn with_storage(
+        account_lock: LockFile,
+        save_manager: SaveManager,
+        mut tox: Tox,
+        toxcore_callback_rx: mpsc::UnboundedReceiver<CoreEvent>,
+        mut storage: Storage,
+        account_event_tx: mpsc::UnboundedSender<AccountEvent>,
+    ) -> Result<Account> {
+        let self_public_key = tox.self_public_key();
+        let tox_id = tox.self_address();
+        let name = tox.self_name();
+        let self_status = tox.self_status();
 
         let mut user_manager = UserManager::new();
 
@@ -109,6 +307,14 @@ impl Account {
 
         let self_user_handle = storage.self_user_handle();
 
+        let mut auto_reply = AutoReply::default();
+        auto_reply.set_message(storage.auto_reply().context("Failed to load auto reply")?);
+
+        let bootstrap_nodes = bootstrap::load_nodes(&BOOTSTRAP_NODES_PATH).unwrap_or_else(|e| {
+            error!("Failed to load bootstrap nodes: {:?}", e);
+            Vec::new()
+        });
+
         Ok(Account {
             _account_lock: account_lock,
             tox,
@@ -118,10 +324,23 @@ impl Account {
             toxcore_callback_rx,
             storage,
             outgoing_messages: HashMap::new(),
+            auto_reply,
+            auto_accept: AutoAccept::default(),
+            flood_guard: FloodGuard::default(),
+            // Opt in, bots and scripts expect their messages to go out as is
+            expand_shortcodes: false,
+            peer_read_messages: PeerReadMessages::default(),
+            pending_replies: HashMap::new(),
+            online: true,
+            typing: TypingState::default(),
+            removal_hints: RemovalHints::new(Instant::now()),
+            bootstrap_nodes,
+            bootstrap_retry: BootstrapRetry::new(Instant::now()),
             user_handle: self_user_handle,
             public_key: self_public_key,
             tox_id,
             name,
+            self_status,
             account_event_tx,
         })
     }
@@ -130,7 +349,6 @@ impl Account {
         &self.user_handle
     }
 
-    #[allow(dead_code)]
     pub fn public_key(&self) -> &PublicKey {
         &self.public_key
     }
@@ -143,10 +361,81 @@ impl Account {
         &self.name
     }
 
+    pub fn set_name(&mut self, name: &str) -> Result<()> {
+        self.tox
+            .self_set_name(name)
+            .context("Failed to set tox name")?;
+        self.name = self.tox.self_name();
+
+        self.storage
+            .update_user_name(&self.user_handle, &self.name)
+            .context("Failed to update self name in storage")?;
+
+        self.save_manager
+            .save(&self.tox.get_savedata())
+            .context("Failed to save tox data after name change")?;
+
+        Ok(())
+    }
+
+    /// Offline and Pending aren't statuses we can pick, going offline is
+    /// go_offline
+    pub fn set_self_status(&mut self, status: Status) -> Result<()> {
+        let status = match status {
+            Status::Online => ToxStatus::Online,
+            Status::Away => ToxStatus::Away,
+            Status::Busy => ToxStatus::Busy,
+            Status::Offline | Status::Pending => bail!("Cannot set own status to {:?}", status),
+        };
+
+        self.tox
+            .self_set_status(status)
+            .context("Failed to set tox status")?;
+        self.self_status = status;
+        self.auto_reply.self_status_changed(status);
+
+        self.save_manager
+            .save(&self.tox.get_savedata())
+            .context("Failed to save tox data after status change")?;
+
+        Ok(())
+    }
+
+    /// Messages longer than this will be split into multiple tox messages
+    pub fn max_message_length(&self) -> usize {
+        self.tox.max_message_length()
+    }
+
     pub fn friends(&self) -> impl Iterator<Item = &Friend> {
         self.user_manager.friends()
     }
 
+    pub fn diagnostics(&self) -> Diagnostics {
+        let connection = match self.tox.self_connection_status() {
+            ConnectionStatus::None => "none",
+            ConnectionStatus::Tcp => "tcp",
+            ConnectionStatus::Udp => "udp",
+        };
+
+        let online_friends = self
+            .friends()
+            .filter(|friend| {
+                matches!(
+                    friend.status(),
+                    Status::Online | Status::Away | Status::Busy
+                )
+            })
+            .count();
+
+        Diagnostics {
+            dht_id: self.tox.self_dht_id(),
+            connection: connection.to_string(),
+            online: self.online,
+            friends: self.friends().count(),
+            online_friends,
+        }
+    }
+
     pub fn blocked_users(&self) -> Result<impl Iterator<Item = User>> {
         Ok(self
             .storage
@@ -163,6 +452,8 @@ impl Account {
             return Ok(friend);
         }
 
+        ensure_not_self(&self.public_key, friend.public_key())?;
+
         bundle.tox_friend = Some(
             self.tox
                 .add_friend_norequest(friend.public_key())
@@ -182,11 +473,120 @@ impl Account {
         Ok(friend)
     }
 
+    /// Records whether the user confirmed friend's public key out of band
+    pub fn set_friend_verified(&mut self, friend_id: &UserHandle, verified: bool) -> Result<()> {
+        if !self
+            .user_manager
+            .friends()
+            .any(|friend| friend.id() == friend_id)
+        {
+            bail!("Unknown friend {}", friend_id.id());
+        }
+
+        self.storage
+            .set_user_verified(friend_id, verified)
+            .context("Failed to save verification state to DB")?;
+
+        self.user_manager
+            .friend_by_user_handle(friend_id)
+            .friend
+            .set_verified(verified);
+
+        Ok(())
+    }
+
+    /// Silences notifications for messages in chat
+    pub fn set_chat_muted(&mut self, chat_handle: &ChatHandle, muted: bool) -> Result<()> {
+        let friend = self
+            .user_manager
+            .friend_bundles_mut()
+            .map(|bundle| &mut bundle.friend)
+            .find(|friend| friend.chat_handle() == chat_handle)
+            .ok_or_else(|| anyhow!("Unknown chat {}", chat_handle.id()))?;
+
+        self.storage
+            .set_chat_muted(chat_handle, muted)
+            .context("Failed to save mute state to DB")?;
+
+        friend.set_muted(muted);
+
+        Ok(())
+    }
+
+    /// Messages from the friend we haven't read yet. Everything counts if the
+    /// chat has never been read
+    pub fn unread_count(&self, chat_handle: &ChatHandle) -> Result<usize> {
+        let read_time = self
+            .storage
+            .chat_read_time(chat_handle)
+            .context("Failed to load read time")?
+            .unwrap_or_else(|| Utc.timestamp(0, 0));
+
+        self.storage.unread_count(chat_handle, read_time)
+    }
+
+    /// Records that we've read chat up to time. Read times never move
+    /// backwards, the stored read time is returned
+    pub fn mark_chat_read(
+        &mut self,
+        chat_handle: &ChatHandle,
+        time: DateTime<Utc>,
+    ) -> Result<DateTime<Utc>> {
+        if !self
+            .user_manager
+            .friends()
+            .any(|friend| friend.chat_handle() == chat_handle)
+        {
+            bail!("Unknown chat {}", chat_handle.id());
+        }
+
+        let previous = self
+            .storage
+            .chat_read_time(chat_handle)
+            .context("Failed to load previous read time")?;
+
+        if let Some(previous) = previous {
+            if previous >= time {
+                return Ok(previous);
+            }
+        }
+
+        self.storage
+            .set_chat_read_time(chat_handle, &time)
+            .context("Failed to save read time to DB")?;
+
+        Ok(time)
+    }
+
     pub fn request_friend(&mut self, tox_id: ToxId, message: String) -> Result<Friend> {
+        ensure_not_self(&self.public_key, &tox_id.public_key())?;
+
+        // They asked first. Requesting them back is the same as accepting,
+        // going through toxcore again would leave us with a second friend
+        // for the same key
+        let pending_friend = self
+            .user_manager
+            .find_by_public_key(&tox_id.public_key())
+            .filter(|friend| *friend.status() == Status::Pending)
+            .map(|friend| *friend.id());
+
+        if let Some(friend_id) = pending_friend {
+            self.storage
+                .set_user_tox_id(&friend_id, &tox_id)
+                .context("Failed to save friend tox id")?;
+
+            let friend = self
+                .add_pending_friend(&friend_id)
+                .context("Failed to accept crossed friend request")?
+                .clone();
+
+            return Ok(friend);
+        }
+
         let name = tox_id.to_string();
         let tox_friend = self
             .tox
-            .add_friend(tox_id, message)
+            .add_friend(tox_id.clone(), message)
             .context("Failed to add friend")?;
 
         let friend = self
@@ -194,6 +594,11 @@ impl Account {
             .add_friend(tox_friend.public_key(), name)
             .context("Failed to add friend to DB")?;
 
+        // Remembered so the friend can be requested again after removal
+        self.storage
+            .set_user_tox_id(friend.id(), &tox_id)
+            .context("Failed to save friend tox id")?;
+
         self.user_manager.add_friend(friend.clone(), tox_friend);
 
         self.save_manager
@@ -250,27 +655,91 @@ impl Account {
         chat_handle: &ChatHandle,
         message: String,
     ) -> Result<Vec<ChatLogEntry>> {
-        let messages = crate::message_parser::parse(message, self.tox.max_message_length())
+        self.send_message_impl(chat_handle, message, None, false)
+    }
+
+    /// Sends message as an action (/me), if it gets split every part is an
+    /// action
+    pub fn send_action(
+        &mut self,
+        chat_handle: &ChatHandle,
+        message: String,
+    ) -> Result<Vec<ChatLogEntry>> {
+        self.send_message_impl(chat_handle, message, None, true)
+    }
+
+    /// Sends message as a reply to reply_to. If the message gets split only
+    /// the first part is marked as a reply
+    pub fn send_reply(
+        &mut self,
+        chat_handle: &ChatHandle,
+        reply_to: &ChatMessageId,
+        message: String,
+    ) -> Result<Vec<ChatLogEntry>> {
+        self.send_message_impl(chat_handle, message, Some(*reply_to), false)
+    }
+
+    fn send_message_impl(
+        &mut self,
+        chat_handle: &ChatHandle,
+        message: String,
+        mut reply_to: Option<ChatMessageId>,
+        action: bool,
+    ) -> Result<Vec<ChatLogEntry>> {
+        let quoted = match &reply_to {
+            Some(reply_to) => Some(
+                self.storage
+                    .message(chat_handle, reply_to)
+                    .context("Failed to find message being replied to")?,
+            ),
+            None => None,
+        };
+
+        let message = if self.expand_shortcodes {
+            crate::message_parser::expand_shortcodes(&message)
+        } else {
+            message
+        };
+
+        let mut messages = crate::message_parser::parse(message, self.tox.max_message_length())
             .context("Failed to parse input message")?;
 
+        if action {
+            messages = messages
+                .into_iter()
+                .map(|message| match message {
+                    Message::Normal(s) | Message::Action(s) => Message::Action(s),
+                })
+                .collect();
+        }
+
+        // Messages to a friend whose request we haven't accepted yet are
+        // queued like messages to an offline friend. They go out once the
+        // friend is accepted and comes online
         let tox_friend = self
             .user_manager
             .friend_by_chat_handle(&chat_handle)
             .tox_friend
             .as_ref();
 
-        if tox_friend.is_none() {
-            return Err(anyhow!("Cannot send message to unaccepted friend"));
-        }
-
-        let tox_friend = tox_friend.unwrap();
-
         let mut ret = Vec::new();
 
         for message in messages {
+            let reply_to = reply_to.take();
+
             // Attempt to send the message to toxcore first. This ensures that we do
             // not store a message in the DB that is not sendable
-            let receipt = if tox_friend.status() != ToxStatus::Offline {
+            // A friend toxcore no longer knows about gets the message queued,
+            // their old friend number could belong to someone else by now
+            let sendable_friend = tox_friend
+                .filter(|tox_friend| sends_immediately(self.online, Some(tox_friend.status())))
+                .filter(|tox_friend| self.tox.friend_exists(tox_friend));
+
+            let receipt = if let Some(tox_friend) = sendable_friend {
+                if let (Some(_), Some(quoted)) = (&reply_to, &quoted) {
+                    send_reply_marker(&mut self.tox, tox_friend, quoted, &message);
+                }
+
                 Some(
                     self.tox
                         .send_message(&tox_friend, &message)
@@ -280,33 +749,478 @@ impl Account {
                 None
             };
 
-            let mut chat_log_entry = self
-                .storage
-                .push_message(chat_handle, self.user_handle, message)
-                .context("Failed to insert message into storage")?;
+            let mut chat_log_entry = match &reply_to {
+                Some(reply_to) => {
+                    self.storage
+                        .push_reply(chat_handle, self.user_handle, message, reply_to)
+                }
+                None => self
+                    .storage
+                    .push_message(chat_handle, self.user_handle, message),
+            }
+            .context("Failed to insert message into storage")?;
+
+            let send_state = if receipt.is_some() {
+                SendState::Sent
+            } else {
+                SendState::Unsent
+            };
 
             chat_log_entry.set_complete(false);
+            chat_log_entry.set_send_state(Some(send_state));
 
             self.storage
-                .add_unresolved_message(chat_log_entry.id())
+                .add_unresolved_message(chat_log_entry.id(), send_state)
                 .context("Failed to flag message as un-delivered in storage")?;
 
             if let Some(receipt) = receipt {
-                self.outgoing_messages
-                    .insert(receipt, (*chat_handle, *chat_log_entry.id()));
+                self.outgoing_messages.insert(
+                    receipt,
+                    (*chat_handle, *chat_log_entry.id(), Instant::now()),
+                );
             }
 
             ret.push(chat_log_entry);
         }
 
+        // The message is out, whatever was being typed has been sent
+        if let Err(e) = self.stop_typing(chat_handle) {
+            warn!("Failed to clear typing state: {}", e);
+        }
+
         Ok(ret)
     }
 
-    // FIXME: In the future this API should support some bounds on which segment
-    // of the chat history we want to load, but for now, since no one who uses
-    // this will have enough messages for it to matter, we just load them all
-    pub fn load_messages(&mut self, chat_handle: &ChatHandle) -> Result<Vec<ChatLogEntry>> {
-        self.storage.load_messages(chat_handle)
+    fn next_bootstrap(&self) -> Option<Instant> {
+        if !self.online || self.bootstrap_nodes.is_empty() {
+            return None;
+        }
+
+        self.bootstrap_retry.next_attempt()
+    }
+
+    fn retry_bootstrap(&mut self) -> Result<()> {
+        if self.tox.self_connection_status() != ConnectionStatus::None {
+            self.bootstrap_retry.connected(Instant::now());
+            return Ok(());
+        }
+
+        for node in &self.bootstrap_nodes {
+            if let Err(e) = self.tox.bootstrap(&node.host, node.port, &node.public_key) {
+                warn!(
+                    "Failed to bootstrap from {}:{}: {}",
+                    node.host, node.port, e
+                );
+            }
+        }
+
+        if self.bootstrap_retry.attempted(Instant::now()) {
+            send_account_event(&self.account_event_tx, AccountEvent::UnableToConnect)
+                .context("Failed to propagate connection failure")?;
+        }
+
+        Ok(())
+    }
+
+    /// Called on every keystroke in chat's message input
+    pub fn set_typing(&mut self, chat_handle: &ChatHandle) -> Result<()> {
+        if !self.typing.keystroke(*chat_handle, Instant::now()) {
+            return Ok(());
+        }
+
+        self.send_typing(chat_handle, true)
+    }
+
+    fn stop_typing(&mut self, chat_handle: &ChatHandle) -> Result<()> {
+        if !self.typing.stop(chat_handle) {
+            return Ok(());
+        }
+
+        self.send_typing(chat_handle, false)
+    }
+
+    /// Typing state is best effort, if there is nobody to tell we just don't
+    fn send_typing(&mut self, chat_handle: &ChatHandle, typing: bool) -> Result<()> {
+        if !self.online {
+            return Ok(());
+        }
+
+        let bundle = self.user_manager.friend_by_chat_handle(chat_handle);
+        let tox_friend = match &bundle.tox_friend {
+            Some(tox_friend) if self.tox.friend_exists(tox_friend) => tox_friend,
+            _ => return Ok(()),
+        };
+
+        self.tox
+            .set_typing(tox_friend, typing)
+            .context("Failed to send typing state")?;
+
+        Ok(())
+    }
+
+    /// Stops talking to the tox network while keeping all state. Friends show
+    /// as offline and outgoing messages queue up until go_online
+    pub fn go_offline(&mut self) -> Result<()> {
+        if !self.online {
+            return Ok(());
+        }
+
+        self.online = false;
+        self.abandon_receipts();
+
+        for bundle in self.user_manager.friend_bundles_mut() {
+            let friend = &mut bundle.friend;
+            if *friend.status() == Status::Offline || *friend.status() == Status::Pending {
+                continue;
+            }
+
+            friend.set_status(Status::Offline);
+            friend.set_last_online(Some(Utc::now()));
+            send_account_event(
+                &self.account_event_tx,
+                AccountEvent::FriendStatusChanged(*friend.id(), Status::Offline),
+            )
+            .context("Failed to propagate status change")?;
+        }
+
+        Ok(())
+    }
+
+    pub fn go_online(&mut self) -> Result<()> {
+        if self.online {
+            return Ok(());
+        }
+
+        self.online = true;
+
+        // After a short break toxcore may never have noticed we were gone, so
+        // there won't be a status callback for friends it still thinks are
+        // connected
+        let connected: Vec<(ChatHandle, ToxFriend)> = self
+            .user_manager
+            .friend_bundles_mut()
+            .filter_map(|bundle| {
+                let tox_friend = bundle.tox_friend.as_ref()?;
+                if tox_friend.status() == ToxStatus::Offline {
+                    return None;
+                }
+                Some((*bundle.friend.chat_handle(), tox_friend.clone()))
+            })
+            .collect();
+
+        for (chat_handle, tox_friend) in connected {
+            self.handle_toxcore_event(CoreEvent::StatusUpdated(tox_friend))
+                .with_context(|| {
+                    format!("Failed to restore status of chat {}", chat_handle.id())
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Receipts for messages in flight when we go offline never arrive.
+    /// Forgetting them lets the next flush send those messages again instead
+    /// of waiting for them to time out
+    fn abandon_receipts(&mut self) {
+        for _ in self.outgoing_messages.drain() {
+            COUNTERS.receipt_abandoned();
+        }
+    }
+
+    fn next_receipt_timeout(&self) -> Option<Instant> {
+        self.outgoing_messages
+            .values()
+            .map(|(_, _, sent)| *sent + RECEIPT_TIMEOUT)
+            .min()
+    }
+
+    /// Gives up on receipts that took too long. The message is marked failed
+    /// so the user can retry it, and the next flush sends it again
+    fn expire_receipts(&mut self, now: Instant) -> Result<()> {
+        let mut expired = Vec::new();
+        self.outgoing_messages.retain(|_, (chat, id, sent)| {
+            if now < *sent + RECEIPT_TIMEOUT {
+                return true;
+            }
+
+            expired.push((*chat, *id));
+            false
+        });
+
+        for (chat_handle, message_id) in expired {
+            warn!("Timed out waiting for receipt of message {}", message_id);
+            COUNTERS.receipt_abandoned();
+
+            if let Err(e) = self.storage.set_send_state(&message_id, SendState::Failed) {
+                warn!("Failed to save send state: {}", e);
+            }
+
+            send_account_event(
+                &self.account_event_tx,
+                AccountEvent::ChatMessageSendStateChanged(
+                    chat_handle,
+                    message_id,
+                    SendState::Failed,
+                ),
+            )
+            .context("Failed to propagate send state")?;
+        }
+
+        Ok(())
+    }
+
+    fn send_unresolved_messages(
+        &mut self,
+        chat_handle: &ChatHandle,
+        tox_friend: &ToxFriend,
+    ) -> Result<()> {
+        let messages = self
+            .storage
+            .unresovled_messages(chat_handle)
+            .context("Failed to retrieve unsent messages")?;
+
+        let messages = messages_without_receipts(
+            messages,
+            self.outgoing_messages.values().map(|(_, id, _)| id),
+        );
+
+        // Same as a fresh send, a removed friend's number could belong to
+        // someone else by now
+        let friend_exists = self.tox.friend_exists(tox_friend);
+
+        for message in messages {
+            let res = if friend_exists {
+                resend_reply_marker(
+                    &mut self.tox,
+                    &mut self.storage,
+                    chat_handle,
+                    tox_friend,
+                    &message,
+                );
+                self.tox.send_message(tox_friend, message.message())
+            } else {
+                Err(ToxSendMessageError::InvalidFriendId)
+            };
+            let receipt = match self.record_send_state(chat_handle, message.id(), res) {
+                Ok(receipt) => receipt,
+                Err(e) => {
+                    // Stays failed for the user to retry, one refused message
+                    // shouldn't hold up the rest of the queue
+                    warn!("Failed to send unsent message {}: {:#}", message.id(), e);
+                    continue;
+                }
+            };
+            COUNTERS.message_send_retried();
+            self.outgoing_messages
+                .insert(receipt, (*chat_handle, *message.id(), Instant::now()));
+        }
+
+        Ok(())
+    }
+
+    pub fn resend_message(
+        &mut self,
+        chat_handle: &ChatHandle,
+        message_id: &ChatMessageId,
+    ) -> Result<()> {
+        let tox_friend = self
+            .user_manager
+            .friend_by_chat_handle(&chat_handle)
+            .tox_friend
+            .as_ref()
+            .ok_or_else(|| anyhow!("Cannot send message to unaccepted friend"))?;
+
+        if !self.online || tox_friend.status() == ToxStatus::Offline {
+            // Unresolved messages are flushed when the friend comes online
+            return Err(anyhow!("Cannot resend message to offline friend"));
+        }
+
+        let message = self
+            .storage
+            .unresolved_message(chat_handle, message_id)
+            .context("Failed to retrieve unsent message")?
+            .ok_or_else(|| anyhow!("Message {} is not pending delivery", message_id))?;
+
+        let res = if self.tox.friend_exists(tox_friend) {
+            resend_reply_marker(
+                &mut self.tox,
+                &mut self.storage,
+                chat_handle,
+                tox_friend,
+                &message,
+            );
+            self.tox.send_message(&tox_friend, message.message())
+        } else {
+            Err(ToxSendMessageError::InvalidFriendId)
+        };
+        let receipt = self
+            .record_send_state(chat_handle, message_id, res)
+            .context("Failed to resend message to tox friend")?;
+
+        // Any receipt from an earlier attempt is stale now, the message
+        // stays in the chat log as is and gets completed by whichever
+        // receipt we track
+        let num_outgoing = self.outgoing_messages.len();
+        self.outgoing_messages
+            .retain(|_, (_, id, _)| id != message_id);
+        if self.outgoing_messages.len() < num_outgoing {
+            COUNTERS.receipt_abandoned();
+        }
+
+        self.outgoing_messages
+            .insert(receipt, (*chat_handle, *message_id, Instant::now()));
+        COUNTERS.message_send_retried();
+
+        Ok(())
+    }
+
+    /// Persists the outcome of sending an already stored message so it shows
+    /// up correctly after a restart, and tells the UI
+    fn record_send_state(
+        &mut self,
+        chat_handle: &ChatHandle,
+        message_id: &ChatMessageId,
+        res: Result<Receipt, ToxSendMessageError>,
+    ) -> Result<Receipt> {
+        let state = match &res {
+            Ok(_) => SendState::Sent,
+            Err(_) => SendState::Failed,
+        };
+
+        if let Err(e) = self.storage.set_send_state(message_id, state) {
+            warn!("Failed to save send state: {}", e);
+        }
+
+        send_account_event(
+            &self.account_event_tx,
+            AccountEvent::ChatMessageSendStateChanged(*chat_handle, *message_id, state),
+        )
+        .context("Failed to propagate send state")?;
+
+        Ok(res?)
+    }
+
+    fn check_removal_hints(&mut self) {
+        let now = Utc::now();
+        let removal_hints = &mut self.removal_hints;
+        let changes: Vec<(UserHandle, bool)> = self
+            .user_manager
+            .friends()
+            .filter_map(|friend| {
+                removal_hints
+                    .update(friend, now)
+                    .map(|removed| (*friend.id(), removed))
+            })
+            .collect();
+
+        for (user, possibly_removed) in changes {
+            self.report_removal_hint(user, possibly_removed);
+        }
+
+        self.removal_hints.checked(Instant::now());
+    }
+
+    fn report_removal_hint(&self, user: UserHandle, possibly_removed: bool) {
+        if possibly_removed {
+            warn!("Friend {} may have removed us", user.id());
+        }
+
+        if let Err(e) = send_account_event(
+            &self.account_event_tx,
+            AccountEvent::FriendPossiblyRemoved(user, possibly_removed),
+        ) {
+            error!("Failed to propagate friend removal hint: {}", e);
+        }
+    }
+
+    /// Sets the message automatically sent to friends who message us, or
+    /// disables it with `None`. Kept across restarts
+    pub fn set_auto_reply(&mut self, message: Option<String>) -> Result<()> {
+        let message = message.filter(|message| !message.is_empty());
+
+        self.storage
+            .set_auto_reply(message.as_deref())
+            .context("Failed to save auto reply")?;
+        self.auto_reply.set_message(message);
+
+        Ok(())
+    }
+
+    /// Keeps the previous policy if the new one is invalid
+    pub fn set_auto_accept(&mut self, policy: AutoAcceptPolicy) -> Result<()> {
+        self.auto_accept = AutoAccept::from_policy(policy)?;
+        Ok(())
+    }
+
+    /// Keeps the previous limits if the new ones are invalid
+    pub fn set_flood_limits(&mut self, limits: FloodLimits) -> Result<()> {
+        self.flood_guard.set_limits(limits)
+    }
+
+    /// Sets whether :shortcode: sequences in outgoing messages are replaced
+    /// with emoji
+    pub fn set_shortcode_expansion(&mut self, enabled: bool) {
+        self.expand_shortcodes = enabled;
+    }
+
+    /// Tells the peer of the chat that we have read everything up to
+    /// timestamp. Read markers are best effort, nothing is sent if the
+    /// friend is offline
+    pub fn send_read_marker(
+        &mut self,
+        chat_handle: &ChatHandle,
+        timestamp: DateTime<Utc>,
+    ) -> Result<()> {
+        let tox_friend = match self
+            .user_manager
+            .friend_by_chat_handle(&chat_handle)
+            .tox_friend
+            .as_ref()
+        {
+            Some(f) if f.status() != ToxStatus::Offline => f,
+            _ => return Ok(()),
+        };
+
+        self.tox
+            .send_lossless_packet(&tox_friend, &ReadMarker { timestamp }.encode())
+            .context("Failed to send read marker")?;
+
+        Ok(())
+    }
+
+    /// Newest messages of the chat, older history is paged in with
+    /// load_messages_before
+    pub fn load_messages(
+        &mut self,
+        chat_handle: &ChatHandle,
+        limit: usize,
+    ) -> Result<Vec<ChatLogEntry>> {
+        self.storage.load_recent_messages(chat_handle, limit)
+    }
+
+    pub fn load_messages_before(
+        &mut self,
+        chat_handle: &ChatHandle,
+        before: &ChatMessageId,
+        limit: usize,
+    ) -> Result<Vec<ChatLogEntry>> {
+        self.storage
+            .load_messages_before(chat_handle, before, limit)
+    }
+
+    pub fn load_messages_around(
+        &mut self,
+        chat_handle: &ChatHandle,
+        time: &DateTime<Utc>,
+        before: usize,
+        after: usize,
+    ) -> Result<Vec<ChatLogEntry>> {
+        self.storage
+            .load_messages_around(chat_handle, time, before, after)
+    }
+
+    pub fn call_states(&self) -> Vec<(ChatHandle, CallState)> {
+        self.call_manager.active_call_states()
     }
 
     pub fn join_call(&mut self, chat_handle: &ChatHandle) -> Result<CallState> {
@@ -318,6 +1232,11 @@ impl Account {
                     .context("Failed to accept call")?;
             }
             CallState::Active | CallState::Outgoing => (),
+            CallState::Held => {
+                self.call_manager
+                    .set_paused(chat_handle, false)
+                    .context("Failed to resume call")?;
+            }
             CallState::Idle => {
                 let core_friend = self
                     .user_manager
@@ -327,9 +1246,10 @@ impl Account {
                 let core_friend =
                     core_friend.ok_or_else(|| anyhow!("Cannot join call with offline friend"))?;
 
+                let bit_rate = initial_audio_bit_rate(core_friend.connection_status());
                 let outgoing_call = self
                     .tox
-                    .call_friend(&core_friend)
+                    .call_friend(&core_friend, bit_rate)
                     .context("Failed to initiate call with friend")?;
 
                 self.call_manager.outgoing_call(*chat_handle, outgoing_call);
@@ -339,34 +1259,131 @@ impl Account {
         Ok(self.call_manager.call_state(chat_handle))
     }
 
-    pub fn leave_call(&mut self, chat_handle: &ChatHandle) {
-        self.call_manager.drop_call(chat_handle);
+    /// Returns why the call ended, None if there was no call
+    pub fn leave_call(&mut self, chat_handle: &ChatHandle) -> Option<CallEndReason> {
+        let state = self.call_manager.drop_call(chat_handle);
+        let reason = CallEndReason::for_local_end(&state);
+
+        if let Some(reason) = reason {
+            info!("Left call in chat {}: {:?}", chat_handle.id(), reason);
+        }
+
+        reason
+    }
+
+    pub fn set_call_paused(&mut self, chat_handle: &ChatHandle, paused: bool) -> Result<CallState> {
+        self.call_manager.set_paused(chat_handle, paused)
     }
 
     pub fn send_audio_frame(&mut self, frame: AudioFrame) -> Result<()> {
         self.call_manager.send_audio_frame(frame)
     }
 
+    fn store_received_message(
+        &mut self,
+        chat_handle: ChatHandle,
+        sender: UserHandle,
+        message: Message,
+        reply_to: Option<ChatMessageId>,
+    ) -> Result<()> {
+        let chat_log_entry = match &reply_to {
+            Some(reply_to) => self
+                .storage
+                .push_reply(&chat_handle, sender, message, reply_to),
+            None => self.storage.push_message(&chat_handle, sender, message),
+        }
+        .context("Failed to insert incoming message into storage")?;
+        send_account_event(
+            &self.account_event_tx,
+            AccountEvent::ChatMessageInserted(chat_handle, chat_log_entry),
+        )
+        .context("Failed to propagate received message")?;
+
+        let unread_count = self.unread_count(&chat_handle)?;
+        send_account_event(
+            &self.account_event_tx,
+            AccountEvent::UnreadCountChanged(chat_handle, unread_count),
+        )
+        .context("Failed to propagate unread count")?;
+
+        Ok(())
+    }
+
+    fn release_held_messages(&mut self, now: Instant) {
+        for (user, message) in self.flood_guard.release(now) {
+            // The friend may have been removed while we held their messages
+            let chat_handle = match self.user_manager.friends().find(|f| *f.id() == user) {
+                Some(friend) => *friend.chat_handle(),
+                None => continue,
+            };
+
+            if let Err(e) = self.store_received_message(chat_handle, user, message, None) {
+                error!("Failed to store held back messages: {}", e);
+            }
+        }
+    }
+
     fn handle_toxcore_event(&mut self, event: CoreEvent) -> Result<()> {
         match event {
             CoreEvent::MessageReceived(tox_friend, message) => {
                 let friend = self
                     .user_manager
                     .friend_by_public_key(&tox_friend.public_key());
-                let chat_log_entry = self
-                    .storage
-                    .push_message(friend.chat_handle(), *friend.id(), message)
-                    .context("Failed to insert incoming message into storage")?;
-                self.account_event_tx
-                    .unbounded_send(AccountEvent::ChatMessageInserted(
-                        *friend.chat_handle(),
-                        chat_log_entry,
-                    ))
-                    .context("Failed to propagate received message")?;
+                let chat_handle = *friend.chat_handle();
+                let friend_id = *friend.id();
+
+                // Held back messages don't use up a reply marker, it stays
+                // around for the message it was sent for
+                if !self.flood_guard.allow(friend_id, Instant::now()) {
+                    self.flood_guard.hold(friend_id, &message);
+                    return Ok(());
+                }
+
+                let reply_to = match self.pending_replies.remove(&chat_handle) {
+                    Some((marker, reply_to)) if marker.is_for(&message) => reply_to,
+                    _ => None,
+                };
+
+                self.store_received_message(chat_handle, friend_id, message, reply_to)?;
+
+                if let Some(Message::Normal(reply)) =
+                    self.auto_reply.reply_for(&friend_id, self.self_status)
+                {
+                    let entries = self
+                        .send_message(&chat_handle, reply)
+                        .context("Failed to send auto-reply")?;
+
+                    for entry in entries {
+                        send_account_event(
+                            &self.account_event_tx,
+                            AccountEvent::ChatMessageInserted(chat_handle, entry),
+                        )
+                        .context("Failed to propagate auto-reply")?;
+                    }
+                }
             }
             CoreEvent::FriendRequest(request) => {
                 // FIXME: reject incoming request if the user is blocked
 
+                // Either a repeated request or ours crossed with theirs. In
+                // both cases the chat already exists, and toxcore connects us
+                // once both sides have added each other
+                if self
+                    .user_manager
+                    .find_by_public_key(&request.public_key)
+                    .is_some()
+                {
+                    info!(
+                        "Ignoring friend request from existing friend {}",
+                        request.public_key
+                    );
+                    return Ok(());
+                }
+
+                let auto_accept = self
+                    .auto_accept
+                    .accepts(&request.public_key, &request.message);
+
                 let friend: Friend = self
                     .storage
                     .add_pending_friend(request.public_key)
@@ -380,58 +1397,78 @@ impl Account {
                     )
                     .context("Failed to write friend request message to storage")?;
                 self.user_manager.add_pending_friend(friend.clone());
-                self.account_event_tx
-                    .unbounded_send(AccountEvent::FriendAdded(friend.clone()))
-                    .context("Failed to propagate friend request")?;
-                self.account_event_tx
-                    .unbounded_send(AccountEvent::ChatMessageInserted(
-                        *friend.chat_handle(),
-                        chat_log_entry,
-                    ))
-                    .context("Failed to propagate friend request message")?;
+                send_account_event(
+                    &self.account_event_tx,
+                    AccountEvent::FriendAdded(friend.clone()),
+                )
+                .context("Failed to propagate friend request")?;
+                send_account_event(
+                    &self.account_event_tx,
+                    AccountEvent::ChatMessageInserted(*friend.chat_handle(), chat_log_entry),
+                )
+                .context("Failed to propagate friend request message")?;
+
+                if auto_accept {
+                    let status = *self
+                        .add_pending_friend(friend.id())
+                        .context("Failed to auto accept friend request")?
+                        .status();
+
+                    send_account_event(
+                        &self.account_event_tx,
+                        AccountEvent::FriendStatusChanged(*friend.id(), status),
+                    )
+                    .context("Failed to propagate auto accepted friend")?;
+                }
             }
             CoreEvent::ReadReceipt(receipt) => {
-                if let Some((handle, message_id)) = self.outgoing_messages.remove(&receipt) {
+                if let Some((handle, message_id, _)) = self.outgoing_messages.remove(&receipt) {
                     self.storage
                         .resolve_message(&handle, &message_id)
                         .context("Failed to resolve message")?;
-
-                    self.account_event_tx
-                        .unbounded_send(AccountEvent::ChatMessageCompleted(handle, message_id))
-                        .context("Failed to propagate message completion")?;
+                    send_account_event(
+                        &self.account_event_tx,
+                        AccountEvent::ChatMessageCompleted(handle, message_id),
+                    )
+                    .context("Failed to propagate message completion")?;
                 } else {
                     error!("Received receipt to unknown message");
                 }
             }
             CoreEvent::StatusUpdated(tox_friend) => {
-                let friend = self
-                    .user_manager
-                    .friend_by_public_key(&tox_friend.public_key());
+                let public_key = tox_friend.public_key();
+
+                // Receipts are kept while the friend is away, so a quick
+                // reconnect doesn't send in flight messages twice. Receipts
+                // that were really lost time out instead
+                let friend = self.user_manager.friend_by_public_key(&public_key);
 
                 if *friend.status() == Status::Offline && tox_friend.status() != ToxStatus::Offline
                 {
-                    let messages = self
-                        .storage
-                        .unresovled_messages(friend.chat_handle())
-                        .context("Failed to retrieve unsent messages")?;
-
-                    for message in messages {
-                        let receipt = self
-                            .tox
-                            .send_message(&tox_friend, message.message())
-                            .context("Failed to send unsent message")?;
-                        self.outgoing_messages
-                            .insert(receipt, (*friend.chat_handle(), *message.id()));
+                    let chat_handle = *friend.chat_handle();
+                    // The status change still has to go through
+                    if let Err(e) = self.send_unresolved_messages(&chat_handle, &tox_friend) {
+                        error!("Failed to flush unsent messages: {:#}", e);
                     }
                 }
 
+                let friend = self.user_manager.friend_by_public_key(&public_key);
+                if *friend.status() != Status::Offline && tox_friend.status() == ToxStatus::Offline
+                {
+                    friend.set_last_online(Some(Utc::now()));
+                }
                 friend.set_status(Status::from(tox_friend.status()));
-                self.account_event_tx
-                    .unbounded_send(AccountEvent::FriendStatusChanged(
-                        *friend.id(),
-                        *friend.status(),
-                    ))
-                    .context("Failed to propagate status change")?;
+                send_account_event(
+                    &self.account_event_tx,
+                    AccountEvent::FriendStatusChanged(*friend.id(), *friend.status()),
+                )
+                .context("Failed to propagate status change")?;
+
+                // Showing up again clears any hint right away
+                let user = *friend.id();
+                if let Some(removed) = self.removal_hints.update(friend, Utc::now()) {
+                    self.report_removal_hint(user, removed);
+                }
             }
             CoreEvent::NameUpdated(tox_friend) => {
                 let friend = self
@@ -448,12 +1485,65 @@ impl Account {
                     error!("Failed to update tox save for user name change: {}", e);
                 }
 
-                self.account_event_tx
-                    .unbounded_send(AccountEvent::UserNameChanged(
-                        *friend.id(),
-                        friend.name().to_string(),
-                    ))
-                    .context("Failed to propagate name change")?;
+                send_account_event(
+                    &self.account_event_tx,
+                    AccountEvent::UserNameChanged(*friend.id(), friend.name().to_string()),
+                )
+                .context("Failed to propagate name change")?;
+            }
+            CoreEvent::CustomPacketReceived(tox_friend, data) => {
+                let friend = self
+                    .user_manager
+                    .friend_by_public_key(&tox_friend.public_key());
+                let chat_handle = *friend.chat_handle();
+
+                if let Some(marker) = ReplyMarker::decode(&data) {
+                    let reply_to = self
+                        .storage
+                        .find_message_by_text(&chat_handle, &marker.quoted)
+                        .context("Failed to look up quoted message")?;
+
+                    // Their history may have messages ours doesn't, the
+                    // message still goes through, just not as a reply
+                    if reply_to.is_none() {
+                        debug!("Quoted message not found in chat {}", chat_handle.id());
+                    }
+
+                    self.pending_replies.insert(chat_handle, (marker, reply_to));
+                    return Ok(());
+                }
+
+                let marker = match ReadMarker::decode(&data) {
+                    Some(m) => m,
+                    None => {
+                        debug!("Ignoring unknown custom packet");
+                        return Ok(());
+                    }
+                };
+
+                debug!(
+                    "Chat {} read by peer at {} (their clock)",
+                    chat_handle.id(),
+                    marker.timestamp
+                );
+
+                let read_up_to = self
+                    .storage
+                    .newest_delivered_message(&chat_handle)
+                    .context("Failed to look up newest delivered message")?;
+
+                let read_up_to = match read_up_to {
+                    Some(id) => id,
+                    None => return Ok(()),
+                };
+
+                if self.peer_read_messages.update(chat_handle, read_up_to) {
+                    send_account_event(
+                        &self.account_event_tx,
+                        AccountEvent::PeerReadUpTo(chat_handle, read_up_to),
+                    )
+                    .context("Failed to propagate peer read message")?;
+                }
             }
             CoreEvent::IncomingCall(call) => {
                 info!("Incoming call from {}", call.friend().name());
@@ -464,12 +1554,11 @@ impl Account {
 
                 self.call_manager.incoming_call(*friend.chat_handle(), call);
 
-                self.account_event_tx
-                    .unbounded_send(AccountEvent::CallStateChanged(
-                        *friend.chat_handle(),
-                        CallState::Incoming,
-                    ))
-                    .context("Failed to propagate incoming call")?;
+                send_account_event(
+                    &self.account_event_tx,
+                    AccountEvent::CallStateChanged(*friend.chat_handle(), CallState::Incoming),
+                )
+                .context("Failed to propagate incoming call")?;
             }
         }
 
@@ -478,20 +1567,37 @@ impl Account {
 
     fn handle_call_event(&mut self, event: CallEvent) -> Result<()> {
         match event {
-            CallEvent::CallEnded(chat) => {
-                self.account_event_tx
-                    .unbounded_send(AccountEvent::CallStateChanged(chat, CallState::Idle))
-                    .context("Failed to propagate ended call")?;
+            CallEvent::CallEnded(chat, reason) => {
+                send_account_event(
+                    &self.account_event_tx,
+                    AccountEvent::CallStateChanged(chat, CallState::Idle),
+                )
+                .context("Failed to propagate ended call")?;
+
+                send_account_event(
+                    &self.account_event_tx,
+                    AccountEvent::CallEnded(chat, reason),
+                )
+                .context("Failed to propagate call end reason")?;
             }
             CallEvent::AudioReceived(chat, frame) => {
-                self.account_event_tx
-                    .unbounded_send(AccountEvent::AudioDataReceived(chat, frame))
-                    .context("Failed to propagate audio data")?;
+                // Peer may not have noticed the hold yet
+                if self.call_manager.is_held(&chat) {
+                    return Ok(());
+                }
+
+                send_account_event(
+                    &self.account_event_tx,
+                    AccountEvent::AudioDataReceived(chat, frame),
+                )
+                .context("Failed to propagate audio data")?;
             }
             CallEvent::CallAccepted(chat) => {
-                self.account_event_tx
-                    .unbounded_send(AccountEvent::CallStateChanged(chat, CallState::Active))
-                    .context("Failed to propagate ended call")?;
+                send_account_event(
+                    &self.account_event_tx,
+                    AccountEvent::CallStateChanged(chat, CallState::Active),
+                )
+                .context("Failed to propagate ended call")?;
             }
         }
 
@@ -501,7 +1607,7 @@ impl Account {
     pub(crate) async fn run(&mut self) -> Result<()> {
         loop {
             futures::select! {
-                _ = self.tox.run().fuse() => {
+                _ = run_while_online(self.online, self.tox.run()).fuse() => {
                     Err(ExitError::Ungraceful)
                         .context("Tox account unexpectedly stopped")?;
                 },
@@ -519,6 +1625,29 @@ impl Account {
                         error!("Failed to handle call event: {}", e)
                     }
                 }
+                _ = sleep_until(self.next_bootstrap()).fuse() => {
+                    if let Err(e) = self.retry_bootstrap() {
+                        error!("Failed to bootstrap: {}", e)
+                    }
+                }
+                _ = sleep_until(Some(self.removal_hints.next_check())).fuse() => {
+                    self.check_removal_hints();
+                }
+                _ = sleep_until(self.next_receipt_timeout()).fuse() => {
+                    if let Err(e) = self.expire_receipts(Instant::now()) {
+                        error!("Failed to expire receipts: {}", e)
+                    }
+                }
+                _ = sleep_until(self.flood_guard.next_release()).fuse() => {
+                    self.release_held_messages(Instant::now());
+                }
+                _ = sleep_until(self.typing.next_deadline()).fuse() => {
+                    for chat_handle in self.typing.expire(Instant::now()) {
+                        if let Err(e) = self.send_typing(&chat_handle, false) {
+                            error!("Failed to clear typing state: {}", e)
+                        }
+                    }
+                }
             }
         }
     }
@@ -532,6 +1661,19 @@ impl Drop for Account {
     }
 }
 
+/// Connectivity details for debugging an account that won't connect
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostics {
+    /// Key other DHT nodes know us by, changes every run
+    pub dht_id: PublicKey,
+    /// How we reach the tox network, one of "none", "tcp" or "udp"
+    pub connection: String,
+    /// False if the user took the account offline
+    pub online: bool,
+    pub friends: usize,
+    pub online_friends: usize,
+}
+
 #[derive(Copy, Clone, Hash, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct AccountId {
     id: i64,
@@ -594,6 +1736,12 @@ impl AccountManager {
         account_id
     }
 
+    pub fn accounts(&self) -> impl Iterator<Item = (&AccountId, &Account)> {
+        self.accounts
+            .iter()
+            .map(|(id, bundle)| (id, &bundle.account))
+    }
+
     pub fn accounts_mut(&mut self) -> impl ExactSizeIterator<Item = &mut Account> {
         self.accounts.iter_mut().map(|bundle| &mut bundle.1.account)
     }
@@ -608,16 +1756,8 @@ impl AccountManager {
             .map(|bundle| &mut bundle.account)
     }
 
-    async fn run_account_bundle(
-        id: AccountId,
-        bundle: &mut AccountBundle,
-    ) -> Option<(AccountId, AccountEvent)> {
-        futures::select! {
-            _  = bundle.account.run().fuse() => None,
-            event = bundle.account_events.next().fuse() => {
-                event.map(|event| (id, event))
-            }
-        }
+    async fn run_account_bundle(id: AccountId, bundle: &mut AccountBundle) -> AccountOutcome {
+        next_account_outcome(id, bundle.account.run(), &mut bundle.account_events).await
     }
 
     pub async fn run(&mut self) -> Result<TocksEvent> {
@@ -637,12 +1777,50 @@ impl AccountManager {
         // select_all returns a list of all remaining events as the second
         // element. We don't care about the accounts where nothing happened,
         // we'll catch those next time
-        Ok(account_events
-            .await
-            .0
-            .context(ExitError::Ungraceful)
-            .context("All accounts unexpectedly dropped")?
-            .into())
+        let outcome = account_events.await.0;
+
+        match outcome {
+            AccountOutcome::Event(id, event) => Ok((id, event).into()),
+            AccountOutcome::Stopped(id, e) => {
+                // A stopped account is ready again as soon as it's polled, so
+                // keeping it around would report the same failure forever.
+                // Other accounts keep running
+                self.accounts.remove(&id);
+
+                let e = e.context(format!("Account {} stopped", id));
+                error!("{:?}", e);
+                Ok(TocksEvent::AccountRemoved(id, format!("{:#}", e)))
+            }
+        }
+    }
+}
+
+enum AccountOutcome {
+    Event(AccountId, AccountEvent),
+    // The account can't do anything more, it should be dropped
+    Stopped(AccountId, Error),
+}
+
+/// Waits for the next event from an account, or for the account to stop.
+/// account_run only finishes if the account hit an error it can't recover
+/// from, and the event channel only closes once the account is gone
+async fn next_account_outcome(
+    id: AccountId,
+    account_run: impl Future<Output = Result<()>>,
+    account_events: &mut mpsc::UnboundedReceiver<AccountEvent>,
+) -> AccountOutcome {
+    futures::select! {
+        res = account_run.fuse() => {
+            let e = match res {
+                Ok(()) => anyhow!("Account unexpectedly finished"),
+                Err(e) => e,
+            };
+            AccountOutcome::Stopped(id, e)
+        }
+        event = account_events.next().fuse() => match event {
+            Some(event) => AccountOutcome::Event(id, event),
+            None => AccountOutcome::Stopped(id, anyhow!("Account event channel closed")),
+        }
     }
 }
 
@@ -660,12 +1838,44 @@ pub fn retrieve_account_list() -> Result<Vec<String>> {
     Ok(accounts)
 }
 
+/// Toxcore refuses our own key with an opaque error on request, and makes no
+/// promises at all for norequest adds, so catch it before getting that far
+fn ensure_not_self(self_public_key: &PublicKey, public_key: &PublicKey) -> Result<()> {
+    if self_public_key == public_key {
+        bail!("Cannot add yourself as a friend");
+    }
+
+    Ok(())
+}
+
+fn read_tox_save(path: &Path, save_password: &str) -> Result<Vec<u8>> {
+    let savedata = fs::read(path)
+        .with_context(|| format!("Failed to read tox save {}", path.to_string_lossy()))?;
+
+    if !PassKey::is_data_encrypted(&savedata) {
+        return Ok(savedata);
+    }
+
+    if save_password.is_empty() {
+        return Err(anyhow!("Tox save is encrypted, a password is required"));
+    }
+
+    PassKey::from_encrypted_slice(save_password, &savedata)
+        .context("Failed to derive key for encrypted tox save")?
+        .decrypt(&savedata)
+        .context("Failed to decrypt tox save, check the password")
+}
+
 fn create_save_manager(account_name: String, password: &str) -> Result<SaveManager> {
     let mut account_file = account_name;
     account_file.push_str(".tox");
     let account_file_path = TOX_SAVE_DIR.join(account_file);
 
     let save_manager = if password.is_empty() {
+        if savemanager::is_encrypted(&account_file_path) {
+            return Err(Error::from(PasswordRequiredError)).context("Tox save is encrypted");
+        }
+
         SaveManager::new_unencrypted(account_file_path)
     } else {
         SaveManager::new_with_password(account_file_path, password)
@@ -689,21 +1899,11 @@ fn handle_savedata_failure(savedata: Result<Vec<u8>>) -> Result<Option<Vec<u8>>>
     }
 }
 
-fn create_tox(
-    savedata: Result<Vec<u8>>,
-) -> Result<(Tox, mpsc::UnboundedReceiver<toxcore::Event>), Error> {
+fn create_tox(savedata: SaveData) -> Result<(Tox, mpsc::UnboundedReceiver<toxcore::Event>), Error> {
     let (toxcore_callback_tx, toxcore_callback_rx) = mpsc::unbounded();
 
-    let builder = Tox::builder()?;
-
-    let savedata = handle_savedata_failure(savedata)?;
-
-    let builder = match savedata {
-        Some(d) => builder.savedata(toxcore::SaveData::ToxSave(d)),
-        None => builder,
-    };
-
-    let tox = builder
+    let tox = Tox::builder()?
+        .savedata(savedata)
         .event_callback(move |event| {
             toxcore_callback_tx
                 .unbounded_send(event)
@@ -715,9 +1915,48 @@ fn create_tox(
     Ok((tox, toxcore_callback_rx))
 }
 
+fn describe_tox_creation_error(e: Error) -> Error {
+    let context = match e.downcast_ref::<ToxBuildError>() {
+        Some(ToxBuildError::ToxCreationError(ToxCreationError::LoadEncrypted)) => {
+            "Tox save is still encrypted, it may be protected by another tool"
+        }
+        Some(ToxBuildError::ToxCreationError(ToxCreationError::BadLoadFormat)) => {
+            "File is not a valid tox save"
+        }
+        _ => return e,
+    };
+
+    e.context(context)
+}
+
+fn storage_path(account_name: &str) -> PathBuf {
+    APP_DIRS.data_dir.join(format!("{}.db", account_name))
+}
+
+/// Finds which of accounts, if any, owns public_key. Accounts whose DB can't
+/// be read are skipped, they can't be told apart from ones that don't match
+fn account_with_identity<'a>(
+    accounts: &'a [String],
+    public_key: &PublicKey,
+    db_path: impl Fn(&str) -> PathBuf,
+) -> Option<&'a str> {
+    accounts
+        .iter()
+        .find(|account| {
+            let owner = Storage::owner_public_key(db_path(account));
+            match owner {
+                Ok(owner) => owner.as_ref() == Some(public_key),
+                Err(e) => {
+                    warn!("Failed to check identity of account {}: {:?}", account, e);
+                    false
+                }
+            }
+        })
+        .map(String::as_str)
+}
+
 fn create_storage(account_name: &str, self_pk: &PublicKey, current_name: &str) -> Result<Storage> {
-    let db_name = format!("{}.db", account_name);
-    let storage = Storage::open(APP_DIRS.data_dir.join(&db_name), self_pk, current_name);
+    let storage = Storage::open(storage_path(account_name), self_pk, current_name);
 
     let storage = match storage {
         Ok(s) => s,
@@ -771,6 +2010,13 @@ fn initialize_friend_lists(
                 .context("Failed to remove pending friend request from storage")?;
         }
 
+        // toxcore saves this with the rest of the friend list, so it also
+        // covers friends that went offline while we weren't running
+        match tox.friend_last_online(&tox_friend) {
+            Ok(last_online) => friend.set_last_online(last_online.map(DateTime::<Utc>::from)),
+            Err(e) => warn!("Failed to get last online time for {}: {}", friend.id(), e),
+        }
+
         if friend.name() != tox_friend.name() {
             friend.set_name(tox_friend.name());
             storage
@@ -799,18 +2045,1387 @@ fn initialize_friend_lists(
     Ok(())
 }
 
-fn lock_account(mut account_name: String) -> Result<LockFile> {
-    account_name.push_str(".lock");
+/// Sends event to whoever owns the account. The receiver only goes away while
+/// tocks is shutting down, nobody is listening for events at that point so
+/// they are dropped quietly instead of failing whatever produced them
+fn send_account_event(
+    account_event_tx: &mpsc::UnboundedSender<AccountEvent>,
+    event: AccountEvent,
+) -> Result<()> {
+    match account_event_tx.unbounded_send(event) {
+        Ok(()) => Ok(()),
+        Err(e) if e.is_disconnected() => {
+            debug!("Account event receiver is gone, dropping event");
+            Ok(())
+        }
+        Err(e) => Err(anyhow!("Failed to send account event: {}", e)),
+    }
+}
+
+/// Whether a message can go to toxcore now rather than waiting in the
+/// unresolved queue. tox_friend_status is None for unaccepted friends
+fn sends_immediately(online: bool, tox_friend_status: Option<ToxStatus>) -> bool {
+    match tox_friend_status {
+        Some(status) => online && status != ToxStatus::Offline,
+        None => false,
+    }
+}
+
+/// Filters out messages that are already waiting on a receipt. A friend
+/// flapping between online and offline would otherwise receive duplicates of
+/// messages that just haven't been acknowledged yet
+fn messages_without_receipts<'a, I>(
+    messages: Vec<UnsentMessage>,
+    in_flight: I,
+) -> Vec<UnsentMessage>
+where
+    I: IntoIterator<Item = &'a ChatMessageId>,
+{
+    let in_flight = in_flight.into_iter().collect::<HashSet<_>>();
+
+    messages
+        .into_iter()
+        .filter(|message| !in_flight.contains(message.id()))
+        .collect()
+}
+
+/// Waits until deadline, or forever if there isn't one
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => future::pending().await,
+    }
+}
+
+/// The reply is still worth sending without the quote, failing to send the
+/// marker is only logged
+fn send_reply_marker(tox: &mut Tox, tox_friend: &ToxFriend, quoted: &Message, reply: &Message) {
+    let marker = ReplyMarker::new(quoted, reply);
+    if let Err(e) = tox.send_lossless_packet(tox_friend, &marker.encode()) {
+        warn!("Failed to send reply marker: {}", e);
+    }
+}
+
+/// Replies that were queued or failed get their marker again when they're
+/// finally sent
+fn resend_reply_marker(
+    tox: &mut Tox,
+    storage: &mut Storage,
+    chat_handle: &ChatHandle,
+    tox_friend: &ToxFriend,
+    message: &UnsentMessage,
+) {
+    let reply_to = match message.reply_to() {
+        Some(reply_to) => reply_to,
+        None => return,
+    };
+
+    match storage.message(chat_handle, reply_to) {
+        Ok(quoted) => send_reply_marker(tox, tox_friend, &quoted, message.message()),
+        Err(e) => warn!("Failed to find message being replied to: {}", e),
+    }
+}
+
+/// Drives fut only while online, otherwise never completes
+async fn run_while_online<F: Future>(online: bool, fut: F) -> F::Output {
+    if !online {
+        future::pending::<()>().await;
+    }
+
+    fut.await
+}
 
-    let lock_path = APP_DIRS.data_dir.join(account_name);
+fn lock_account(account_name: String) -> Result<LockFile> {
+    let lock_path = APP_DIRS.data_dir.join(format!("{}.lock", account_name));
+    lock_account_at(&lock_path, account_name)
+}
 
-    let mut lock_file = LockFile::open(&lock_path).context("Failed to open lock file")?;
+/// The lock is held until the returned LockFile is dropped, i.e. until the
+/// Account goes away
+fn lock_account_at(lock_path: &Path, account_name: String) -> Result<LockFile> {
+    let mut lock_file = LockFile::open(lock_path).context("Failed to open lock file")?;
 
     let lock_success = lock_file.try_lock().context("Io error on lock file")?;
 
     if !lock_success {
-        return Err(anyhow!("Failed to lock account"));
+        return Err(AccountInUseError(account_name).into());
     }
 
     Ok(lock_file)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::removal_hint::DAYS_BEFORE_HINT;
+
+    use rusty_fork::rusty_fork_test;
+    use toxcore::mock::{self, ffi, sys, FixtureContexts, ToxFixture};
+
+    use std::{any::Any, cell::RefCell, rc::Rc};
+
+    const SELF_PUBLIC_KEY: &[u8] = b"selfkey1";
+    const SELF_ADDRESS: &[u8] = b"selfkey1nospam";
+
+    /// An account on a mocked tox. Its only friend is the tox fixture's
+    /// default peer, who is online. Expectations tests set go in contexts so
+    /// they outlive the account
+    struct AccountFixture {
+        account: Account,
+        event_rx: mpsc::UnboundedReceiver<AccountEvent>,
+        contexts: Vec<Box<dyn Any>>,
+        peer_public_key: PublicKey,
+        removed_peers: Rc<RefCell<Vec<u32>>>,
+        _tox_contexts: FixtureContexts,
+        _dir: tempfile::TempDir,
+    }
+
+    impl AccountFixture {
+        fn new() -> AccountFixture {
+            let fixture = ToxFixture::new();
+            let peer_public_key = fixture.default_peer_pk.clone();
+            let peer_id = fixture.default_peer_id;
+            let (tox, tox_contexts) = fixture.into_tox();
+
+            let mut contexts: Vec<Box<dyn Any>> = Vec::new();
+
+            let ctx = sys::tox_self_get_public_key_context();
+            ctx.expect().returning_st(|_, public_key| unsafe {
+                std::ptr::copy_nonoverlapping(
+                    SELF_PUBLIC_KEY.as_ptr(),
+                    public_key,
+                    SELF_PUBLIC_KEY.len(),
+                )
+            });
+            contexts.push(Box::new(ctx));
+
+            let ctx = sys::tox_address_size_context();
+            ctx.expect().return_const(SELF_ADDRESS.len() as u32);
+            contexts.push(Box::new(ctx));
+
+            let ctx = sys::tox_self_get_address_context();
+            ctx.expect().returning_st(|_, address| unsafe {
+                std::ptr::copy_nonoverlapping(SELF_ADDRESS.as_ptr(), address, SELF_ADDRESS.len())
+            });
+            contexts.push(Box::new(ctx));
+
+            let ctx = sys::tox_self_get_name_size_context();
+            ctx.expect().return_const(4u64);
+            contexts.push(Box::new(ctx));
+
+            let ctx = sys::tox_self_get_name_context();
+            ctx.expect().returning_st(|_, name| unsafe {
+                std::ptr::copy_nonoverlapping(b"self".as_ptr(), name, 4)
+            });
+            contexts.push(Box::new(ctx));
+
+            let ctx = sys::tox_self_get_status_context();
+            ctx.expect().return_const(ffi::TOX_USER_STATUS_NONE);
+            contexts.push(Box::new(ctx));
+
+            let ctx = sys::tox_self_get_friend_list_size_context();
+            ctx.expect().return_const(1u64);
+            contexts.push(Box::new(ctx));
+
+            let ctx = sys::tox_self_get_friend_list_context();
+            ctx.expect()
+                .returning_st(move |_, friend_list| unsafe { *friend_list = peer_id });
+            contexts.push(Box::new(ctx));
+
+            let ctx = sys::tox_friend_get_last_online_context();
+            ctx.expect().return_const(u64::MAX);
+            contexts.push(Box::new(ctx));
+
+            let ctx = sys::tox_get_savedata_size_context();
+            ctx.expect().return_const(0u64);
+            contexts.push(Box::new(ctx));
+
+            let ctx = sys::tox_get_savedata_context();
+            ctx.expect().return_const(());
+            contexts.push(Box::new(ctx));
+
+            let ctx = sys::tox_max_message_length_context();
+            ctx.expect().return_const(1372u32);
+            contexts.push(Box::new(ctx));
+
+            let ctx = sys::tox_max_friend_request_length_context();
+            ctx.expect().return_const(921u32);
+            contexts.push(Box::new(ctx));
+
+            let dir = tempfile::tempdir().unwrap();
+            let account_lock =
+                lock_account_at(&dir.path().join("account.lock"), "account".to_string()).unwrap();
+            let save_manager = SaveManager::new_unencrypted(dir.path().join("account.tox"));
+            let storage = Storage::open_ram(&tox.self_public_key(), "self").unwrap();
+
+            let (_toxcore_callback_tx, toxcore_callback_rx) = mpsc::unbounded();
+            let (account_event_tx, event_rx) = mpsc::unbounded();
+
+            let account = Account::with_storage(
+                account_lock,
+                save_manager,
+                tox,
+                toxcore_callback_rx,
+                storage,
+                account_event_tx,
+            )
+            .unwrap();
+
+            AccountFixture {
+                account,
+                event_rx,
+                contexts,
+                peer_public_key,
+                removed_peers: Rc::new(RefCell::new(Vec::new())),
+                _tox_contexts: tox_contexts,
+                _dir: dir,
+            }
+        }
+
+        fn peer(&mut self) -> (Friend, ToxFriend) {
+            let public_key = self.peer_public_key.clone();
+            let friend = self
+                .account
+                .user_manager
+                .friend_by_public_key(&public_key)
+                .clone();
+            let tox_friend = self
+                .account
+                .user_manager
+                .friend_by_user_handle(friend.id())
+                .tox_friend
+                .clone()
+                .unwrap();
+
+            (friend, tox_friend)
+        }
+
+        /// Lets toxcore add a second peer with public_key as friend number id.
+        /// The peer has no name and isn't connected
+        fn expect_new_peer(&mut self, id: u32, public_key: &'static [u8]) -> PublicKey {
+            let ctx = sys::tox_friend_add_norequest_context();
+            ctx.expect()
+                .withf_st(move |_, key, _| {
+                    let key = unsafe { std::slice::from_raw_parts(*key, public_key.len()) };
+                    key == public_key
+                })
+                .return_const(id);
+            self.contexts.push(Box::new(ctx));
+
+            let removed_peers = Rc::clone(&self.removed_peers);
+            let ctx = sys::tox_friend_get_public_key_context();
+            ctx.expect()
+                .withf_st(move |_, friend, _, _| *friend == id)
+                .returning_st(move |_, _, output, _| {
+                    if removed_peers.borrow().contains(&id) {
+                        return false;
+                    }
+                    unsafe {
+                        std::ptr::copy_nonoverlapping(public_key.as_ptr(), output, public_key.len())
+                    };
+                    true
+                });
+            self.contexts.push(Box::new(ctx));
+
+            let ctx = sys::tox_friend_get_name_size_context();
+            ctx.expect()
+                .withf_st(move |_, friend, _| *friend == id)
+                .return_const(0u32);
+            self.contexts.push(Box::new(ctx));
+
+            let ctx = sys::tox_friend_get_name_context();
+            ctx.expect()
+                .withf_st(move |_, friend, _, _| *friend == id)
+                .return_const(true);
+            self.contexts.push(Box::new(ctx));
+
+            let ctx = sys::tox_friend_get_connection_status_context();
+            ctx.expect()
+                .withf_st(move |_, friend, _| *friend == id)
+                .return_const(ffi::TOX_CONNECTION_NONE);
+            self.contexts.push(Box::new(ctx));
+
+            mock::public_key(public_key)
+        }
+
+        /// Makes toxcore forget friend number id of a peer from
+        /// expect_new_peer, as if another client had removed them
+        fn remove_peer_from_tox(&mut self, id: u32) {
+            self.removed_peers.borrow_mut().push(id);
+        }
+
+        /// Hands out receipt ids counting up from 1 for every message sent
+        fn expect_messages_sent(&mut self) {
+            let next_receipt = std::cell::Cell::new(1);
+            let ctx = sys::tox_friend_send_message_context();
+            ctx.expect().returning_st(move |_, _, _, _, _, _| {
+                let receipt = next_receipt.get();
+                next_receipt.set(receipt + 1);
+                receipt
+            });
+            self.contexts.push(Box::new(ctx));
+        }
+
+        /// Delivers a status change for the peer as toxcore would
+        fn set_peer_status(&mut self, status: ToxStatus, connection_status: ConnectionStatus) {
+            let (_, tox_friend) = self.peer();
+            mock::set_friend_status(&tox_friend, status, connection_status);
+            self.account
+                .handle_toxcore_event(CoreEvent::StatusUpdated(tox_friend))
+                .unwrap();
+        }
+
+        fn events(&mut self) -> Vec<AccountEvent> {
+            std::iter::from_fn(|| self.event_rx.try_next().ok().flatten()).collect()
+        }
+    }
+
+    #[test]
+    fn test_account_outcome() {
+        let id = AccountId::from(1);
+        let (tx, mut rx) = mpsc::unbounded();
+
+        tx.unbounded_send(AccountEvent::UnableToConnect).unwrap();
+        let running = future::pending::<Result<()>>();
+        let outcome = futures::executor::block_on(next_account_outcome(id, running, &mut rx));
+        assert!(matches!(
+            outcome,
+            AccountOutcome::Event(event_id, AccountEvent::UnableToConnect) if event_id == id
+        ));
+
+        // A closed channel stops the account once instead of looking like
+        // a failure of every account
+        drop(tx);
+        let running = future::pending::<Result<()>>();
+        let outcome = futures::executor::block_on(next_account_outcome(id, running, &mut rx));
+        assert!(matches!(outcome, AccountOutcome::Stopped(stopped_id, _) if stopped_id == id));
+
+        let (_tx, mut rx) = mpsc::unbounded();
+        let failed = future::ready(Err(anyhow!("tox stopped")));
+        let outcome = futures::executor::block_on(next_account_outcome(id, failed, &mut rx));
+        match outcome {
+            AccountOutcome::Stopped(_, e) => assert_eq!(e.to_string(), "tox stopped"),
+            _ => panic!("Unexpected outcome"),
+        }
+    }
+
+    #[test]
+    fn test_event_send_after_shutdown() {
+        let (tx, rx) = mpsc::unbounded();
+
+        assert!(send_account_event(&tx, AccountEvent::UnableToConnect).is_ok());
+
+        drop(rx);
+        assert!(send_account_event(&tx, AccountEvent::UnableToConnect).is_ok());
+    }
+
+    #[test]
+    fn test_pending_friend_messages_queued() -> Result<()> {
+        // Nothing goes to toxcore before the friend is accepted
+        assert!(!sends_immediately(true, None));
+        assert!(!sends_immediately(true, Some(ToxStatus::Offline)));
+        assert!(!sends_immediately(false, Some(ToxStatus::Online)));
+        assert!(sends_immediately(true, Some(ToxStatus::Online)));
+
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let mut storage = Storage::open_ram(&selfpk, "self")?;
+
+        let friend_pk = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+        let friend = storage.add_pending_friend(friend_pk)?;
+
+        let entry = storage.push_message(
+            friend.chat_handle(),
+            storage.self_user_handle(),
+            Message::Normal("hello".to_string()),
+        )?;
+        storage.add_unresolved_message(entry.id(), SendState::Unsent)?;
+
+        // Accepting the request keeps the queue, the flush when the friend
+        // comes online sends it
+        storage.resolve_pending_friend_request(friend.id())?;
+
+        let messages = storage.unresovled_messages(friend.chat_handle())?;
+        let to_send = messages_without_receipts(messages, &[]);
+        assert_eq!(to_send.len(), 1);
+        assert_eq!(to_send[0].id(), entry.id());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_skips_in_flight_messages() -> Result<()> {
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let mut storage = Storage::open_ram(&selfpk, "self")?;
+
+        let friend_pk = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+        let friend = storage.add_friend(friend_pk, "friend".to_string())?;
+
+        for message in &["msg1", "msg2"] {
+            let entry = storage.push_message(
+                friend.chat_handle(),
+                storage.self_user_handle(),
+                Message::Normal(message.to_string()),
+            )?;
+            storage.add_unresolved_message(entry.id(), SendState::Unsent)?;
+        }
+
+        let mut in_flight = Vec::new();
+
+        // First online transition, everything needs to go out
+        let messages = storage.unresovled_messages(friend.chat_handle())?;
+        let to_send = messages_without_receipts(messages, &in_flight);
+        assert_eq!(to_send.len(), 2);
+        in_flight.extend(to_send.iter().map(|message| *message.id()));
+
+        // Friend goes offline and back online before the receipts arrive
+        let messages = storage.unresovled_messages(friend.chat_handle())?;
+        assert_eq!(messages.len(), 2);
+        assert!(messages_without_receipts(messages, &in_flight).is_empty());
+
+        // A new message written while the receipts are outstanding still goes
+        // out
+        let entry = storage.push_message(
+            friend.chat_handle(),
+            storage.self_user_handle(),
+            Message::Normal("msg3".to_string()),
+        )?;
+        storage.add_unresolved_message(entry.id(), SendState::Unsent)?;
+
+        let messages = storage.unresovled_messages(friend.chat_handle())?;
+        let to_send = messages_without_receipts(messages, &in_flight);
+        assert_eq!(to_send.len(), 1);
+        assert_eq!(to_send[0].id(), entry.id());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_identity_used_by_existing_account() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db_path = |account: &str| dir.path().join(format!("{}.db", account));
+
+        let alice_pk = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+        let bob_pk = PublicKey::from_bytes(vec![2; PublicKey::SIZE])?;
+        drop(Storage::open(db_path("alice"), &alice_pk, "alice")?);
+        drop(Storage::open(db_path("bob"), &bob_pk, "bob")?);
+
+        // Accounts that never got as far as creating a DB don't match anything
+        let accounts = vec!["alice".to_string(), "bob".to_string(), "carol".to_string()];
+
+        assert_eq!(
+            account_with_identity(&accounts, &bob_pk, db_path),
+            Some("bob")
+        );
+
+        let new_pk = PublicKey::from_bytes(vec![3; PublicKey::SIZE])?;
+        assert_eq!(account_with_identity(&accounts, &new_pk, db_path), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_while_online() {
+        assert!(run_while_online(false, future::ready(()))
+            .now_or_never()
+            .is_none());
+        assert!(run_while_online(true, future::ready(()))
+            .now_or_never()
+            .is_some());
+    }
+
+    #[test]
+    fn test_account_lock_held_until_drop() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let lock_path = dir.path().join("account.lock");
+
+        let lock = lock_account_at(&lock_path, "account".to_string())?;
+
+        let err = lock_account_at(&lock_path, "account".to_string()).unwrap_err();
+        let in_use = err.downcast_ref::<AccountInUseError>().unwrap();
+        assert_eq!(in_use.0, "account");
+
+        drop(lock);
+        lock_account_at(&lock_path, "account".to_string())?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_tox_save_import() -> Result<()> {
+        let save_dir = tempfile::tempdir()?;
+        let path = save_dir.path().join("import.tox");
+
+        // The save's password has nothing to do with the new account's
+        SaveManager::new_with_password(path.clone(), "save password")?.save(b"data")?;
+
+        assert_eq!(read_tox_save(&path, "save password")?, b"data");
+        assert!(read_tox_save(&path, "account password").is_err());
+        assert!(read_tox_save(&path, "").is_err());
+
+        // Unencrypted saves import regardless of the password given
+        SaveManager::new_unencrypted(path.clone()).save(b"data")?;
+        assert_eq!(read_tox_save(&path, "")?, b"data");
+        assert_eq!(read_tox_save(&path, "save password")?, b"data");
+
+        Ok(())
+    }
+    rusty_fork_test! {
+        #[test]
+        fn test_received_message_inserted() {
+            let mut fixture = AccountFixture::new();
+            let (friend, tox_friend) = fixture.peer();
+
+            fixture
+                .account
+                .handle_toxcore_event(CoreEvent::MessageReceived(
+                    tox_friend,
+                    Message::Normal("hello".to_string()),
+                ))
+                .unwrap();
+
+            let events = fixture.events();
+            assert_eq!(events.len(), 2);
+            match &events[0] {
+                AccountEvent::ChatMessageInserted(chat, entry) => {
+                    assert_eq!(chat, friend.chat_handle());
+                    assert_eq!(entry.sender(), friend.id());
+                    assert_eq!(entry.message(), &Message::Normal("hello".to_string()));
+                }
+                event => panic!("Unexpected event {:?}", event),
+            }
+            assert!(matches!(
+                events[1],
+                AccountEvent::UnreadCountChanged(chat, 1) if chat == *friend.chat_handle()
+            ));
+
+            let messages = fixture.account.load_messages(friend.chat_handle(), 10).unwrap();
+            assert_eq!(messages.len(), 1);
+        }
+
+        #[test]
+        fn test_receipt_completes_message() {
+            let mut fixture = AccountFixture::new();
+            fixture.expect_messages_sent();
+            let (friend, tox_friend) = fixture.peer();
+            let chat = *friend.chat_handle();
+
+            let entries = fixture
+                .account
+                .send_message(&chat, "hello".to_string())
+                .unwrap();
+            assert_eq!(entries.len(), 1);
+            assert!(!entries[0].complete());
+
+            // Receipts for messages we aren't tracking change nothing
+            fixture
+                .account
+                .handle_toxcore_event(CoreEvent::ReadReceipt(mock::receipt(&tox_friend, 2)))
+                .unwrap();
+            assert!(fixture.events().is_empty());
+
+            fixture
+                .account
+                .handle_toxcore_event(CoreEvent::ReadReceipt(mock::receipt(&tox_friend, 1)))
+                .unwrap();
+            assert!(matches!(
+                fixture.events()[..],
+                [AccountEvent::ChatMessageCompleted(event_chat, id)]
+                    if event_chat == chat && id == *entries[0].id()
+            ));
+
+            // Nothing left to flush when the friend next comes online
+            assert!(fixture.account.storage.unresovled_messages(&chat).unwrap().is_empty());
+        }
+
+        #[test]
+        fn test_in_flight_messages_resent_after_disconnect() {
+            let mut fixture = AccountFixture::new();
+            fixture.expect_messages_sent();
+            let (friend, tox_friend) = fixture.peer();
+            let chat = *friend.chat_handle();
+
+            fixture.set_peer_status(ToxStatus::Online, ConnectionStatus::Udp);
+
+            let entries = fixture
+                .account
+                .send_message(&chat, "hello".to_string())
+                .unwrap();
+            let message_id = *entries[0].id();
+            let in_flight = |fixture: &AccountFixture| -> Vec<u32> {
+                fixture
+                    .account
+                    .outgoing_messages
+                    .iter()
+                    .filter(|(_, (_, id, _))| *id == message_id)
+                    .map(|(receipt, _)| receipt.id())
+                    .collect()
+            };
+            assert_eq!(in_flight(&fixture), vec![1]);
+
+            // The receipt for the first attempt will never come
+            fixture.account.go_offline().unwrap();
+            assert!(in_flight(&fixture).is_empty());
+
+            fixture.account.go_online().unwrap();
+            assert_eq!(in_flight(&fixture), vec![2]);
+
+            // The friend dropping off keeps the receipt, it may still come
+            fixture.set_peer_status(ToxStatus::Offline, ConnectionStatus::None);
+            assert_eq!(in_flight(&fixture), vec![2]);
+
+            fixture.set_peer_status(ToxStatus::Online, ConnectionStatus::Udp);
+            assert_eq!(in_flight(&fixture), vec![2]);
+
+            fixture
+                .account
+                .handle_toxcore_event(CoreEvent::ReadReceipt(mock::receipt(&tox_friend, 2)))
+                .unwrap();
+            assert!(fixture.account.storage.unresovled_messages(&chat).unwrap().is_empty());
+        }
+
+        #[test]
+        fn test_resend_replaces_receipt() {
+            let mut fixture = AccountFixture::new();
+            fixture.expect_messages_sent();
+            let (friend, tox_friend) = fixture.peer();
+            let chat = *friend.chat_handle();
+
+            fixture.set_peer_status(ToxStatus::Online, ConnectionStatus::Udp);
+
+            let entries = fixture
+                .account
+                .send_message(&chat, "hello".to_string())
+                .unwrap();
+            let message_id = *entries[0].id();
+
+            fixture.account.resend_message(&chat, &message_id).unwrap();
+
+            // Only the new attempt is tracked, not both
+            let in_flight: Vec<(u32, ChatMessageId)> = fixture
+                .account
+                .outgoing_messages
+                .iter()
+                .map(|(receipt, (_, id, _))| (receipt.id(), *id))
+                .collect();
+            assert_eq!(in_flight, vec![(2, message_id)]);
+
+            // The stale receipt completes nothing
+            fixture
+                .account
+                .handle_toxcore_event(CoreEvent::ReadReceipt(mock::receipt(&tox_friend, 1)))
+                .unwrap();
+            assert_eq!(fixture.account.storage.unresovled_messages(&chat).unwrap().len(), 1);
+
+            fixture
+                .account
+                .handle_toxcore_event(CoreEvent::ReadReceipt(mock::receipt(&tox_friend, 2)))
+                .unwrap();
+            assert!(fixture.account.storage.unresovled_messages(&chat).unwrap().is_empty());
+            assert!(fixture.events().iter().any(|event| matches!(
+                event,
+                AccountEvent::ChatMessageCompleted(_, id) if *id == message_id
+            )));
+        }
+
+        #[test]
+        fn test_friend_reconnect_does_not_resend() {
+            let mut fixture = AccountFixture::new();
+            let (friend, _) = fixture.peer();
+            let chat = *friend.chat_handle();
+
+            fixture.set_peer_status(ToxStatus::Online, ConnectionStatus::Udp);
+
+            let ctx = sys::tox_friend_send_message_context();
+            ctx.expect().return_const(1u32).times(1);
+
+            fixture
+                .account
+                .send_message(&chat, "hello".to_string())
+                .unwrap();
+
+            fixture.set_peer_status(ToxStatus::Offline, ConnectionStatus::None);
+            fixture.set_peer_status(ToxStatus::Online, ConnectionStatus::Udp);
+
+            ctx.checkpoint();
+        }
+
+        #[test]
+        fn test_offline_mode_toggles_friend_status() {
+            let mut fixture = AccountFixture::new();
+            let (friend, _) = fixture.peer();
+            let peer = *friend.id();
+
+            fixture.set_peer_status(ToxStatus::Online, ConnectionStatus::Udp);
+            fixture.events();
+
+            let status_changes = |events: Vec<AccountEvent>| -> Vec<Status> {
+                events
+                    .into_iter()
+                    .filter_map(|event| match event {
+                        AccountEvent::FriendStatusChanged(user, status) if user == peer => {
+                            Some(status)
+                        }
+                        _ => None,
+                    })
+                    .collect()
+            };
+
+            fixture.account.go_offline().unwrap();
+            assert!(!fixture.account.online);
+            assert_eq!(status_changes(fixture.events()), vec![Status::Offline]);
+            assert_eq!(*fixture.peer().0.status(), Status::Offline);
+
+            // Already offline, nothing to report
+            fixture.account.go_offline().unwrap();
+            assert!(fixture.events().is_empty());
+
+            // toxcore still has the peer connected, so they come straight back
+            fixture.account.go_online().unwrap();
+            assert!(fixture.account.online);
+            assert_eq!(status_changes(fixture.events()), vec![Status::Online]);
+            assert_eq!(*fixture.peer().0.status(), Status::Online);
+
+            fixture.account.go_online().unwrap();
+            assert!(fixture.events().is_empty());
+        }
+
+        #[test]
+        fn test_mark_chat_read_persists_and_reports() {
+            let mut fixture = AccountFixture::new();
+            let (friend, tox_friend) = fixture.peer();
+            let chat = *friend.chat_handle();
+
+            fixture
+                .account
+                .handle_toxcore_event(CoreEvent::MessageReceived(
+                    tox_friend,
+                    Message::Normal("hello".to_string()),
+                ))
+                .unwrap();
+            let read_time = match &fixture.events()[0] {
+                AccountEvent::ChatMessageInserted(_, entry) => *entry.timestamp(),
+                event => panic!("Unexpected event {:?}", event),
+            };
+            assert_eq!(fixture.account.unread_count(&chat).unwrap(), 1);
+
+            let AccountFixture {
+                account,
+                event_rx,
+                contexts: _contexts,
+                _tox_contexts,
+                _dir,
+                ..
+            } = fixture;
+
+            let (_ui_event_tx, ui_event_rx) = mpsc::unbounded();
+            let (tocks_event_tx, mut tocks_event_rx) = mpsc::unbounded();
+            let mut tocks = crate::Tocks {
+                account_manager: AccountManager::new(),
+                ui_event_rx,
+                tocks_event_tx,
+            };
+            let account_id = tocks.account_manager.add_account(account, event_rx);
+
+            tocks
+                .handle_ui_request(crate::TocksUiEvent::MarkChatRead(account_id, chat, read_time))
+                .unwrap();
+
+            let events: Vec<TocksEvent> =
+                std::iter::from_fn(|| tocks_event_rx.try_next().ok().flatten()).collect();
+            assert!(matches!(
+                events[..],
+                [
+                    TocksEvent::ChatReadTimeUpdated(read_account, read_chat, time),
+                    TocksEvent::UnreadCountChanged(unread_account, unread_chat, 0),
+                ] if read_account == account_id
+                    && read_chat == chat
+                    && time == read_time
+                    && unread_account == account_id
+                    && unread_chat == chat
+            ));
+
+            let account = tocks.account_manager.get(&account_id).unwrap();
+            assert_eq!(account.storage.chat_read_time(&chat).unwrap(), Some(read_time));
+        }
+
+        #[test]
+        fn test_flushed_message_send_state_reported() {
+            let mut fixture = AccountFixture::new();
+            fixture.expect_messages_sent();
+            let (friend, _) = fixture.peer();
+            let chat = *friend.chat_handle();
+
+            fixture.set_peer_status(ToxStatus::Offline, ConnectionStatus::None);
+            let entries = fixture
+                .account
+                .send_message(&chat, "hello".to_string())
+                .unwrap();
+            let message_id = *entries[0].id();
+            assert_eq!(entries[0].send_state(), Some(SendState::Unsent));
+            fixture.events();
+
+            fixture.set_peer_status(ToxStatus::Online, ConnectionStatus::Udp);
+            assert!(fixture.events().iter().any(|event| matches!(
+                event,
+                AccountEvent::ChatMessageSendStateChanged(event_chat, id, SendState::Sent)
+                    if *event_chat == chat && *id == message_id
+            )));
+
+            let loaded = fixture.account.load_messages(&chat, 10).unwrap();
+            assert_eq!(loaded[0].send_state(), Some(SendState::Sent));
+        }
+
+        #[test]
+        fn test_auto_reply_once_per_away_period() {
+            let mut fixture = AccountFixture::new();
+            let (_, tox_friend) = fixture.peer();
+            fixture.set_peer_status(ToxStatus::Online, ConnectionStatus::Udp);
+
+            let sent = Rc::new(RefCell::new(Vec::new()));
+            let sent_clone = Rc::clone(&sent);
+            let ctx = sys::tox_friend_send_message_context();
+            ctx.expect()
+                .returning_st(move |_, _, _, message, length, _| unsafe {
+                    let message = std::slice::from_raw_parts(message, length as usize);
+                    sent_clone.borrow_mut().push(message.to_vec());
+                    1
+                });
+
+            let ctx = sys::tox_self_set_status_context();
+            ctx.expect().return_const(());
+
+            fixture
+                .account
+                .set_auto_reply(Some("brb".to_string()))
+                .unwrap();
+
+            let receive = |fixture: &mut AccountFixture| {
+                fixture
+                    .account
+                    .handle_toxcore_event(CoreEvent::MessageReceived(
+                        tox_friend.clone(),
+                        Message::Normal("hello".to_string()),
+                    ))
+                    .unwrap();
+            };
+
+            // Only while away or busy
+            receive(&mut fixture);
+            assert!(sent.borrow().is_empty());
+
+            fixture.account.set_self_status(Status::Away).unwrap();
+            receive(&mut fixture);
+            receive(&mut fixture);
+            fixture.account.set_self_status(Status::Busy).unwrap();
+            receive(&mut fixture);
+            assert_eq!(*sent.borrow(), vec![b"brb".to_vec()]);
+
+            // Being back online starts a new away period
+            fixture.account.set_self_status(Status::Online).unwrap();
+            receive(&mut fixture);
+            fixture.account.set_self_status(Status::Away).unwrap();
+            receive(&mut fixture);
+            assert_eq!(*sent.borrow(), vec![b"brb".to_vec(), b"brb".to_vec()]);
+        }
+
+        #[test]
+        fn test_refused_message_does_not_stop_flush() {
+            let mut fixture = AccountFixture::new();
+            let (friend, _) = fixture.peer();
+            let chat = *friend.chat_handle();
+
+            fixture.set_peer_status(ToxStatus::Offline, ConnectionStatus::None);
+            let refused = fixture
+                .account
+                .send_message(&chat, "refused".to_string())
+                .unwrap();
+            let queued = fixture
+                .account
+                .send_message(&chat, "queued".to_string())
+                .unwrap();
+            fixture.events();
+
+            let sent = Rc::new(RefCell::new(Vec::new()));
+            let sent_clone = Rc::clone(&sent);
+            let ctx = sys::tox_friend_send_message_context();
+            ctx.expect()
+                .returning_st(move |_, _, _, message, length, err| unsafe {
+                    let message = std::slice::from_raw_parts(message, length as usize);
+                    if message == b"refused" {
+                        *err = ffi::TOX_ERR_FRIEND_SEND_MESSAGE_SENDQ;
+                        return 0;
+                    }
+                    sent_clone.borrow_mut().push(message.to_vec());
+                    1
+                });
+
+            fixture.set_peer_status(ToxStatus::Online, ConnectionStatus::Udp);
+            assert_eq!(*sent.borrow(), vec![b"queued".to_vec()]);
+
+            let events = fixture.events();
+            assert!(events.iter().any(|event| matches!(
+                event,
+                AccountEvent::ChatMessageSendStateChanged(_, id, SendState::Failed)
+                    if id == refused[0].id()
+            )));
+            assert!(events.iter().any(|event| matches!(
+                event,
+                AccountEvent::ChatMessageSendStateChanged(_, id, SendState::Sent)
+                    if id == queued[0].id()
+            )));
+            assert!(events.iter().any(|event| matches!(
+                event,
+                AccountEvent::FriendStatusChanged(_, Status::Online)
+            )));
+        }
+
+        #[test]
+        fn test_removed_friend_not_sent_to() {
+            let mut fixture = AccountFixture::new();
+            let public_key = fixture.expect_new_peer(11, b"testkey2");
+            fixture
+                .account
+                .set_auto_accept(AutoAcceptPolicy::All)
+                .unwrap();
+            fixture
+                .account
+                .handle_toxcore_event(CoreEvent::FriendRequest(toxcore::FriendRequest {
+                    public_key: public_key.clone(),
+                    message: "hi".to_string(),
+                }))
+                .unwrap();
+
+            let bundle = fixture
+                .account
+                .user_manager
+                .friend_bundles_mut()
+                .find(|bundle| *bundle.friend.public_key() == public_key)
+                .unwrap();
+            let chat = *bundle.friend.chat_handle();
+            let tox_friend = bundle.tox_friend.clone().unwrap();
+
+            let queued = fixture
+                .account
+                .send_message(&chat, "queued".to_string())
+                .unwrap();
+            fixture.events();
+
+            // No tox_friend_send_message expectation, a send to the stale
+            // friend number would fail the test
+            fixture.remove_peer_from_tox(11);
+            mock::set_friend_status(&tox_friend, ToxStatus::Online, ConnectionStatus::Udp);
+            fixture
+                .account
+                .handle_toxcore_event(CoreEvent::StatusUpdated(tox_friend))
+                .unwrap();
+
+            let failed = |events: Vec<AccountEvent>| {
+                events.iter().any(|event| matches!(
+                    event,
+                    AccountEvent::ChatMessageSendStateChanged(_, id, SendState::Failed)
+                        if id == queued[0].id()
+                ))
+            };
+            assert!(failed(fixture.events()));
+
+            assert!(fixture
+                .account
+                .resend_message(&chat, queued[0].id())
+                .is_err());
+            assert!(failed(fixture.events()));
+        }
+
+        #[test]
+        fn test_missing_receipt_times_out() {
+            let mut fixture = AccountFixture::new();
+            fixture.expect_messages_sent();
+            let (friend, _) = fixture.peer();
+            let chat = *friend.chat_handle();
+
+            fixture.set_peer_status(ToxStatus::Online, ConnectionStatus::Udp);
+            let entries = fixture
+                .account
+                .send_message(&chat, "hello".to_string())
+                .unwrap();
+            let message_id = *entries[0].id();
+            fixture.events();
+
+            let deadline = fixture.account.next_receipt_timeout().unwrap();
+            fixture
+                .account
+                .expire_receipts(deadline - Duration::from_secs(1))
+                .unwrap();
+            assert_eq!(fixture.account.outgoing_messages.len(), 1);
+            assert!(fixture.events().is_empty());
+
+            fixture.account.expire_receipts(deadline).unwrap();
+            assert!(fixture.account.outgoing_messages.is_empty());
+            assert_eq!(fixture.account.next_receipt_timeout(), None);
+            assert!(fixture.events().iter().any(|event| matches!(
+                event,
+                AccountEvent::ChatMessageSendStateChanged(event_chat, id, SendState::Failed)
+                    if *event_chat == chat && *id == message_id
+            )));
+
+            let loaded = fixture.account.load_messages(&chat, 10).unwrap();
+            assert_eq!(loaded[0].send_state(), Some(SendState::Failed));
+        }
+
+        #[test]
+        fn test_queued_reply_sends_marker() {
+            let mut fixture = AccountFixture::new();
+            fixture.expect_messages_sent();
+            let (friend, _) = fixture.peer();
+            let chat = *friend.chat_handle();
+
+            let question = fixture
+                .account
+                .storage
+                .push_message(&chat, *friend.id(), Message::Normal("/me asks about lunch".into()))
+                .unwrap();
+
+            // The peer is offline, so the reply is queued along with its marker
+            fixture
+                .account
+                .send_reply(&chat, question.id(), "sure".to_string())
+                .unwrap();
+
+            let packets = Rc::new(RefCell::new(Vec::new()));
+            let ctx = sys::tox_friend_send_lossless_packet_context();
+            let sent = Rc::clone(&packets);
+            ctx.expect().returning_st(move |_, _, data, length, _| {
+                let data = unsafe { std::slice::from_raw_parts(data, length as usize) };
+                sent.borrow_mut().push(data.to_vec());
+                true
+            });
+            fixture.contexts.push(Box::new(ctx));
+
+            fixture.set_peer_status(ToxStatus::Online, ConnectionStatus::Udp);
+
+            let packets = packets.borrow();
+            assert_eq!(packets.len(), 1);
+            let marker = ReplyMarker::decode(&packets[0]).unwrap();
+            assert_eq!(marker.quoted, "/me asks about lunch");
+            assert!(marker.is_for(&Message::Normal("sure".into())));
+        }
+
+        #[test]
+        fn test_reply_marker_only_applies_to_its_reply() {
+            let mut fixture = AccountFixture::new();
+            let (friend, tox_friend) = fixture.peer();
+            let chat = *friend.chat_handle();
+
+            let question = fixture
+                .account
+                .storage
+                .push_message(
+                    &chat,
+                    fixture.account.user_handle,
+                    Message::Normal("lunch?".into()),
+                )
+                .unwrap();
+            let marker = ReplyMarker::new(
+                &Message::Normal("lunch?".into()),
+                &Message::Normal("sure".into()),
+            );
+
+            let receive = |fixture: &mut AccountFixture, message: &str| {
+                fixture
+                    .account
+                    .handle_toxcore_event(CoreEvent::CustomPacketReceived(
+                        tox_friend.clone(),
+                        marker.encode(),
+                    ))
+                    .unwrap();
+                fixture
+                    .account
+                    .handle_toxcore_event(CoreEvent::MessageReceived(
+                        tox_friend.clone(),
+                        Message::Normal(message.to_string()),
+                    ))
+                    .unwrap();
+
+                match fixture.events().into_iter().next() {
+                    Some(AccountEvent::ChatMessageInserted(_, entry)) => entry.reply_to().copied(),
+                    event => panic!("Unexpected event {:?}", event),
+                }
+            };
+
+            // The reply never made it, the next message must not take its place
+            assert_eq!(receive(&mut fixture, "something else"), None);
+            assert_eq!(receive(&mut fixture, "sure"), Some(*question.id()));
+        }
+
+        #[test]
+        fn test_flooded_messages_released_together() {
+            let mut fixture = AccountFixture::new();
+            let (friend, tox_friend) = fixture.peer();
+            let window = std::time::Duration::from_secs(10);
+            fixture
+                .account
+                .set_flood_limits(FloodLimits {
+                    max_messages: 1,
+                    window,
+                })
+                .unwrap();
+
+            for text in &["one", "two", "three"] {
+                fixture
+                    .account
+                    .handle_toxcore_event(CoreEvent::MessageReceived(
+                        tox_friend.clone(),
+                        Message::Normal(text.to_string()),
+                    ))
+                    .unwrap();
+            }
+
+            let inserted = |events: Vec<AccountEvent>| -> Vec<Message> {
+                events
+                    .into_iter()
+                    .filter_map(|event| match event {
+                        AccountEvent::ChatMessageInserted(_, entry) => {
+                            Some(entry.message().clone())
+                        }
+                        _ => None,
+                    })
+                    .collect()
+            };
+            assert_eq!(inserted(fixture.events()), vec![Message::Normal("one".into())]);
+
+            // Nothing is released until the window has room again
+            fixture.account.release_held_messages(Instant::now());
+            assert!(fixture.events().is_empty());
+
+            fixture.account.release_held_messages(Instant::now() + window);
+            assert_eq!(
+                inserted(fixture.events()),
+                vec![Message::Normal("two\nthree".into())]
+            );
+
+            let messages = fixture.account.load_messages(friend.chat_handle(), 10).unwrap();
+            assert_eq!(messages.len(), 2);
+        }
+
+        #[test]
+        fn test_shortcodes_expanded_only_when_enabled() {
+            let mut fixture = AccountFixture::new();
+            fixture.expect_messages_sent();
+            let (friend, _) = fixture.peer();
+            let chat = *friend.chat_handle();
+
+            fixture.set_peer_status(ToxStatus::Online, ConnectionStatus::Udp);
+
+            let entries = fixture
+                .account
+                .send_message(&chat, ":smile:".to_string())
+                .unwrap();
+            assert_eq!(entries[0].message(), &Message::Normal(":smile:".into()));
+
+            fixture.account.set_shortcode_expansion(true);
+            let entries = fixture
+                .account
+                .send_message(&chat, ":smile:".to_string())
+                .unwrap();
+            assert_eq!(entries[0].message(), &Message::Normal("😄".into()));
+        }
+
+        #[test]
+        fn test_friending_self_rejected() {
+            let mut fixture = AccountFixture::new();
+            let self_public_key = mock::public_key(SELF_PUBLIC_KEY);
+
+            let add_ctx = sys::tox_friend_add_context();
+            add_ctx.expect().never();
+            let add_norequest_ctx = sys::tox_friend_add_norequest_context();
+            add_norequest_ctx.expect().never();
+
+            // Our key with a different nospam is still us
+            let err = fixture
+                .account
+                .request_friend(mock::tox_id(&self_public_key), "hi".to_string())
+                .unwrap_err();
+            assert_eq!(err.to_string(), "Cannot add yourself as a friend");
+
+            fixture
+                .account
+                .handle_toxcore_event(CoreEvent::FriendRequest(toxcore::FriendRequest {
+                    public_key: self_public_key.clone(),
+                    message: "hi".to_string(),
+                }))
+                .unwrap();
+            let friend_id = *fixture
+                .account
+                .user_manager
+                .find_by_public_key(&self_public_key)
+                .unwrap()
+                .id();
+
+            let err = fixture.account.add_pending_friend(&friend_id).unwrap_err();
+            assert_eq!(err.to_string(), "Cannot add yourself as a friend");
+        }
+
+        #[test]
+        fn test_auto_accepted_request_becomes_friend() {
+            let mut fixture = AccountFixture::new();
+            let public_key = fixture.expect_new_peer(11, b"testkey2");
+            fixture
+                .account
+                .set_auto_accept(AutoAcceptPolicy::All)
+                .unwrap();
+
+            fixture
+                .account
+                .handle_toxcore_event(CoreEvent::FriendRequest(toxcore::FriendRequest {
+                    public_key: public_key.clone(),
+                    message: "hi".to_string(),
+                }))
+                .unwrap();
+
+            let bundle = fixture
+                .account
+                .user_manager
+                .friend_bundles_mut()
+                .find(|bundle| *bundle.friend.public_key() == public_key)
+                .unwrap();
+            assert_eq!(*bundle.friend.status(), Status::Offline);
+            assert!(bundle.tox_friend.is_some());
+            let friend_id = *bundle.friend.id();
+
+            // Nothing is left waiting for the user to accept
+            let stored = fixture
+                .account
+                .storage
+                .friends()
+                .unwrap()
+                .into_iter()
+                .find(|friend| *friend.id() == friend_id)
+                .unwrap();
+            assert_eq!(*stored.status(), Status::Offline);
+
+            assert!(fixture.events().iter().any(|event| matches!(
+                event,
+                AccountEvent::FriendStatusChanged(id, Status::Offline) if *id == friend_id
+            )));
+        }
+
+        #[test]
+        fn test_requesting_pending_friend_accepts_them() {
+            let mut fixture = AccountFixture::new();
+            let public_key = fixture.expect_new_peer(11, b"testkey2");
+
+            fixture
+                .account
+                .handle_toxcore_event(CoreEvent::FriendRequest(toxcore::FriendRequest {
+                    public_key: public_key.clone(),
+                    message: "hi".to_string(),
+                }))
+                .unwrap();
+            fixture.events();
+
+            // No tox_friend_add expectation, a second request through
+            // toxcore would fail the test
+            let friend = fixture
+                .account
+                .request_friend(mock::tox_id(&public_key), "hello".to_string())
+                .unwrap();
+            assert_eq!(*friend.status(), Status::Offline);
+
+            let friends: Vec<&Friend> = fixture
+                .account
+                .user_manager
+                .friends()
+                .filter(|friend| *friend.public_key() == public_key)
+                .collect();
+            assert_eq!(friends.len(), 1);
+            assert_eq!(friends[0].id(), friend.id());
+            assert_eq!(*friends[0].status(), Status::Offline);
+        }
+
+        #[test]
+        fn test_friend_request_from_existing_friend_ignored() {
+            let mut fixture = AccountFixture::new();
+            let (friend, _) = fixture.peer();
+            let num_friends = fixture.account.storage.friends().unwrap().len();
+
+            fixture
+                .account
+                .handle_toxcore_event(CoreEvent::FriendRequest(toxcore::FriendRequest {
+                    public_key: friend.public_key().clone(),
+                    message: "add me again".to_string(),
+                }))
+                .unwrap();
+
+            assert!(fixture.events().is_empty());
+            assert_eq!(fixture.account.storage.friends().unwrap().len(), num_friends);
+            assert!(fixture
+                .account
+                .load_messages(friend.chat_handle(), 10)
+                .unwrap()
+                .is_empty());
+            let (friend_after, _) = fixture.peer();
+            assert_eq!(friend_after.status(), friend.status());
+        }
+
+        #[test]
+        fn test_sending_message_stops_typing() {
+            let mut fixture = AccountFixture::new();
+            fixture.expect_messages_sent();
+            let (friend, _) = fixture.peer();
+            let chat = *friend.chat_handle();
+
+            fixture.set_peer_status(ToxStatus::Online, ConnectionStatus::Udp);
+
+            let typing_states = Rc::new(RefCell::new(Vec::new()));
+            let ctx = sys::tox_self_set_typing_context();
+            let states = Rc::clone(&typing_states);
+            ctx.expect().returning_st(move |_, _, typing, _| {
+                states.borrow_mut().push(typing);
+                true
+            });
+            fixture.contexts.push(Box::new(ctx));
+
+            fixture.account.set_typing(&chat).unwrap();
+            fixture
+                .account
+                .send_message(&chat, "hello".to_string())
+                .unwrap();
+            assert_eq!(*typing_states.borrow(), vec![true, false]);
+
+            // Nobody to tell while offline, that isn't an error
+            fixture.account.go_offline().unwrap();
+            fixture.account.set_typing(&chat).unwrap();
+            assert_eq!(*typing_states.borrow(), vec![true, false]);
+        }
+
+        #[test]
+        fn test_removal_hint_follows_last_online() {
+            let mut fixture = AccountFixture::new();
+            let (friend, _) = fixture.peer();
+            let long_ago = Utc::now() - chrono::Duration::days(DAYS_BEFORE_HINT);
+
+            fixture.account.check_removal_hints();
+            assert!(fixture.events().is_empty());
+
+            fixture
+                .account
+                .user_manager
+                .friend_by_user_handle(friend.id())
+                .friend
+                .set_last_online(Some(long_ago));
+            fixture.account.check_removal_hints();
+            let events = fixture.events();
+            assert_eq!(events.len(), 1);
+            assert!(matches!(
+                events[0],
+                AccountEvent::FriendPossiblyRemoved(user, true) if user == *friend.id()
+            ));
+
+            // Showing up again is proof enough that we're still friends
+            fixture.set_peer_status(ToxStatus::Online, ConnectionStatus::Udp);
+            assert!(fixture.events().iter().any(|event| matches!(
+                event,
+                AccountEvent::FriendPossiblyRemoved(user, false) if user == friend.id()
+            )));
+        }
+    }
+}
@@ -9,6 +9,8 @@ use openal_sys as oal;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::metrics::COUNTERS;
+
 use futures::{
     channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
     prelude::*,
@@ -21,6 +23,10 @@ const CAPTURE_SAMPLE_RATE: u32 = 48000;
 const CAPTURE_SAMPLE_READ_INTERVAL: i32 = 960;
 // Give a little room in case we back up a little
 const CAPTURE_BUFFER_SIZE: i32 = CAPTURE_SAMPLE_READ_INTERVAL * 4 * (CAPTURE_CHANNELS as i32);
+// Frames queued per capture observer. Each frame is 20ms of audio, so a slow
+// observer hears at most ~100ms of backlog before we start dropping the oldest
+// frames. Stale audio is worse than missing audio in a call
+pub const CAPTURE_CHANNEL_DEPTH: usize = 5;
 
 #[cfg_attr(test, mockall::automock)]
 mod oal_func_impl {
@@ -74,8 +80,13 @@ use oal_func_impl as oal_func;
 use std::{
     collections::VecDeque,
     ffi::{c_void, CStr, CString},
+    pin::Pin,
     ptr::NonNull,
-    sync::Mutex,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context as TaskContext, Poll},
     time::Duration,
 };
 
@@ -130,6 +141,13 @@ struct OalSource {
     // to be fed in
     available_buffers: Vec<u32>,
     processing_buffers: VecDeque<u32>,
+    // Whether we've ever started playback. A started source that is found
+    // stopped when new data arrives has run out of data
+    started: bool,
+    looping: bool,
+    // OpenAL format and sample rate of the queued buffers. Every buffer in a
+    // source's queue has to match
+    queued_format: Option<(i32, i32)>,
 }
 
 impl OalSource {
@@ -155,6 +173,9 @@ impl OalSource {
                 source,
                 available_buffers: buffers,
                 processing_buffers: Default::default(),
+                started: false,
+                looping,
+                queued_format: None,
             })
         }
     }
@@ -187,6 +208,8 @@ impl OalSource {
                 oal_func::alSourceQueueBuffers(self.source, 1, bufid);
                 oal_result().context("Failed to queue buffer on source")?;
             }
+
+            self.queued_format = Some((frame.data.get_oal_format(), frame.sample_rate));
         } else {
             return Err(anyhow!("Cannot queue any more samples onto source"));
         }
@@ -196,6 +219,12 @@ impl OalSource {
 
         unsafe {
             if !self.playing()? {
+                if self.started {
+                    debug!("Audio source {} underran", self.source);
+                    COUNTERS.playback_underrun();
+                }
+                self.started = true;
+
                 debug!("Starting audio source {}", self.source);
                 oal_func::alSourcePlay(self.source);
                 oal_result().context("Failed to play source")?;
@@ -205,6 +234,22 @@ impl OalSource {
         Ok(())
     }
 
+    /// Whether frame can be queued behind the audio already on the source
+    fn accepts_format(&mut self, frame: &AudioFrame) -> Result<bool> {
+        self.reclaim_processed_buffers()
+            .context("Failed to reclaim processed buffers")?;
+
+        if self.processing_buffers.is_empty() {
+            return Ok(true);
+        }
+
+        Ok(self.queued_format == Some((frame.data.get_oal_format(), frame.sample_rate)))
+    }
+
+    fn num_buffers(&self) -> usize {
+        self.available_buffers.len() + self.processing_buffers.len()
+    }
+
     fn playing(&self) -> Result<bool> {
         unsafe {
             let mut source_state = oal::AL_STOPPED as i32;
@@ -284,7 +329,7 @@ impl Drop for OalSource {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum OutputDevice {
     Default,
     Named(String),
@@ -299,9 +344,33 @@ impl ToString for OutputDevice {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum FormattedAudio {
     Mp3(Vec<u8>),
+    Wav(Vec<u8>),
+}
+
+impl FormattedAudio {
+    /// Picks the container from the file contents rather than trusting the
+    /// extension. Anything that isn't a RIFF/WAVE file is treated as mp3
+    pub fn from_bytes(data: Vec<u8>) -> FormattedAudio {
+        if data.starts_with(b"RIFF") {
+            FormattedAudio::Wav(data)
+        } else {
+            FormattedAudio::Mp3(data)
+        }
+    }
+
+    /// Checks that the audio decodes to at least one playable frame
+    pub fn validate(&self) -> Result<()> {
+        let (tx, mut rx) = mpsc::unbounded();
+        decode_into_channel(self.clone(), &tx)?;
+
+        match rx.try_next() {
+            Ok(Some(_)) => Ok(()),
+            _ => bail!("No playable audio"),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -331,8 +400,222 @@ pub struct AudioFrame {
     pub sample_rate: i32,
 }
 
+impl AudioFrame {
+    fn duration(&self) -> Duration {
+        let samples_per_channel = match &self.data {
+            AudioData::Mono8(data) => data.len(),
+            AudioData::Mono16(data) => data.len(),
+            AudioData::Stereo8(data) => data.len() / 2,
+            AudioData::Stereo16(data) => data.len() / 2,
+        };
+
+        if self.sample_rate <= 0 {
+            return Duration::from_secs(0);
+        }
+
+        Duration::from_secs_f64(samples_per_channel as f64 / self.sample_rate as f64)
+    }
+}
+
 type Streams = Vec<(UnboundedReceiver<AudioFrame>, OalSource)>;
 
+struct CaptureChannelShared {
+    frames: Mutex<VecDeque<AudioFrame>>,
+    waker: futures::task::AtomicWaker,
+    sender_dropped: AtomicBool,
+    receiver_dropped: AtomicBool,
+}
+
+#[derive(Error, Debug)]
+#[error("Capture receiver dropped")]
+struct CaptureChannelClosed;
+
+/// Sending half of a capture channel. Sending never blocks, if the receiver
+/// falls behind the oldest queued frame is dropped
+struct CaptureSender {
+    shared: Arc<CaptureChannelShared>,
+    depth: usize,
+}
+
+impl CaptureSender {
+    fn send(&self, frame: AudioFrame) -> Result<(), CaptureChannelClosed> {
+        if self.shared.receiver_dropped.load(Ordering::Acquire) {
+            return Err(CaptureChannelClosed);
+        }
+
+        {
+            let mut frames = self.shared.frames.lock().unwrap();
+            if frames.len() >= self.depth {
+                debug!("Capture observer is behind, dropping oldest frame");
+                frames.pop_front();
+                COUNTERS.capture_frame_dropped();
+            }
+            frames.push_back(frame);
+        }
+
+        self.shared.waker.wake();
+
+        Ok(())
+    }
+}
+
+impl Drop for CaptureSender {
+    fn drop(&mut self) {
+        self.shared.sender_dropped.store(true, Ordering::Release);
+        self.shared.waker.wake();
+    }
+}
+
+/// Receiving half of a capture channel, see [`AudioManager::create_capture_channel`]
+pub struct CaptureReceiver {
+    shared: Arc<CaptureChannelShared>,
+}
+
+impl Stream for CaptureReceiver {
+    type Item = AudioFrame;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<AudioFrame>> {
+        self.shared.waker.register(cx.waker());
+
+        if let Some(frame) = self.shared.frames.lock().unwrap().pop_front() {
+            return Poll::Ready(Some(frame));
+        }
+
+        if self.shared.sender_dropped.load(Ordering::Acquire) {
+            return Poll::Ready(None);
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for CaptureReceiver {
+    fn drop(&mut self) {
+        self.shared.receiver_dropped.store(true, Ordering::Release);
+    }
+}
+
+fn capture_channel(depth: usize) -> (CaptureSender, CaptureReceiver) {
+    let shared = Arc::new(CaptureChannelShared {
+        frames: Mutex::new(VecDeque::with_capacity(depth)),
+        waker: Default::default(),
+        sender_dropped: AtomicBool::new(false),
+        receiver_dropped: AtomicBool::new(false),
+    });
+
+    (
+        CaptureSender {
+            shared: Arc::clone(&shared),
+            depth,
+        },
+        CaptureReceiver { shared },
+    )
+}
+
+/// Lengths of audio frame toxav will encode. Sending any other length fails
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FramePeriod {
+    Ms10,
+    Ms20,
+    Ms40,
+    Ms60,
+}
+
+impl Default for FramePeriod {
+    fn default() -> FramePeriod {
+        FramePeriod::Ms20
+    }
+}
+
+impl FramePeriod {
+    pub fn duration(&self) -> Duration {
+        let ms = match self {
+            FramePeriod::Ms10 => 10,
+            FramePeriod::Ms20 => 20,
+            FramePeriod::Ms40 => 40,
+            FramePeriod::Ms60 => 60,
+        };
+
+        Duration::from_millis(ms)
+    }
+
+    fn samples_per_channel(&self, sample_rate: i32) -> usize {
+        (sample_rate.max(0) as u128 * self.duration().as_millis() / 1000) as usize
+    }
+}
+
+/// Re-chunks captured audio into frames of exactly one [`FramePeriod`]. The
+/// capture device hands us whatever it has buffered, which doesn't have to
+/// line up with what toxav accepts
+pub struct CaptureFramer {
+    period: FramePeriod,
+    pending: Vec<i16>,
+    channels: usize,
+    sample_rate: i32,
+}
+
+impl CaptureFramer {
+    pub fn new(period: FramePeriod) -> CaptureFramer {
+        CaptureFramer {
+            period,
+            pending: Vec::new(),
+            channels: 1,
+            sample_rate: 0,
+        }
+    }
+
+    /// Buffers frame and returns every complete frame now available. Leftover
+    /// samples wait for the next push
+    pub fn push(&mut self, frame: AudioFrame) -> Vec<AudioFrame> {
+        let (data, channels) = match frame.data {
+            AudioData::Mono16(data) => (data, 1),
+            AudioData::Stereo16(data) => (data, 2),
+            _ => {
+                warn!("Dropping captured audio in a format toxav can't send");
+                return Vec::new();
+            }
+        };
+
+        if channels != self.channels || frame.sample_rate != self.sample_rate {
+            if !self.pending.is_empty() {
+                debug!("Capture format changed, dropping partial frame");
+            }
+            self.pending.clear();
+            self.channels = channels;
+            self.sample_rate = frame.sample_rate;
+        }
+
+        self.pending.extend(data);
+
+        let frame_len = self.period.samples_per_channel(self.sample_rate) * self.channels;
+        if frame_len == 0 {
+            self.pending.clear();
+            return Vec::new();
+        }
+
+        let mut frames = Vec::new();
+        while self.pending.len() >= frame_len {
+            let rest = self.pending.split_off(frame_len);
+            let data = std::mem::replace(&mut self.pending, rest);
+
+            frames.push(AudioFrame {
+                data: match self.channels {
+                    1 => AudioData::Mono16(data),
+                    _ => AudioData::Stereo16(data),
+                },
+                sample_rate: self.sample_rate,
+            });
+        }
+
+        frames
+    }
+
+    /// Drops any partial frame, e.g. when capture stops between calls
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
+
 /// Wrapper around openal for our purposes.
 pub struct AudioManager {
     output_device_handle: NonNull<oal::ALCdevice>,
@@ -343,7 +626,7 @@ pub struct AudioManager {
     // poll these at some interval and drop them when the queued data is complete
     finishing_streams: Vec<OalSource>,
     capture_device_handle: *mut oal::ALCdevice,
-    capture_channels: Vec<UnboundedSender<AudioFrame>>,
+    capture_channels: Vec<CaptureSender>,
 }
 
 pub struct RepeatingAudioHandle {
@@ -352,6 +635,63 @@ pub struct RepeatingAudioHandle {
     _handle: UnboundedSender<AudioFrame>,
 }
 
+/// Plays captured audio back out of the output device so users can check
+/// their microphone and speakers before a call. Playback lags capture by a
+/// fixed delay so you hear yourself after you stop talking instead of over
+/// the top of yourself. Dropping the loopback releases both channels, see
+/// [`AudioManager::create_loopback`]
+pub struct AudioLoopback {
+    capture: CaptureReceiver,
+    playback: UnboundedSender<AudioFrame>,
+    delay: Duration,
+    pending: VecDeque<AudioFrame>,
+    pending_duration: Duration,
+}
+
+impl AudioLoopback {
+    fn new(
+        capture: CaptureReceiver,
+        playback: UnboundedSender<AudioFrame>,
+        delay: Duration,
+    ) -> AudioLoopback {
+        AudioLoopback {
+            capture,
+            playback,
+            delay,
+            pending: VecDeque::new(),
+            pending_duration: Duration::from_secs(0),
+        }
+    }
+
+    /// Forwards captured audio to playback. Returns once capture or playback
+    /// has shut down. Safe to cancel, no captured audio is lost if the
+    /// returned future is dropped
+    pub async fn run(&mut self) {
+        while let Some(frame) = self.capture.next().await {
+            if !self.push_frame(frame) {
+                return;
+            }
+        }
+    }
+
+    /// Returns false if playback has gone away
+    fn push_frame(&mut self, frame: AudioFrame) -> bool {
+        self.pending_duration += frame.duration();
+        self.pending.push_back(frame);
+
+        while self.pending_duration > self.delay {
+            let frame = self.pending.pop_front().unwrap();
+            self.pending_duration -= frame.duration();
+
+            if self.playback.unbounded_send(frame).is_err() {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
 impl AudioManager {
     pub fn new() -> Result<AudioManager> {
         unsafe {
@@ -365,7 +705,8 @@ impl AudioManager {
             // Clear OpenAL error state
             oal_func::alGetError();
 
-            // FIXME: Read device handle from storage
+            // Always start on the default device, callers switch to a saved
+            // preference with set_output_device
             let device_handle = NonNull::new(oal_func::alcOpenDevice(std::ptr::null()))
                 .context("OpenAL returned null device pointer")?;
 
@@ -454,7 +795,10 @@ impl AudioManager {
         RepeatingAudioHandle { _handle: handle }
     }
 
-    pub fn create_capture_channel(&mut self) -> Result<UnboundedReceiver<AudioFrame>> {
+    /// Returns a stream of captured audio. At most [`CAPTURE_CHANNEL_DEPTH`]
+    /// frames are queued, older frames are dropped if the stream is not
+    /// polled fast enough
+    pub fn create_capture_channel(&mut self) -> Result<CaptureReceiver> {
         if self.capture_device_handle.is_null() {
             unsafe {
                 self.capture_device_handle = oal::alcCaptureOpenDevice(
@@ -469,11 +813,23 @@ impl AudioManager {
             }
         }
 
-        let (tx, rx) = mpsc::unbounded();
+        let (tx, rx) = capture_channel(CAPTURE_CHANNEL_DEPTH);
         self.capture_channels.push(tx);
         Ok(rx)
     }
 
+    /// Starts playing captured audio back after delay
+    pub fn create_loopback(&mut self, delay: Duration) -> Result<AudioLoopback> {
+        let capture = self
+            .create_capture_channel()
+            .context("Failed to create loopback capture channel")?;
+        let playback = self
+            .create_playback_channel(50)
+            .context("Failed to create loopback playback channel")?;
+
+        Ok(AudioLoopback::new(capture, playback, delay))
+    }
+
     pub async fn run(&mut self) {
         loop {
             futures::select! {
@@ -522,7 +878,7 @@ impl AudioManager {
     }
 
     async fn service_capture_timer(
-        capture_channels: &[UnboundedSender<AudioFrame>],
+        capture_channels: &[CaptureSender],
         capture_device_handle: *mut oal::ALCdevice,
     ) {
         if capture_channels.is_empty() || capture_device_handle.is_null() {
@@ -571,8 +927,8 @@ impl AudioManager {
     ) -> UnboundedSender<AudioFrame> {
         let notification_handle = self.create_playback_channel_priv(50, looping).unwrap();
 
-        match container {
-            FormattedAudio::Mp3(data) => Self::decode_mp3_into_channel(data, &notification_handle),
+        if let Err(e) = decode_into_channel(container, &notification_handle) {
+            error!("Failed to decode audio: {:?}", e);
         }
 
         notification_handle
@@ -581,7 +937,7 @@ impl AudioManager {
     fn handle_incoming_audio_frame(&mut self, frame: Option<AudioFrame>, index: usize) {
         match frame {
             Some(frame) => {
-                if let Err(e) = self.streams[index].1.push_frame(frame) {
+                if let Err(e) = self.push_frame_to_stream(frame, index) {
                     error!("Failed to push frame to OpenAL source: {:?}", e);
                 }
             }
@@ -596,6 +952,26 @@ impl AudioManager {
         }
     }
 
+    fn push_frame_to_stream(&mut self, frame: AudioFrame, index: usize) -> Result<()> {
+        let source = &mut self.streams[index].1;
+
+        // A peer can switch between mono and stereo mid call, but OpenAL
+        // won't queue buffers of a different format on the same source. Move
+        // the stream onto a fresh source and let the old one finish playing
+        if !source.accepts_format(&frame)? {
+            debug!(
+                "Audio format changed on source {}, switching sources",
+                source.source
+            );
+            let new_source = OalSource::new(source.num_buffers(), source.looping)
+                .context("Failed to allocate OpenAL source")?;
+            let old_source = std::mem::replace(source, new_source);
+            self.finishing_streams.push(old_source);
+        }
+
+        self.streams[index].1.push_frame(frame)
+    }
+
     fn cleanup_finished_streams(&mut self) {
         if !self.finishing_streams.is_empty() {
             let mut finishing_streams = Vec::new();
@@ -644,10 +1020,7 @@ impl AudioManager {
 
     fn send_audio_frame_to_capture_channels(&mut self, frame: AudioFrame) -> Result<()> {
         if self.capture_channels.len() == 1 {
-            if self.capture_channels[0]
-                .unbounded_send(frame)
-                .is_err()
-            {
+            if self.capture_channels[0].send(frame).is_err() {
                 self.capture_channels.pop();
             }
         } else {
@@ -656,7 +1029,7 @@ impl AudioManager {
             warn!("Multiple capture channels results in extra copies which maybe be inefficient");
             let (live_channels, _dead_channels) = live_channels
                 .into_iter()
-                .partition(|channel| channel.unbounded_send(frame.clone()).is_ok());
+                .partition(|channel| channel.send(frame.clone()).is_ok());
 
             self.capture_channels = live_channels;
         };
@@ -694,25 +1067,162 @@ impl AudioManager {
 
         Ok(())
     }
+}
+
+fn decode_into_channel(
+    container: FormattedAudio,
+    channel: &UnboundedSender<AudioFrame>,
+) -> Result<()> {
+    match container {
+        FormattedAudio::Mp3(data) => decode_mp3_into_channel(data, channel),
+        FormattedAudio::Wav(data) => decode_wav_into_channel(data, channel),
+    }
+}
 
-    fn decode_mp3_into_channel(data: Vec<u8>, channel: &UnboundedSender<AudioFrame>) {
-        let mut mp3_decoder = minimp3::Decoder::new(&data[..]);
+fn decode_mp3_into_channel(data: Vec<u8>, channel: &UnboundedSender<AudioFrame>) -> Result<()> {
+    let mut mp3_decoder = minimp3::Decoder::new(&data[..]);
 
-        while let Ok(frame) = mp3_decoder.next_frame() {
-            let data = match frame.channels {
-                1 => AudioData::Mono16(frame.data),
-                2 => AudioData::Stereo16(frame.data),
-                _ => continue,
-            };
+    while let Ok(frame) = mp3_decoder.next_frame() {
+        let data = match frame.channels {
+            1 => AudioData::Mono16(frame.data),
+            2 => AudioData::Stereo16(frame.data),
+            _ => continue,
+        };
+
+        channel
+            .unbounded_send(AudioFrame {
+                data,
+                sample_rate: frame.sample_rate,
+            })
+            .context("Failed to send notification data to audio thread")?;
+    }
+
+    Ok(())
+}
+
+const WAV_FORMAT_PCM: u16 = 1;
+
+struct WavFormat {
+    channels: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+}
+
+/// Finds the format and sample data in a RIFF/WAVE file
+fn parse_wav(data: &[u8]) -> Result<(WavFormat, &[u8])> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        bail!("Not a WAV file");
+    }
+
+    let read_u16 = |buf: &[u8], pos: usize| u16::from_le_bytes([buf[pos], buf[pos + 1]]);
+    let read_u32 = |buf: &[u8], pos: usize| {
+        u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+    };
+
+    let mut format = None;
+    let mut samples = None;
+
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let size = read_u32(data, pos + 4) as usize;
+
+        // Some writers leave the size of the last chunk unset when streaming,
+        // take what's there
+        let end = pos.saturating_add(8).saturating_add(size).min(data.len());
+        let body = &data[pos + 8..end];
+
+        match id {
+            b"fmt " => {
+                if body.len() < 16 {
+                    bail!("WAV format chunk too short");
+                }
 
-            channel
-                .unbounded_send(AudioFrame {
-                    data,
-                    sample_rate: frame.sample_rate,
+                let encoding = read_u16(body, 0);
+                if encoding != WAV_FORMAT_PCM {
+                    bail!("Unsupported WAV encoding {}", encoding);
+                }
+
+                format = Some(WavFormat {
+                    channels: read_u16(body, 2),
+                    sample_rate: read_u32(body, 4),
+                    bits_per_sample: read_u16(body, 14),
+                });
+            }
+            b"data" => samples = Some(body),
+            _ => (),
+        }
+
+        // Chunks are padded to an even length
+        pos = end + size % 2;
+    }
+
+    let format = format.context("WAV file has no format chunk")?;
+    let samples = samples.context("WAV file has no data chunk")?;
+
+    Ok((format, samples))
+}
+
+fn decode_wav_into_channel(data: Vec<u8>, channel: &UnboundedSender<AudioFrame>) -> Result<()> {
+    let (format, samples) = parse_wav(&data)?;
+
+    if format.sample_rate == 0 || format.sample_rate > i32::MAX as u32 {
+        bail!("Invalid WAV sample rate {}", format.sample_rate);
+    }
+
+    let channels = match format.channels {
+        1 | 2 => format.channels as usize,
+        n => bail!("Unsupported WAV channel count {}", n),
+    };
+
+    // A quarter second per frame keeps a typical notification sound within
+    // the buffers of a single playback channel
+    let frame_len = (format.sample_rate as usize / 4).max(1) * channels;
+
+    let frames: Vec<AudioData> = match format.bits_per_sample {
+        8 => {
+            // 8 bit WAV samples are unsigned, as are OpenAL's 8 bit formats,
+            // so the bytes are passed through untouched
+            let mut samples: Vec<i8> = samples.iter().map(|b| *b as i8).collect();
+            samples.truncate(samples.len() - samples.len() % channels);
+
+            samples
+                .chunks(frame_len)
+                .map(|chunk| match channels {
+                    1 => AudioData::Mono8(chunk.to_vec()),
+                    _ => AudioData::Stereo8(chunk.to_vec()),
                 })
-                .expect("Failed to send notification data to audio thread");
+                .collect()
         }
+        16 => {
+            let mut samples: Vec<i16> = samples
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                .collect();
+            // Drop a trailing partial sample frame from a truncated file
+            samples.truncate(samples.len() - samples.len() % channels);
+
+            samples
+                .chunks(frame_len)
+                .map(|chunk| match channels {
+                    1 => AudioData::Mono16(chunk.to_vec()),
+                    _ => AudioData::Stereo16(chunk.to_vec()),
+                })
+                .collect()
+        }
+        bits => bail!("Unsupported WAV sample size {}", bits),
+    };
+
+    for data in frames {
+        channel
+            .unbounded_send(AudioFrame {
+                data,
+                sample_rate: format.sample_rate as i32,
+            })
+            .context("Failed to send notification data to audio thread")?;
     }
+
+    Ok(())
 }
 
 impl Drop for AudioManager {
@@ -812,6 +1322,68 @@ mod test {
         }
     }
 
+    fn mono_frame(samples: std::ops::Range<i16>) -> AudioFrame {
+        AudioFrame {
+            data: AudioData::Mono16(samples.collect()),
+            sample_rate: CAPTURE_SAMPLE_RATE as i32,
+        }
+    }
+
+    fn frame_samples(frame: &AudioFrame) -> &[i16] {
+        match &frame.data {
+            AudioData::Mono16(data) | AudioData::Stereo16(data) => data,
+            _ => panic!("Unexpected format"),
+        }
+    }
+
+    #[test]
+    fn test_capture_framer() {
+        // 20ms at 48kHz
+        let frame_len = 960;
+        let mut framer = CaptureFramer::new(FramePeriod::Ms20);
+
+        assert!(framer.push(mono_frame(0..500)).is_empty());
+
+        let frames = framer.push(mono_frame(500..2500));
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frame_samples(&frames[0]), &(0..960).collect::<Vec<_>>()[..]);
+        assert_eq!(frame_samples(&frames[1])[0], 960);
+        assert!(frames.iter().all(|f| frame_samples(f).len() == frame_len));
+
+        // Leftover samples carry over to the next frame
+        let frames = framer.push(mono_frame(2500..2880));
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frame_samples(&frames[0]).len(), frame_len);
+        assert_eq!(frame_samples(&frames[0])[0], 1920);
+
+        // A format change starts over instead of mixing samples
+        assert!(framer.push(mono_frame(0..100)).is_empty());
+        let stereo = AudioFrame {
+            data: AudioData::Stereo16(vec![0; 2 * frame_len]),
+            sample_rate: CAPTURE_SAMPLE_RATE as i32,
+        };
+        let frames = framer.push(stereo);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frame_samples(&frames[0]).len(), 2 * frame_len);
+    }
+
+    #[test]
+    fn test_capture_framer_periods() {
+        for period in &[
+            FramePeriod::Ms10,
+            FramePeriod::Ms20,
+            FramePeriod::Ms40,
+            FramePeriod::Ms60,
+        ] {
+            let mut framer = CaptureFramer::new(*period);
+            let frames = framer.push(mono_frame(0..2880));
+            assert!(!frames.is_empty());
+            let frame_len = period.samples_per_channel(CAPTURE_SAMPLE_RATE as i32);
+            assert!(frames.iter().all(|f| frame_samples(f).len() == frame_len));
+            assert_eq!(frames.len(), 2880 / frame_len);
+        }
+    }
+
     rusty_fork_test! {
         // FIXME: Lots more tests could be added but for the time being I don't
         // feel like it
@@ -902,4 +1474,308 @@ mod test {
             assert!(*buf_data.lock().unwrap() == sent_buf);
         }
     }
+
+    rusty_fork_test! {
+        #[test]
+        fn test_playback_underrun_counted() {
+            let al_get_error_ctx = oal_func::alGetError_context();
+            al_get_error_ctx.expect().return_const_st(0);
+
+            let al_gen_sources_ctx = oal_func::alGenSources_context();
+            al_gen_sources_ctx.expect().return_const_st(());
+
+            let al_gen_buffers_ctx = oal_func::alGenBuffers_context();
+            al_gen_buffers_ctx.expect().return_const_st(());
+
+            let al_sourcei_ctx = oal_func::alSourcei_context();
+            al_sourcei_ctx.expect().return_const_st(());
+
+            let al_buffer_data_ctx = oal_func::alBufferData_context();
+            al_buffer_data_ctx.expect().return_const_st(());
+
+            let al_source_queue_buffers_ctx = oal_func::alSourceQueueBuffers_context();
+            al_source_queue_buffers_ctx.expect().return_const_st(());
+
+            let al_source_play_ctx = oal_func::alSourcePlay_context();
+            al_source_play_ctx.expect().return_const_st(());
+
+            let al_delete_sources_ctx = oal_func::alDeleteSources_context();
+            al_delete_sources_ctx.expect().return_const_st(());
+
+            let al_delete_buffers_ctx = oal_func::alDeleteBuffers_context();
+            al_delete_buffers_ctx.expect().return_const_st(());
+
+            // Source never reports playing, every push after the first one
+            // finds a source that ran dry
+            let al_get_sourcei_ctx = oal_func::alGetSourcei_context();
+            al_get_sourcei_ctx.expect().withf_st(|_source, param, _value| *param == oal::AL_BUFFERS_PROCESSED as i32)
+                .returning_st(|_source, _param, value| unsafe {*value = 0i32; });
+
+            al_get_sourcei_ctx.expect().withf_st(|_source, param, _value| *param == oal::AL_SOURCE_STATE as i32)
+                .returning_st(|_source, _param, value| unsafe {*value = oal::AL_STOPPED as i32; });
+
+            let mut source = OalSource::new(4, false).unwrap();
+
+            source.push_frame(test_frame(48000)).unwrap();
+            assert_eq!(COUNTERS.snapshot().playback_underruns, 0);
+
+            source.push_frame(test_frame(48000)).unwrap();
+            assert_eq!(COUNTERS.snapshot().playback_underruns, 1);
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn test_playback_format_change() {
+            let al_delete_sources_ctx = oal_func::alDeleteSources_context();
+            al_delete_sources_ctx.expect().return_const_st(());
+
+            let al_delete_buffers_ctx = oal_func::alDeleteBuffers_context();
+            al_delete_buffers_ctx.expect().return_const_st(());
+
+            let mut fixture = create_audio_manager();
+
+            let al_gen_sources_ctx = oal_func::alGenSources_context();
+            let mut next_source = 0;
+            al_gen_sources_ctx.expect().returning_st(move |_, source| unsafe {
+                next_source += 1;
+                *source = next_source;
+            });
+
+            let al_gen_buffers_ctx = oal_func::alGenBuffers_context();
+            al_gen_buffers_ctx.expect().return_const_st(());
+
+            let al_sourcei_ctx = oal_func::alSourcei_context();
+            al_sourcei_ctx.expect().return_const_st(());
+
+            let al_source_play_ctx = oal_func::alSourcePlay_context();
+            al_source_play_ctx.expect().return_const_st(());
+
+            // Nothing ever finishes playing, so every frame so far is still
+            // queued when the next one arrives
+            let al_get_sourcei_ctx = oal_func::alGetSourcei_context();
+            al_get_sourcei_ctx.expect().withf_st(|_source, param, _value| *param == oal::AL_BUFFERS_PROCESSED as i32)
+                .returning_st(|_source, _param, value| unsafe {*value = 0i32; });
+
+            al_get_sourcei_ctx.expect().withf_st(|_source, param, _value| *param == oal::AL_SOURCE_STATE as i32)
+                .returning_st(|_source, _param, value| unsafe {*value = oal::AL_PLAYING as i32; });
+
+            let formats: Arc<Mutex<Vec<i32>>> = Arc::new(Mutex::new(Vec::new()));
+            let formats_clone = Arc::clone(&formats);
+            let al_buffer_data_ctx = oal_func::alBufferData_context();
+            al_buffer_data_ctx.expect()
+                .returning_st(move |_, format, _, _, _| formats_clone.lock().unwrap().push(format));
+
+            let queued_sources: Arc<Mutex<Vec<u32>>> = Arc::new(Mutex::new(Vec::new()));
+            let queued_sources_clone = Arc::clone(&queued_sources);
+            let al_source_queue_buffers_ctx = oal_func::alSourceQueueBuffers_context();
+            al_source_queue_buffers_ctx.expect()
+                .returning_st(move |source, _, _| queued_sources_clone.lock().unwrap().push(source));
+
+            let _playback_channel = fixture.audio_manager.create_playback_channel(50).unwrap();
+
+            let frame = |data| AudioFrame { data, sample_rate: 48000 };
+            let audio_manager = &mut fixture.audio_manager;
+            audio_manager.handle_incoming_audio_frame(Some(frame(AudioData::Mono16(vec![0; 4]))), 0);
+            audio_manager.handle_incoming_audio_frame(Some(frame(AudioData::Mono16(vec![0; 4]))), 0);
+            audio_manager.handle_incoming_audio_frame(Some(frame(AudioData::Stereo16(vec![0; 8]))), 0);
+
+            assert_eq!(
+                *formats.lock().unwrap(),
+                vec![
+                    oal::AL_FORMAT_MONO16 as i32,
+                    oal::AL_FORMAT_MONO16 as i32,
+                    oal::AL_FORMAT_STEREO16 as i32
+                ]
+            );
+            assert_eq!(*queued_sources.lock().unwrap(), vec![1, 1, 2]);
+
+            // The mono audio is left to finish on the old source
+            assert_eq!(audio_manager.finishing_streams.len(), 1);
+            assert_eq!(audio_manager.streams[0].1.source, 2);
+        }
+    }
+
+    fn test_frame(sample_rate: i32) -> AudioFrame {
+        AudioFrame {
+            data: AudioData::Mono16(vec![0; 4]),
+            sample_rate,
+        }
+    }
+
+    /// Builds a PCM WAV file around samples
+    fn wav_file(channels: u16, sample_rate: u32, bits_per_sample: u16, samples: &[u8]) -> Vec<u8> {
+        let block_align = channels * bits_per_sample / 8;
+
+        let mut fmt = Vec::new();
+        fmt.extend_from_slice(&WAV_FORMAT_PCM.to_le_bytes());
+        fmt.extend_from_slice(&channels.to_le_bytes());
+        fmt.extend_from_slice(&sample_rate.to_le_bytes());
+        fmt.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        fmt.extend_from_slice(&block_align.to_le_bytes());
+        fmt.extend_from_slice(&bits_per_sample.to_le_bytes());
+
+        let mut body = b"WAVE".to_vec();
+        for (id, chunk) in &[(b"fmt ", &fmt[..]), (b"data", samples)] {
+            body.extend_from_slice(*id);
+            body.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            body.extend_from_slice(chunk);
+            if chunk.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+
+        let mut file = b"RIFF".to_vec();
+        file.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        file.extend_from_slice(&body);
+        file
+    }
+
+    fn decode(audio: FormattedAudio) -> Result<Vec<AudioFrame>> {
+        let (tx, mut rx) = mpsc::unbounded();
+        decode_into_channel(audio, &tx)?;
+        drop(tx);
+
+        Ok(std::iter::from_fn(|| rx.next().now_or_never().flatten()).collect())
+    }
+
+    #[test]
+    fn test_wav_decode() -> Result<()> {
+        // 8 stereo samples at 16 Hz, a quarter second is 4 samples per frame
+        let samples: Vec<i16> = (0..16).map(|i| i * 100 - 800).collect();
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+
+        let audio = FormattedAudio::from_bytes(wav_file(2, 16, 16, &bytes));
+        assert!(matches!(audio, FormattedAudio::Wav(_)));
+        audio.validate()?;
+
+        let frames = decode(audio)?;
+        assert_eq!(frames.len(), 2);
+
+        let mut decoded = Vec::new();
+        for frame in frames {
+            assert_eq!(frame.sample_rate, 16);
+            match frame.data {
+                AudioData::Stereo16(data) => decoded.extend(data),
+                _ => panic!("Unexpected audio format"),
+            }
+        }
+        assert_eq!(decoded, samples);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wav_decode_formats() -> Result<()> {
+        let mono16: Vec<i16> = vec![i16::MIN, -1, 0, 1, i16::MAX];
+        let bytes: Vec<u8> = mono16.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let frames = decode(FormattedAudio::Wav(wav_file(1, 44100, 16, &bytes)))?;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].sample_rate, 44100);
+        assert!(matches!(&frames[0].data, AudioData::Mono16(data) if *data == mono16));
+
+        // Odd length data chunk exercises the chunk padding
+        let mono8 = [0u8, 128, 255];
+        let frames = decode(FormattedAudio::Wav(wav_file(1, 8000, 8, &mono8)))?;
+        assert_eq!(frames[0].sample_rate, 8000);
+        assert!(matches!(
+            &frames[0].data,
+            AudioData::Mono8(data) if data.iter().map(|s| *s as u8).eq(mono8.iter().copied())
+        ));
+
+        // Trailing half of a stereo sample is dropped
+        let stereo8 = [1u8, 2, 3, 4, 5];
+        let frames = decode(FormattedAudio::Wav(wav_file(2, 22050, 8, &stereo8)))?;
+        assert!(matches!(&frames[0].data, AudioData::Stereo8(data) if *data == [1, 2, 3, 4]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_sound_rejected() {
+        assert!(FormattedAudio::from_bytes(b"not a sound file".to_vec())
+            .validate()
+            .is_err());
+
+        // Valid header but no samples
+        assert!(FormattedAudio::from_bytes(wav_file(1, 44100, 16, &[]))
+            .validate()
+            .is_err());
+
+        // 24 bit isn't supported
+        assert!(FormattedAudio::from_bytes(wav_file(1, 44100, 24, &[0; 6]))
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn test_capture_overflow_drops_oldest() {
+        let (tx, mut rx) = capture_channel(2);
+
+        // None of these should block even though nobody is reading
+        for i in 0..5 {
+            tx.send(test_frame(i)).unwrap();
+        }
+
+        let received: Vec<i32> = std::iter::from_fn(|| rx.next().now_or_never().flatten())
+            .map(|frame| frame.sample_rate)
+            .collect();
+
+        assert_eq!(received, vec![3, 4]);
+        assert!(COUNTERS.snapshot().capture_frames_dropped >= 3);
+    }
+
+    #[test]
+    fn test_capture_channel_close() {
+        let (tx, mut rx) = capture_channel(2);
+        tx.send(test_frame(1)).unwrap();
+        drop(tx);
+
+        // Queued frames are still delivered before the stream ends
+        assert_eq!(rx.next().now_or_never().unwrap().unwrap().sample_rate, 1);
+        assert!(rx.next().now_or_never().unwrap().is_none());
+
+        let (tx, rx) = capture_channel(2);
+        drop(rx);
+        assert!(tx.send(test_frame(1)).is_err());
+    }
+
+    #[test]
+    fn test_loopback_forwards_capture_to_playback() {
+        // 4 samples at 100Hz, 40ms per frame
+        let frame = |sample: i16| AudioFrame {
+            data: AudioData::Mono16(vec![sample; 4]),
+            sample_rate: 100,
+        };
+
+        let (capture_tx, capture_rx) = capture_channel(CAPTURE_CHANNEL_DEPTH);
+        let (playback_tx, mut playback_rx) = mpsc::unbounded();
+        let mut loopback = AudioLoopback::new(capture_rx, playback_tx, Duration::from_millis(100));
+
+        for sample in 0..5 {
+            capture_tx.send(frame(sample)).unwrap();
+        }
+        drop(capture_tx);
+
+        assert!(loopback.run().now_or_never().is_some());
+
+        // The last 80ms is still held back to cover the delay
+        let mut played = Vec::new();
+        while let Some(Some(frame)) = playback_rx.next().now_or_never() {
+            match frame.data {
+                AudioData::Mono16(data) => played.push(data[0]),
+                data => panic!("Unexpected audio data {:?}", data),
+            }
+        }
+        assert_eq!(played, vec![0, 1, 2]);
+
+        // Playback going away ends the loopback
+        let (capture_tx, capture_rx) = capture_channel(CAPTURE_CHANNEL_DEPTH);
+        let (playback_tx, playback_rx) = mpsc::unbounded();
+        let mut loopback = AudioLoopback::new(capture_rx, playback_tx, Duration::from_secs(0));
+        drop(playback_rx);
+
+        capture_tx.send(frame(0)).unwrap();
+        assert!(loopback.run().now_or_never().is_some());
+    }
 }
@@ -0,0 +1,130 @@
+use toxcore::PublicKey;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::{collections::HashSet, str::FromStr};
+
+/// Which incoming friend requests are accepted without asking the user.
+/// Mostly useful for bots and kiosks where nobody is around to click accept
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum AutoAcceptPolicy {
+    Manual,
+    All,
+    Allowlist(Vec<String> /* hex public keys */),
+    // Requests whose message contains the text, ignoring case. Lets people
+    // add you by sending a passphrase you've shared with them
+    MessageContains(String),
+}
+
+impl Default for AutoAcceptPolicy {
+    fn default() -> AutoAcceptPolicy {
+        AutoAcceptPolicy::Manual
+    }
+}
+
+/// Validated form of AutoAcceptPolicy
+pub(crate) enum AutoAccept {
+    Manual,
+    All,
+    Allowlist(HashSet<PublicKey>),
+    // Lowercase
+    MessageContains(String),
+}
+
+impl Default for AutoAccept {
+    fn default() -> AutoAccept {
+        AutoAccept::Manual
+    }
+}
+
+impl AutoAccept {
+    pub fn from_policy(policy: AutoAcceptPolicy) -> Result<AutoAccept> {
+        let ret = match policy {
+            AutoAcceptPolicy::Manual => AutoAccept::Manual,
+            AutoAcceptPolicy::All => AutoAccept::All,
+            AutoAcceptPolicy::Allowlist(keys) => {
+                let keys = keys
+                    .iter()
+                    .map(|key| {
+                        PublicKey::from_str(key.trim()).with_context(|| {
+                            format!("Invalid public key in auto accept allowlist: {}", key)
+                        })
+                    })
+                    .collect::<Result<HashSet<PublicKey>>>()?;
+
+                AutoAccept::Allowlist(keys)
+            }
+            AutoAcceptPolicy::MessageContains(pattern) => {
+                // An empty pattern would match every request
+                if pattern.trim().is_empty() {
+                    bail!("Auto accept message pattern cannot be empty");
+                }
+
+                AutoAccept::MessageContains(pattern.to_lowercase())
+            }
+        };
+
+        Ok(ret)
+    }
+
+    pub fn accepts(&self, public_key: &PublicKey, message: &str) -> bool {
+        match self {
+            AutoAccept::Manual => false,
+            AutoAccept::All => true,
+            AutoAccept::Allowlist(keys) => keys.contains(public_key),
+            AutoAccept::MessageContains(pattern) => message.to_lowercase().contains(pattern),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> PublicKey {
+        PublicKey::from_bytes(vec![byte; PublicKey::SIZE]).unwrap()
+    }
+
+    #[test]
+    fn test_manual_by_default() {
+        assert!(!AutoAccept::default().accepts(&key(1), "hello"));
+    }
+
+    #[test]
+    fn test_allowlist() -> Result<()> {
+        let policy = AutoAcceptPolicy::Allowlist(vec![key(1).to_string()]);
+        let auto_accept = AutoAccept::from_policy(policy)?;
+
+        assert!(auto_accept.accepts(&key(1), ""));
+        assert!(!auto_accept.accepts(&key(2), ""));
+
+        let auto_accept = AutoAccept::from_policy(AutoAcceptPolicy::All)?;
+        assert!(auto_accept.accepts(&key(2), ""));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_pattern() -> Result<()> {
+        let policy = AutoAcceptPolicy::MessageContains("Open Sesame".to_string());
+        let auto_accept = AutoAccept::from_policy(policy)?;
+
+        assert!(auto_accept.accepts(&key(1), "hi, open sesame please"));
+        assert!(auto_accept.accepts(&key(2), "OPEN SESAME"));
+        assert!(!auto_accept.accepts(&key(1), "let me in"));
+        assert!(!auto_accept.accepts(&key(1), ""));
+
+        let policy = AutoAcceptPolicy::MessageContains("  ".to_string());
+        assert!(AutoAccept::from_policy(policy).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_key_rejected() {
+        let policy = AutoAcceptPolicy::Allowlist(vec![key(1).to_string(), "abcd".to_string()]);
+        let err = AutoAccept::from_policy(policy).err().unwrap();
+        assert!(err.to_string().contains("abcd"));
+    }
+}
@@ -0,0 +1,117 @@
+use crate::storage::UserHandle;
+
+use toxcore::{Message, Status};
+
+use std::collections::HashSet;
+
+/// Opt-in automatic reply for when the user is away or busy. Each friend gets
+/// at most one reply per away period, which ends when the user is back online
+/// or changes the auto-reply
+#[derive(Default)]
+pub(crate) struct AutoReply {
+    message: Option<String>,
+    replied: HashSet<UserHandle>,
+}
+
+impl AutoReply {
+    pub fn set_message(&mut self, message: Option<String>) {
+        // Changing the message starts a new away period, everyone should see
+        // the new reply
+        self.replied.clear();
+        self.message = message.filter(|message| !message.is_empty());
+    }
+
+    /// Coming back online ends the away period
+    pub fn self_status_changed(&mut self, status: Status) {
+        if status == Status::Online {
+            self.replied.clear();
+        }
+    }
+
+    /// Returns the reply to send to the given user, if we're away and they
+    /// have not already received one
+    pub fn reply_for(&mut self, user: &UserHandle, self_status: Status) -> Option<Message> {
+        if self_status != Status::Away && self_status != Status::Busy {
+            return None;
+        }
+
+        let message = self.message.as_ref()?;
+
+        if !self.replied.insert(*user) {
+            return None;
+        }
+
+        Some(Message::Normal(message.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disabled_by_default() {
+        let mut auto_reply = AutoReply::default();
+        assert!(auto_reply
+            .reply_for(&UserHandle::from(1), Status::Away)
+            .is_none());
+    }
+
+    #[test]
+    fn test_reply_once_per_period() {
+        let mut auto_reply = AutoReply::default();
+        auto_reply.set_message(Some("away".to_string()));
+
+        let user1 = UserHandle::from(1);
+        let user2 = UserHandle::from(2);
+
+        assert_eq!(
+            auto_reply.reply_for(&user1, Status::Away),
+            Some(Message::Normal("away".to_string()))
+        );
+        assert!(auto_reply.reply_for(&user1, Status::Away).is_none());
+        assert_eq!(
+            auto_reply.reply_for(&user2, Status::Away),
+            Some(Message::Normal("away".to_string()))
+        );
+
+        // Coming back and leaving again starts a new period
+        auto_reply.set_message(None);
+        assert!(auto_reply.reply_for(&user1, Status::Away).is_none());
+
+        auto_reply.set_message(Some("away again".to_string()));
+        assert_eq!(
+            auto_reply.reply_for(&user1, Status::Away),
+            Some(Message::Normal("away again".to_string()))
+        );
+        assert!(auto_reply.reply_for(&user1, Status::Away).is_none());
+    }
+
+    #[test]
+    fn test_only_while_away() {
+        let mut auto_reply = AutoReply::default();
+        auto_reply.set_message(Some("away".to_string()));
+
+        let user = UserHandle::from(1);
+        assert!(auto_reply.reply_for(&user, Status::Online).is_none());
+        assert!(auto_reply.reply_for(&user, Status::Busy).is_some());
+        assert!(auto_reply.reply_for(&user, Status::Away).is_none());
+
+        // Going away without coming back online is the same period
+        auto_reply.self_status_changed(Status::Away);
+        assert!(auto_reply.reply_for(&user, Status::Away).is_none());
+
+        auto_reply.self_status_changed(Status::Online);
+        auto_reply.self_status_changed(Status::Away);
+        assert!(auto_reply.reply_for(&user, Status::Away).is_some());
+    }
+
+    #[test]
+    fn test_empty_message_disables() {
+        let mut auto_reply = AutoReply::default();
+        auto_reply.set_message(Some(String::new()));
+        assert!(auto_reply
+            .reply_for(&UserHandle::from(1), Status::Away)
+            .is_none());
+    }
+}
@@ -0,0 +1,205 @@
+use toxcore::PublicKey;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// toxcore doesn't tell us when we lose the DHT, so once connected we look
+/// at the connection this often to notice
+const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Consecutive failed attempts before the user is told we can't connect
+const FAILURES_BEFORE_WARNING: u32 = 3;
+
+#[derive(Deserialize, Debug)]
+struct NodeEntry {
+    host: String,
+    port: u16,
+    public_key: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BootstrapNode {
+    pub host: String,
+    pub port: u16,
+    pub public_key: PublicKey,
+}
+
+/// Reads the DHT nodes to bootstrap from. The file is a JSON list of
+/// { "host", "port", "public_key" } objects. A missing file means no nodes,
+/// in which case we only find peers through local discovery
+pub(crate) fn load_nodes(path: &Path) -> Result<Vec<BootstrapNode>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", path.to_string_lossy()))
+        }
+    };
+
+    let entries: Vec<NodeEntry> = serde_json::from_slice(&data)
+        .with_context(|| format!("Failed to parse {}", path.to_string_lossy()))?;
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            let public_key = PublicKey::from_str(&entry.public_key)
+                .with_context(|| format!("Invalid public key for node {}", entry.host))?;
+
+            Ok(BootstrapNode {
+                host: entry.host,
+                port: entry.port,
+                public_key,
+            })
+        })
+        .collect()
+}
+
+/// Schedules bootstrap attempts with exponential backoff until we're
+/// connected to the DHT, and starts over if the connection drops again
+pub(crate) struct BootstrapRetry {
+    next_attempt: Option<Instant>,
+    delay: Duration,
+    attempts: u32,
+    connected: bool,
+}
+
+impl BootstrapRetry {
+    pub fn new(now: Instant) -> BootstrapRetry {
+        BootstrapRetry {
+            next_attempt: Some(now),
+            delay: INITIAL_RETRY_DELAY,
+            attempts: 0,
+            connected: false,
+        }
+    }
+
+    /// When to next bootstrap, or to check that we're still connected
+    pub fn next_attempt(&self) -> Option<Instant> {
+        self.next_attempt
+    }
+
+    /// Records a bootstrap attempt at now. Attempts are only made while still
+    /// disconnected, so every attempt after the first means the previous one
+    /// failed. Returns true exactly once, when it's time to warn the user
+    pub fn attempted(&mut self, now: Instant) -> bool {
+        if self.connected {
+            // Lost the connection, back to retrying quickly
+            self.connected = false;
+            self.delay = INITIAL_RETRY_DELAY;
+            self.attempts = 0;
+        }
+
+        self.next_attempt = Some(now + self.delay);
+        self.delay = (self.delay * 2).min(MAX_RETRY_DELAY);
+        self.attempts += 1;
+        self.attempts == FAILURES_BEFORE_WARNING + 1
+    }
+
+    pub fn connected(&mut self, now: Instant) {
+        self.next_attempt = Some(now + CONNECTION_CHECK_INTERVAL);
+        self.delay = INITIAL_RETRY_DELAY;
+        self.attempts = 0;
+        self.connected = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retried_while_offline() {
+        let now = Instant::now();
+        let mut retry = BootstrapRetry::new(now);
+
+        // First attempt is immediate
+        assert_eq!(retry.next_attempt(), Some(now));
+        assert!(!retry.attempted(now));
+
+        let mut expected_delay = INITIAL_RETRY_DELAY;
+        let mut attempt_time = now;
+        for failures in 1..=6 {
+            let next = retry.next_attempt().unwrap();
+            assert_eq!(next, attempt_time + expected_delay);
+
+            assert_eq!(retry.attempted(next), failures == FAILURES_BEFORE_WARNING);
+
+            attempt_time = next;
+            expected_delay = (expected_delay * 2).min(MAX_RETRY_DELAY);
+        }
+
+        let connected_time = attempt_time + expected_delay;
+        retry.connected(connected_time);
+        assert_eq!(
+            retry.next_attempt(),
+            Some(connected_time + CONNECTION_CHECK_INTERVAL)
+        );
+    }
+
+    #[test]
+    fn test_retried_after_disconnect() {
+        let now = Instant::now();
+        let mut retry = BootstrapRetry::new(now);
+
+        for _ in 0..FAILURES_BEFORE_WARNING {
+            retry.attempted(now);
+        }
+        retry.connected(now);
+
+        // Dropping the connection starts over from the shortest delay, and
+        // the user gets warned again if it doesn't come back
+        let dropped = now + CONNECTION_CHECK_INTERVAL;
+        assert!(!retry.attempted(dropped));
+        assert_eq!(retry.next_attempt(), Some(dropped + INITIAL_RETRY_DELAY));
+
+        let warnings = (1..=FAILURES_BEFORE_WARNING)
+            .filter(|_| retry.attempted(dropped))
+            .count();
+        assert_eq!(warnings, 1);
+    }
+
+    #[test]
+    fn test_load_nodes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("nodes.json");
+
+        assert!(load_nodes(&path)?.is_empty());
+
+        let key = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+        fs::write(
+            &path,
+            format!(
+                r#"[{{ "host": "node.example", "port": 33445, "public_key": "{}" }}]"#,
+                key
+            ),
+        )?;
+
+        assert_eq!(
+            load_nodes(&path)?,
+            vec![BootstrapNode {
+                host: "node.example".to_string(),
+                port: 33445,
+                public_key: key,
+            }]
+        );
+
+        fs::write(
+            &path,
+            r#"[{ "host": "node.example", "port": 33445, "public_key": "abcd" }]"#,
+        )?;
+        assert!(load_nodes(&path).is_err());
+
+        Ok(())
+    }
+}
@@ -3,9 +3,12 @@ use crate::{
     ChatHandle,
 };
 
-use toxcore::av::{
-    ActiveCall, AudioFrame as CoreFrame, CallEvent as CoreCallEvent, CallState as CoreCallState,
-    IncomingCall,
+use toxcore::{
+    av::{
+        ActiveCall, AudioFrame as CoreFrame, CallEvent as CoreCallEvent,
+        CallState as CoreCallState, IncomingCall,
+    },
+    ConnectionStatus,
 };
 
 use anyhow::{bail, Context, Result};
@@ -13,7 +16,7 @@ use futures::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     convert::{TryFrom, TryInto},
     sync::Arc,
 };
@@ -23,13 +26,43 @@ pub enum CallState {
     Incoming,
     Outgoing,
     Active,
+    // Active, but put on hold by us. No audio flows either way
+    Held,
     Idle,
 }
 
+/// Why a call went back to idle
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallEndReason {
+    // We gave up on our call before the peer answered
+    Cancelled,
+    // We turned down an incoming call
+    Rejected,
+    // The peer turned down our call
+    Declined,
+    // The peer gave up calling before we answered
+    Missed,
+    // Either side hung up an answered call
+    HungUp,
+}
+
+impl CallEndReason {
+    /// Reason for us ending a call that was in state, None if there was no
+    /// call to end
+    pub fn for_local_end(state: &CallState) -> Option<CallEndReason> {
+        match state {
+            CallState::Outgoing => Some(CallEndReason::Cancelled),
+            CallState::Incoming => Some(CallEndReason::Rejected),
+            CallState::Active | CallState::Held => Some(CallEndReason::HungUp),
+            CallState::Idle => None,
+        }
+    }
+}
+
 pub enum CallEvent {
     AudioReceived(ChatHandle, AudioFrame),
     CallAccepted(ChatHandle),
-    CallEnded(ChatHandle),
+    CallEnded(ChatHandle, CallEndReason),
 }
 
 impl TryFrom<(ChatHandle, CoreCallEvent)> for CallEvent {
@@ -53,9 +86,6 @@ impl TryFrom<(ChatHandle, CoreCallEvent)> for CallEvent {
                     },
                 ))
             }
-            CoreCallEvent::CallStateChanged(CoreCallState::Finished) => {
-                Ok(CallEvent::CallEnded(event.0))
-            }
             CoreCallEvent::CallStateChanged(CoreCallState::Active) => {
                 Ok(CallEvent::CallAccepted(event.0))
             }
@@ -88,6 +118,10 @@ impl TryFrom<AudioFrame> for CoreFrame {
 pub struct CallManager {
     incoming_calls: HashMap<ChatHandle, IncomingCall>,
     active_calls: HashMap<ChatHandle, ActiveCall>,
+    held_calls: HashSet<ChatHandle>,
+    // Calls that got past ringing. Once toxcore reports a call finished its
+    // state no longer says how far it got
+    answered_calls: HashSet<ChatHandle>,
 }
 
 impl CallManager {
@@ -95,6 +129,8 @@ impl CallManager {
         CallManager {
             incoming_calls: Default::default(),
             active_calls: Default::default(),
+            held_calls: Default::default(),
+            answered_calls: Default::default(),
         }
     }
 
@@ -102,17 +138,23 @@ impl CallManager {
         if self.incoming_calls.contains_key(chat) {
             CallState::Incoming
         } else if let Some(call) = self.active_calls.get(chat) {
-            match call.call_state() {
-                CoreCallState::Active => CallState::Active,
-                CoreCallState::Finished => CallState::Idle,
-                CoreCallState::WaitingForPeerAnswer => CallState::Outgoing,
-                CoreCallState::WaitingForSelfAnswer => CallState::Incoming,
-            }
+            active_call_state(call.call_state(), self.held_calls.contains(chat))
         } else {
             CallState::Idle
         }
     }
 
+    /// Every chat with a call that isn't idle, ordered by chat
+    pub fn active_call_states(&self) -> Vec<(ChatHandle, CallState)> {
+        call_states(
+            self.incoming_calls.keys().copied(),
+            self.active_calls
+                .iter()
+                .map(|(chat, call)| (*chat, call.call_state())),
+            &self.held_calls,
+        )
+    }
+
     pub fn incoming_call(&mut self, chat: ChatHandle, handle: IncomingCall) {
         self.incoming_calls.insert(chat, handle);
     }
@@ -124,20 +166,64 @@ impl CallManager {
             .context("Incoming call handle not available")?;
 
         let chat = *chat;
-        let active_call = incoming_call.accept().context("Failed to accept call")?;
+        // Same starting point as when we place the call ourselves
+        let bit_rate = initial_audio_bit_rate(incoming_call.friend().connection_status());
+        let active_call = incoming_call
+            .accept(bit_rate)
+            .context("Failed to accept call")?;
 
         self.active_calls.insert(chat, active_call);
+        self.answered_calls.insert(chat);
 
         Ok(())
     }
 
+    /// Puts an active call on hold or resumes it, returning the new state
+    pub fn set_paused(&mut self, chat: &ChatHandle, paused: bool) -> Result<CallState> {
+        let call = self
+            .active_calls
+            .get(chat)
+            .context("No call to pause/resume")?;
+
+        if call.call_state() != CoreCallState::Active {
+            bail!("Only active calls can be paused");
+        }
+
+        call.set_paused(paused)
+            .context("Failed to send pause/resume to toxcore")?;
+
+        if paused {
+            self.held_calls.insert(*chat);
+        } else {
+            self.held_calls.remove(chat);
+        }
+
+        Ok(self.call_state(chat))
+    }
+
+    pub fn is_held(&self, chat: &ChatHandle) -> bool {
+        self.held_calls.contains(chat)
+    }
+
     pub fn outgoing_call(&mut self, chat: ChatHandle, call: ActiveCall) {
         self.active_calls.insert(chat, call);
     }
 
-    pub fn drop_call(&mut self, chat: &ChatHandle) {
+    /// Hangs up, rejects or cancels the call depending on how far it got.
+    /// Returns the state the call was in
+    pub fn drop_call(&mut self, chat: &ChatHandle) -> CallState {
+        let state = self.call_state(chat);
+
+        // Dropping an incoming call handle rejects it
         self.incoming_calls.remove(chat);
-        self.active_calls.remove(chat);
+        self.held_calls.remove(chat);
+        self.answered_calls.remove(chat);
+
+        if let Some(call) = self.active_calls.remove(chat) {
+            call.cancel();
+        }
+
+        state
     }
 
     pub fn send_audio_frame(&mut self, frame: AudioFrame) -> Result<()> {
@@ -145,8 +231,10 @@ impl CallManager {
             .try_into()
             .context("Failed to convert audio frame to core audio frame")?;
 
+        let held_calls = &self.held_calls;
         self.active_calls
             .iter_mut()
+            .filter(|(chat, _)| !held_calls.contains(chat))
             .try_for_each(|(_, call)| {
                 call.send_audio_frame(core_frame.clone())
                     .map_err(anyhow::Error::from)
@@ -159,12 +247,14 @@ impl CallManager {
             event = Self::wait_for_active_call_event(&mut self.active_calls).fuse() => {
                 let (handle, event) = event;
                 let event = event.unwrap();
-                self.handle_call_event(&handle, &event);
-                (handle, event).try_into().unwrap()
+                match self.handle_call_event(&handle, &event) {
+                    Some(reason) => CallEvent::CallEnded(handle, reason),
+                    None => (handle, event).try_into().unwrap(),
+                }
             }
             hungup_handle = Self::wait_for_incoming_hangups(&mut self.incoming_calls).fuse() => {
                 self.incoming_calls.remove(&hungup_handle);
-                CallEvent::CallEnded(hungup_handle)
+                CallEvent::CallEnded(hungup_handle, CallEndReason::Missed)
             }
         }
     }
@@ -195,9 +285,173 @@ impl CallManager {
         futures::future::select_all(iter).await.0
     }
 
-    fn handle_call_event(&mut self, chat: &ChatHandle, event: &CoreCallEvent) {
-        if let CoreCallEvent::CallStateChanged(CoreCallState::Finished) = event {
-            self.active_calls.remove(chat);
+    /// Returns why the call ended if event ended it
+    fn handle_call_event(
+        &mut self,
+        chat: &ChatHandle,
+        event: &CoreCallEvent,
+    ) -> Option<CallEndReason> {
+        match event {
+            CoreCallEvent::CallStateChanged(CoreCallState::Active) => {
+                self.answered_calls.insert(*chat);
+                None
+            }
+            CoreCallEvent::CallStateChanged(CoreCallState::Finished) => {
+                self.active_calls.remove(chat);
+                self.held_calls.remove(chat);
+
+                // Only our outgoing calls are active calls before they're
+                // answered
+                if self.answered_calls.remove(chat) {
+                    Some(CallEndReason::HungUp)
+                } else {
+                    Some(CallEndReason::Declined)
+                }
+            }
+            _ => None,
         }
     }
 }
+
+/// Audio bit rate in kbit/s to start a call with. A direct UDP connection
+/// gets full quality opus voice. TCP goes through a relay that adds latency
+/// and may be shared with others, so we start lower to leave headroom. Not
+/// being connected at all shouldn't happen when calling, treat it like TCP
+pub(crate) fn initial_audio_bit_rate(connection: ConnectionStatus) -> u32 {
+    match connection {
+        ConnectionStatus::Udp => 64,
+        ConnectionStatus::Tcp | ConnectionStatus::None => 32,
+    }
+}
+
+fn active_call_state(core_state: CoreCallState, held: bool) -> CallState {
+    match core_state {
+        CoreCallState::Active if held => CallState::Held,
+        CoreCallState::Active => CallState::Active,
+        CoreCallState::Finished => CallState::Idle,
+        CoreCallState::WaitingForPeerAnswer => CallState::Outgoing,
+        CoreCallState::WaitingForSelfAnswer => CallState::Incoming,
+    }
+}
+
+fn call_states(
+    incoming: impl Iterator<Item = ChatHandle>,
+    active: impl Iterator<Item = (ChatHandle, CoreCallState)>,
+    held: &HashSet<ChatHandle>,
+) -> Vec<(ChatHandle, CallState)> {
+    let incoming = incoming.map(|chat| (chat, CallState::Incoming));
+    let active = active.map(|(chat, state)| (chat, active_call_state(state, held.contains(&chat))));
+
+    let mut states: Vec<_> = incoming
+        .chain(active)
+        .filter(|(_, state)| !matches!(state, CallState::Idle))
+        .collect();
+    states.sort_by_key(|(chat, _)| *chat);
+
+    states
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tcp_calls_start_at_lower_bit_rate() {
+        assert_eq!(initial_audio_bit_rate(ConnectionStatus::Udp), 64);
+        assert!(
+            initial_audio_bit_rate(ConnectionStatus::Tcp)
+                < initial_audio_bit_rate(ConnectionStatus::Udp)
+        );
+        assert_eq!(
+            initial_audio_bit_rate(ConnectionStatus::None),
+            initial_audio_bit_rate(ConnectionStatus::Tcp)
+        );
+    }
+
+    #[test]
+    fn test_local_end_reason() {
+        assert_eq!(
+            CallEndReason::for_local_end(&CallState::Outgoing),
+            Some(CallEndReason::Cancelled)
+        );
+        assert_eq!(
+            CallEndReason::for_local_end(&CallState::Incoming),
+            Some(CallEndReason::Rejected)
+        );
+        assert_eq!(
+            CallEndReason::for_local_end(&CallState::Held),
+            Some(CallEndReason::HungUp)
+        );
+        assert_eq!(CallEndReason::for_local_end(&CallState::Idle), None);
+    }
+
+    #[test]
+    fn test_remote_end_reason() {
+        let mut manager = CallManager::new();
+        let chat = ChatHandle::from(1);
+        let finished = CoreCallEvent::CallStateChanged(CoreCallState::Finished);
+
+        // Never answered
+        assert_eq!(
+            manager.handle_call_event(&chat, &finished),
+            Some(CallEndReason::Declined)
+        );
+
+        let answered = CoreCallEvent::CallStateChanged(CoreCallState::Active);
+        assert_eq!(manager.handle_call_event(&chat, &answered), None);
+        assert_eq!(
+            manager.handle_call_event(&chat, &finished),
+            Some(CallEndReason::HungUp)
+        );
+
+        // The next call starts over
+        assert_eq!(
+            manager.handle_call_event(&chat, &finished),
+            Some(CallEndReason::Declined)
+        );
+    }
+
+    #[test]
+    fn test_held_call_states() {
+        assert!(matches!(
+            active_call_state(CoreCallState::Active, false),
+            CallState::Active
+        ));
+        assert!(matches!(
+            active_call_state(CoreCallState::Active, true),
+            CallState::Held
+        ));
+
+        // Hold only means something once the call is running, and a finished
+        // call is finished regardless
+        assert!(matches!(
+            active_call_state(CoreCallState::WaitingForPeerAnswer, true),
+            CallState::Outgoing
+        ));
+        assert!(matches!(
+            active_call_state(CoreCallState::Finished, true),
+            CallState::Idle
+        ));
+    }
+
+    #[test]
+    fn test_call_states() {
+        let incoming = vec![ChatHandle::from(3)];
+        let active = vec![
+            (ChatHandle::from(1), CoreCallState::Active),
+            (ChatHandle::from(2), CoreCallState::WaitingForPeerAnswer),
+            (ChatHandle::from(4), CoreCallState::Active),
+            (ChatHandle::from(5), CoreCallState::Finished),
+        ];
+        let held = vec![ChatHandle::from(4)].into_iter().collect();
+
+        let states = call_states(incoming.into_iter(), active.into_iter(), &held);
+
+        // Finished calls are as good as gone
+        assert_eq!(states.len(), 4);
+        assert!(matches!(states[0], (chat, CallState::Active) if chat == ChatHandle::from(1)));
+        assert!(matches!(states[1], (chat, CallState::Outgoing) if chat == ChatHandle::from(2)));
+        assert!(matches!(states[2], (chat, CallState::Incoming) if chat == ChatHandle::from(3)));
+        assert!(matches!(states[3], (chat, CallState::Held) if chat == ChatHandle::from(4)));
+    }
+}
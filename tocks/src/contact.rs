@@ -1,8 +1,9 @@
 use crate::storage::{ChatHandle, UserHandle};
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use toxcore::{Friend as ToxFriend, PublicKey, Status as ToxStatus};
+use toxcore::{Friend as ToxFriend, PublicKey, Status as ToxStatus, ToxId};
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Status {
@@ -32,6 +33,15 @@ pub struct Friend {
     public_key: PublicKey,
     name: String,
     status: Status,
+    // Whether the user confirmed the public key out of band. Purely local
+    #[serde(default)]
+    verified: bool,
+    // Whether incoming messages in the friend's chat should stay quiet
+    #[serde(default)]
+    muted: bool,
+    // When we were last connected to the friend, None if we never were
+    #[serde(default)]
+    last_online: Option<DateTime<Utc>>,
 }
 
 impl Friend {
@@ -48,6 +58,9 @@ impl Friend {
             public_key,
             name,
             status,
+            verified: false,
+            muted: false,
+            last_online: None,
         }
     }
 
@@ -78,6 +91,30 @@ impl Friend {
     pub fn set_status(&mut self, status: Status) {
         self.status = status
     }
+
+    pub fn verified(&self) -> bool {
+        self.verified
+    }
+
+    pub fn set_verified(&mut self, verified: bool) {
+        self.verified = verified
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted
+    }
+
+    pub fn last_online(&self) -> Option<&DateTime<Utc>> {
+        self.last_online.as_ref()
+    }
+
+    pub fn set_last_online(&mut self, last_online: Option<DateTime<Utc>>) {
+        self.last_online = last_online
+    }
 }
 
 pub type Friends = HashMap<UserHandle, Friend>;
@@ -93,6 +130,10 @@ pub struct User {
     id: UserHandle,
     public_key: PublicKey,
     name: String,
+    // Only known if we sent the friend request ourselves, a full tox ID can't
+    // be rebuilt from the public key
+    #[serde(default)]
+    tox_id: Option<ToxId>,
 }
 
 impl User {
@@ -101,6 +142,7 @@ impl User {
             id,
             public_key,
             name,
+            tox_id: None,
         }
     }
 
@@ -115,6 +157,16 @@ impl User {
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    /// Address to send a new friend request to, e.g. to add back a removed
+    /// friend
+    pub fn tox_id(&self) -> Option<&ToxId> {
+        self.tox_id.as_ref()
+    }
+
+    pub fn set_tox_id(&mut self, tox_id: Option<ToxId>) {
+        self.tox_id = tox_id;
+    }
 }
 
 #[derive(Default)]
@@ -185,6 +237,12 @@ impl UserManager {
         &self.friends[self.chat_mapping[handle]]
     }
 
+    pub fn find_by_public_key(&self, key: &PublicKey) -> Option<&Friend> {
+        self.pk_mapping
+            .get(key)
+            .map(|idx| &self.friends[*idx].friend)
+    }
+
     pub fn friend_by_public_key(&mut self, key: &PublicKey) -> &mut Friend {
         &mut self.friends[self.pk_mapping[key]].friend
     }
@@ -196,4 +254,8 @@ impl UserManager {
     pub fn friends(&self) -> impl Iterator<Item = &Friend> {
         self.friends.iter().map(|item| &item.friend)
     }
+
+    pub fn friend_bundles_mut(&mut self) -> impl Iterator<Item = &mut FriendBundle> {
+        self.friends.iter_mut()
+    }
 }
@@ -1,3 +1,11 @@
+use crate::AccountId;
+
+use toxcore::error::{
+    CallControlError, DecryptionError, ToxBootstrapError, ToxCallError, ToxCustomPacketError,
+    ToxSendMessageError,
+};
+
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -7,3 +15,112 @@ pub enum ExitError {
     #[error("Unexpected exit")]
     Ungraceful,
 }
+
+#[derive(Error, Debug)]
+#[error("A valid password is required to load this account")]
+pub struct PasswordRequiredError;
+
+#[derive(Error, Debug)]
+#[error("Tox save is corrupt")]
+pub struct CorruptSaveError;
+
+#[derive(Error, Debug)]
+#[error("Account {0} is already open in another tocks instance")]
+pub struct AccountInUseError(pub String);
+
+#[derive(Error, Debug)]
+#[error("Unknown account {0}")]
+pub struct UnknownAccountError(pub AccountId);
+
+/// Class of failure for clients that need to react to an error rather than
+/// just show it. The message is the same text we'd log or display
+#[derive(Error, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TocksError {
+    #[error("{0}")]
+    Auth(String),
+    #[error("{0}")]
+    Storage(String),
+    #[error("{0}")]
+    Network(String),
+    // The account a request was for doesn't exist
+    #[error("{1}")]
+    UnknownAccount(AccountId, String),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<&anyhow::Error> for TocksError {
+    fn from(e: &anyhow::Error) -> TocksError {
+        let message = format!("{:#}", e);
+
+        // downcast_ref also looks through any context we've attached, so
+        // this finds the error wherever it ended up in the chain
+        if e.downcast_ref::<PasswordRequiredError>().is_some()
+            || e.downcast_ref::<DecryptionError>().is_some()
+        {
+            TocksError::Auth(message)
+        } else if let Some(UnknownAccountError(account)) = e.downcast_ref::<UnknownAccountError>() {
+            TocksError::UnknownAccount(*account, message)
+        } else if e.downcast_ref::<AccountInUseError>().is_some()
+            || e.downcast_ref::<CorruptSaveError>().is_some()
+            || e.downcast_ref::<rusqlite::Error>().is_some()
+            || e.downcast_ref::<std::io::Error>().is_some()
+        {
+            TocksError::Storage(message)
+        } else if e.downcast_ref::<ToxSendMessageError>().is_some()
+            || e.downcast_ref::<ToxCustomPacketError>().is_some()
+            || e.downcast_ref::<ToxBootstrapError>().is_some()
+            || e.downcast_ref::<ToxCallError>().is_some()
+            || e.downcast_ref::<CallControlError>().is_some()
+        {
+            TocksError::Network(message)
+        } else {
+            TocksError::Other(message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::savemanager::SaveManager;
+
+    use anyhow::{Context, Result};
+
+    #[test]
+    fn test_wrong_password_is_auth() -> Result<()> {
+        let save_dir = tempfile::tempdir()?;
+        let path = save_dir.path().join("account.tox");
+
+        SaveManager::new_with_password(path.clone(), "password")?.save(b"data")?;
+
+        let err = SaveManager::new_with_password(path, "wrong")?
+            .load()
+            .context("Failed to load tox save")
+            .unwrap_err();
+
+        assert!(matches!(TocksError::from(&err), TocksError::Auth(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_classes() {
+        let err = anyhow::Error::from(UnknownAccountError(4.into()))
+            .context("Failed to handle UI request");
+        assert_eq!(
+            TocksError::from(&err),
+            TocksError::UnknownAccount(
+                4.into(),
+                "Failed to handle UI request: Unknown account 4".to_string()
+            )
+        );
+
+        let err = anyhow::Error::from(CorruptSaveError).context("Failed to load tox save");
+        assert!(matches!(TocksError::from(&err), TocksError::Storage(_)));
+
+        let err = anyhow::anyhow!("Something else");
+        assert!(matches!(TocksError::from(&err), TocksError::Other(_)));
+    }
+}
@@ -15,66 +15,162 @@ use crate::{TocksEvent, TocksUiEvent};
 use anyhow::{Context, Result};
 use futures::{
     channel::mpsc::{UnboundedReceiver, UnboundedSender},
+    future::{AbortHandle, Aborted, BoxFuture},
+    stream::FuturesUnordered,
     FutureExt, Stream, StreamExt,
 };
 use log::{error, info};
 
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 
-use std::task::Poll;
+use std::{collections::HashMap, task::Poll};
+
+/// Maximum number of serialized bytes we are willing to queue for a single
+/// client before we consider it stuck and disconnect it
+const MAX_CLIENT_BACKLOG: usize = 1024 * 1024;
+
+type ClientId = u64;
+
+type ClientFlush =
+    BoxFuture<'static, Result<(ClientId, EventStreamWriteHalf, std::io::Result<()>), Aborted>>;
+
+struct ClientWriter {
+    // None while a flush owns the stream
+    stream: Option<EventStreamWriteHalf>,
+    backlog: Vec<u8>,
+    // Bytes handed to the in flight flush, they still count towards
+    // MAX_CLIENT_BACKLOG
+    in_flight: usize,
+    abort_flush: Option<AbortHandle>,
+}
+
+impl Drop for ClientWriter {
+    fn drop(&mut self) {
+        // A flush to a client that stopped reading would otherwise keep its
+        // socket open forever
+        if let Some(abort_flush) = &self.abort_flush {
+            abort_flush.abort();
+        }
+    }
+}
 
 pub struct EventServer {
     tocks_event_rx: UnboundedReceiver<TocksEvent>,
     tocks_event_tx: UnboundedSender<TocksEvent>,
     ui_event_tx: UnboundedSender<TocksUiEvent>,
-    event_client_listener: Listener,
-    clients: Vec<EventStream>,
+    // None if we failed to bind, the embedded UI still needs events relayed
+    event_client_listener: Option<Listener>,
+    next_client_id: ClientId,
+    client_readers: HashMap<ClientId, EventStreamReadHalf>,
+    client_writers: HashMap<ClientId, ClientWriter>,
+    // Flushes live here rather than in run() so that a flush interrupted by
+    // another select! branch picks up where it left off instead of losing a
+    // partially written backlog
+    client_flushes: FuturesUnordered<ClientFlush>,
 }
 
 impl EventServer {
+    /// Failing to create the socket is not fatal, events are still relayed to
+    /// tocks_event_tx but external clients will not be able to connect
     pub fn new(
         tocks_event_rx: UnboundedReceiver<TocksEvent>,
         tocks_event_tx: UnboundedSender<TocksEvent>,
         ui_event_tx: UnboundedSender<TocksUiEvent>,
-    ) -> Result<EventServer> {
+    ) -> EventServer {
         let socket_path = get_socket_addr();
         let event_client_listener = create_event_client_listener(socket_path)
-            .context("Failed to create event client listener")?;
+            .context("Failed to create event client listener");
 
-        Ok(EventServer {
+        Self::with_listener(
             tocks_event_rx,
             tocks_event_tx,
             ui_event_tx,
             event_client_listener,
-            clients: Default::default(),
-        })
+        )
+    }
+
+    fn with_listener(
+        tocks_event_rx: UnboundedReceiver<TocksEvent>,
+        tocks_event_tx: UnboundedSender<TocksEvent>,
+        ui_event_tx: UnboundedSender<TocksUiEvent>,
+        event_client_listener: Result<Listener>,
+    ) -> EventServer {
+        let event_client_listener = match event_client_listener {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                error!("Event clients will not be able to connect: {:#}", e);
+                None
+            }
+        };
+
+        EventServer {
+            tocks_event_rx,
+            tocks_event_tx,
+            ui_event_tx,
+            event_client_listener,
+            next_client_id: 0,
+            client_readers: Default::default(),
+            client_writers: Default::default(),
+            client_flushes: Default::default(),
+        }
     }
 
     pub async fn run(&mut self) -> Result<()> {
         loop {
+            self.start_client_flushes();
+
             futures::select! {
                 client = wait_for_client(&mut self.event_client_listener).fuse() => {
                     match client {
-                        Ok(client) => self.clients.push(client),
+                        Ok(client) => self.add_client(client),
                         Err(e) => error!("Failed to handle new event client: {}", e),
                     }
                 }
                 tocks_event = self.tocks_event_rx.next() => {
                     // FIXME: Better error handling
-                    if let Err(e) = self.handle_tocks_event(tocks_event).await {
+                    if let Err(e) = self.handle_tocks_event(tocks_event) {
                         error!("{}", e);
                     }
                 }
-                ui_event = wait_for_ui_event(&mut self.clients).fuse() => {
-                    if let Err(e) = self.handle_ui_event(ui_event) {
+                (client_id, ui_event) = wait_for_ui_event(&mut self.client_readers).fuse() => {
+                    if let Err(e) = self.handle_ui_event(client_id, ui_event) {
                         error!("Failed to handle incoming event: {}", e);
                     }
                 }
+                flushed = self.client_flushes.select_next_some() => {
+                    // Aborted flushes belong to clients we already removed
+                    if let Ok((client_id, stream, written)) = flushed {
+                        self.handle_client_write(client_id, stream, written);
+                    }
+                }
             }
         }
     }
 
-    async fn handle_tocks_event(&mut self, event: Option<TocksEvent>) -> Result<()> {
+    fn add_client(&mut self, client: EventStream) {
+        let id = self.next_client_id;
+        self.next_client_id += 1;
+
+        let (reader, writer) = client.into_split();
+        self.client_readers.insert(id, reader);
+        self.client_writers.insert(
+            id,
+            ClientWriter {
+                stream: Some(writer),
+                backlog: Vec::new(),
+                in_flight: 0,
+                abort_flush: None,
+            },
+        );
+    }
+
+    fn remove_client(&mut self, id: ClientId) {
+        info!("Removing client {}", id);
+        self.client_readers.remove(&id);
+        self.client_writers.remove(&id);
+    }
+
+    fn handle_tocks_event(&mut self, event: Option<TocksEvent>) -> Result<()> {
         if event.is_none() {
             anyhow::bail!("No more tocks events");
         }
@@ -96,28 +192,78 @@ impl EventServer {
             .unbounded_send(event)
             .context("Failed to propogate event")?;
 
+        // Writes happen in the background as clients become writable, a
+        // client that stops reading should not hold up everyone else
         let mut clients_to_remove = vec![];
-        for (idx, client) in self.clients.iter_mut().enumerate() {
-            if client.write_all(&serialized).await.is_err() {
-                clients_to_remove.push(idx);
+        for (id, client) in self.client_writers.iter_mut() {
+            if client.in_flight + client.backlog.len() + serialized.len() > MAX_CLIENT_BACKLOG {
+                error!("Event client {} is not keeping up", id);
+                clients_to_remove.push(*id);
+                continue;
             }
+
+            client.backlog.extend_from_slice(&serialized);
         }
 
-        for client in clients_to_remove.into_iter().rev() {
-            info!("Removing client {}", client);
-            self.clients.remove(client);
+        for client in clients_to_remove {
+            self.remove_client(client);
         }
 
         Ok(())
     }
 
-    fn handle_ui_event(&mut self, ui_event: Result<Option<TocksUiEvent>>) -> Result<()> {
+    /// Hands the backlog of every idle client to a new flush. Events that
+    /// arrive in the meantime queue up for the next one
+    fn start_client_flushes(&mut self) {
+        for (id, client) in self.client_writers.iter_mut() {
+            if client.backlog.is_empty() {
+                continue;
+            }
+
+            let stream = match client.stream.take() {
+                Some(stream) => stream,
+                None => continue,
+            };
+
+            let backlog = std::mem::take(&mut client.backlog);
+            client.in_flight = backlog.len();
+
+            let (flush, abort_flush) =
+                futures::future::abortable(flush_client_backlog(*id, stream, backlog));
+            client.abort_flush = Some(abort_flush);
+            self.client_flushes.push(flush.boxed());
+        }
+    }
+
+    fn handle_client_write(
+        &mut self,
+        id: ClientId,
+        stream: EventStreamWriteHalf,
+        written: std::io::Result<()>,
+    ) {
+        if let Err(e) = written {
+            error!("Failed to write to event client {}: {}", id, e);
+            self.remove_client(id);
+            return;
+        }
+
+        if let Some(client) = self.client_writers.get_mut(&id) {
+            client.stream = Some(stream);
+            client.in_flight = 0;
+            client.abort_flush = None;
+        }
+    }
+
+    fn handle_ui_event(
+        &mut self,
+        id: ClientId,
+        ui_event: Result<Option<TocksUiEvent>>,
+    ) -> Result<()> {
         let ui_event = ui_event?;
         if let Some(ui_event) = ui_event {
             self.ui_event_tx.unbounded_send(ui_event)?;
         } else {
-            // Over the top kill of all clients to avoid 100% CPU usage loop
-            self.clients.clear();
+            self.remove_client(id);
         }
         Ok(())
     }
@@ -125,6 +271,8 @@ impl EventServer {
 
 pub struct EventClient {
     socket_stream: BufReader<EventStream>,
+    // Partially read lines have to outlive a single poll
+    read_buf: Vec<u8>,
 }
 
 impl EventClient {
@@ -138,6 +286,7 @@ impl EventClient {
 
         Ok(EventClient {
             socket_stream: buffered_reader,
+            read_buf: Vec::new(),
         })
     }
 
@@ -162,19 +311,19 @@ impl Stream for EventClient {
         mut self: core::pin::Pin<&mut Self>,
         cx: &mut core::task::Context<'_>,
     ) -> core::task::Poll<Option<Self::Item>> {
-        let mut v = Vec::new();
+        let this = &mut *self;
         let res = {
-            let mut stream = self.socket_stream.read_until(b'\n', &mut v).boxed();
+            let mut stream = this
+                .socket_stream
+                .read_until(b'\n', &mut this.read_buf)
+                .boxed();
             let pin = stream.as_mut();
             pin.poll(cx)
         };
         match res {
-            Poll::Ready(Ok(size)) => {
-                if size == 0 {
-                    return Poll::Ready(None);
-                }
-                let res = serde_json::from_slice(&v).map_err(anyhow::Error::from);
-                Poll::Ready(Some(res))
+            Poll::Ready(Ok(_)) => {
+                let v = std::mem::take(&mut this.read_buf);
+                Poll::Ready(parse_frame(&v))
             }
             Poll::Ready(Err(e)) => {
                 error!("Failed to read from event server: {}", e);
@@ -185,15 +334,35 @@ impl Stream for EventClient {
     }
 }
 
-async fn wait_for_client(client_listener: &mut Listener) -> Result<EventStream> {
-    Ok(client_listener.accept().await?.0)
+/// Parses a single newline terminated frame read by an EventClient. A frame
+/// that isn't newline terminated means the connection went away part way
+/// through, which ends the stream. A complete frame that fails to parse only
+/// produces an error for that frame, the connection is still usable
+fn parse_frame(frame: &[u8]) -> Option<Result<TocksEvent>> {
+    if frame.is_empty() {
+        return None;
+    }
+
+    if !frame.ends_with(b"\n") {
+        error!("Event server connection closed mid event");
+        return None;
+    }
+
+    Some(serde_json::from_slice(frame).context("Failed to parse event"))
 }
 
-async fn wait_for_ui_event_from_client(client: &mut EventStream) -> Result<Option<TocksUiEvent>> {
+async fn wait_for_client(client_listener: &mut Option<Listener>) -> Result<EventStream> {
+    match client_listener {
+        Some(listener) => Ok(listener.accept().await?.0),
+        None => futures::future::pending().await,
+    }
+}
+
+async fn wait_for_ui_event_from_client(
+    client: &mut EventStreamReadHalf,
+) -> Result<Option<TocksUiEvent>> {
     let mut buf = Vec::new();
-    let res = BufReader::new(client.split().0)
-        .read_until(b'\n', &mut buf)
-        .await?;
+    let res = BufReader::new(client).read_until(b'\n', &mut buf).await?;
     if res == 0 {
         return Ok(None);
     }
@@ -203,19 +372,33 @@ async fn wait_for_ui_event_from_client(client: &mut EventStream) -> Result<Optio
     Ok(Some(event))
 }
 
-async fn wait_for_ui_event(clients: &mut Vec<EventStream>) -> Result<Option<TocksUiEvent>> {
+async fn wait_for_ui_event(
+    clients: &mut HashMap<ClientId, EventStreamReadHalf>,
+) -> (ClientId, Result<Option<TocksUiEvent>>) {
     if clients.is_empty() {
         // If there are no clients we block forever to avoid waking up our event
         // loop
         futures::future::pending::<()>().await;
     }
 
-    let next_event_futures = clients
-        .iter_mut()
-        .map(|client| wait_for_ui_event_from_client(client).boxed());
+    let next_event_futures = clients.iter_mut().map(|(id, client)| {
+        let id = *id;
+        async move { (id, wait_for_ui_event_from_client(client).await) }.boxed()
+    });
     futures::future::select_all(next_event_futures).await.0
 }
 
+/// Owns the stream until the whole backlog is written, the stream is handed
+/// back so the client can be flushed again
+async fn flush_client_backlog(
+    id: ClientId,
+    mut stream: EventStreamWriteHalf,
+    backlog: Vec<u8>,
+) -> (ClientId, EventStreamWriteHalf, std::io::Result<()>) {
+    let written = stream.write_all(&backlog).await;
+    (id, stream, written)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -248,7 +431,7 @@ mod tests {
                 tocks_event_channel.1,
                 event_server_channel.0,
                 ui_event_channel.0,
-            )?;
+            );
 
             // Run the server until the connection handshake completes
             let mut fixture = futures::select! {
@@ -318,6 +501,70 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_frame() {
+        let mut frame = serde_json::to_vec(&TocksEvent::Error("Test".to_string())).unwrap();
+
+        // Connection dropped before the newline
+        assert!(parse_frame(&frame).is_none());
+        assert!(parse_frame(&[]).is_none());
+
+        frame.push(b'\n');
+        match parse_frame(&frame) {
+            Some(Ok(TocksEvent::Error(e))) => assert_eq!(e, "Test"),
+            _ => panic!("Unexpected parse result"),
+        }
+
+        match parse_frame(b"{ not an event }\n") {
+            Some(Err(e)) => assert!(e.downcast_ref::<serde_json::Error>().is_some()),
+            _ => panic!("Malformed frame should be an error"),
+        }
+    }
+
+    #[test]
+    fn test_action_sent_round_trip() -> Result<()> {
+        let event = TocksUiEvent::ActionSent(1.into(), 2.into(), "waves".to_string());
+        let serialized = serde_json::to_string(&event)?;
+
+        match serde_json::from_str(&serialized)? {
+            TocksUiEvent::ActionSent(account, chat, message) => {
+                assert_eq!(account, 1.into());
+                assert_eq!(chat, 2.into());
+                assert_eq!(message, "waves");
+            }
+            _ => panic!("Unexpected event"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mark_chat_read_round_trip() -> Result<()> {
+        use chrono::{TimeZone, Utc};
+
+        let time = Utc.timestamp_millis(1_625_097_600_123);
+        let event = TocksUiEvent::MarkChatRead(1.into(), 2.into(), time);
+        let serialized = serde_json::to_string(&event)?;
+
+        match serde_json::from_str(&serialized)? {
+            TocksUiEvent::MarkChatRead(account, chat, read_time) => {
+                assert_eq!(account, 1.into());
+                assert_eq!(chat, 2.into());
+                assert_eq!(read_time, time);
+            }
+            _ => panic!("Unexpected event"),
+        }
+
+        let event = TocksEvent::ChatReadTimeUpdated(1.into(), 2.into(), time);
+        let serialized = serde_json::to_string(&event)?;
+        match serde_json::from_str(&serialized)? {
+            TocksEvent::ChatReadTimeUpdated(_, _, read_time) => assert_eq!(read_time, time),
+            _ => panic!("Unexpected event"),
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_tocks_event_propagation() -> Result<()> {
         // Ensure that when a tocks event is sent it's correctly propagated to
@@ -357,6 +604,36 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_events_relayed_without_listener() -> Result<()> {
+        // The embedded UI only sees events through the event server, so it
+        // has to keep working if the socket can't be created
+        let (tocks_event_tx, tocks_event_rx) = mpsc::unbounded();
+        let (event_server_tx, mut event_server_rx) = mpsc::unbounded();
+        let (ui_event_tx, _ui_event_rx) = mpsc::unbounded();
+
+        let mut server = EventServer::with_listener(
+            tocks_event_rx,
+            event_server_tx,
+            ui_event_tx,
+            Err(anyhow::anyhow!("Address in use")),
+        );
+
+        tocks_event_tx.unbounded_send(TocksEvent::Error("Test".to_string()))?;
+
+        let propagated = futures::select! {
+            propagated = event_server_rx.next() => propagated,
+            _ = server.run().fuse() => panic!("Server exited early"),
+        };
+
+        match propagated {
+            Some(TocksEvent::Error(e)) => assert_eq!(e, "Test"),
+            _ => panic!("Unexpected event"),
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_tocks_ui_event_propagation() -> Result<()> {
         // Ensure that when the client sends a UI event it gets propagated to
@@ -383,6 +660,30 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_set_self_name_propagation() -> Result<()> {
+        let mut fixture = Fixture::new().await?;
+        fixture
+            .client
+            .send(TocksUiEvent::SetSelfName(1.into(), "new name".to_string()))
+            .await?;
+
+        let event = futures::select! {
+            received = fixture.ui_channel_rx.next() => received,
+            _ = fixture.server.run().fuse() => panic!("Server exited unexpectedly"),
+        };
+
+        match event {
+            Some(TocksUiEvent::SetSelfName(account, name)) => {
+                assert_eq!(account, 1.into());
+                assert_eq!(name, "new name");
+            }
+            _ => panic!("Unexpected event"),
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_multiple_client_recv() -> Result<()> {
         let mut fixture = Fixture2Client::new().await?;
@@ -411,6 +712,136 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_sent_message_seen_by_other_clients() -> Result<()> {
+        // Tocks only reports a sent message through MessageInserted, every
+        // connected client has to see it to stay in sync with the sender
+        use crate::storage::Storage;
+        use toxcore::{Message, PublicKey};
+
+        let mut storage =
+            Storage::open_ram(&PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?, "self")?;
+        let friend = storage.add_friend(
+            PublicKey::from_bytes(vec![1; PublicKey::SIZE])?,
+            "friend".to_string(),
+        )?;
+        let chat = *friend.chat_handle();
+
+        let mut fixture = Fixture2Client::new().await?;
+        fixture
+            .client1
+            .send(TocksUiEvent::MessageSent(
+                1.into(),
+                chat,
+                "hello".to_string(),
+            ))
+            .await?;
+
+        let server = &mut fixture.server;
+        let ui_channel_rx = &mut fixture.ui_channel_rx;
+        let tocks_event_tx = &fixture.tocks_event_tx;
+        let client1 = &mut fixture.client1;
+        let client2 = &mut fixture.client2;
+
+        // Stand in for tocks, which stores the message and reports it back
+        let respond_and_receive = async {
+            match ui_channel_rx.next().await {
+                Some(TocksUiEvent::MessageSent(account, chat, message)) => {
+                    let self_handle = storage.self_user_handle();
+                    let entry = storage
+                        .push_message(&chat, self_handle, Message::Normal(message))
+                        .unwrap();
+                    tocks_event_tx
+                        .unbounded_send(TocksEvent::MessageInserted(account, chat, entry))
+                        .unwrap();
+                }
+                _ => panic!("Unexpected ui event"),
+            }
+
+            futures::future::join(client1.next(), client2.next()).await
+        };
+
+        let (result1, result2) = futures::select! {
+            res = respond_and_receive.fuse() => res,
+            _ = server.run().fuse() => panic!("Server exited early"),
+        };
+
+        let check_event = |event| match event {
+            Some(Ok(TocksEvent::MessageInserted(account, inserted_chat, entry))) => {
+                assert_eq!(account, 1.into());
+                assert_eq!(inserted_chat, chat);
+                assert_eq!(entry.display_text(), "hello");
+            }
+            _ => panic!("Unexpected event"),
+        };
+
+        // The sender sees it too, not just the other client
+        check_event(result1);
+        check_event(result2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_slow_client_disconnected() -> Result<()> {
+        // Ensure that a client that stops reading does not prevent other
+        // clients from receiving events, and is eventually dropped
+        let mut fixture = Fixture2Client::new().await?;
+
+        // client1 never reads, send enough data to blow through both the
+        // socket buffers and our own backlog limit
+        let message = "a".repeat(16 * 1024);
+        let num_events = 2 * MAX_CLIENT_BACKLOG / message.len();
+
+        for _ in 0..num_events {
+            fixture
+                .tocks_event_tx
+                .unbounded_send(TocksEvent::Error(message.clone()))?;
+
+            let received = futures::select! {
+                received = fixture.client2.next().fuse() => received,
+                _ = fixture.server.run().fuse() => panic!("Server exited early"),
+            };
+
+            match received {
+                Some(Ok(TocksEvent::Error(e))) => assert_eq!(e, message),
+                _ => panic!("Unexpected event"),
+            }
+        }
+
+        assert_eq!(fixture.server.client_writers.len(), 1);
+        assert_eq!(fixture.server.client_readers.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_flush_completes() -> Result<()> {
+        let mut fixture = Fixture::new().await?;
+
+        // Too big for the socket buffer, so the flush needs several writes
+        let message = "a".repeat(MAX_CLIENT_BACKLOG / 2);
+        fixture
+            .tocks_event_tx
+            .unbounded_send(TocksEvent::Error(message.clone()))?;
+
+        // Keep stopping the server part way through the flush
+        let received = loop {
+            futures::select! {
+                received = fixture.client.next().fuse() => break received,
+                _ = fixture.server.run().fuse() => panic!("Server exited early"),
+                _ = tokio::task::yield_now().fuse() => (),
+            }
+        };
+
+        match received {
+            Some(Ok(TocksEvent::Error(e))) => assert_eq!(e, message),
+            _ => panic!("Unexpected event"),
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_multiple_client_send() -> Result<()> {
         let mut fixture = Fixture2Client::new().await?;
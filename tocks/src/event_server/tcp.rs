@@ -4,6 +4,8 @@ use std::net::SocketAddr;
 
 pub type Listener = tokio::net::TcpListener;
 pub type EventStream = tokio::net::TcpStream;
+pub type EventStreamReadHalf = tokio::net::tcp::OwnedReadHalf;
+pub type EventStreamWriteHalf = tokio::net::tcp::OwnedWriteHalf;
 pub type EventServerAddr = SocketAddr;
 
 pub fn get_socket_addr() -> EventServerAddr {
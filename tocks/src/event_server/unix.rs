@@ -3,6 +3,8 @@ use std::{env, path::PathBuf};
 
 pub type Listener = tokio::net::UnixListener;
 pub type EventStream = tokio::net::UnixStream;
+pub type EventStreamReadHalf = tokio::net::unix::OwnedReadHalf;
+pub type EventStreamWriteHalf = tokio::net::unix::OwnedWriteHalf;
 pub type EventServerAddr = PathBuf;
 
 pub fn get_socket_addr() -> EventServerAddr {
@@ -0,0 +1,278 @@
+use crate::{message_parser::ACTION_PREFIX, storage::UserHandle};
+
+use anyhow::{bail, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use toxcore::Message;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// How many messages a friend can send us within a window before the rest
+/// are held back
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FloodLimits {
+    pub max_messages: usize,
+    pub window: Duration,
+}
+
+impl Default for FloodLimits {
+    fn default() -> FloodLimits {
+        FloodLimits {
+            max_messages: 30,
+            window: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Held back text beyond this is dropped, a peer flooding us for a long time
+/// shouldn't be able to fill up our memory instead
+const MAX_HELD_LEN: usize = 64 * 1024;
+
+#[derive(Default)]
+struct Held {
+    text: String,
+    dropped: u64,
+}
+
+/// Every incoming message costs a DB write and an event to every client, so
+/// a peer spamming us can bog down the whole app. Messages beyond the limit
+/// are held back and released as a single message once the window has room
+/// again. Nothing is lost that way, including the middle of a long message
+/// that arrived split into a burst of parts
+#[derive(Default)]
+pub(crate) struct FloodGuard {
+    limits: FloodLimits,
+    // Arrival times of the messages let through within the current window
+    recent: HashMap<UserHandle, VecDeque<Instant>>,
+    held: HashMap<UserHandle, Held>,
+}
+
+impl FloodGuard {
+    /// Keeps the previous limits if the new ones are invalid
+    pub fn set_limits(&mut self, limits: FloodLimits) -> Result<()> {
+        if limits.max_messages == 0 || limits.window == Duration::from_secs(0) {
+            bail!("Flood limits would drop every message");
+        }
+
+        // Anything held back goes out on the next release
+        self.limits = limits;
+        self.recent.clear();
+
+        Ok(())
+    }
+
+    /// Returns true if a message from user arriving at now can be stored
+    /// right away, otherwise it has to be passed to hold
+    pub fn allow(&mut self, user: UserHandle, now: Instant) -> bool {
+        // Messages arriving behind held back ones wait their turn, so the
+        // chat stays in order
+        if self.held.contains_key(&user) {
+            return false;
+        }
+
+        let recent = self.recent.entry(user).or_default();
+        expire(recent, now, self.limits.window);
+
+        if recent.len() >= self.limits.max_messages {
+            return false;
+        }
+
+        recent.push_back(now);
+        true
+    }
+
+    pub fn hold(&mut self, user: UserHandle, message: &Message) {
+        let held = self.held.entry(user).or_insert_with(|| {
+            warn!("Friend {} is flooding us, holding back messages", user);
+            Held::default()
+        });
+
+        let (prefix, text) = match message {
+            Message::Normal(s) => ("", s),
+            Message::Action(s) => (ACTION_PREFIX, s),
+        };
+
+        if held.text.len() + prefix.len() + text.len() >= MAX_HELD_LEN {
+            held.dropped += 1;
+            return;
+        }
+
+        if !held.text.is_empty() {
+            held.text.push('\n');
+        }
+        held.text.push_str(prefix);
+        held.text.push_str(text);
+    }
+
+    /// When the next held back messages can be released
+    pub fn next_release(&self) -> Option<Instant> {
+        self.held.keys().map(|user| self.release_time(user)).min()
+    }
+
+    /// Held back messages of every friend whose window has room again, each
+    /// coalesced into a single message. The release counts as one message
+    /// against the limit
+    pub fn release(&mut self, now: Instant) -> Vec<(UserHandle, Message)> {
+        let ready: Vec<UserHandle> = self
+            .held
+            .keys()
+            .filter(|user| self.release_time(user) <= now)
+            .copied()
+            .collect();
+
+        let mut ret = Vec::new();
+        for user in ready {
+            let held = match self.held.remove(&user) {
+                Some(held) => held,
+                None => continue,
+            };
+
+            let recent = self.recent.entry(user).or_default();
+            expire(recent, now, self.limits.window);
+            recent.push_back(now);
+
+            let mut text = held.text;
+            if held.dropped > 0 {
+                warn!("Dropped {} messages from friend {}", held.dropped, user);
+                text.push_str(&format!("\n[{} more messages dropped]", held.dropped));
+            }
+
+            ret.push((user, Message::Normal(text)));
+        }
+
+        ret
+    }
+
+    fn release_time(&self, user: &UserHandle) -> Instant {
+        // Held back messages only exist while the window is full, so there is
+        // room once the oldest message in it leaves
+        match self.recent.get(user).and_then(|recent| recent.front()) {
+            Some(oldest) => *oldest + self.limits.window,
+            None => Instant::now(),
+        }
+    }
+}
+
+fn expire(recent: &mut VecDeque<Instant>, now: Instant, window: Duration) {
+    while let Some(arrival) = recent.front() {
+        if now.saturating_duration_since(*arrival) < window {
+            break;
+        }
+        recent.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_throttled() -> Result<()> {
+        let mut guard = FloodGuard::default();
+        guard.set_limits(FloodLimits {
+            max_messages: 3,
+            window: Duration::from_secs(10),
+        })?;
+
+        let user = UserHandle::from(1);
+        let other = UserHandle::from(2);
+        let now = Instant::now();
+
+        for i in 0..3 {
+            assert!(guard.allow(user, now + Duration::from_millis(i)));
+        }
+        assert!(!guard.allow(user, now + Duration::from_millis(3)));
+        assert!(!guard.allow(user, now + Duration::from_secs(5)));
+
+        // One friend flooding doesn't silence the others
+        assert!(guard.allow(other, now + Duration::from_secs(5)));
+
+        // Only the first message has left the window
+        assert!(guard.allow(user, now + Duration::from_secs(10)));
+        assert!(!guard.allow(user, now + Duration::from_secs(10)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_held_messages_coalesced() -> Result<()> {
+        let mut guard = FloodGuard::default();
+        guard.set_limits(FloodLimits {
+            max_messages: 1,
+            window: Duration::from_secs(10),
+        })?;
+
+        let user = UserHandle::from(1);
+        let now = Instant::now();
+
+        assert!(guard.allow(user, now));
+        assert_eq!(guard.next_release(), None);
+
+        guard.hold(user, &Message::Normal("first".into()));
+        guard.hold(user, &Message::Action("waves".into()));
+        assert_eq!(guard.next_release(), Some(now + Duration::from_secs(10)));
+
+        // Later messages queue up behind the held ones even if there's room
+        assert!(!guard.allow(user, now + Duration::from_secs(10)));
+        guard.hold(user, &Message::Normal("last".into()));
+
+        assert!(guard.release(now + Duration::from_secs(5)).is_empty());
+
+        let released = guard.release(now + Duration::from_secs(10));
+        assert_eq!(
+            released,
+            vec![(user, Message::Normal("first\n/me waves\nlast".into()))]
+        );
+        assert_eq!(guard.next_release(), None);
+
+        // The release used up the window
+        assert!(!guard.allow(user, now + Duration::from_secs(11)));
+        assert!(guard.allow(user, now + Duration::from_secs(20)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_held_text_capped() {
+        let mut guard = FloodGuard::default();
+        let user = UserHandle::from(1);
+        let now = Instant::now();
+
+        let part = "a".repeat(MAX_HELD_LEN / 2);
+        for _ in 0..4 {
+            guard.hold(user, &Message::Normal(part.clone()));
+        }
+
+        let released = guard.release(now + FloodLimits::default().window);
+        assert_eq!(released.len(), 1);
+        match &released[0].1 {
+            Message::Normal(text) => {
+                assert!(text.len() < MAX_HELD_LEN + 100);
+                assert!(text.ends_with("[3 more messages dropped]"));
+            }
+            message => panic!("Unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn test_invalid_limits_rejected() {
+        let mut guard = FloodGuard::default();
+
+        assert!(guard
+            .set_limits(FloodLimits {
+                max_messages: 0,
+                window: Duration::from_secs(1),
+            })
+            .is_err());
+        assert!(guard
+            .set_limits(FloodLimits {
+                max_messages: 1,
+                window: Duration::from_secs(0),
+            })
+            .is_err());
+        assert_eq!(guard.limits, FloodLimits::default());
+    }
+}
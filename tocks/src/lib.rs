@@ -6,30 +6,47 @@ pub mod contact;
 pub mod audio;
 
 mod account;
+mod auto_accept;
+mod auto_reply;
+mod bootstrap;
 mod calls;
 mod error;
 mod event_server;
+mod flood;
+mod log_buffer;
 mod message_parser;
+mod metrics;
+mod read_marker;
+mod removal_hint;
+mod reply_marker;
 mod savemanager;
 mod storage;
+mod typing;
 
 pub use crate::{
-    account::AccountId,
-    calls::CallState,
+    account::{AccountId, Diagnostics},
+    auto_accept::AutoAcceptPolicy,
+    calls::{CallEndReason, CallState},
     contact::{Friend, Status, User},
+    error::TocksError,
     event_server::{EventClient, EventServer},
-    storage::{ChatHandle, ChatLogEntry, ChatMessageId, UserHandle},
+    flood::FloodLimits,
+    log_buffer::{LogEntry, TeeLogger},
+    message_parser::{Span, SpanStyle},
+    metrics::Metrics,
+    storage::{ChatHandle, ChatLogEntry, ChatMessageId, SendState, UserHandle},
 };
 
 use anyhow::{bail, Context, Result};
 use audio::AudioFrame;
+use chrono::{DateTime, Utc};
 
 use crate::{
     account::{Account, AccountManager},
-    error::ExitError,
+    error::{AccountInUseError, ExitError, PasswordRequiredError, UnknownAccountError},
 };
 
-use toxcore::ToxId;
+use toxcore::{SecretKey, ToxId};
 
 use futures::{channel::mpsc, prelude::*};
 use lazy_static::lazy_static;
@@ -37,6 +54,8 @@ use log::*;
 use platform_dirs::AppDirs;
 use serde::{Deserialize, Serialize};
 
+use std::{path::PathBuf, str::FromStr};
+
 lazy_static! {
     pub static ref APP_DIRS: AppDirs = AppDirs::new(Some("tocks"), false).unwrap();
 }
@@ -46,15 +65,72 @@ lazy_static! {
 pub enum TocksUiEvent {
     Close,
     CreateAccount(String /*name*/, String /*password*/),
+    CreateAccountFromKey(
+        String, /*name*/
+        String, /*password*/
+        String, /*secret key hex*/
+    ),
+    ImportToxSave(
+        String, /*name*/
+        String, /*password*/
+        PathBuf,
+        String, /*save password*/
+    ),
     AcceptPendingFriend(AccountId, UserHandle),
     RequestFriend(AccountId, ToxId, String /*message*/),
     BlockUser(AccountId, UserHandle),
+    SetFriendVerified(AccountId, UserHandle, bool /* verified */),
+    SetChatMuted(AccountId, ChatHandle, bool /* muted */),
     PurgeUser(AccountId, UserHandle),
     Login(String /* Tox account name */, String /*password*/),
+    SetSelfName(AccountId, String /* name */),
+    // Online, Away or Busy. The auto-reply only goes out while Away or Busy
+    SetSelfStatus(AccountId, Status),
+    // Disconnect from (false) or reconnect to (true) the tox network without
+    // logging out
+    SetOnline(AccountId, bool),
     MessageSent(AccountId, ChatHandle, String /* message */),
-    LoadMessages(AccountId, ChatHandle),
+    // Like MessageSent, but sent as an action (/me)
+    ActionSent(AccountId, ChatHandle, String),
+    ResendMessage(AccountId, ChatHandle, ChatMessageId),
+    // Like MessageSent, replying to the given message
+    ReplySent(AccountId, ChatHandle, ChatMessageId, String),
+    SetAutoReply(AccountId, Option<String> /* message */),
+    SetAutoAccept(AccountId, AutoAcceptPolicy),
+    SetFloodLimits(AccountId, FloodLimits),
+    SetShortcodeExpansion(AccountId, bool /* enabled */),
+    // Tell the peer we've read everything in the chat up to now
+    SendReadMarker(AccountId, ChatHandle),
+    // The user is composing a message in the chat. Repeat on every keystroke,
+    // the peer is told we stopped once the message is sent or input goes idle
+    UserTyping(AccountId, ChatHandle),
+    // Everything in the chat up to the given time has been seen locally
+    MarkChatRead(AccountId, ChatHandle, DateTime<Utc>),
+    QueryMetrics,
+    // Recent log lines, only captured if the app installed a TeeLogger
+    QueryLogs,
+    QueryFriends(AccountId),
+    QueryDiagnostics(AccountId),
+    // Calls that aren't idle on every account, so a client that connects
+    // mid call can show it
+    QueryCalls,
+    // Newest messages of a chat, up to the given limit
+    LoadMessages(AccountId, ChatHandle, usize /* limit */),
+    // Page of messages older than the given message, for history that was
+    // dropped from memory
+    LoadOlderMessages(AccountId, ChatHandle, ChatMessageId, usize /* limit */),
+    // Messages either side of a point in time, for jumping to a date
+    LoadMessagesAround(
+        AccountId,
+        ChatHandle,
+        DateTime<Utc>,
+        usize, /* before */
+        usize, /* after */
+    ),
     JoinCall(AccountId, ChatHandle),
     LeaveCall(AccountId, ChatHandle),
+    // Puts an active call on hold (true) or resumes it (false)
+    SetCallPaused(AccountId, ChatHandle, bool),
     IncomingAudioFrame(AudioFrame),
 }
 
@@ -62,18 +138,63 @@ pub enum TocksUiEvent {
 #[derive(Serialize, Deserialize, Debug)]
 pub enum TocksEvent {
     Error(String),
+    // A failed UI request, classed so clients can react to it. Failures
+    // with their own event (PasswordRequired, AccountInUse) only get that
+    TypedError(TocksError),
     AccountListLoaded(Vec<String>),
-    AccountLoggedIn(AccountId, UserHandle, ToxId, String),
+    PasswordRequired(String /* Tox account name */),
+    // Another tocks instance has the account open
+    AccountInUse(String /* Tox account name */),
+    // The account stopped and is no longer logged in, with the reason why
+    AccountRemoved(AccountId, String),
+    AccountLoggedIn(
+        AccountId,
+        UserHandle,
+        ToxId,
+        String, /* name */
+        usize,  /* max message length */
+    ),
     FriendAdded(AccountId, Friend),
+    // Every friend of a freshly logged in account in one go. FriendAdded is
+    // still used for friends added afterwards
+    FriendsLoaded(AccountId, Vec<Friend>),
     FriendRemoved(AccountId, UserHandle),
     BlockedUserAdded(AccountId, User),
     MessagesLoaded(AccountId, ChatHandle, Vec<ChatLogEntry>),
+    OlderMessagesLoaded(AccountId, ChatHandle, Vec<ChatLogEntry>),
+    MessagesAroundLoaded(AccountId, ChatHandle, Vec<ChatLogEntry>),
     MessageInserted(AccountId, ChatHandle, ChatLogEntry),
     MessageCompleted(AccountId, ChatHandle, ChatMessageId),
+    // A message that hasn't been delivered yet was handed to toxcore again
+    MessageSendStateChanged(AccountId, ChatHandle, ChatMessageId, SendState),
     FriendStatusChanged(AccountId, UserHandle, Status),
     UserNameChanged(AccountId, UserHandle, String),
+    SelfNameChanged(AccountId, String),
+    FriendVerifiedChanged(AccountId, UserHandle, bool /* verified */),
+    ChatMutedChanged(AccountId, ChatHandle, bool /* muted */),
+    // We haven't been connected to the friend in a long time, they may
+    // have removed us
+    FriendPossiblyRemoved(AccountId, UserHandle, bool),
     ChatCallStateChanged(AccountId, ChatHandle, CallState),
+    // Sent after the call goes back to idle
+    CallEnded(AccountId, ChatHandle, CallEndReason),
     AudioDataReceived(AccountId, ChatHandle, AudioFrame),
+    // Newest of our messages the peer has read, everything we sent before it
+    // has been read too
+    PeerReadUpTo(AccountId, ChatHandle, ChatMessageId),
+    // Bootstrapping has repeatedly failed to get the account onto the tox
+    // network. Retries continue in the background
+    UnableToConnect(AccountId),
+    ChatReadTimeUpdated(AccountId, ChatHandle, DateTime<Utc>),
+    // Messages from the friend newer than our read time. Sent on login for
+    // chats with anything unread, when messages arrive and when the chat is
+    // read
+    UnreadCountChanged(AccountId, ChatHandle, usize),
+    Metrics(Metrics),
+    Logs(Vec<LogEntry>),
+    FriendList(AccountId, Vec<Friend>),
+    Diagnostics(AccountId, Diagnostics),
+    ActiveCalls(Vec<(AccountId, ChatHandle, CallState)>),
 }
 
 pub struct Tocks {
@@ -116,6 +237,7 @@ impl Tocks {
                     return;
                 }
                 error!("{:?}", e);
+                Self::send_tocks_event(&self.tocks_event_tx, TocksEvent::TypedError((&e).into()));
             }
         }
     }
@@ -163,14 +285,65 @@ impl Tocks {
                         *account.user_handle(),
                         account.address().clone(),
                         account.name().to_string(),
+                        account.max_message_length(),
+                    ),
+                );
+            }
+            TocksUiEvent::CreateAccountFromKey(name, password, secret_key) => {
+                let secret_key = SecretKey::from_str(&secret_key).context("Invalid secret key")?;
+
+                let (account_event_tx, account_event_rx) = mpsc::unbounded();
+                let account =
+                    Account::from_secret_key(name, password, secret_key, account_event_tx)
+                        .context("Failed to create account from secret key")?;
+
+                let account_id = self.account_manager.add_account(account, account_event_rx);
+                let account = self.account_manager.get(&account_id).unwrap();
+
+                Self::send_tocks_event(
+                    &self.tocks_event_tx,
+                    TocksEvent::AccountLoggedIn(
+                        account_id,
+                        *account.user_handle(),
+                        account.address().clone(),
+                        account.name().to_string(),
+                        account.max_message_length(),
                     ),
                 );
             }
+            TocksUiEvent::ImportToxSave(name, password, path, save_password) => {
+                let (account_event_tx, account_event_rx) = mpsc::unbounded();
+                let account = Account::from_tox_save_file(
+                    name,
+                    password,
+                    &path,
+                    &save_password,
+                    account_event_tx,
+                )
+                .with_context(|| format!("Failed to import tox save {}", path.to_string_lossy()))?;
+
+                let account_id = self.account_manager.add_account(account, account_event_rx);
+                let account = self.account_manager.get(&account_id).unwrap();
+
+                Self::send_tocks_event(
+                    &self.tocks_event_tx,
+                    TocksEvent::AccountLoggedIn(
+                        account_id,
+                        *account.user_handle(),
+                        account.address().clone(),
+                        account.name().to_string(),
+                        account.max_message_length(),
+                    ),
+                );
+
+                Self::send_friends_loaded(&self.tocks_event_tx, account_id, account.friends());
+                Self::send_unread_counts(&self.tocks_event_tx, account_id, account)?;
+            }
             TocksUiEvent::AcceptPendingFriend(account_id, user_handle) => {
                 let account = self
                     .account_manager
                     .get_mut(&account_id)
-                    .with_context(|| format!("Failed to find account {}", account_id))?;
+                    .ok_or(UnknownAccountError(account_id))?;
 
                 let friend = account
                     .add_pending_friend(&user_handle)
@@ -181,26 +354,88 @@ impl Tocks {
                     TocksEvent::FriendStatusChanged(account_id, *friend.id(), *friend.status()),
                 );
             }
+            TocksUiEvent::SetSelfName(account_id, name) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                account.set_name(&name).context("Failed to set name")?;
+
+                Self::send_tocks_event(
+                    &self.tocks_event_tx,
+                    TocksEvent::SelfNameChanged(account_id, account.name().to_string()),
+                );
+            }
+            TocksUiEvent::SetSelfStatus(account_id, status) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                account
+                    .set_self_status(status)
+                    .context("Failed to set status")?;
+            }
+            TocksUiEvent::SetFriendVerified(account_id, user_handle, verified) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                account
+                    .set_friend_verified(&user_handle, verified)
+                    .context("Failed to set friend verification")?;
+
+                Self::send_tocks_event(
+                    &self.tocks_event_tx,
+                    TocksEvent::FriendVerifiedChanged(account_id, user_handle, verified),
+                );
+            }
+            TocksUiEvent::SetChatMuted(account_id, chat_handle, muted) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                account
+                    .set_chat_muted(&chat_handle, muted)
+                    .context("Failed to set chat mute state")?;
+
+                Self::send_tocks_event(
+                    &self.tocks_event_tx,
+                    TocksEvent::ChatMutedChanged(account_id, chat_handle, muted),
+                );
+            }
             TocksUiEvent::RequestFriend(account_id, tox_id, message) => {
                 let account = self
                     .account_manager
                     .get_mut(&account_id)
-                    .with_context(|| format!("Failed to find account {}", account_id))?;
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                // Requesting someone who already asked us accepts them, the
+                // UI already has them listed as pending
+                let public_key = tox_id.public_key();
+                let already_listed = account
+                    .friends()
+                    .any(|friend| *friend.public_key() == public_key);
 
                 let friend = account
                     .request_friend(tox_id, message)
                     .context("Failed to add friend")?;
 
-                Self::send_tocks_event(
-                    &self.tocks_event_tx,
-                    TocksEvent::FriendAdded(account_id, friend),
-                );
+                let event = if already_listed {
+                    TocksEvent::FriendStatusChanged(account_id, *friend.id(), *friend.status())
+                } else {
+                    TocksEvent::FriendAdded(account_id, friend)
+                };
+                Self::send_tocks_event(&self.tocks_event_tx, event);
             }
             TocksUiEvent::BlockUser(account_id, user_handle) => {
                 let account = self
                     .account_manager
                     .get_mut(&account_id)
-                    .with_context(|| format!("Failed to find account {}", account_id))?;
+                    .ok_or(UnknownAccountError(account_id))?;
 
                 let blocked_user = account
                     .block_user(&user_handle)
@@ -220,7 +455,7 @@ impl Tocks {
                 let account = self
                     .account_manager
                     .get_mut(&account_id)
-                    .with_context(|| format!("Failed to find account {}", account_id))?;
+                    .ok_or(UnknownAccountError(account_id))?;
 
                 account
                     .purge_user(&user_handle)
@@ -233,9 +468,33 @@ impl Tocks {
             }
             TocksUiEvent::Login(account_name, password) => {
                 let (account_event_tx, account_event_rx) = mpsc::unbounded();
-                let account =
-                    Account::from_account_name(account_name.clone(), password, account_event_tx)
-                        .with_context(|| format!("Failed to create account {}", account_name))?;
+                let account = match Account::from_account_name(
+                    account_name.clone(),
+                    password,
+                    account_event_tx,
+                ) {
+                    Ok(account) => account,
+                    Err(e) if e.downcast_ref::<PasswordRequiredError>().is_some() => {
+                        warn!("{:?}", e);
+                        Self::send_tocks_event(
+                            &self.tocks_event_tx,
+                            TocksEvent::PasswordRequired(account_name),
+                        );
+                        return Ok(());
+                    }
+                    Err(e) if e.downcast_ref::<AccountInUseError>().is_some() => {
+                        warn!("{:?}", e);
+                        Self::send_tocks_event(
+                            &self.tocks_event_tx,
+                            TocksEvent::AccountInUse(account_name),
+                        );
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        return Err(e)
+                            .with_context(|| format!("Failed to create account {}", account_name))
+                    }
+                };
 
                 let account_id = self.account_manager.add_account(account, account_event_rx);
                 let account = self.account_manager.get(&account_id).unwrap();
@@ -251,15 +510,12 @@ impl Tocks {
                         *user_handle,
                         address.clone(),
                         name.to_string(),
+                        account.max_message_length(),
                     ),
                 );
 
-                for friend in account.friends() {
-                    Self::send_tocks_event(
-                        &self.tocks_event_tx,
-                        TocksEvent::FriendAdded(account_id, friend.clone()),
-                    );
-                }
+                Self::send_friends_loaded(&self.tocks_event_tx, account_id, account.friends());
+                Self::send_unread_counts(&self.tocks_event_tx, account_id, account)?;
 
                 for user in account.blocked_users()? {
                     Self::send_tocks_event(
@@ -268,11 +524,23 @@ impl Tocks {
                     );
                 }
             }
+            TocksUiEvent::SetOnline(account_id, online) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                if online {
+                    account.go_online().context("Failed to go online")?;
+                } else {
+                    account.go_offline().context("Failed to go offline")?;
+                }
+            }
             TocksUiEvent::MessageSent(account_id, chat_handle, message) => {
                 let account = self
                     .account_manager
                     .get_mut(&account_id)
-                    .with_context(|| format!("Failed to find account {}", account_id))?;
+                    .ok_or(UnknownAccountError(account_id))?;
 
                 let entries = account
                     .send_message(&chat_handle, message)
@@ -291,23 +559,247 @@ impl Tocks {
                     );
                 }
             }
-            TocksUiEvent::LoadMessages(account_id, chat_handle) => {
+            TocksUiEvent::ActionSent(account_id, chat_handle, message) => {
                 let account = self
                     .account_manager
                     .get_mut(&account_id)
-                    .with_context(|| format!("Failed to find account {}", account_id))?;
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                let entries = account
+                    .send_action(&chat_handle, message)
+                    .with_context(|| {
+                        format!(
+                            "Failed to send action to {} on account {}",
+                            chat_handle.id(),
+                            account_id.id()
+                        )
+                    })?;
+
+                for entry in entries {
+                    Self::send_tocks_event(
+                        &self.tocks_event_tx,
+                        TocksEvent::MessageInserted(account_id, chat_handle, entry),
+                    );
+                }
+            }
+            TocksUiEvent::ReplySent(account_id, chat_handle, reply_to, message) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                let entries = account
+                    .send_reply(&chat_handle, &reply_to, message)
+                    .with_context(|| {
+                        format!(
+                            "Failed to send reply to {} on account {}",
+                            chat_handle.id(),
+                            account_id.id()
+                        )
+                    })?;
+
+                for entry in entries {
+                    Self::send_tocks_event(
+                        &self.tocks_event_tx,
+                        TocksEvent::MessageInserted(account_id, chat_handle, entry),
+                    );
+                }
+            }
+            TocksUiEvent::ResendMessage(account_id, chat_handle, message_id) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                account
+                    .resend_message(&chat_handle, &message_id)
+                    .with_context(|| {
+                        format!(
+                            "Failed to resend message {} to {} on account {}",
+                            message_id,
+                            chat_handle.id(),
+                            account_id.id()
+                        )
+                    })?;
+            }
+            TocksUiEvent::SetAutoReply(account_id, message) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                account
+                    .set_auto_reply(message)
+                    .context("Failed to set auto reply")?;
+            }
+            TocksUiEvent::SetAutoAccept(account_id, policy) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                // A typo in the allowlist would otherwise silently leave
+                // requests pending
+                if let Err(e) = account.set_auto_accept(policy) {
+                    Self::send_tocks_event(
+                        &self.tocks_event_tx,
+                        TocksEvent::Error(format!("{:#}", e)),
+                    );
+                }
+            }
+            TocksUiEvent::SetFloodLimits(account_id, limits) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                if let Err(e) = account.set_flood_limits(limits) {
+                    Self::send_tocks_event(
+                        &self.tocks_event_tx,
+                        TocksEvent::Error(format!("{:#}", e)),
+                    );
+                }
+            }
+            TocksUiEvent::SetShortcodeExpansion(account_id, enabled) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                account.set_shortcode_expansion(enabled);
+            }
+            TocksUiEvent::SendReadMarker(account_id, chat_handle) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                account
+                    .send_read_marker(&chat_handle, Utc::now())
+                    .context("Failed to send read marker")?;
+            }
+            TocksUiEvent::UserTyping(account_id, chat_handle) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                account
+                    .set_typing(&chat_handle)
+                    .context("Failed to set typing state")?;
+            }
+            TocksUiEvent::MarkChatRead(account_id, chat_handle, time) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                let read_time = account
+                    .mark_chat_read(&chat_handle, time)
+                    .context("Failed to mark chat read")?;
+
+                Self::send_tocks_event(
+                    &self.tocks_event_tx,
+                    TocksEvent::ChatReadTimeUpdated(account_id, chat_handle, read_time),
+                );
+
+                let unread_count = account
+                    .unread_count(&chat_handle)
+                    .context("Failed to count unread messages")?;
+                Self::send_tocks_event(
+                    &self.tocks_event_tx,
+                    TocksEvent::UnreadCountChanged(account_id, chat_handle, unread_count),
+                );
+            }
+            TocksUiEvent::QueryFriends(account_id) => {
+                let account = self
+                    .account_manager
+                    .get(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                Self::send_tocks_event(
+                    &self.tocks_event_tx,
+                    TocksEvent::FriendList(account_id, account.friends().cloned().collect()),
+                );
+            }
+            TocksUiEvent::QueryDiagnostics(account_id) => {
+                let account = self
+                    .account_manager
+                    .get(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
 
-                let messages = account.load_messages(&chat_handle)?;
+                Self::send_tocks_event(
+                    &self.tocks_event_tx,
+                    TocksEvent::Diagnostics(account_id, account.diagnostics()),
+                );
+            }
+            TocksUiEvent::QueryCalls => {
+                let calls = self
+                    .account_manager
+                    .accounts()
+                    .flat_map(|(account_id, account)| {
+                        account
+                            .call_states()
+                            .into_iter()
+                            .map(move |(chat, state)| (*account_id, chat, state))
+                    })
+                    .collect();
+
+                Self::send_tocks_event(&self.tocks_event_tx, TocksEvent::ActiveCalls(calls));
+            }
+            TocksUiEvent::QueryMetrics => {
+                Self::send_tocks_event(
+                    &self.tocks_event_tx,
+                    TocksEvent::Metrics(metrics::COUNTERS.snapshot()),
+                );
+            }
+            TocksUiEvent::QueryLogs => {
+                Self::send_tocks_event(
+                    &self.tocks_event_tx,
+                    TocksEvent::Logs(log_buffer::LOG_BUFFER.entries()),
+                );
+            }
+            TocksUiEvent::LoadMessages(account_id, chat_handle, limit) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                let messages = account.load_messages(&chat_handle, limit)?;
                 Self::send_tocks_event(
                     &self.tocks_event_tx,
                     TocksEvent::MessagesLoaded(account_id, chat_handle, messages),
                 );
             }
+            TocksUiEvent::LoadOlderMessages(account_id, chat_handle, before, limit) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                let messages = account.load_messages_before(&chat_handle, &before, limit)?;
+                Self::send_tocks_event(
+                    &self.tocks_event_tx,
+                    TocksEvent::OlderMessagesLoaded(account_id, chat_handle, messages),
+                );
+            }
+            TocksUiEvent::LoadMessagesAround(account_id, chat_handle, time, before, after) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                let messages = account.load_messages_around(&chat_handle, &time, before, after)?;
+                Self::send_tocks_event(
+                    &self.tocks_event_tx,
+                    TocksEvent::MessagesAroundLoaded(account_id, chat_handle, messages),
+                );
+            }
             TocksUiEvent::JoinCall(account_id, chat_handle) => {
                 let account = self
                     .account_manager
                     .get_mut(&account_id)
-                    .with_context(|| format!("Failed to find account {}", account_id))?;
+                    .ok_or(UnknownAccountError(account_id))?;
 
                 let new_state = account
                     .join_call(&chat_handle)
@@ -322,14 +814,36 @@ impl Tocks {
                 let account = self
                     .account_manager
                     .get_mut(&account_id)
-                    .with_context(|| format!("Failed to find account {}", account_id))?;
+                    .ok_or(UnknownAccountError(account_id))?;
 
-                account.leave_call(&chat_handle);
+                let reason = account.leave_call(&chat_handle);
 
                 Self::send_tocks_event(
                     &self.tocks_event_tx,
                     TocksEvent::ChatCallStateChanged(account_id, chat_handle, CallState::Idle),
                 );
+
+                if let Some(reason) = reason {
+                    Self::send_tocks_event(
+                        &self.tocks_event_tx,
+                        TocksEvent::CallEnded(account_id, chat_handle, reason),
+                    );
+                }
+            }
+            TocksUiEvent::SetCallPaused(account_id, chat_handle, paused) => {
+                let account = self
+                    .account_manager
+                    .get_mut(&account_id)
+                    .ok_or(UnknownAccountError(account_id))?;
+
+                let new_state = account
+                    .set_call_paused(&chat_handle, paused)
+                    .context("Failed to pause/resume call")?;
+
+                Self::send_tocks_event(
+                    &self.tocks_event_tx,
+                    TocksEvent::ChatCallStateChanged(account_id, chat_handle, new_state),
+                );
             }
             TocksUiEvent::IncomingAudioFrame(frame) => {
                 let mut accounts = self.account_manager.accounts_mut();
@@ -352,4 +866,101 @@ impl Tocks {
         // external library wants to service my events
         let _ = tocks_event_tx.unbounded_send(event);
     }
+
+    /// Large friend lists would otherwise flood the UI and every event server
+    /// client with one event per friend
+    fn send_friends_loaded<'a>(
+        tocks_event_tx: &mpsc::UnboundedSender<TocksEvent>,
+        account_id: AccountId,
+        friends: impl Iterator<Item = &'a Friend>,
+    ) {
+        Self::send_tocks_event(
+            tocks_event_tx,
+            TocksEvent::FriendsLoaded(account_id, friends.cloned().collect()),
+        );
+    }
+
+    /// Chats with nothing unread are left out, the UI starts every chat at 0
+    fn send_unread_counts(
+        tocks_event_tx: &mpsc::UnboundedSender<TocksEvent>,
+        account_id: AccountId,
+        account: &Account,
+    ) -> Result<()> {
+        for friend in account.friends() {
+            let unread_count = account
+                .unread_count(friend.chat_handle())
+                .context("Failed to count unread messages")?;
+
+            if unread_count > 0 {
+                Self::send_tocks_event(
+                    tocks_event_tx,
+                    TocksEvent::UnreadCountChanged(account_id, *friend.chat_handle(), unread_count),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use toxcore::PublicKey;
+
+    #[test]
+    fn test_friends_loaded_batched() {
+        let (tocks_event_tx, tocks_event_rx) = mpsc::unbounded();
+
+        let friends: Vec<Friend> = (0..1000)
+            .map(|i| {
+                Friend::new(
+                    i.into(),
+                    (i + 1000).into(),
+                    PublicKey::from_bytes(vec![0; PublicKey::SIZE]).unwrap(),
+                    format!("friend{}", i),
+                    Status::Offline,
+                )
+            })
+            .collect();
+
+        Tocks::send_friends_loaded(&tocks_event_tx, 3.into(), friends.iter());
+        drop(tocks_event_tx);
+
+        let events: Vec<TocksEvent> = futures::executor::block_on_stream(tocks_event_rx).collect();
+        assert_eq!(events.len(), 1);
+        match &events[0] {
+            TocksEvent::FriendsLoaded(account, loaded) => {
+                assert_eq!(*account, 3.into());
+                assert_eq!(loaded.len(), friends.len());
+            }
+            _ => panic!("Unexpected event"),
+        }
+    }
+
+    #[test]
+    fn test_create_account_from_invalid_key() {
+        let (_ui_event_tx, ui_event_rx) = mpsc::unbounded();
+        let (tocks_event_tx, tocks_event_rx) = mpsc::unbounded();
+
+        // Built by hand, Tocks::new would go looking at the real account dir
+        let mut tocks = Tocks {
+            account_manager: AccountManager::new(),
+            ui_event_rx,
+            tocks_event_tx,
+        };
+
+        let result = tocks.handle_ui_request(TocksUiEvent::CreateAccountFromKey(
+            "imported".to_string(),
+            String::new(),
+            "not a key".to_string(),
+        ));
+        assert!(result.is_err());
+
+        // Nothing was logged in, run() reports the error
+        drop(tocks);
+        let events: Vec<TocksEvent> = futures::executor::block_on_stream(tocks_event_rx).collect();
+        assert!(events.is_empty());
+    }
 }
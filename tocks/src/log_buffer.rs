@@ -0,0 +1,148 @@
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::{Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+/// Log lines kept for clients to query. Older lines are dropped
+const LOG_BUFFER_SIZE: usize = 1000;
+
+lazy_static! {
+    pub(crate) static ref LOG_BUFFER: Arc<LogBuffer> = Arc::new(LogBuffer::new(LOG_BUFFER_SIZE));
+}
+
+/// A single captured log line
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub timestamp: DateTime<Utc>,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+/// Ring buffer of the most recent log lines
+pub(crate) struct LogBuffer {
+    entries: Mutex<VecDeque<LogEntry>>,
+    capacity: usize,
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> LogBuffer {
+        LogBuffer {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn push(&self, record: &Record) {
+        let entry = LogEntry {
+            timestamp: Utc::now(),
+            level: record.level().to_string(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Oldest first
+    pub fn entries(&self) -> Vec<LogEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Logger that keeps whatever inner lets through in memory as well, so users
+/// without a console can still see what went wrong. Install it with
+/// `log::set_boxed_logger` in place of inner
+pub struct TeeLogger {
+    inner: Box<dyn Log>,
+    buffer: Arc<LogBuffer>,
+}
+
+impl TeeLogger {
+    pub fn new(inner: Box<dyn Log>) -> TeeLogger {
+        TeeLogger {
+            inner,
+            buffer: Arc::clone(&LOG_BUFFER),
+        }
+    }
+}
+
+impl Log for TeeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner.enabled(record.metadata()) {
+            self.buffer.push(record);
+        }
+
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use log::Level;
+
+    struct InfoLogger;
+
+    impl Log for InfoLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Info
+        }
+
+        fn log(&self, _record: &Record) {}
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_logs_captured() {
+        let buffer = Arc::new(LogBuffer::new(2));
+        let logger = TeeLogger {
+            inner: Box::new(InfoLogger),
+            buffer: Arc::clone(&buffer),
+        };
+
+        for i in 0..3 {
+            logger.log(
+                &Record::builder()
+                    .args(format_args!("Failed attempt {}", i))
+                    .level(Level::Error)
+                    .target("tocks::account")
+                    .build(),
+            );
+        }
+
+        // Filtered out by the inner logger, so not captured either
+        logger.log(
+            &Record::builder()
+                .args(format_args!("Noisy"))
+                .level(Level::Debug)
+                .build(),
+        );
+
+        let entries = buffer.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].message, "Failed attempt 1");
+        assert_eq!(entries[1].message, "Failed attempt 2");
+        assert_eq!(entries[1].level, "ERROR");
+        assert_eq!(entries[1].target, "tocks::account");
+    }
+}
@@ -7,22 +7,242 @@ use thiserror::Error;
 pub enum ParseError {
     #[error("Message empty")]
     EmptyMessage,
+    #[error("Character does not fit in a single message")]
+    CharacterTooLong,
 }
 
-fn find_split_point(utf8_string: &[u8], desired_split_point: usize) -> usize {
+/// Largest byte index <= desired_split_point that doesn't land in the middle
+/// of a character
+fn find_split_point(s: &str, desired_split_point: usize) -> usize {
     let mut ret = desired_split_point;
 
-    if ret >= utf8_string.len() {
-        return utf8_string.len();
+    if ret >= s.len() {
+        return s.len();
     }
 
-    while utf8_string[ret] & 0b1100_0000 == 0b1000_0000 {
+    while !s.is_char_boundary(ret) {
         ret -= 1;
     }
 
     ret
 }
 
+pub(crate) const ACTION_PREFIX: &str = "/me ";
+
+/// Text of a message without any markup. Actions are just the action text,
+/// it's up to the UI to show who did it. Normal messages are shown as sent,
+/// even if they happen to start with "/me". Control messages never show up
+/// here since they're sent as custom packets
+pub fn display_text(message: &Message) -> &str {
+    match message {
+        Message::Action(s) | Message::Normal(s) => s,
+    }
+}
+
+// Sorted by shortcode for binary search
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("angry", "😠"),
+    ("blush", "😊"),
+    ("broken_heart", "💔"),
+    ("clap", "👏"),
+    ("confused", "😕"),
+    ("cry", "😢"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("frowning", "😦"),
+    ("grin", "😁"),
+    ("heart", "❤️"),
+    ("joy", "😂"),
+    ("laughing", "😆"),
+    ("neutral_face", "😐"),
+    ("ok_hand", "👌"),
+    ("open_mouth", "😮"),
+    ("pray", "🙏"),
+    ("rofl", "🤣"),
+    ("scream", "😱"),
+    ("slightly_smiling_face", "🙂"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("sob", "😭"),
+    ("sunglasses", "😎"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("wave", "👋"),
+    ("wink", "😉"),
+];
+
+fn is_shortcode_char(c: char) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '+' || c == '-'
+}
+
+/// Replaces :shortcode: sequences with their emoji. Unknown shortcodes are
+/// left as typed, a backslash before the colon (\:smile:) keeps the shortcode
+/// literal and nothing inside `code` spans is touched
+pub fn expand_shortcodes(message: &str) -> String {
+    let mut ret = String::with_capacity(message.len());
+    let mut in_code = false;
+    let mut rest = message;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '`' {
+            in_code = !in_code;
+        } else if !in_code && c == '\\' && rest[1..].starts_with(':') {
+            ret.push(':');
+            rest = &rest[2..];
+            continue;
+        } else if !in_code && c == ':' {
+            let name_len = rest[1..]
+                .find(|c: char| !is_shortcode_char(c))
+                .unwrap_or(rest.len() - 1);
+            let name = &rest[1..1 + name_len];
+
+            if rest[1 + name_len..].starts_with(':') {
+                if let Ok(idx) = SHORTCODES.binary_search_by_key(&name, |&(code, _)| code) {
+                    ret.push_str(SHORTCODES[idx].1);
+                    rest = &rest[name_len + 2..];
+                    continue;
+                }
+            }
+        }
+
+        ret.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    ret
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanStyle {
+    Plain,
+    Bold,
+    Italic,
+    Code,
+    CodeBlock,
+}
+
+/// Piece of a message rendered with a single style. Markup characters are
+/// not included in text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span<'a> {
+    pub text: &'a str,
+    pub style: SpanStyle,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric()
+}
+
+/// Finds the closing emphasis marker for an opening marker at the start of
+/// text. The closing marker has to hug the text it closes and can't be in the
+/// middle of a word, so snake_case and 2*3*4 are left alone
+fn find_emphasis_end(text: &str, marker: char) -> Option<usize> {
+    let mut prev = marker;
+    for (idx, c) in text.char_indices().skip(1) {
+        if c == marker && !prev.is_whitespace() && idx > marker.len_utf8() {
+            let next = text[idx + c.len_utf8()..].chars().next();
+            if !next.map_or(false, is_word_char) {
+                return Some(idx);
+            }
+        }
+        prev = c;
+    }
+
+    None
+}
+
+/// Splits text into styled spans for *bold*, _italic_, `code` and ```code
+/// blocks```. Markup that isn't closed is shown literally. Styles don't nest,
+/// and links are never styled since URLs routinely contain these characters
+pub fn parse_markdown(text: &str) -> Vec<Span> {
+    let mut ret = Vec::new();
+    let mut plain_start = 0;
+    let mut idx = 0;
+    let mut prev: Option<char> = None;
+
+    while idx < text.len() {
+        let rest = &text[idx..];
+        let word_start = prev.map_or(true, char::is_whitespace);
+
+        let styled = if word_start && (rest.starts_with("http://") || rest.starts_with("https://"))
+        {
+            // Skip the whole link as plain text
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            idx += end;
+            prev = rest[..end].chars().last();
+            continue;
+        } else if rest.starts_with("```") {
+            rest[3..].find("```").map(|end| {
+                let content = &rest[3..3 + end];
+                let content = content.strip_prefix('\n').unwrap_or(content);
+                (content, SpanStyle::CodeBlock, end + 6)
+            })
+        } else if rest.starts_with('`') {
+            match rest[1..].find('`') {
+                Some(end) if end > 0 => Some((&rest[1..1 + end], SpanStyle::Code, end + 2)),
+                _ => None,
+            }
+        } else if rest.starts_with('*') || rest.starts_with('_') {
+            let marker = rest.chars().next().unwrap();
+            let opens = !prev.map_or(false, |c| is_word_char(c) || c == marker)
+                && rest[1..]
+                    .chars()
+                    .next()
+                    .map_or(false, |c| !c.is_whitespace());
+
+            let style = if marker == '*' {
+                SpanStyle::Bold
+            } else {
+                SpanStyle::Italic
+            };
+
+            if opens {
+                find_emphasis_end(rest, marker).map(|end| (&rest[1..end], style, end + 1))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        match styled {
+            Some((content, style, len)) => {
+                if plain_start < idx {
+                    ret.push(Span {
+                        text: &text[plain_start..idx],
+                        style: SpanStyle::Plain,
+                    });
+                }
+                ret.push(Span {
+                    text: content,
+                    style,
+                });
+                idx += len;
+                plain_start = idx;
+                prev = text[..idx].chars().last();
+            }
+            None => {
+                let c = rest.chars().next().unwrap();
+                idx += c.len_utf8();
+                prev = Some(c);
+            }
+        }
+    }
+
+    if plain_start < text.len() {
+        ret.push(Span {
+            text: &text[plain_start..],
+            style: SpanStyle::Plain,
+        });
+    }
+
+    ret
+}
+
 pub fn parse(message: String, max_message_length: usize) -> Result<Vec<Message>> {
     if message.is_empty() {
         bail!(ParseError::EmptyMessage);
@@ -32,18 +252,23 @@ pub fn parse(message: String, max_message_length: usize) -> Result<Vec<Message>>
         return Ok(vec![Message::Normal(message)]);
     }
 
-    let message_bytes = message.into_bytes();
-
-    let mut cursor = 0;
+    let mut remaining = message.as_str();
 
     let mut ret = Vec::new();
-    while cursor < message_bytes.len() {
-        let start = cursor;
-        cursor = find_split_point(&message_bytes, cursor + max_message_length);
+    while !remaining.is_empty() {
+        let split_point = find_split_point(remaining, max_message_length);
 
-        let s = unsafe { std::str::from_utf8_unchecked(&message_bytes[start..cursor]).to_string() };
+        // toxcore measures the limit in bytes, every part has to fit exactly
+        // and stay valid UTF-8 on its own or peers get garbage
+        if split_point == 0 {
+            bail!(ParseError::CharacterTooLong);
+        }
 
-        ret.push(Message::Normal(s));
+        let (part, rest) = remaining.split_at(split_point);
+        debug_assert!(part.len() <= max_message_length);
+
+        ret.push(Message::Normal(part.to_string()));
+        remaining = rest;
     }
 
     Ok(ret)
@@ -108,4 +333,169 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn emoji_straddling_max_length() -> Result<()> {
+        // 😄 is a 4 byte utf8 character, the first one sits on bytes 3..7
+        let message = "123😄😄".to_string();
+        let res = parse(message.clone(), 5)?;
+        assert_eq!(
+            res,
+            vec![
+                Message::Normal("123".into()),
+                Message::Normal("😄".into()),
+                Message::Normal("😄".into())
+            ]
+        );
+
+        // Each part fits exactly and glues back into the original
+        let rejoined: String = res
+            .iter()
+            .map(|part| match part {
+                Message::Normal(s) => {
+                    assert!(s.len() <= 5);
+                    s.as_str()
+                }
+                Message::Action(_) => panic!("Unexpected action"),
+            })
+            .collect();
+        assert_eq!(rejoined, message);
+
+        // A character bigger than the limit can never be sent
+        let err = parse("😄".into(), 3).unwrap_err();
+        assert_eq!(
+            err.downcast::<ParseError>().unwrap(),
+            ParseError::CharacterTooLong
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn shortcode_table_sorted() {
+        assert!(SHORTCODES.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(SHORTCODES
+            .iter()
+            .all(|(code, _)| code.chars().all(is_shortcode_char)));
+    }
+
+    #[test]
+    fn known_shortcodes_expand() {
+        assert_eq!(expand_shortcodes(":smile:"), "😄");
+        assert_eq!(expand_shortcodes("nice :+1: :tada:!"), "nice 👍 🎉!");
+        assert_eq!(expand_shortcodes(":wave::wave:"), "👋👋");
+        assert_eq!(expand_shortcodes("time 12:30 :smile:"), "time 12:30 😄");
+    }
+
+    #[test]
+    fn unknown_shortcodes_kept() {
+        assert_eq!(expand_shortcodes(":notacode:"), ":notacode:");
+        assert_eq!(expand_shortcodes("a:b:smile:"), "a:b😄");
+        assert_eq!(expand_shortcodes(":smile"), ":smile");
+        assert_eq!(expand_shortcodes("::"), "::");
+        assert_eq!(expand_shortcodes(":"), ":");
+        assert_eq!(expand_shortcodes(":Smile:"), ":Smile:");
+        assert_eq!(expand_shortcodes("é:smile:é"), "é😄é");
+    }
+
+    #[test]
+    fn escaped_and_code_shortcodes_kept() {
+        assert_eq!(expand_shortcodes("\\:smile:"), ":smile:");
+        assert_eq!(expand_shortcodes("\\"), "\\");
+        assert_eq!(expand_shortcodes("`:smile:` :smile:"), "`:smile:` 😄");
+        assert_eq!(
+            expand_shortcodes("`unterminated :smile:"),
+            "`unterminated :smile:"
+        );
+    }
+
+    fn span(text: &str, style: SpanStyle) -> Span {
+        Span { text, style }
+    }
+
+    #[test]
+    fn markdown_styles() {
+        use SpanStyle::*;
+
+        assert_eq!(parse_markdown("plain"), vec![span("plain", Plain)]);
+        assert_eq!(parse_markdown(""), vec![]);
+        assert_eq!(
+            parse_markdown("a *bold* and _italic_ word"),
+            vec![
+                span("a ", Plain),
+                span("bold", Bold),
+                span(" and ", Plain),
+                span("italic", Italic),
+                span(" word", Plain),
+            ]
+        );
+        assert_eq!(
+            parse_markdown("run `cargo *test*` now"),
+            vec![
+                span("run ", Plain),
+                span("cargo *test*", Code),
+                span(" now", Plain),
+            ]
+        );
+        assert_eq!(
+            parse_markdown("```\nfn main() {}\n```"),
+            vec![span("fn main() {}\n", CodeBlock)]
+        );
+        assert_eq!(
+            parse_markdown("(*yes*), _é_!"),
+            vec![
+                span("(", Plain),
+                span("yes", Bold),
+                span("), ", Plain),
+                span("é", Italic),
+                span("!", Plain),
+            ]
+        );
+    }
+
+    #[test]
+    fn markdown_unbalanced_is_literal() {
+        use SpanStyle::*;
+
+        for text in &[
+            "*not closed",
+            "_not closed",
+            "`not closed",
+            "```not closed",
+            "``",
+            "* spaced *",
+            "snake_case_name",
+            "2*3*4",
+            "**",
+            "*",
+        ] {
+            assert_eq!(parse_markdown(text), vec![span(text, Plain)], "{}", text);
+        }
+    }
+
+    #[test]
+    fn markdown_skips_links() {
+        use SpanStyle::*;
+
+        assert_eq!(
+            parse_markdown("see https://example.com/_a_/*b* *now*"),
+            vec![
+                span("see https://example.com/_a_/*b* ", Plain),
+                span("now", Bold),
+            ]
+        );
+    }
+
+    #[test]
+    fn display_text_keeps_normal_text_verbatim() {
+        assert_eq!(display_text(&Message::Normal("hello".into())), "hello");
+        assert_eq!(display_text(&Message::Action("waves".into())), "waves");
+
+        // Only our own input is parsed for /me, what the peer sent as normal
+        // text is shown as is
+        assert_eq!(
+            display_text(&Message::Normal("/me waves".into())),
+            "/me waves"
+        );
+    }
 }
@@ -0,0 +1,64 @@
+use serde::{Deserialize, Serialize};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Process wide diagnostic counters. Everything here is bumped from hot paths
+/// (audio callbacks, message sends) so updates are single relaxed atomic adds
+pub(crate) struct Counters {
+    capture_frames_dropped: AtomicU64,
+    playback_underruns: AtomicU64,
+    message_send_retries: AtomicU64,
+    receipts_abandoned: AtomicU64,
+}
+
+pub(crate) static COUNTERS: Counters = Counters::new();
+
+impl Counters {
+    const fn new() -> Counters {
+        Counters {
+            capture_frames_dropped: AtomicU64::new(0),
+            playback_underruns: AtomicU64::new(0),
+            message_send_retries: AtomicU64::new(0),
+            receipts_abandoned: AtomicU64::new(0),
+        }
+    }
+
+    pub fn capture_frame_dropped(&self) {
+        self.capture_frames_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn playback_underrun(&self) {
+        self.playback_underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn message_send_retried(&self) {
+        self.message_send_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn receipt_abandoned(&self) {
+        self.receipts_abandoned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Metrics {
+        Metrics {
+            capture_frames_dropped: self.capture_frames_dropped.load(Ordering::Relaxed),
+            playback_underruns: self.playback_underruns.load(Ordering::Relaxed),
+            message_send_retries: self.message_send_retries.load(Ordering::Relaxed),
+            receipts_abandoned: self.receipts_abandoned.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point in time copy of the diagnostic counters
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metrics {
+    /// Capture frames dropped because an observer fell behind
+    pub capture_frames_dropped: u64,
+    /// Times a playback source ran dry while a stream was still active
+    pub playback_underruns: u64,
+    /// Messages re-sent, either by the user or when a friend came back online
+    pub message_send_retries: u64,
+    /// Receipts we stopped waiting on because the message was sent again or
+    /// the receipt timed out
+    pub receipts_abandoned: u64,
+}
@@ -0,0 +1,117 @@
+use crate::storage::{ChatHandle, ChatMessageId};
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use std::{collections::HashMap, convert::TryInto};
+
+/// Lossless custom packet id used for control messages between tocks clients.
+/// Toxcore reserves 160-191 for lossless custom packets
+pub(crate) const CONTROL_PACKET_ID: u8 = 172;
+
+const READ_MARKER_TYPE: u8 = 1;
+const READ_MARKER_LEN: usize = 2 + std::mem::size_of::<i64>();
+
+/// "I have read everything up to here" marker sent to a peer. Clients that do
+/// not understand the packet just drop it
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ReadMarker {
+    pub timestamp: DateTime<Utc>,
+}
+
+impl ReadMarker {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(READ_MARKER_LEN);
+        ret.push(CONTROL_PACKET_ID);
+        ret.push(READ_MARKER_TYPE);
+        ret.extend_from_slice(&self.timestamp.timestamp_millis().to_be_bytes());
+        ret
+    }
+
+    /// Returns None for anything that is not a well formed read marker
+    pub fn decode(data: &[u8]) -> Option<ReadMarker> {
+        if data.len() != READ_MARKER_LEN
+            || data[0] != CONTROL_PACKET_ID
+            || data[1] != READ_MARKER_TYPE
+        {
+            return None;
+        }
+
+        let millis = i64::from_be_bytes(data[2..].try_into().ok()?);
+        let timestamp = Utc.timestamp_millis_opt(millis).single()?;
+
+        Some(ReadMarker { timestamp })
+    }
+}
+
+/// Newest of our messages the peer of each chat has read. The timestamp in
+/// a marker comes from the peer's clock, so rather than comparing it against
+/// ours a marker is taken to cover everything delivered before it arrived
+#[derive(Default)]
+pub(crate) struct PeerReadMessages {
+    read: HashMap<ChatHandle, ChatMessageId>,
+}
+
+impl PeerReadMessages {
+    /// Records that the peer has read up to message_id. The read message only
+    /// ever moves forward. Returns whether it changed
+    pub fn update(&mut self, chat: ChatHandle, message_id: ChatMessageId) -> bool {
+        match self.read.get(&chat) {
+            Some(existing) if *existing >= message_id => false,
+            _ => {
+                self.read.insert(chat, message_id);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let marker = ReadMarker {
+            timestamp: Utc.timestamp_millis(1_625_000_000_123),
+        };
+
+        let encoded = marker.encode();
+        assert_eq!(encoded.len(), READ_MARKER_LEN);
+        assert_eq!(ReadMarker::decode(&encoded), Some(marker));
+    }
+
+    #[test]
+    fn test_unknown_packets_ignored() {
+        let mut encoded = ReadMarker {
+            timestamp: Utc.timestamp_millis(0),
+        }
+        .encode();
+
+        assert!(ReadMarker::decode(&[]).is_none());
+        assert!(ReadMarker::decode(&encoded[..encoded.len() - 1]).is_none());
+
+        encoded[1] = READ_MARKER_TYPE + 1;
+        assert!(ReadMarker::decode(&encoded).is_none());
+
+        encoded[1] = READ_MARKER_TYPE;
+        encoded[0] = CONTROL_PACKET_ID + 1;
+        assert!(ReadMarker::decode(&encoded).is_none());
+    }
+
+    #[test]
+    fn test_peer_read_message_only_moves_forward() {
+        let mut read = PeerReadMessages::default();
+        let chat = ChatHandle::from(1);
+        let other_chat = ChatHandle::from(2);
+
+        let earlier = ChatMessageId::from(10);
+        let later = ChatMessageId::from(20);
+
+        assert!(read.update(chat, later));
+        assert!(!read.update(chat, earlier));
+        assert!(!read.update(chat, later));
+
+        assert!(read.update(other_chat, earlier));
+        assert!(read.update(other_chat, later));
+    }
+}
@@ -0,0 +1,124 @@
+use crate::{
+    contact::{Friend, Status},
+    storage::UserHandle,
+};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+/// How long a friend has to be gone before we guess they removed us
+pub(crate) const DAYS_BEFORE_HINT: i64 = 30;
+
+/// Last online times only matter at the granularity of days, no need to look
+/// at them more often than this
+pub(crate) const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// toxcore never tells us when a friend removes us, the friend just looks
+/// offline forever. A friend we haven't been connected to for a long time is
+/// the closest thing to a signal we get, so we raise a soft "possibly
+/// removed" hint for those until they show up again
+pub(crate) struct RemovalHints {
+    hinted: HashSet<UserHandle>,
+    next_check: Instant,
+}
+
+impl RemovalHints {
+    pub fn new(now: Instant) -> RemovalHints {
+        RemovalHints {
+            hinted: HashSet::new(),
+            next_check: now,
+        }
+    }
+
+    /// Re-evaluates the hint for friend. Returns the new hint state if it
+    /// changed
+    pub fn update(&mut self, friend: &Friend, now: DateTime<Utc>) -> Option<bool> {
+        let possibly_removed = possibly_removed(friend, now);
+
+        let changed = if possibly_removed {
+            self.hinted.insert(*friend.id())
+        } else {
+            self.hinted.remove(friend.id())
+        };
+
+        if changed {
+            Some(possibly_removed)
+        } else {
+            None
+        }
+    }
+
+    pub fn next_check(&self) -> Instant {
+        self.next_check
+    }
+
+    pub fn checked(&mut self, now: Instant) {
+        self.next_check = now + CHECK_INTERVAL;
+    }
+}
+
+fn possibly_removed(friend: &Friend, now: DateTime<Utc>) -> bool {
+    // Pending friends never connected in the first place, and a friend we
+    // never saw has no gap to measure
+    if *friend.status() != Status::Offline {
+        return false;
+    }
+
+    friend.last_online().map_or(false, |last_online| {
+        now.signed_duration_since(*last_online) >= ChronoDuration::days(DAYS_BEFORE_HINT)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::ChatHandle;
+    use toxcore::PublicKey;
+
+    fn offline_friend(last_online: Option<DateTime<Utc>>) -> Friend {
+        let mut friend = Friend::new(
+            UserHandle::from(1),
+            ChatHandle::from(1),
+            PublicKey::from_bytes(vec![1; PublicKey::SIZE]).unwrap(),
+            "test".to_string(),
+            Status::Offline,
+        );
+        friend.set_last_online(last_online);
+        friend
+    }
+
+    #[test]
+    fn test_long_absence_hints_removal() {
+        let mut hints = RemovalHints::new(Instant::now());
+        let now = Utc::now();
+
+        let recent = offline_friend(Some(now - ChronoDuration::days(DAYS_BEFORE_HINT - 1)));
+        assert_eq!(hints.update(&recent, now), None);
+
+        let mut friend = offline_friend(Some(now - ChronoDuration::days(DAYS_BEFORE_HINT)));
+        assert_eq!(hints.update(&friend, now), Some(true));
+
+        // Only reported once
+        assert_eq!(hints.update(&friend, now), None);
+
+        friend.set_status(Status::Online);
+        assert_eq!(hints.update(&friend, now), Some(false));
+        assert_eq!(hints.update(&friend, now), None);
+    }
+
+    #[test]
+    fn test_never_seen_friends_are_not_hinted() {
+        let mut hints = RemovalHints::new(Instant::now());
+        let now = Utc::now();
+
+        assert_eq!(hints.update(&offline_friend(None), now), None);
+
+        let mut pending = offline_friend(Some(now - ChronoDuration::days(365)));
+        pending.set_status(Status::Pending);
+        assert_eq!(hints.update(&pending, now), None);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::read_marker::CONTROL_PACKET_ID;
+
+use toxcore::Message;
+
+const REPLY_MARKER_TYPE: u8 = 2;
+
+/// Quotes longer than this are truncated to keep the packet under toxcore's
+/// custom packet limit (1373 bytes)
+const MAX_QUOTE_LEN: usize = 1024;
+
+/// Only the start of the reply itself is sent, enough to tell it apart from
+/// whatever else the peer sends next
+const MAX_REPLY_PREFIX_LEN: usize = 128;
+
+/// Sent immediately before a message that replies to another one. Message ids
+/// are local to each client, so the quoted message is identified by its text
+/// and the peer looks for it in its own history. Custom packets and messages
+/// share the same ordered connection, so the reply is the next message from
+/// the peer. The marker also carries the start of the reply, if that message
+/// never made it out the marker doesn't stick to the next one instead
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ReplyMarker {
+    pub quoted: String,
+    reply_prefix: String,
+}
+
+impl ReplyMarker {
+    pub fn new(quoted: &Message, reply: &Message) -> ReplyMarker {
+        ReplyMarker {
+            quoted: truncate(message_text(quoted), MAX_QUOTE_LEN).to_string(),
+            reply_prefix: truncate(message_text(reply), MAX_REPLY_PREFIX_LEN).to_string(),
+        }
+    }
+
+    /// Whether message is the reply this marker was sent for
+    pub fn is_for(&self, message: &Message) -> bool {
+        message_text(message).starts_with(&self.reply_prefix)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(3 + self.reply_prefix.len() + self.quoted.len());
+        ret.push(CONTROL_PACKET_ID);
+        ret.push(REPLY_MARKER_TYPE);
+        // Always fits, MAX_REPLY_PREFIX_LEN is below 256
+        ret.push(self.reply_prefix.len() as u8);
+        ret.extend_from_slice(self.reply_prefix.as_bytes());
+        ret.extend_from_slice(self.quoted.as_bytes());
+        ret
+    }
+
+    /// Returns None for anything that is not a well formed reply marker
+    pub fn decode(data: &[u8]) -> Option<ReplyMarker> {
+        if data.len() < 3 || data[0] != CONTROL_PACKET_ID || data[1] != REPLY_MARKER_TYPE {
+            return None;
+        }
+
+        let prefix_len = data[2] as usize;
+        let data = &data[3..];
+        if data.len() < prefix_len {
+            return None;
+        }
+
+        let reply_prefix = std::str::from_utf8(&data[..prefix_len]).ok()?;
+        let quoted = std::str::from_utf8(&data[prefix_len..]).ok()?;
+
+        Some(ReplyMarker {
+            quoted: quoted.to_string(),
+            reply_prefix: reply_prefix.to_string(),
+        })
+    }
+}
+
+/// The text as stored on both ends. Actions are quoted as is, stripping the
+/// "/me " of a literal one would keep the peer from finding it
+fn message_text(message: &Message) -> &str {
+    match message {
+        Message::Normal(s) | Message::Action(s) => s,
+    }
+}
+
+fn truncate(s: &str, max_len: usize) -> &str {
+    let mut len = s.len().min(max_len);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    &s[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::read_marker::ReadMarker;
+
+    use chrono::{TimeZone, Utc};
+
+    fn normal(s: &str) -> Message {
+        Message::Normal(s.to_string())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let marker = ReplyMarker::new(&normal("what time works for you?"), &normal("noon"));
+        assert_eq!(ReplyMarker::decode(&marker.encode()), Some(marker));
+    }
+
+    #[test]
+    fn test_long_quote_truncated() {
+        // Multi byte characters straddling the limit must not be split
+        let quoted = "é".repeat(MAX_QUOTE_LEN);
+        let reply = "ü".repeat(MAX_REPLY_PREFIX_LEN);
+        let marker = ReplyMarker::new(&normal(&quoted), &normal(&reply));
+
+        assert!(marker.quoted.len() <= MAX_QUOTE_LEN);
+        assert!(quoted.starts_with(&marker.quoted));
+        assert!(marker.is_for(&normal(&reply)));
+        assert_eq!(ReplyMarker::decode(&marker.encode()), Some(marker));
+    }
+
+    #[test]
+    fn test_marker_bound_to_reply() {
+        let marker = ReplyMarker::new(&normal("lunch?"), &normal("sure"));
+        let marker = ReplyMarker::decode(&marker.encode()).unwrap();
+
+        assert!(marker.is_for(&normal("sure")));
+        // The reply never arrived, the next message isn't it
+        assert!(!marker.is_for(&normal("unrelated")));
+    }
+
+    #[test]
+    fn test_actions_quoted_verbatim() {
+        let marker = ReplyMarker::new(&normal("/me waves"), &Message::Action("waves back".into()));
+        assert_eq!(marker.quoted, "/me waves");
+        assert!(marker.is_for(&Message::Action("waves back".into())));
+    }
+
+    #[test]
+    fn test_other_packets_ignored() {
+        let read_marker = ReadMarker {
+            timestamp: Utc.timestamp_millis(0),
+        };
+        assert!(ReplyMarker::decode(&read_marker.encode()).is_none());
+
+        assert!(ReplyMarker::decode(&[]).is_none());
+        assert!(ReplyMarker::decode(&[CONTROL_PACKET_ID]).is_none());
+        assert!(ReplyMarker::decode(&[CONTROL_PACKET_ID, REPLY_MARKER_TYPE]).is_none());
+        // Reply prefix longer than the packet
+        assert!(ReplyMarker::decode(&[CONTROL_PACKET_ID, REPLY_MARKER_TYPE, 4, b'a']).is_none());
+        assert!(ReplyMarker::decode(&[CONTROL_PACKET_ID, REPLY_MARKER_TYPE, 0, 0xff]).is_none());
+    }
+}
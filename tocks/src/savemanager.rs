@@ -1,6 +1,9 @@
+use crate::error::{CorruptSaveError, PasswordRequiredError};
+
 use toxcore::PassKey;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use log::*;
 use tempfile::NamedTempFile;
 
 use std::{
@@ -25,7 +28,15 @@ impl SaveManager {
     pub fn new_with_password(path: PathBuf, password: &str) -> Result<SaveManager> {
         let passkey = if path.exists() {
             let buf = path_to_buf(&path)?;
-            PassKey::from_encrypted_slice(password, &buf)?
+            match PassKey::from_encrypted_slice(password, &buf) {
+                Ok(passkey) => passkey,
+                // Every save shares the same salt, so a corrupt save can still
+                // be opened with the backup's
+                Err(e) => match path_to_buf(backup_path(&path)) {
+                    Ok(backup) => PassKey::from_encrypted_slice(password, &backup)?,
+                    Err(_) => return Err(e.into()),
+                },
+            }
         } else {
             PassKey::new(password)?
         };
@@ -36,12 +47,61 @@ impl SaveManager {
         })
     }
 
+    /// Loads the save, falling back to the backup if the save is unreadable.
+    /// A missing save is not recovered from, that is a new account
     pub fn load(&self) -> Result<Vec<u8>> {
-        let buf = path_to_buf(&self.path)?;
+        let err = match self.load_from(&self.path) {
+            Ok(data) => return Ok(data),
+            Err(e) => e,
+        };
+
+        let not_found = err
+            .downcast_ref::<std::io::Error>()
+            .map_or(false, |e| e.kind() == std::io::ErrorKind::NotFound);
+        if not_found {
+            return Err(err);
+        }
+
+        match self.load_backup() {
+            Ok(data) => {
+                warn!(
+                    "Tox save {} is unreadable, recovered from backup: {:#}",
+                    self.path.to_string_lossy(),
+                    err
+                );
+                Ok(data)
+            }
+            Err(_) => Err(err),
+        }
+    }
+
+    /// Loads the save from before the last successful save. For when the
+    /// current save loads but tox can't make sense of it
+    pub fn load_backup(&self) -> Result<Vec<u8>> {
+        self.load_from(&backup_path(&self.path))
+            .context("Failed to load tox save backup")
+    }
+
+    fn load_from(&self, path: &Path) -> Result<Vec<u8>> {
+        let buf = path_to_buf(path)?;
+        self.decrypt(&buf)
+    }
+
+    fn decrypt(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        let encrypted = PassKey::is_data_encrypted(buf);
 
         match &self.passkey {
-            Some(key) => key.decrypt(&buf).context("Failed to decrypt tox save"),
-            None => Ok(buf),
+            // toxcore can't tell a wrong key from a mangled body, but a save
+            // with an intact header is most likely just the wrong password
+            Some(key) if encrypted => key
+                .decrypt(buf)
+                .context(PasswordRequiredError)
+                .context("Failed to decrypt tox save"),
+            // Truncated or not a tox encrypted save at all, another password
+            // won't help
+            Some(_) => Err(CorruptSaveError).context("Failed to decrypt tox save"),
+            None if encrypted => Err(PasswordRequiredError).context("Tox save is encrypted"),
+            None => Ok(buf.to_vec()),
         }
     }
 
@@ -60,22 +120,74 @@ impl SaveManager {
             Some(key) => {
                 let encrypted = key.encrypt(data).context("Failed to encrypted tox save")?;
                 tempfile
-                    .write(&encrypted)
+                    .write_all(&encrypted)
                     .context("Failed to write encrypted tox save to temp file")?;
             }
             None => {
                 tempfile
-                    .write(data)
+                    .write_all(data)
                     .context("Failed to write unencrypted tox save to temp file")?;
             }
         }
 
+        tempfile
+            .as_file()
+            .sync_all()
+            .context("Failed to flush tox save")?;
+
+        // Never replace a good save with something we can't read back
+        let written = self
+            .load_from(tempfile.path())
+            .context("Failed to read back tox save")?;
+        if written != data {
+            bail!("Tox save did not read back correctly");
+        }
+
+        self.rotate_backup()?;
+
         tempfile
             .persist(&self.path)
             .context("Failed to overwrite save")?;
 
         Ok(())
     }
+
+    /// Keeps the current save around as a backup before it is overwritten.
+    /// A save that is already unreadable would only clobber a good backup, so
+    /// it is left alone
+    fn rotate_backup(&self) -> Result<()> {
+        if self.load_from(&self.path).is_err() {
+            return Ok(());
+        }
+
+        let current = path_to_buf(&self.path)?;
+
+        let mut tempfile = NamedTempFile::new_in(self.path.parent().unwrap())
+            .context("Failed to open temporary file for backup")?;
+        tempfile
+            .write_all(&current)
+            .context("Failed to write tox save backup")?;
+        tempfile
+            .persist(backup_path(&self.path))
+            .context("Failed to overwrite tox save backup")?;
+
+        Ok(())
+    }
+}
+
+/// Returns true if the save at the given path exists and is password
+/// protected
+pub fn is_encrypted<P: AsRef<Path>>(path: P) -> bool {
+    match path_to_buf(path) {
+        Ok(buf) => PassKey::is_data_encrypted(&buf),
+        Err(_) => false,
+    }
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    PathBuf::from(backup)
 }
 
 fn path_to_buf<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
@@ -91,3 +203,204 @@ fn path_to_buf<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
 
     Ok(buf)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir_entries(path: &Path) -> Result<Vec<PathBuf>> {
+        let mut entries = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort();
+        Ok(entries)
+    }
+
+    #[test]
+    fn test_unencrypted_round_trip() -> Result<()> {
+        let save_dir = tempfile::tempdir()?;
+        let path = save_dir.path().join("account.tox");
+
+        let save_manager = SaveManager::new_unencrypted(path.clone());
+        save_manager.save(b"data")?;
+
+        // Unencrypted saves are written as is
+        assert_eq!(std::fs::read(&path)?, b"data");
+        assert_eq!(save_manager.load()?, b"data");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_round_trip() -> Result<()> {
+        let save_dir = tempfile::tempdir()?;
+        let path = save_dir.path().join("account.tox");
+
+        SaveManager::new_with_password(path.clone(), "password")?.save(b"data")?;
+
+        assert_ne!(std::fs::read(&path)?, b"data");
+
+        // A fresh manager has to recover the salt from the existing save
+        let save_manager = SaveManager::new_with_password(path, "password")?;
+        assert_eq!(save_manager.load()?, b"data");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_overwrites_atomically() -> Result<()> {
+        let save_dir = tempfile::tempdir()?;
+        let path = save_dir.path().join("account.tox");
+
+        let save_manager = SaveManager::new_unencrypted(path.clone());
+        save_manager.save(b"longer data")?;
+        save_manager.save(b"data")?;
+
+        // No stale tail from the previous save, and no temporary files left
+        // behind
+        assert_eq!(save_manager.load()?, b"data");
+        assert_eq!(
+            dir_entries(save_dir.path())?,
+            vec![path.clone(), backup_path(&path)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_save_leaves_no_temp_files() -> Result<()> {
+        let save_dir = tempfile::tempdir()?;
+
+        // A non-empty directory in place of the save can't be replaced
+        let path = save_dir.path().join("account.tox");
+        std::fs::create_dir(&path)?;
+        std::fs::write(path.join("existing"), b"existing")?;
+
+        let save_manager = SaveManager::new_unencrypted(path.clone());
+        assert!(save_manager.save(b"data").is_err());
+
+        assert_eq!(dir_entries(save_dir.path())?, vec![path.clone()]);
+        assert_eq!(std::fs::read(path.join("existing"))?, b"existing");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_save_recovered_from_backup() -> Result<()> {
+        let save_dir = tempfile::tempdir()?;
+        let path = save_dir.path().join("account.tox");
+
+        let save_manager = SaveManager::new_with_password(path.clone(), "password")?;
+        save_manager.save(b"data1")?;
+        save_manager.save(b"data2")?;
+        assert_eq!(save_manager.load_backup()?, b"data1");
+
+        std::fs::write(&path, b"garbage")?;
+
+        let save_manager = SaveManager::new_with_password(path.clone(), "password")?;
+        assert_eq!(save_manager.load()?, b"data1");
+
+        // The corrupt save must not replace the good backup
+        save_manager.save(b"data3")?;
+        assert_eq!(save_manager.load()?, b"data3");
+        assert_eq!(save_manager.load_backup()?, b"data1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unencrypted_backup() -> Result<()> {
+        let save_dir = tempfile::tempdir()?;
+        let path = save_dir.path().join("account.tox");
+
+        let save_manager = SaveManager::new_unencrypted(path);
+        save_manager.save(b"data1")?;
+        assert!(save_manager.load_backup().is_err());
+
+        save_manager.save(b"data2")?;
+        assert_eq!(save_manager.load()?, b"data2");
+        assert_eq!(save_manager.load_backup()?, b"data1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_save_not_recovered() -> Result<()> {
+        let save_dir = tempfile::tempdir()?;
+        let path = save_dir.path().join("account.tox");
+
+        std::fs::write(backup_path(&path), b"old")?;
+
+        // A missing save means a new account, don't resurrect an old identity
+        let err = SaveManager::new_unencrypted(path).load().unwrap_err();
+        let io_err = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io_err.kind(), std::io::ErrorKind::NotFound);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_save_detection() -> Result<()> {
+        let save_dir = tempfile::tempdir()?;
+
+        let encrypted_path = save_dir.path().join("encrypted.tox");
+        SaveManager::new_with_password(encrypted_path.clone(), "password")?.save(b"data")?;
+        assert!(is_encrypted(&encrypted_path));
+
+        let unencrypted_path = save_dir.path().join("unencrypted.tox");
+        SaveManager::new_unencrypted(unencrypted_path.clone()).save(b"data")?;
+        assert!(!is_encrypted(&unencrypted_path));
+
+        assert!(!is_encrypted(save_dir.path().join("missing.tox")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrong_password_requires_password() -> Result<()> {
+        let save_dir = tempfile::tempdir()?;
+        let path = save_dir.path().join("account.tox");
+
+        SaveManager::new_with_password(path.clone(), "password")?.save(b"data")?;
+
+        let err = SaveManager::new_with_password(path, "wrong")?
+            .load()
+            .unwrap_err();
+        assert!(err.downcast_ref::<PasswordRequiredError>().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_save_does_not_require_password() -> Result<()> {
+        let save_dir = tempfile::tempdir()?;
+        let path = save_dir.path().join("account.tox");
+
+        let save_manager = SaveManager::new_with_password(path.clone(), "password")?;
+        save_manager.save(b"data")?;
+
+        // Truncated into the encryption header
+        let save = std::fs::read(&path)?;
+        std::fs::write(&path, &save[..8])?;
+        std::fs::remove_file(backup_path(&path)).ok();
+
+        let err = save_manager.load().unwrap_err();
+        assert!(err.downcast_ref::<CorruptSaveError>().is_some());
+        assert!(err.downcast_ref::<PasswordRequiredError>().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_save_without_key_requires_password() -> Result<()> {
+        let save_dir = tempfile::tempdir()?;
+        let path = save_dir.path().join("account.tox");
+
+        SaveManager::new_with_password(path.clone(), "password")?.save(b"data")?;
+
+        let err = SaveManager::new_unencrypted(path).load().unwrap_err();
+        assert!(err.downcast_ref::<PasswordRequiredError>().is_some());
+
+        Ok(())
+    }
+}
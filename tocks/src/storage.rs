@@ -1,22 +1,38 @@
-use crate::contact::{Friend, Status, User};
+use crate::{
+    contact::{Friend, Status, User},
+    message_parser::Span,
+};
 
-use toxcore::{Message, PublicKey};
+use toxcore::{Message, PublicKey, ToxId};
 
-use anyhow::{anyhow, Context, Error, Result};
+use anyhow::{anyhow, bail, Context, Error, Result};
 use chrono::{DateTime, Utc};
-use rusqlite::{params, types::ValueRef, Connection, OptionalExtension, Transaction};
+use rusqlite::{params, types::ValueRef, Connection, OpenFlags, OptionalExtension, Transaction};
 use serde::{Deserialize, Serialize};
 
 use std::{fmt, path::Path};
 
 const SELF_USER_ID: i64 = 0;
+const ACCOUNT_SETTINGS_ID: i64 = 0;
 
 // Wrapper around sqlite message table id
-#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug, Hash, Serialize, Deserialize)]
 pub struct ChatMessageId {
     msg_id: i64,
 }
 
+impl ChatMessageId {
+    pub fn id(&self) -> i64 {
+        self.msg_id
+    }
+}
+
+impl From<i64> for ChatMessageId {
+    fn from(id: i64) -> Self {
+        Self { msg_id: id }
+    }
+}
+
 impl fmt::Display for ChatMessageId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.msg_id)
@@ -32,9 +48,34 @@ pub struct ChatLogEntry {
     message: Message,
     timestamp: DateTime<Utc>,
     complete: bool,
+    #[serde(default)]
+    reply_to: Option<ChatMessageId>,
+    // None once delivered
+    #[serde(default)]
+    send_state: Option<SendState>,
 }
 
 impl ChatLogEntry {
+    /// Entries start out completed, if the caller wants to deal with receipts
+    /// they can update this once the receipt is injected into storage
+    pub fn new(
+        id: ChatMessageId,
+        sender: UserHandle,
+        message: Message,
+        timestamp: DateTime<Utc>,
+        reply_to: Option<ChatMessageId>,
+    ) -> ChatLogEntry {
+        ChatLogEntry {
+            id,
+            sender,
+            message,
+            timestamp,
+            complete: true,
+            reply_to,
+            send_state: None,
+        }
+    }
+
     pub fn id(&self) -> &ChatMessageId {
         &self.id
     }
@@ -47,6 +88,20 @@ impl ChatLogEntry {
         &self.message
     }
 
+    pub fn is_action(&self) -> bool {
+        matches!(self.message, Message::Action(_))
+    }
+
+    /// Plain text of the message for rendering, spell checking, etc.
+    pub fn display_text(&self) -> &str {
+        crate::message_parser::display_text(&self.message)
+    }
+
+    /// display_text split into markdown styled spans
+    pub fn styled_text(&self) -> Vec<Span> {
+        crate::message_parser::parse_markdown(self.display_text())
+    }
+
     pub fn timestamp(&self) -> &DateTime<Utc> {
         &self.timestamp
     }
@@ -58,6 +113,53 @@ impl ChatLogEntry {
     pub fn set_complete(&mut self, complete: bool) {
         self.complete = complete;
     }
+
+    /// Message this one is a reply to, if any
+    pub fn reply_to(&self) -> Option<&ChatMessageId> {
+        self.reply_to.as_ref()
+    }
+
+    /// How far an outgoing message got, None once it's been delivered
+    pub fn send_state(&self) -> Option<SendState> {
+        self.send_state
+    }
+
+    pub fn set_send_state(&mut self, send_state: Option<SendState>) {
+        self.send_state = send_state;
+    }
+}
+
+/// How far an undelivered outgoing message got
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SendState {
+    // Waiting for the friend to come online
+    Unsent,
+    // Handed to toxcore, waiting for the read receipt
+    Sent,
+    // toxcore refused the message, or its read receipt never came. Retried
+    // with the rest when the friend next comes online
+    Failed,
+}
+
+// NOTE: These are written to the DB, don't change existing values
+impl SendState {
+    fn to_db(self) -> i64 {
+        match self {
+            SendState::Unsent => 0,
+            SendState::Sent => 1,
+            SendState::Failed => 2,
+        }
+    }
+
+    fn from_db(value: i64) -> SendState {
+        match value {
+            1 => SendState::Sent,
+            2 => SendState::Failed,
+            // Rows from before states were tracked. Treating them as unsent
+            // is what happened to them anyway
+            _ => SendState::Unsent,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
@@ -103,6 +205,7 @@ impl From<i64> for UserHandle {
 pub struct UnsentMessage {
     id: ChatMessageId,
     message: Message,
+    reply_to: Option<ChatMessageId>,
 }
 
 impl UnsentMessage {
@@ -113,6 +216,10 @@ impl UnsentMessage {
     pub fn message(&self) -> &Message {
         &self.message
     }
+
+    pub fn reply_to(&self) -> Option<&ChatMessageId> {
+        self.reply_to.as_ref()
+    }
 }
 
 pub(crate) struct Storage {
@@ -124,6 +231,7 @@ impl Storage {
         let mut connection = Connection::open(&path)
             .with_context(|| format!("Failed to open db at {}", path.as_ref().to_string_lossy()))?;
 
+        enable_foreign_keys(&connection)?;
         initialize_db(&mut connection, self_pk, self_name)?;
 
         Ok(Storage { connection })
@@ -133,10 +241,35 @@ impl Storage {
         let mut connection =
             Connection::open_in_memory().context("Failed to open sqlite db in ram")?;
 
+        enable_foreign_keys(&connection)?;
         initialize_db(&mut connection, self_pk, self_name)?;
         Ok(Storage { connection })
     }
 
+    /// Public key of the identity that owns the DB at path, without
+    /// claiming the DB for anyone. None if there's no DB there yet
+    pub fn owner_public_key<P: AsRef<Path>>(path: P) -> Result<Option<PublicKey>> {
+        if !path.as_ref().exists() {
+            return Ok(None);
+        }
+
+        let connection = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("Failed to open db at {}", path.as_ref().to_string_lossy()))?;
+
+        let public_key_bytes = connection
+            .query_row(
+                "SELECT public_key FROM users WHERE id = ?1",
+                params![SELF_USER_ID],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()
+            .context("Failed to get self user public key")?;
+
+        public_key_bytes
+            .map(|bytes| PublicKey::from_bytes(bytes).context("Failed to parse public key"))
+            .transpose()
+    }
+
     pub fn self_user_handle(&self) -> UserHandle {
         UserHandle {
             user_id: SELF_USER_ID,
@@ -147,9 +280,11 @@ impl Storage {
         let mut statement = self
             .connection
             .prepare(
-                "SELECT chat_id, friends.user_id, users.public_key, users.name, pending_friends.id \
+                "SELECT chat_id, friends.user_id, users.public_key, users.name, pending_friends.id, \
+                users.verified, chats.muted \
                 FROM friends \
                 LEFT JOIN users ON friends.user_id = users.id \
+                LEFT JOIN chats ON friends.chat_id = chats.id \
                 LEFT JOIN pending_friends ON friends.user_id = pending_friends.user_id \
                 WHERE friends.user_id NOT IN (SELECT user_id from blocked_users)",
             )
@@ -167,8 +302,18 @@ impl Storage {
                 let name: String = row.get(3)?;
 
                 let pending: bool = row.get_ref_unwrap(4) != ValueRef::Null;
-
-                Ok((chat_handle, user_handle, public_key_bytes, name, pending))
+                let verified: bool = row.get(5)?;
+                let muted: bool = row.get(6)?;
+
+                Ok((
+                    chat_handle,
+                    user_handle,
+                    public_key_bytes,
+                    name,
+                    pending,
+                    verified,
+                    muted,
+                ))
             })
             .context("Failed to map friend list response")?;
 
@@ -176,19 +321,22 @@ impl Storage {
             .into_iter()
             .filter_map(std::result::Result::ok)
             .map(
-                |(chat_handle, user_handle, public_key_bytes, name, pending)| {
+                |(chat_handle, user_handle, public_key_bytes, name, pending, verified, muted)| {
                     let status = if pending {
                         Status::Pending
                     } else {
                         Status::Offline
                     };
-                    Ok(Friend::new(
+                    let mut friend = Friend::new(
                         user_handle,
                         chat_handle,
                         PublicKey::from_bytes(public_key_bytes)?,
                         name,
                         status,
-                    ))
+                    );
+                    friend.set_verified(verified);
+                    friend.set_muted(muted);
+                    Ok(friend)
                 },
             )
             .collect::<Result<Vec<Friend>>>()
@@ -213,7 +361,8 @@ impl Storage {
 
         transaction
             .execute(
-                "INSERT INTO pending_friends (user_id) VALUES (?1)",
+                "INSERT INTO pending_friends (user_id) \
+                SELECT ?1 WHERE NOT EXISTS (SELECT 1 FROM pending_friends WHERE user_id = ?1)",
                 params![friend.id().id()],
             )
             .context("Failed to insert into pending friend")?;
@@ -326,15 +475,16 @@ impl Storage {
     }
 
     pub fn get_user(&self, id: &UserHandle) -> Result<User> {
-        let (public_key_bytes, name) = self
+        let (public_key_bytes, name, tox_id_bytes) = self
             .connection
             .query_row(
-                "SELECT public_key, name FROM users WHERE id = ?1",
+                "SELECT public_key, name, tox_id FROM users WHERE id = ?1",
                 params![id.id()],
                 |row| {
                     let public_key_bytes: Vec<u8> = row.get(0)?;
                     let name: String = row.get(1)?;
-                    Ok((public_key_bytes, name))
+                    let tox_id_bytes: Option<Vec<u8>> = row.get(2)?;
+                    Ok((public_key_bytes, name, tox_id_bytes))
                 },
             )
             .context("Failed to retrieve from users table")?;
@@ -342,7 +492,10 @@ impl Storage {
         let public_key =
             PublicKey::from_bytes(public_key_bytes).context("Failed to parse public key")?;
 
-        Ok(User::new(*id, public_key, name))
+        let mut user = User::new(*id, public_key, name);
+        user.set_tox_id(parse_tox_id(tox_id_bytes)?);
+
+        Ok(user)
     }
 
     pub fn block_user(&mut self, user_id: &UserHandle) -> Result<User> {
@@ -384,6 +537,8 @@ impl Storage {
             .transaction()
             .context("Failed to prepare transaction")?;
 
+        // Foreign keys are enforced, everything referencing the user or chat
+        // has to go before they do
         let (friend_id, chat_id) = transaction
             .query_row(
                 "SELECT id, chat_id \
@@ -398,24 +553,6 @@ impl Storage {
             )
             .context("Failed to retrieve user information")?;
 
-        transaction
-            .execute("DELETE FROM chats WHERE chats.id = ?1", params![chat_id])
-            .context("Failed to delete chat with user")?;
-
-        transaction
-            .execute(
-                "DELETE FROM users WHERE users.id = ?1",
-                params![user_id.user_id],
-            )
-            .context("Failed to delete from users table")?;
-
-        transaction
-            .execute(
-                "DELETE FROM friends WHERE friends.id = ?1",
-                params![friend_id],
-            )
-            .context("Failed to delete friend")?;
-
         transaction
             .execute(
                 "DELETE FROM text_messages WHERE id IN ( \
@@ -457,6 +594,24 @@ impl Storage {
             )
             .context("Failed to remove from blocked users")?;
 
+        transaction
+            .execute(
+                "DELETE FROM friends WHERE friends.id = ?1",
+                params![friend_id],
+            )
+            .context("Failed to delete friend")?;
+
+        transaction
+            .execute("DELETE FROM chats WHERE chats.id = ?1", params![chat_id])
+            .context("Failed to delete chat with user")?;
+
+        transaction
+            .execute(
+                "DELETE FROM users WHERE users.id = ?1",
+                params![user_id.user_id],
+            )
+            .context("Failed to delete from users table")?;
+
         transaction
             .commit()
             .context("Failed to commit transaction")?;
@@ -468,7 +623,7 @@ impl Storage {
         let mut statement = self
             .connection
             .prepare(
-                "SELECT users.id, users.public_key, users.name FROM users \
+                "SELECT users.id, users.public_key, users.name, users.tox_id FROM users \
             JOIN blocked_users ON users.id = blocked_users.user_id",
             )
             .context("Failed to prepare blocked users query")?;
@@ -480,19 +635,24 @@ impl Storage {
                 };
                 let public_key_bytes: Vec<u8> = row.get(1)?;
                 let name: String = row.get(2)?;
+                let tox_id_bytes: Option<Vec<u8>> = row.get(3)?;
 
-                Ok((user_handle, public_key_bytes, name))
+                Ok((user_handle, public_key_bytes, name, tox_id_bytes))
             })
             .context("Failed to parse blocked users")?;
 
         let mut ret = Vec::new();
         for res in mapped_rows {
-            let (user_handle, public_key_bytes, name) = res.context("Failed to parse DB row")?;
+            let (user_handle, public_key_bytes, name, tox_id_bytes) =
+                res.context("Failed to parse DB row")?;
 
             let public_key =
                 PublicKey::from_bytes(public_key_bytes).context("Failed to parse public key")?;
 
-            ret.push(User::new(user_handle, public_key, name));
+            let mut user = User::new(user_handle, public_key, name);
+            user.set_tox_id(parse_tox_id(tox_id_bytes)?);
+
+            ret.push(user);
         }
 
         Ok(ret)
@@ -509,6 +669,120 @@ impl Storage {
         Ok(())
     }
 
+    pub fn set_user_verified(&mut self, user_handle: &UserHandle, verified: bool) -> Result<()> {
+        self.connection
+            .execute(
+                "UPDATE users SET verified = ?2 WHERE id = ?1",
+                params![user_handle.id(), verified],
+            )
+            .context("Failed to update user verification")?;
+
+        Ok(())
+    }
+
+    pub fn set_user_tox_id(&mut self, user_handle: &UserHandle, tox_id: &ToxId) -> Result<()> {
+        self.connection
+            .execute(
+                "UPDATE users SET tox_id = ?2 WHERE id = ?1",
+                params![user_handle.id(), tox_id.as_bytes()],
+            )
+            .context("Failed to update user tox id")?;
+
+        Ok(())
+    }
+
+    /// Time we last read chat up to, None if it has never been read
+    pub fn chat_read_time(&self, chat: &ChatHandle) -> Result<Option<DateTime<Utc>>> {
+        self.connection
+            .query_row(
+                "SELECT last_read FROM chats WHERE id = ?1",
+                params![chat.id()],
+                |row| row.get(0),
+            )
+            .context("Failed to retrieve chat read time")
+    }
+
+    pub fn set_chat_read_time(&mut self, chat: &ChatHandle, time: &DateTime<Utc>) -> Result<()> {
+        self.connection
+            .execute(
+                "UPDATE chats SET last_read = ?2 WHERE id = ?1",
+                params![chat.id(), time],
+            )
+            .context("Failed to update chat read time")?;
+
+        Ok(())
+    }
+
+    /// Newest message we sent in chat that has been delivered, None if
+    /// nothing has been
+    pub fn newest_delivered_message(&self, chat: &ChatHandle) -> Result<Option<ChatMessageId>> {
+        let msg_id: Option<i64> = self
+            .connection
+            .query_row(
+                "SELECT MAX(messages.id) FROM messages \
+                LEFT JOIN pending_messages ON messages.id = pending_messages.message_id \
+                WHERE chat_id = ?1 AND sender_id = ?2 AND pending_messages.id IS NULL",
+                params![chat.id(), SELF_USER_ID],
+                |row| row.get(0),
+            )
+            .context("Failed to retrieve newest delivered message")?;
+
+        Ok(msg_id.map(|msg_id| ChatMessageId { msg_id }))
+    }
+
+    /// Number of messages in chat newer than since, not counting our own
+    pub fn unread_count(&self, chat: &ChatHandle, since: DateTime<Utc>) -> Result<usize> {
+        let count: i64 = self
+            .connection
+            .query_row(
+                "SELECT COUNT(*) FROM messages \
+                WHERE chat_id = ?1 AND sender_id != ?2 AND timestamp > ?3",
+                params![chat.id(), SELF_USER_ID, since],
+                |row| row.get(0),
+            )
+            .context("Failed to count unread messages")?;
+
+        Ok(count as usize)
+    }
+
+    pub fn set_chat_muted(&mut self, chat: &ChatHandle, muted: bool) -> Result<()> {
+        self.connection
+            .execute(
+                "UPDATE chats SET muted = ?2 WHERE id = ?1",
+                params![chat.id(), muted],
+            )
+            .context("Failed to update chat mute state")?;
+
+        Ok(())
+    }
+
+    /// Auto-reply message set for the account, None if it's turned off
+    pub fn auto_reply(&self) -> Result<Option<String>> {
+        let auto_reply: Option<Option<String>> = self
+            .connection
+            .query_row(
+                "SELECT auto_reply FROM account_settings WHERE id = ?1",
+                params![ACCOUNT_SETTINGS_ID],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Failed to retrieve auto reply")?;
+
+        Ok(auto_reply.flatten())
+    }
+
+    pub fn set_auto_reply(&mut self, message: Option<&str>) -> Result<()> {
+        self.connection
+            .execute(
+                "INSERT INTO account_settings (id, auto_reply) VALUES (?1, ?2) \
+                ON CONFLICT(id) DO UPDATE SET auto_reply = excluded.auto_reply",
+                params![ACCOUNT_SETTINGS_ID, message],
+            )
+            .context("Failed to update auto reply")?;
+
+        Ok(())
+    }
+
     pub fn resolve_pending_friend_request(&mut self, user_handle: &UserHandle) -> Result<()> {
         self.connection
             .execute(
@@ -525,6 +799,28 @@ impl Storage {
         chat: &ChatHandle,
         sender: UserHandle,
         message: Message,
+    ) -> Result<ChatLogEntry> {
+        self.insert_message(chat, sender, message, None)
+    }
+
+    /// Like push_message, but records that the message replies to reply_to,
+    /// which has to be in the same chat
+    pub fn push_reply(
+        &mut self,
+        chat: &ChatHandle,
+        sender: UserHandle,
+        message: Message,
+        reply_to: &ChatMessageId,
+    ) -> Result<ChatLogEntry> {
+        self.insert_message(chat, sender, message, Some(*reply_to))
+    }
+
+    fn insert_message(
+        &mut self,
+        chat: &ChatHandle,
+        sender: UserHandle,
+        message: Message,
+        reply_to: Option<ChatMessageId>,
     ) -> Result<ChatLogEntry> {
         let timestamp = Utc::now();
 
@@ -535,11 +831,35 @@ impl Storage {
 
         let transaction = self.connection.transaction()?;
 
+        if let Some(reply_to) = &reply_to {
+            let reply_chat: Option<i64> = transaction
+                .query_row(
+                    "SELECT chat_id FROM messages WHERE id = ?1",
+                    params![reply_to.msg_id],
+                    |row| row.get(0),
+                )
+                .optional()
+                .context("Failed to look up replied to message")?;
+
+            if reply_chat != Some(chat.chat_id) {
+                return Err(anyhow!(
+                    "Message {} is not in chat {}",
+                    reply_to,
+                    chat.chat_id
+                ));
+            }
+        }
+
         transaction
             .execute(
-                "INSERT INTO messages (chat_id, sender_id, timestamp) \
-                VALUES (?1, ?2, ?3)",
-                params![chat.chat_id, sender.user_id, timestamp],
+                "INSERT INTO messages (chat_id, sender_id, timestamp, reply_to) \
+                VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    chat.chat_id,
+                    sender.user_id,
+                    timestamp,
+                    reply_to.map(|id| id.msg_id)
+                ],
             )
             .context("Failed to insert message into messages table")?;
 
@@ -557,31 +877,167 @@ impl Storage {
 
         transaction.commit()?;
 
-        Ok(ChatLogEntry {
-            id,
-            sender,
-            message,
-            timestamp,
-            // Default to completed, if the caller wants to deal with receipts
-            // they can update this once the receipt is injected into storage
-            complete: true,
-        })
+        Ok(ChatLogEntry::new(id, sender, message, timestamp, reply_to))
+    }
+
+    /// Finds the newest message in chat whose text starts with prefix. Used to
+    /// find the message a peer is replying to, since they can't know our ids
+    pub fn find_message_by_text(
+        &mut self,
+        chat: &ChatHandle,
+        prefix: &str,
+    ) -> Result<Option<ChatMessageId>> {
+        self.connection
+            .query_row(
+                "SELECT messages.id \
+                FROM messages \
+                JOIN text_messages ON messages.id = text_messages.message_id \
+                WHERE chat_id = ?1 AND substr(text_messages.message, 1, length(?2)) = ?2 \
+                ORDER BY messages.id DESC \
+                LIMIT 1",
+                params![chat.chat_id, prefix],
+                |row| {
+                    Ok(ChatMessageId {
+                        msg_id: row.get(0)?,
+                    })
+                },
+            )
+            .optional()
+            .context("Failed to search for message")
+    }
+
+    pub fn message(&mut self, chat: &ChatHandle, message_id: &ChatMessageId) -> Result<Message> {
+        self.connection
+            .query_row(
+                "SELECT text_messages.message, text_messages.action \
+                FROM messages \
+                JOIN text_messages ON messages.id = text_messages.message_id \
+                WHERE messages.chat_id = ?1 AND messages.id = ?2",
+                params![chat.chat_id, message_id.msg_id],
+                |row| {
+                    let message_str = row.get(0)?;
+                    let action = row.get(1)?;
+
+                    Ok(match action {
+                        true => Message::Action(message_str),
+                        false => Message::Normal(message_str),
+                    })
+                },
+            )
+            .optional()
+            .context("Failed to query message")?
+            .ok_or_else(|| anyhow!("Message {} is not in chat {}", message_id, chat.chat_id))
     }
 
     pub fn load_messages(&mut self, chat: &ChatHandle) -> Result<Vec<ChatLogEntry>> {
+        self.load_messages_filtered(chat, None, None)
+    }
+
+    /// Loads up to limit of the newest messages in the chat, oldest first
+    pub fn load_recent_messages(
+        &mut self,
+        chat: &ChatHandle,
+        limit: usize,
+    ) -> Result<Vec<ChatLogEntry>> {
+        self.load_messages_filtered(chat, None, Some(limit))
+    }
+
+    /// Loads up to limit of the newest messages older than before, oldest
+    /// first
+    pub fn load_messages_before(
+        &mut self,
+        chat: &ChatHandle,
+        before: &ChatMessageId,
+        limit: usize,
+    ) -> Result<Vec<ChatLogEntry>> {
+        self.load_messages_filtered(chat, Some(before), Some(limit))
+    }
+
+    /// Loads up to before messages older than time and up to after messages
+    /// from time on, oldest first. If nothing was sent from time on, the
+    /// newest messages before it are returned instead
+    pub fn load_messages_around(
+        &mut self,
+        chat: &ChatHandle,
+        time: &DateTime<Utc>,
+        before: usize,
+        after: usize,
+    ) -> Result<Vec<ChatLogEntry>> {
+        let first_after: Option<i64> = self
+            .connection
+            .query_row(
+                "SELECT MIN(id) FROM messages WHERE chat_id = ?1 AND timestamp >= ?2",
+                params![chat.id(), time],
+                |row| row.get(0),
+            )
+            .context("Failed to find messages after time")?;
+
+        let first_after = match first_after {
+            Some(msg_id) => msg_id,
+            None => return self.load_messages_filtered(chat, None, Some(before)),
+        };
+
+        // Limits come straight from clients, sqlite would treat a wrapped
+        // negative offset as no offset at all
+        let after_offset = after.min(i64::MAX as usize) as i64;
+
+        // Everything below the first message past the after limit, so the
+        // page loaded from there covers both sides of time
+        let end: Option<i64> = self
+            .connection
+            .query_row(
+                "SELECT id FROM messages WHERE chat_id = ?1 AND id >= ?2 \
+                ORDER BY id LIMIT 1 OFFSET ?3",
+                params![chat.id(), first_after, after_offset],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Failed to find end of messages after time")?;
+
+        let end = end.map(|msg_id| ChatMessageId { msg_id });
+        let limit = before.saturating_add(after);
+        let mut messages = self.load_messages_filtered(chat, end.as_ref(), Some(limit))?;
+
+        // Fewer messages after time than asked for would otherwise let older
+        // messages fill up the page
+        let num_before = messages
+            .iter()
+            .filter(|entry| entry.id().msg_id < first_after)
+            .count();
+        if num_before > before {
+            messages.drain(..num_before - before);
+        }
+
+        Ok(messages)
+    }
+
+    fn load_messages_filtered(
+        &mut self,
+        chat: &ChatHandle,
+        before: Option<&ChatMessageId>,
+        limit: Option<usize>,
+    ) -> Result<Vec<ChatLogEntry>> {
+        let before = before.map_or(i64::MAX, |id| id.msg_id);
+        // Negative limits mean no limit in sqlite, huge ones must not wrap
+        // into that by accident
+        let limit = limit.map_or(-1, |limit| limit.min(i64::MAX as usize) as i64);
+
         let mut statement = self
             .connection
             .prepare(
-                "SELECT messages.id, sender_id, timestamp, message, action, pending_messages.id \
+                "SELECT messages.id, sender_id, timestamp, message, action, pending_messages.id, \
+                reply_to, pending_messages.state \
                 FROM messages \
                 LEFT JOIN text_messages ON messages.id = text_messages.message_id \
                 LEFT JOIN pending_messages ON messages.id = pending_messages.message_id \
-                WHERE chat_id = ?1",
+                WHERE chat_id = ?1 AND messages.id < ?2 \
+                ORDER BY messages.id DESC \
+                LIMIT ?3",
             )
             .context("Failed to prepare statement to retrieve messages from DB")?;
 
         let query_map = statement
-            .query_map(params![chat.id()], |row| {
+            .query_map(params![chat.id(), before, limit], |row| {
                 let id = ChatMessageId {
                     msg_id: row.get(0)?,
                 };
@@ -592,6 +1048,8 @@ impl Storage {
                 let message_str: String = row.get(3)?;
                 let is_action: bool = row.get(4)?;
                 let complete: bool = row.get_ref_unwrap(5) == ValueRef::Null;
+                let reply_to: Option<i64> = row.get(6)?;
+                let send_state: Option<i64> = row.get(7)?;
 
                 let message = if is_action {
                     Message::Action(message_str)
@@ -605,27 +1063,69 @@ impl Storage {
                     message,
                     timestamp,
                     complete,
+                    reply_to: reply_to.map(|msg_id| ChatMessageId { msg_id }),
+                    send_state: send_state.map(SendState::from_db),
                 })
             })
             .context("Failed to retrieve messages from DB")?;
 
-        query_map
+        let mut messages = query_map
             .into_iter()
             .map(|item| item.map_err(Error::from))
             .collect::<Result<Vec<_>>>()
-            .context("Failed to convert messages from DB")
+            .context("Failed to convert messages from DB")?;
+
+        // Queried newest first so the limit keeps the newest messages
+        messages.reverse();
+
+        Ok(messages)
     }
 
-    pub fn add_unresolved_message(&mut self, message_id: &ChatMessageId) -> Result<()> {
+    pub fn add_unresolved_message(
+        &mut self,
+        message_id: &ChatMessageId,
+        state: SendState,
+    ) -> Result<()> {
         self.connection
             .execute(
-                "INSERT OR REPLACE INTO pending_messages (message_id) VALUES (?1)",
-                params![message_id.msg_id],
+                "INSERT OR REPLACE INTO pending_messages (message_id, state) VALUES (?1, ?2)",
+                params![message_id.msg_id, state.to_db()],
             )
             .context("Failed to insert receipt into DB")?;
         Ok(())
     }
 
+    pub fn set_send_state(&mut self, message_id: &ChatMessageId, state: SendState) -> Result<()> {
+        let updated = self
+            .connection
+            .execute(
+                "UPDATE pending_messages SET state = ?2 WHERE message_id = ?1",
+                params![message_id.msg_id, state.to_db()],
+            )
+            .context("Failed to update message send state")?;
+
+        if updated == 0 {
+            bail!("Message {} is not pending delivery", message_id);
+        }
+
+        Ok(())
+    }
+
+    /// None if the message isn't waiting to be delivered
+    pub fn send_state(&self, message_id: &ChatMessageId) -> Result<Option<SendState>> {
+        let state: Option<i64> = self
+            .connection
+            .query_row(
+                "SELECT state FROM pending_messages WHERE message_id = ?1",
+                params![message_id.msg_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Failed to query message send state")?;
+
+        Ok(state.map(SendState::from_db))
+    }
+
     pub fn resolve_message(
         &mut self,
         _chat_handle: &ChatHandle,
@@ -645,7 +1145,8 @@ impl Storage {
         let mut statement = self
             .connection
             .prepare(
-                "SELECT messages.id, text_messages.message, text_messages.action \
+                "SELECT messages.id, text_messages.message, text_messages.action, \
+                messages.reply_to \
                 FROM pending_messages \
                 JOIN messages \
                 ON pending_messages.message_id = messages.id \
@@ -660,6 +1161,7 @@ impl Storage {
                 let id: i64 = row.get(0)?;
                 let message_str = row.get(1)?;
                 let action = row.get(2)?;
+                let reply_to: Option<i64> = row.get(3)?;
 
                 let message = match action {
                     true => Message::Action(message_str),
@@ -669,6 +1171,7 @@ impl Storage {
                 Ok(UnsentMessage {
                     id: ChatMessageId { msg_id: id },
                     message,
+                    reply_to: reply_to.map(|msg_id| ChatMessageId { msg_id }),
                 })
             })
             .context("Failed to query unresolved messages")?
@@ -678,48 +1181,106 @@ impl Storage {
 
         res
     }
-}
-
-fn initialize_db(connection: &mut Connection, self_pk: &PublicKey, self_name: &str) -> Result<()> {
-    let transaction = connection.transaction()?;
-
-    transaction
+    pub fn unresolved_message(
+        &mut self,
+        chat_handle: &ChatHandle,
+        message_id: &ChatMessageId,
+    ) -> Result<Option<UnsentMessage>> {
+        self.connection
+            .query_row(
+                "SELECT text_messages.message, text_messages.action, messages.reply_to \
+                FROM pending_messages \
+                JOIN messages \
+                ON pending_messages.message_id = messages.id \
+                JOIN text_messages \
+                ON messages.id = text_messages.message_id \
+                WHERE messages.chat_id = ?1 AND messages.id = ?2",
+                params![chat_handle.chat_id, message_id.msg_id],
+                |row| {
+                    let message_str = row.get(0)?;
+                    let action = row.get(1)?;
+                    let reply_to: Option<i64> = row.get(2)?;
+
+                    let message = match action {
+                        true => Message::Action(message_str),
+                        false => Message::Normal(message_str),
+                    };
+
+                    Ok(UnsentMessage {
+                        id: *message_id,
+                        message,
+                        reply_to: reply_to.map(|msg_id| ChatMessageId { msg_id }),
+                    })
+                },
+            )
+            .optional()
+            .context("Failed to query unresolved message")
+    }
+}
+
+/// Foreign keys are a per connection setting that's off by default, and
+/// setting it does nothing inside a transaction. Has to run on every
+/// connection before anything else touches it
+fn enable_foreign_keys(connection: &Connection) -> Result<()> {
+    connection
         .execute("PRAGMA foreign_keys = ON", [])
         .context("Failed to enable foreign key support")?;
 
+    Ok(())
+}
+
+const FRIENDS_COLUMNS: &str = "\
+    id INTEGER PRIMARY KEY, \
+    user_id INTEGER NOT NULL, \
+    chat_id INTEGER NOT NULL, \
+    FOREIGN KEY (user_id) REFERENCES users(id), \
+    FOREIGN KEY (chat_id) REFERENCES chats(id)";
+
+fn initialize_db(connection: &mut Connection, self_pk: &PublicKey, self_name: &str) -> Result<()> {
+    let transaction = connection.transaction()?;
+
     // Create a chat id table that acts solely to link messages to
     // friends/groups
     transaction
         .execute(
             "CREATE TABLE IF NOT EXISTS chats (\
-            id INTEGER PRIMARY KEY)",
+            id INTEGER PRIMARY KEY, \
+            last_read TEXT, \
+            muted BOOL NOT NULL DEFAULT 0)",
             [],
         )
         .context("Failed to create chats table")?;
 
+    // Added after the chats table was first released
+    add_column_if_missing(&transaction, "chats", "last_read", "TEXT")?;
+    add_column_if_missing(&transaction, "chats", "muted", "BOOL NOT NULL DEFAULT 0")?;
+
     transaction
         .execute(
             "CREATE TABLE IF NOT EXISTS users (\
             id INTEGER PRIMARY KEY, \
             public_key BLOB NOT NULL UNIQUE,\
-            name TEXT)",
+            name TEXT, \
+            verified BOOL NOT NULL DEFAULT 0, \
+            tox_id BLOB)",
             [],
         )
         .context("Failed to create users table")?;
 
+    // Added after the users table was first released
+    add_column_if_missing(&transaction, "users", "verified", "BOOL NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&transaction, "users", "tox_id", "BLOB")?;
+
     // Friends is split from users since we know groups will be coming in later
     transaction
         .execute(
-            "CREATE TABLE IF NOT EXISTS friends (\
-            id INTEGER PRIMARY KEY, \
-            user_id INTEGER NOT NULL, \
-            chat_id INTEGER NOT NULL, \
-            FOREIGN KEY (user_id) REFERENCES users(id), \
-            FOREIGN KEY (chat_id) REFERENCES chat_id(id))",
+            &format!("CREATE TABLE IF NOT EXISTS friends ({})", FRIENDS_COLUMNS),
             [],
         )
         .context("Failed to create friends table")?;
 
+    fix_friends_chat_foreign_key(&transaction)?;
+
     transaction
         .execute(
             "CREATE TABLE IF NOT EXISTS messages (\
@@ -727,12 +1288,22 @@ fn initialize_db(connection: &mut Connection, self_pk: &PublicKey, self_name: &s
             chat_id INTEGER NOT NULL, \
             sender_id INTEGER NOT NULL, \
             timestamp TEXT NOT NULL, \
+            reply_to INTEGER, \
             FOREIGN KEY (chat_id) REFERENCES chats(id), \
-            FOREIGN KEY (sender_id) REFERENCES users(id))",
+            FOREIGN KEY (sender_id) REFERENCES users(id), \
+            FOREIGN KEY (reply_to) REFERENCES messages(id))",
             [],
         )
         .context("Failed to create messages table")?;
 
+    // Added after the messages table was first released
+    add_column_if_missing(
+        &transaction,
+        "messages",
+        "reply_to",
+        "INTEGER REFERENCES messages(id)",
+    )?;
+
     // Text messages are separate from messages since we know that file
     // transfers are incoming
     transaction
@@ -754,11 +1325,20 @@ fn initialize_db(connection: &mut Connection, self_pk: &PublicKey, self_name: &s
             id INTEGER PRIMARY KEY, \
             message_id INTEGER NOT NULL, \
             receipt_id INTEGER, \
+            state INTEGER NOT NULL DEFAULT 0, \
             FOREIGN KEY (message_id) REFERENCES messages(id))",
             [],
         )
         .context("Failed to create pending_messages table")?;
 
+    // Added after the pending_messages table was first released
+    add_column_if_missing(
+        &transaction,
+        "pending_messages",
+        "state",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+
     transaction
         .execute(
             "CREATE TABLE IF NOT EXISTS pending_friends (\
@@ -779,6 +1359,16 @@ fn initialize_db(connection: &mut Connection, self_pk: &PublicKey, self_name: &s
         )
         .context("Failed to create blocked users table")?;
 
+    // Single row of per account preferences that need to survive a restart
+    transaction
+        .execute(
+            "CREATE TABLE IF NOT EXISTS account_settings ( \
+            id INTEGER PRIMARY KEY, \
+            auto_reply TEXT)",
+            [],
+        )
+        .context("Failed to create account settings table")?;
+
     let public_key = transaction
         .query_row(
             "SELECT public_key FROM users WHERE id = ?1",
@@ -799,11 +1389,14 @@ fn initialize_db(connection: &mut Connection, self_pk: &PublicKey, self_name: &s
 
     // NOTE: We insert our name into the DB, but we never actually read it back.
     // We might as well populate it correctly in case we want it in the future
-    // though
+    // though. INSERT OR REPLACE would delete and re-create the row, and with
+    // foreign keys enforced that delete fails as soon as anything references
+    // our user (every message we sent)
     transaction
         .execute(
-            "INSERT OR REPLACE INTO users (id, public_key, name) \
-            VALUES (?1, ?2, ?3)",
+            "INSERT INTO users (id, public_key, name) \
+            VALUES (?1, ?2, ?3) \
+            ON CONFLICT(id) DO UPDATE SET name = excluded.name",
             params![SELF_USER_ID, self_pk.as_bytes(), self_name],
         )
         .context("Failed to update self info")?;
@@ -815,10 +1408,94 @@ fn initialize_db(connection: &mut Connection, self_pk: &PublicKey, self_name: &s
     Ok(())
 }
 
+fn parse_tox_id(tox_id_bytes: Option<Vec<u8>>) -> Result<Option<ToxId>> {
+    tox_id_bytes
+        .map(|bytes| ToxId::from_bytes(bytes).context("Failed to parse tox id"))
+        .transpose()
+}
+
+/// The friends table used to be created with its chat_id pointing at a
+/// table called chat_id instead of chats. SQLite can't change a foreign key
+/// in place, so DBs created back then get the table rebuilt
+fn fix_friends_chat_foreign_key(transaction: &Transaction) -> Result<()> {
+    let mut statement = transaction
+        .prepare("PRAGMA foreign_key_list(friends)")
+        .context("Failed to prepare friends foreign key query")?;
+
+    let broken = statement
+        .query_map([], |row| row.get::<_, String>(2))
+        .context("Failed to query friends foreign keys")?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("Failed to read friends foreign keys")?
+        .iter()
+        .any(|table| table == "chat_id");
+
+    if !broken {
+        return Ok(());
+    }
+
+    transaction
+        .execute(
+            &format!("CREATE TABLE friends_new ({})", FRIENDS_COLUMNS),
+            [],
+        )
+        .context("Failed to create replacement friends table")?;
+
+    transaction
+        .execute(
+            "INSERT INTO friends_new (id, user_id, chat_id) \
+            SELECT id, user_id, chat_id FROM friends",
+            [],
+        )
+        .context("Failed to copy friends")?;
+
+    transaction
+        .execute("DROP TABLE friends", [])
+        .context("Failed to drop old friends table")?;
+
+    transaction
+        .execute("ALTER TABLE friends_new RENAME TO friends", [])
+        .context("Failed to replace friends table")?;
+
+    Ok(())
+}
+
+fn add_column_if_missing(
+    transaction: &Transaction,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let mut statement = transaction
+        .prepare(&format!("PRAGMA table_info({})", table))
+        .with_context(|| format!("Failed to prepare {} column query", table))?;
+
+    let exists = statement
+        .query_map([], |row| row.get::<_, String>(1))
+        .with_context(|| format!("Failed to query {} columns", table))?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .with_context(|| format!("Failed to read {} columns", table))?
+        .iter()
+        .any(|name| name == column);
+
+    if !exists {
+        transaction
+            .execute(
+                &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+                [],
+            )
+            .with_context(|| format!("Failed to add {} to {}", column, table))?;
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use chrono::TimeZone;
+
     #[test]
     fn add_friend() -> Result<(), Error> {
         let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
@@ -1086,6 +1763,618 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn timestamp_precision_preserved() -> Result<()> {
+        // Timestamps are shown next to messages and compared against read
+        // markers in milliseconds, make sure nothing on the way truncates them
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let mut storage = Storage::open_ram(&selfpk, "self")?;
+        let friend_pk = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+        let friend = storage.add_friend(friend_pk, "friend".to_string())?;
+
+        let pushed = storage.push_message(
+            friend.chat_handle(),
+            storage.self_user_handle(),
+            Message::Normal("msg".into()),
+        )?;
+
+        let loaded = storage.load_messages(friend.chat_handle())?;
+        assert_eq!(loaded[0].timestamp(), pushed.timestamp());
+
+        let serialized = serde_json::to_string(&pushed)?;
+        let deserialized: ChatLogEntry = serde_json::from_str(&serialized)?;
+        assert_eq!(deserialized.timestamp(), pushed.timestamp());
+
+        let entry: ChatLogEntry = serde_json::from_value(serde_json::json!({
+            "id": { "msg_id": 1 },
+            "sender": { "user_id": 1 },
+            "message": { "Normal": "msg" },
+            "timestamp": "2021-07-01T00:00:00.123Z",
+            "complete": true,
+        }))?;
+        assert_eq!(entry.timestamp().timestamp_millis() % 1000, 123);
+        let round_tripped: ChatLogEntry = serde_json::from_str(&serde_json::to_string(&entry)?)?;
+        assert_eq!(round_tripped.timestamp(), entry.timestamp());
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_messages_before() -> Result<()> {
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let mut storage = Storage::open_ram(&selfpk, "self")?;
+        let self_user_handle = storage.self_user_handle();
+
+        let friend1 = storage.add_friend(
+            PublicKey::from_bytes(vec![1; PublicKey::SIZE])?,
+            "test1".to_string(),
+        )?;
+        let friend2 = storage.add_friend(
+            PublicKey::from_bytes(vec![2; PublicKey::SIZE])?,
+            "test2".to_string(),
+        )?;
+
+        let mut ids = Vec::new();
+        for i in 0..5 {
+            let entry = storage.push_message(
+                friend1.chat_handle(),
+                self_user_handle,
+                Message::Normal(i.to_string()),
+            )?;
+            ids.push(*entry.id());
+
+            // Interleave another chat to make sure it doesn't leak in
+            storage.push_message(
+                friend2.chat_handle(),
+                self_user_handle,
+                Message::Normal(i.to_string()),
+            )?;
+        }
+
+        let loaded_ids =
+            |messages: Vec<ChatLogEntry>| messages.iter().map(|m| *m.id()).collect::<Vec<_>>();
+
+        let page = storage.load_messages_before(friend1.chat_handle(), &ids[4], 2)?;
+        assert_eq!(loaded_ids(page), ids[2..4].to_vec());
+
+        let page = storage.load_messages_before(friend1.chat_handle(), &ids[2], 10)?;
+        assert_eq!(loaded_ids(page), ids[0..2].to_vec());
+
+        let page = storage.load_messages_before(friend1.chat_handle(), &ids[0], 10)?;
+        assert!(page.is_empty());
+
+        assert_eq!(
+            loaded_ids(storage.load_messages(friend1.chat_handle())?),
+            ids
+        );
+
+        assert_eq!(
+            loaded_ids(storage.load_recent_messages(friend1.chat_handle(), 3)?),
+            ids[2..].to_vec()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn replies() -> Result<()> {
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let mut storage = Storage::open_ram(&selfpk, "self")?;
+        let self_user_handle = storage.self_user_handle();
+
+        let friend1 = storage.add_friend(
+            PublicKey::from_bytes(vec![1; PublicKey::SIZE])?,
+            "test1".to_string(),
+        )?;
+        let friend2 = storage.add_friend(
+            PublicKey::from_bytes(vec![2; PublicKey::SIZE])?,
+            "test2".to_string(),
+        )?;
+
+        let question = storage.push_message(
+            friend1.chat_handle(),
+            *friend1.id(),
+            Message::Normal("lunch tomorrow?".into()),
+        )?;
+        let reply = storage.push_reply(
+            friend1.chat_handle(),
+            self_user_handle,
+            Message::Normal("sure".into()),
+            question.id(),
+        )?;
+        assert_eq!(reply.reply_to(), Some(question.id()));
+
+        // Can't reply across chats
+        assert!(storage
+            .push_reply(
+                friend2.chat_handle(),
+                self_user_handle,
+                Message::Normal("sure".into()),
+                question.id(),
+            )
+            .is_err());
+
+        let messages = storage.load_messages(friend1.chat_handle())?;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].reply_to(), None);
+        assert_eq!(messages[1].reply_to(), Some(question.id()));
+
+        assert_eq!(
+            storage.message(friend1.chat_handle(), question.id())?,
+            Message::Normal("lunch tomorrow?".into())
+        );
+        assert!(storage
+            .message(friend2.chat_handle(), question.id())
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_message_by_text() -> Result<()> {
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let mut storage = Storage::open_ram(&selfpk, "self")?;
+        let self_user_handle = storage.self_user_handle();
+
+        let friend = storage.add_friend(
+            PublicKey::from_bytes(vec![1; PublicKey::SIZE])?,
+            "test1".to_string(),
+        )?;
+        let chat = friend.chat_handle();
+
+        storage.push_message(chat, self_user_handle, Message::Normal("hello".into()))?;
+        let newest = storage.push_message(
+            chat,
+            self_user_handle,
+            Message::Normal("hello again".into()),
+        )?;
+
+        // Newest match wins, and quotes may be truncated
+        assert_eq!(
+            storage.find_message_by_text(chat, "hello")?,
+            Some(*newest.id())
+        );
+        assert_eq!(
+            storage.find_message_by_text(chat, "hello ag")?,
+            Some(*newest.id())
+        );
+        assert_eq!(storage.find_message_by_text(chat, "goodbye")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn friend_verified_persists() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("verified.db");
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let pk1 = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+        let pk2 = PublicKey::from_bytes(vec![2; PublicKey::SIZE])?;
+
+        {
+            let mut storage = Storage::open(&path, &selfpk, "self")?;
+            let friend1 = storage.add_friend(pk1.clone(), "name1".to_string())?;
+            storage.add_friend(pk2.clone(), "name2".to_string())?;
+
+            assert!(!friend1.verified());
+            storage.set_user_verified(friend1.id(), true)?;
+        }
+
+        let mut storage = Storage::open(&path, &selfpk, "self")?;
+        let friends = storage.friends()?;
+        let verified = |pk: &PublicKey| {
+            friends
+                .iter()
+                .find(|friend| friend.public_key() == pk)
+                .unwrap()
+                .verified()
+        };
+        assert!(verified(&pk1));
+        assert!(!verified(&pk2));
+
+        let friend1_id = *friends
+            .iter()
+            .find(|friend| *friend.public_key() == pk1)
+            .unwrap()
+            .id();
+        storage.set_user_verified(&friend1_id, false)?;
+        assert!(storage.friends()?.iter().all(|friend| !friend.verified()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn self_messages_survive_reopen() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("self.db");
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let friend_pk = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+
+        let chat = {
+            let mut storage = Storage::open(&path, &selfpk, "self")?;
+            let friend = storage.add_friend(friend_pk, "test1".to_string())?;
+            let self_user_handle = storage.self_user_handle();
+            storage.push_message(
+                friend.chat_handle(),
+                self_user_handle,
+                Message::Normal("mine".into()),
+            )?;
+            *friend.chat_handle()
+        };
+
+        // Opening again rewrites our name, it must not touch our messages
+        let mut storage = Storage::open(&path, &selfpk, "renamed")?;
+        let messages = storage.load_messages(&chat)?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(*messages[0].sender(), storage.self_user_handle());
+
+        let name: String = storage.connection.query_row(
+            "SELECT name FROM users WHERE id = ?1",
+            params![SELF_USER_ID],
+            |row| row.get(0),
+        )?;
+        assert_eq!(name, "renamed");
+
+        Ok(())
+    }
+
+    #[test]
+    fn chat_muted_persists() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("muted.db");
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let pk1 = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+        let pk2 = PublicKey::from_bytes(vec![2; PublicKey::SIZE])?;
+
+        let chat1 = {
+            let mut storage = Storage::open(&path, &selfpk, "self")?;
+            let friend1 = storage.add_friend(pk1.clone(), "name1".to_string())?;
+            storage.add_friend(pk2.clone(), "name2".to_string())?;
+
+            assert!(!friend1.muted());
+            storage.set_chat_muted(friend1.chat_handle(), true)?;
+            *friend1.chat_handle()
+        };
+
+        let mut storage = Storage::open(&path, &selfpk, "self")?;
+        let friends = storage.friends()?;
+        let muted = |pk: &PublicKey| {
+            friends
+                .iter()
+                .find(|friend| friend.public_key() == pk)
+                .unwrap()
+                .muted()
+        };
+        assert!(muted(&pk1));
+        assert!(!muted(&pk2));
+
+        storage.set_chat_muted(&chat1, false)?;
+        assert!(storage.friends()?.iter().all(|friend| !friend.muted()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_messages_around_time() -> Result<()> {
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let pk = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+        let mut storage = Storage::open_ram(&selfpk, "self")?;
+        let friend = storage.add_friend(pk, "name".to_string())?;
+        let chat = *friend.chat_handle();
+
+        let empty = storage.load_messages_around(&chat, &Utc::now(), 2, 2)?;
+        assert!(empty.is_empty());
+
+        let mut ids = Vec::new();
+        let mut times = Vec::new();
+        for i in 0..10 {
+            let entry =
+                storage.push_message(&chat, *friend.id(), Message::Normal(format!("msg{}", i)))?;
+            ids.push(*entry.id());
+            times.push(*entry.timestamp());
+            // Keep every message's timestamp distinct
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+
+        let loaded_ids = |messages: Vec<ChatLogEntry>| -> Vec<ChatMessageId> {
+            messages.iter().map(|entry| *entry.id()).collect()
+        };
+
+        let around = storage.load_messages_around(&chat, &times[5], 2, 3)?;
+        assert_eq!(loaded_ids(around), ids[3..8].to_vec());
+
+        // Not enough history on either side
+        let around = storage.load_messages_around(&chat, &times[1], 3, 20)?;
+        assert_eq!(loaded_ids(around), ids.clone());
+
+        // Nothing after the time, fall back to the newest messages
+        let around = storage.load_messages_around(&chat, &Utc::now(), 2, 2)?;
+        assert_eq!(loaded_ids(around), ids[8..].to_vec());
+
+        // Nothing before the time
+        let around = storage.load_messages_around(&chat, &Utc.timestamp(0, 0), 2, 2)?;
+        assert_eq!(loaded_ids(around), ids[..2].to_vec());
+
+        // Limits too big for sqlite mean everything rather than wrapping
+        let around = storage.load_messages_around(&chat, &times[5], usize::MAX, usize::MAX)?;
+        assert_eq!(loaded_ids(around), ids.clone());
+        let around = storage.load_messages_around(&chat, &times[5], 1, usize::MAX)?;
+        assert_eq!(loaded_ids(around), ids[4..].to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn crossed_friend_requests_single_friend() -> Result<()> {
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let pk = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+        let mut storage = Storage::open_ram(&selfpk, "self")?;
+
+        // Their request arrives twice, then we request them back
+        let incoming = storage.add_pending_friend(pk.clone())?;
+        let repeated = storage.add_pending_friend(pk.clone())?;
+        assert_eq!(incoming.id(), repeated.id());
+        assert_eq!(incoming.chat_handle(), repeated.chat_handle());
+
+        let outgoing = storage.add_friend(pk.clone(), "name".to_string())?;
+        assert_eq!(incoming.id(), outgoing.id());
+        assert_eq!(incoming.chat_handle(), outgoing.chat_handle());
+
+        storage.resolve_pending_friend_request(incoming.id())?;
+
+        let friends = storage.friends()?;
+        assert_eq!(friends.len(), 1);
+        assert_eq!(*friends[0].public_key(), pk);
+        assert_ne!(*friends[0].status(), Status::Pending);
+
+        Ok(())
+    }
+
+    #[test]
+    fn auto_reply_persisted() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("account.db");
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+
+        let mut storage = Storage::open(&path, &selfpk, "self")?;
+        assert_eq!(storage.auto_reply()?, None);
+
+        storage.set_auto_reply(Some("away"))?;
+        assert_eq!(storage.auto_reply()?.as_deref(), Some("away"));
+
+        drop(storage);
+        let mut storage = Storage::open(&path, &selfpk, "self")?;
+        assert_eq!(storage.auto_reply()?.as_deref(), Some("away"));
+
+        storage.set_auto_reply(None)?;
+        assert_eq!(storage.auto_reply()?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unread_count_skips_own_messages() -> Result<()> {
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let pk = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+        let mut storage = Storage::open_ram(&selfpk, "self")?;
+        let friend = storage.add_friend(pk, "name".to_string())?;
+        let chat = *friend.chat_handle();
+        let self_user = storage.self_user_handle();
+
+        let push = |storage: &mut Storage, sender: UserHandle| {
+            storage.push_message(&chat, sender, Message::Normal("msg".to_string()))
+        };
+
+        push(&mut storage, *friend.id())?;
+        let read = push(&mut storage, self_user)?;
+        // Keep the messages after the read time strictly newer
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        push(&mut storage, *friend.id())?;
+        push(&mut storage, self_user)?;
+        push(&mut storage, *friend.id())?;
+
+        assert_eq!(storage.unread_count(&chat, Utc.timestamp(0, 0))?, 3);
+        assert_eq!(storage.unread_count(&chat, *read.timestamp())?, 2);
+        assert_eq!(storage.unread_count(&chat, Utc::now())?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn chat_read_time_persists() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("read_time.db");
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let pk1 = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+        let pk2 = PublicKey::from_bytes(vec![2; PublicKey::SIZE])?;
+        let read_time = Utc.timestamp_millis(1_625_097_600_123);
+
+        let (chat1, chat2) = {
+            let mut storage = Storage::open(&path, &selfpk, "self")?;
+            let friend1 = storage.add_friend(pk1, "name1".to_string())?;
+            let friend2 = storage.add_friend(pk2, "name2".to_string())?;
+
+            assert_eq!(storage.chat_read_time(friend1.chat_handle())?, None);
+            storage.set_chat_read_time(friend1.chat_handle(), &read_time)?;
+
+            (*friend1.chat_handle(), *friend2.chat_handle())
+        };
+
+        let storage = Storage::open(&path, &selfpk, "self")?;
+        assert_eq!(storage.chat_read_time(&chat1)?, Some(read_time));
+        assert_eq!(storage.chat_read_time(&chat2)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tox_id_kept_after_block() -> Result<()> {
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let pk1 = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+        let pk2 = PublicKey::from_bytes(vec![2; PublicKey::SIZE])?;
+        let tox_id = ToxId::from_bytes(vec![1; ToxId::SIZE])?;
+
+        let mut storage = Storage::open_ram(&selfpk, "self")?;
+        let friend1 = storage.add_friend(pk1, "name1".to_string())?;
+        let friend2 = storage.add_friend(pk2, "name2".to_string())?;
+
+        storage.set_user_tox_id(friend1.id(), &tox_id)?;
+
+        assert_eq!(storage.get_user(friend1.id())?.tox_id(), Some(&tox_id));
+        assert_eq!(storage.get_user(friend2.id())?.tox_id(), None);
+
+        // Removed friends are still around to be added back
+        storage.block_user(friend1.id())?;
+        let blocked = storage.blocked_users()?;
+        assert_eq!(blocked.len(), 1);
+        assert_eq!(blocked[0].tox_id(), Some(&tox_id));
+
+        Ok(())
+    }
+
+    #[test]
+    fn friends_chat_foreign_key_fixed_in_old_db() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("old.db");
+        let pk = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+
+        {
+            let connection = Connection::open(&path)?;
+            connection.execute("CREATE TABLE chats (id INTEGER PRIMARY KEY)", [])?;
+            connection.execute(
+                "CREATE TABLE users (\
+                id INTEGER PRIMARY KEY, \
+                public_key BLOB NOT NULL UNIQUE,\
+                name TEXT)",
+                [],
+            )?;
+            connection.execute(
+                "CREATE TABLE friends (\
+                id INTEGER PRIMARY KEY, \
+                user_id INTEGER NOT NULL, \
+                chat_id INTEGER NOT NULL, \
+                FOREIGN KEY (user_id) REFERENCES users(id), \
+                FOREIGN KEY (chat_id) REFERENCES chat_id(id))",
+                [],
+            )?;
+
+            // A friend from back then, rebuilding the table must keep them
+            connection.execute("INSERT INTO chats (id) VALUES (5)", [])?;
+            connection.execute(
+                "INSERT INTO users (id, public_key, name) VALUES (2, ?1, 'test1')",
+                params![pk.as_bytes()],
+            )?;
+            connection.execute(
+                "INSERT INTO friends (id, user_id, chat_id) VALUES (3, 2, 5)",
+                [],
+            )?;
+        }
+
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let storage = Storage::open(&path, &selfpk, "self")?;
+
+        let friends = storage.friends()?;
+        assert_eq!(friends.len(), 1);
+        let friend = &friends[0];
+        assert_eq!(friend.id().id(), 2);
+        assert_eq!(friend.chat_handle().id(), 5);
+        assert_eq!(friend.public_key(), &pk);
+        assert_eq!(friend.name(), "test1");
+
+        let friend_row_id: i64 = storage.connection.query_row(
+            "SELECT id FROM friends WHERE user_id = 2",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(friend_row_id, 3);
+
+        // Reopening must not rebuild the table again or lose friends
+        drop(storage);
+        let storage = Storage::open(&path, &selfpk, "self")?;
+        assert_eq!(storage.friends()?.len(), 1);
+
+        storage.connection.execute(
+            "INSERT INTO friends (user_id, chat_id) VALUES (?1, ?2)",
+            params![2, 5],
+        )?;
+        assert!(storage
+            .connection
+            .execute(
+                "INSERT INTO friends (user_id, chat_id) VALUES (?1, ?2)",
+                params![2, 105],
+            )
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn dangling_chat_rejected() -> Result<()> {
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let mut storage = Storage::open_ram(&selfpk, "self")?;
+        let friend = storage.add_friend(
+            PublicKey::from_bytes(vec![1; PublicKey::SIZE])?,
+            "test1".to_string(),
+        )?;
+
+        let missing_chat = ChatHandle {
+            chat_id: friend.chat_handle().id() + 100,
+        };
+        let self_user_handle = storage.self_user_handle();
+        assert!(storage
+            .push_message(
+                &missing_chat,
+                self_user_handle,
+                Message::Normal("lost".into())
+            )
+            .is_err());
+        assert!(storage.load_messages(&missing_chat)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reply_column_added_to_old_db() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("old.db");
+
+        {
+            let connection = Connection::open(&path)?;
+            connection.execute(
+                "CREATE TABLE messages (\
+                id INTEGER PRIMARY KEY, \
+                chat_id INTEGER NOT NULL, \
+                sender_id INTEGER NOT NULL, \
+                timestamp TEXT NOT NULL)",
+                [],
+            )?;
+        }
+
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let mut storage = Storage::open(&path, &selfpk, "self")?;
+        let friend = storage.add_friend(
+            PublicKey::from_bytes(vec![1; PublicKey::SIZE])?,
+            "test1".to_string(),
+        )?;
+
+        let first = storage.push_message(
+            friend.chat_handle(),
+            *friend.id(),
+            Message::Normal("first".into()),
+        )?;
+        storage.push_reply(
+            friend.chat_handle(),
+            *friend.id(),
+            Message::Normal("second".into()),
+            first.id(),
+        )?;
+
+        let messages = storage.load_messages(friend.chat_handle())?;
+        assert_eq!(messages[1].reply_to(), Some(first.id()));
+
+        Ok(())
+    }
+
     #[test]
     fn pending_messages() -> Result<(), Error> {
         let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
@@ -1117,19 +2406,19 @@ mod tests {
             self_user_handle,
             Message::Normal("unresolved_msg1".into()),
         )?;
-        storage.add_unresolved_message(unresolved_msg1.id())?;
+        storage.add_unresolved_message(unresolved_msg1.id(), SendState::Unsent)?;
         let unresolved_msg2 = storage.push_message(
             friend.chat_handle(),
             self_user_handle,
             Message::Normal("unresolved_msg2".into()),
         )?;
-        storage.add_unresolved_message(unresolved_msg2.id())?;
+        storage.add_unresolved_message(unresolved_msg2.id(), SendState::Unsent)?;
         let unresolved_msg3 = storage.push_message(
             friend.chat_handle(),
             self_user_handle,
             Message::Normal("unresolved_msg3".into()),
         )?;
-        storage.add_unresolved_message(unresolved_msg3.id())?;
+        storage.add_unresolved_message(unresolved_msg3.id(), SendState::Unsent)?;
 
         // Ensure that unresolved messages in history are correct
         let unresolved_messages = storage.unresovled_messages(friend.chat_handle())?;
@@ -1158,6 +2447,10 @@ mod tests {
         assert_eq!(loaded_messages[3].complete(), false);
         assert_eq!(loaded_messages[4].complete(), false);
         assert_eq!(loaded_messages[5].complete(), false);
+        assert_eq!(
+            storage.newest_delivered_message(friend.chat_handle())?,
+            Some(*loaded_messages[2].id())
+        );
 
         // Resolve some messages (out of order)
         storage.resolve_message(friend.chat_handle(), unresolved_msg1.id())?;
@@ -1175,6 +2468,161 @@ mod tests {
         assert_eq!(loaded_messages[3].complete(), true);
         assert_eq!(loaded_messages[4].complete(), false);
         assert_eq!(loaded_messages[5].complete(), true);
+        assert_eq!(
+            storage.newest_delivered_message(friend.chat_handle())?,
+            Some(*unresolved_msg3.id())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn send_state_transitions() -> Result<()> {
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let mut storage = Storage::open_ram(&selfpk, "self")?;
+
+        let friend_pk = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+        let friend = storage.add_friend(friend_pk, "test1".to_string())?;
+        let chat = *friend.chat_handle();
+        let self_user_handle = storage.self_user_handle();
+
+        let entry = storage.push_message(&chat, self_user_handle, Message::Normal("msg".into()))?;
+        assert_eq!(storage.send_state(entry.id())?, None);
+
+        // Friend offline
+        storage.add_unresolved_message(entry.id(), SendState::Unsent)?;
+        assert_eq!(storage.send_state(entry.id())?, Some(SendState::Unsent));
+
+        // Flushed when they come online, but toxcore refused it
+        storage.set_send_state(entry.id(), SendState::Failed)?;
+        let loaded = storage.load_messages(&chat)?;
+        assert_eq!(loaded[0].send_state(), Some(SendState::Failed));
+        assert!(!loaded[0].complete());
+
+        // Retried successfully
+        storage.set_send_state(entry.id(), SendState::Sent)?;
+        assert_eq!(storage.send_state(entry.id())?, Some(SendState::Sent));
+
+        // Receipt arrived
+        storage.resolve_message(&chat, entry.id())?;
+        assert_eq!(storage.send_state(entry.id())?, None);
+        let loaded = storage.load_messages(&chat)?;
+        assert_eq!(loaded[0].send_state(), None);
+        assert!(loaded[0].complete());
+
+        // Nothing to update once delivered
+        assert!(storage
+            .set_send_state(entry.id(), SendState::Failed)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn send_state_column_added_to_old_db() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("old.db");
+
+        {
+            let connection = Connection::open(&path)?;
+            connection.execute(
+                "CREATE TABLE pending_messages (\
+                id INTEGER PRIMARY KEY, \
+                message_id INTEGER NOT NULL, \
+                receipt_id INTEGER)",
+                [],
+            )?;
+            connection.execute("INSERT INTO pending_messages (message_id) VALUES (1)", [])?;
+        }
+
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let storage = Storage::open(&path, &selfpk, "self")?;
+
+        // Rows from before states were tracked are treated as never sent
+        let message_id = ChatMessageId { msg_id: 1 };
+        assert_eq!(storage.send_state(&message_id)?, Some(SendState::Unsent));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unresolved_message_lookup() -> Result<()> {
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        let mut storage = Storage::open_ram(&selfpk, "self")?;
+
+        let friend_pk = PublicKey::from_bytes(vec![1; PublicKey::SIZE])?;
+        let friend = storage.add_friend(friend_pk, "test1".to_string())?;
+        let other_friend_pk = PublicKey::from_bytes(vec![2; PublicKey::SIZE])?;
+        let other_friend = storage.add_friend(other_friend_pk, "test2".to_string())?;
+
+        let self_user_handle = storage.self_user_handle();
+
+        let resolved = storage.push_message(
+            friend.chat_handle(),
+            self_user_handle,
+            Message::Normal("resolved".into()),
+        )?;
+
+        let unresolved = storage.push_message(
+            friend.chat_handle(),
+            self_user_handle,
+            Message::Action("unresolved".into()),
+        )?;
+        storage.add_unresolved_message(unresolved.id(), SendState::Unsent)?;
+
+        let found = storage
+            .unresolved_message(friend.chat_handle(), unresolved.id())?
+            .unwrap();
+        assert_eq!(found.id(), unresolved.id());
+        assert_eq!(*found.message(), Message::Action("unresolved".into()));
+
+        // Delivered messages and messages from other chats should not be
+        // eligible for resending
+        assert!(storage
+            .unresolved_message(friend.chat_handle(), resolved.id())?
+            .is_none());
+        assert!(storage
+            .unresolved_message(other_friend.chat_handle(), unresolved.id())?
+            .is_none());
+
+        // Looking up a message should not duplicate it in the chat log
+        assert_eq!(storage.load_messages(friend.chat_handle())?.len(), 2);
+
+        storage.resolve_message(friend.chat_handle(), unresolved.id())?;
+        assert!(storage
+            .unresolved_message(friend.chat_handle(), unresolved.id())?
+            .is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_db_rejects_other_self_key() -> Result<()> {
+        // Imported accounts rely on this to avoid adopting another
+        // identity's history
+        let db_file = tempfile::NamedTempFile::new()?;
+
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        Storage::open(db_file.path(), &selfpk, "self")?;
+
+        // Re-opening with the same identity is fine
+        Storage::open(db_file.path(), &selfpk, "self")?;
+
+        let otherpk = PublicKey::from_bytes(vec![0xfe; PublicKey::SIZE])?;
+        assert!(Storage::open(db_file.path(), &otherpk, "other").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_owner_public_key() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db_path = dir.path().join("account.db");
+        assert_eq!(Storage::owner_public_key(&db_path)?, None);
+
+        let selfpk = PublicKey::from_bytes(vec![0xff; PublicKey::SIZE])?;
+        drop(Storage::open(&db_path, &selfpk, "self")?);
+        assert_eq!(Storage::owner_public_key(&db_path)?, Some(selfpk));
 
         Ok(())
     }
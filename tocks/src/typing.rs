@@ -0,0 +1,92 @@
+use crate::storage::ChatHandle;
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// How long after the last keystroke we keep telling the peer we're typing
+pub(crate) const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tracks the chats we've told toxcore we're typing in. Each method returns
+/// whether the peer needs to be told about a change, so we only hit toxcore
+/// when the typing state actually flips
+#[derive(Default)]
+pub(crate) struct TypingState {
+    deadlines: HashMap<ChatHandle, Instant>,
+}
+
+impl TypingState {
+    /// Returns true if we just started typing in chat
+    pub fn keystroke(&mut self, chat: ChatHandle, now: Instant) -> bool {
+        self.deadlines.insert(chat, now + TYPING_TIMEOUT).is_none()
+    }
+
+    /// Returns true if we were typing in chat
+    pub fn stop(&mut self, chat: &ChatHandle) -> bool {
+        self.deadlines.remove(chat).is_some()
+    }
+
+    /// Removes and returns the chats that have gone quiet
+    pub fn expire(&mut self, now: Instant) -> Vec<ChatHandle> {
+        let expired: Vec<ChatHandle> = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(chat, _)| *chat)
+            .collect();
+
+        for chat in &expired {
+            self.deadlines.remove(chat);
+        }
+
+        expired
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.values().min().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_stops_typing() {
+        let mut state = TypingState::default();
+        let chat = ChatHandle::from(1);
+        let now = Instant::now();
+
+        assert!(state.keystroke(chat, now));
+        // Further keystrokes only push the deadline out
+        assert!(!state.keystroke(chat, now + Duration::from_secs(1)));
+
+        // Sending the message has to tell the peer we stopped, once
+        assert!(state.stop(&chat));
+        assert!(!state.stop(&chat));
+        assert!(state.next_deadline().is_none());
+    }
+
+    #[test]
+    fn test_typing_expires() {
+        let mut state = TypingState::default();
+        let chat1 = ChatHandle::from(1);
+        let chat2 = ChatHandle::from(2);
+        let now = Instant::now();
+
+        state.keystroke(chat1, now);
+        state.keystroke(chat2, now + Duration::from_secs(2));
+        assert_eq!(state.next_deadline(), Some(now + TYPING_TIMEOUT));
+
+        assert!(state.expire(now + Duration::from_secs(1)).is_empty());
+        assert_eq!(state.expire(now + TYPING_TIMEOUT), vec![chat1]);
+        assert_eq!(
+            state.next_deadline(),
+            Some(now + Duration::from_secs(2) + TYPING_TIMEOUT)
+        );
+
+        // Typing again after expiry is a fresh start
+        assert!(state.keystroke(chat1, now + TYPING_TIMEOUT));
+    }
+}
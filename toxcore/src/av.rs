@@ -74,9 +74,11 @@ pub(crate) struct CallData {
 
 /// Control signal sent from internal call objects to toxcore instance
 pub(crate) enum CallControl {
-    Accepted,
+    Accepted(u32 /* audio bit rate */),
     SendAudio(AudioFrame),
     Reject,
+    Pause,
+    Resume,
 }
 
 /// Helper struct for IncomingCall. This allows us to implement drop but still
@@ -99,7 +101,7 @@ struct IncomingCallInner {
 ///         Event::IncomingCall(call) => {
 ///             let friend = call.friend();
 ///             println!("Receiving call from {}", friend.name());
-///             let call = call.accept();
+///             let call = call.accept(64);
 ///             // ...
 ///         }
 ///         _ => { /* ... */ }
@@ -152,7 +154,7 @@ impl IncomingCall {
     ///         }
     ///         ui_event = ui_channel.next().fuse() => {
     ///             match ui_event {
-    ///                 AcceptCall => return incoming_call.accept(),
+    ///                 AcceptCall => return incoming_call.accept(64),
     ///                 _ => { /* ... */ },
     ///             }
     ///         }
@@ -170,8 +172,9 @@ impl IncomingCall {
             .map(|_| ())
     }
 
-    /// Accepts the incoming call, consuming it and returning an [`ActiveCall`]
-    pub fn accept(mut self) -> Result<ActiveCall, ExpiredError> {
+    /// Accepts the incoming call, consuming it and returning an [`ActiveCall`].
+    /// audio_bit_rate is in kbit/s
+    pub fn accept(mut self, audio_bit_rate: u32) -> Result<ActiveCall, ExpiredError> {
         if self.check_hangup() {
             return Err(ExpiredError);
         }
@@ -180,7 +183,7 @@ impl IncomingCall {
 
         inner
             .control
-            .unbounded_send(CallControl::Accepted)
+            .unbounded_send(CallControl::Accepted(audio_bit_rate))
             .map_err(|_| ExpiredError)?;
 
         Ok(ActiveCall::new(
@@ -227,7 +230,7 @@ impl Drop for IncomingCall {
 /// # use toxcore::av::{ActiveCall, CallEvent, IncomingCall};
 /// # use futures::prelude::*;
 /// # async fn service_call(incoming_call: IncomingCall)  -> Result<(), Box<dyn std::error::Error>> {
-///     let mut call = incoming_call.accept()?;
+///     let mut call = incoming_call.accept(64)?;
 ///     while let Some(event) = call.next().await {
 ///         match event {
 ///             CallEvent::AudioReceived(frame) => { /* dispatch audio frame */ },
@@ -241,6 +244,9 @@ pub struct ActiveCall {
     control: UnboundedSender<CallControl>,
     event_channel: UnboundedReceiver<CallEvent>,
     data: Arc<RwLock<CallData>>,
+    // Set once toxcore has been told to end the call, so drop doesn't tell
+    // it again
+    ended: bool,
 }
 
 impl ActiveCall {
@@ -253,6 +259,7 @@ impl ActiveCall {
             control,
             event_channel,
             data,
+            ended: false,
         }
     }
 
@@ -261,6 +268,34 @@ impl ActiveCall {
         self.data.read().unwrap().call_state
     }
 
+    /// Hangs up. If the peer hasn't answered yet this cancels the call so it
+    /// stops ringing on their end. Dropping the handle does the same
+    pub fn cancel(mut self) {
+        self.end();
+    }
+
+    fn end(&mut self) {
+        if !self.ended {
+            let _ = self.control.unbounded_send(CallControl::Reject);
+            self.ended = true;
+        }
+    }
+
+    /// Puts the call on hold, or takes it off hold. Only valid once the call
+    /// is active
+    pub fn set_paused(&self, paused: bool) -> Result<(), ExpiredError> {
+        let control = if paused {
+            CallControl::Pause
+        } else {
+            CallControl::Resume
+        };
+
+        self.control
+            .unbounded_send(control)
+            .map_err(|_| ExpiredError)?;
+        Ok(())
+    }
+
     /// Sends an audio frame to the peer
     pub fn send_audio_frame(&self, frame: AudioFrame) -> Result<(), ExpiredError> {
         self.control
@@ -282,6 +317,6 @@ impl Stream for ActiveCall {
 
 impl Drop for ActiveCall {
     fn drop(&mut self) {
-        let _ = self.control.unbounded_send(CallControl::Reject);
+        self.end();
     }
 }
@@ -252,6 +252,7 @@ mod tests {
         _callback_friend_connection_status_ctx:
             sys::__tox_callback_friend_connection_status::Context,
         _callback_friend_name_ctx: sys::__tox_callback_friend_name::Context,
+        _callback_friend_lossless_packet_ctx: sys::__tox_callback_friend_lossless_packet::Context,
         _kill_ctx: sys::__tox_kill::Context,
         _av_kill_ctx: sys::__toxav_kill::Context,
         _new_ctx: sys::__tox_new::Context,
@@ -280,6 +281,12 @@ mod tests {
         let callback_friend_name_ctx = sys::tox_callback_friend_name_context();
         callback_friend_name_ctx.expect().return_const(());
 
+        let callback_friend_lossless_packet_ctx =
+            sys::tox_callback_friend_lossless_packet_context();
+        callback_friend_lossless_packet_ctx
+            .expect()
+            .return_const(());
+
         let kill_ctx = sys::tox_kill_context();
         kill_ctx.expect().return_const(());
 
@@ -314,6 +321,7 @@ mod tests {
             _callback_friend_status_ctx: callback_friend_status_ctx,
             _callback_friend_connection_status_ctx: callback_friend_connection_status_ctx,
             _callback_friend_name_ctx: callback_friend_name_ctx,
+            _callback_friend_lossless_packet_ctx: callback_friend_lossless_packet_ctx,
             _kill_ctx: kill_ctx,
             _av_kill_ctx: av_kill_ctx,
             _new_ctx: new_ctx,
@@ -4,56 +4,86 @@ use toxcore_sys::*;
 
 pub struct PassKey {
     key: *mut Tox_Pass_Key,
+    salt: Vec<u8>,
 }
 
 unsafe impl Send for PassKey {}
 
 impl PassKey {
+    /// Derives a key with a freshly generated salt
     pub fn new(passphrase: &str) -> Result<PassKey, KeyDerivationError> {
         let mut err = TOX_ERR_KEY_DERIVATION_OK;
-        unsafe {
-            let key =
-                sys::tox_pass_key_derive(passphrase.as_ptr(), passphrase.len() as u64, &mut err);
-
-            if err != TOX_ERR_KEY_DERIVATION_OK {
-                return Err(KeyDerivationError);
-            }
+        let key = unsafe {
+            sys::tox_pass_key_derive(passphrase.as_ptr(), passphrase.len() as u64, &mut err)
+        };
 
-            Ok(PassKey { key })
+        if err != TOX_ERR_KEY_DERIVATION_OK {
+            return Err(KeyDerivationError);
         }
+
+        let mut passkey = PassKey {
+            key,
+            salt: Vec::new(),
+        };
+
+        // toxcore does not tell us which salt it picked, but it is embedded in
+        // anything we encrypt with the key. It refuses to encrypt nothing, so
+        // use a throwaway byte
+        let encrypted = passkey.encrypt(&[0]).map_err(|_| KeyDerivationError)?;
+        passkey.salt = get_salt(&encrypted)?;
+
+        Ok(passkey)
     }
 
-    pub fn from_encrypted_slice(
-        passphrase: &str,
-        input: &[u8],
-    ) -> Result<PassKey, KeyDerivationError> {
-        if input.len() < TOX_PASS_ENCRYPTION_EXTRA_LENGTH as usize {
+    /// Derives a key with a known salt. Key derivation is intentionally slow,
+    /// so callers encrypting several blobs for the same passphrase should
+    /// derive once and share the key (or its salt) instead of calling
+    /// [`PassKey::new`] for each blob
+    pub fn new_with_salt(passphrase: &str, salt: &[u8]) -> Result<PassKey, KeyDerivationError> {
+        if salt.len() != TOX_PASS_SALT_LENGTH as usize {
             return Err(KeyDerivationError);
         }
 
-        unsafe {
-            let mut err = TOX_ERR_GET_SALT_OK;
-            let mut salt = Vec::with_capacity(TOX_PASS_SALT_LENGTH as usize);
-            sys::tox_get_salt(input.as_ptr(), salt.as_mut_ptr(), &mut err);
-            salt.set_len(TOX_PASS_SALT_LENGTH as usize);
-
-            if err != TOX_ERR_GET_SALT_OK {
-                return Err(KeyDerivationError);
-            }
-
-            let mut err = TOX_ERR_KEY_DERIVATION_OK;
-            let key = sys::tox_pass_key_derive_with_salt(
+        let mut err = TOX_ERR_KEY_DERIVATION_OK;
+        let key = unsafe {
+            sys::tox_pass_key_derive_with_salt(
                 passphrase.as_ptr(),
                 passphrase.len() as u64,
                 salt.as_ptr(),
                 &mut err,
-            );
-            if err != TOX_ERR_KEY_DERIVATION_OK {
-                return Err(KeyDerivationError);
-            }
+            )
+        };
+
+        if err != TOX_ERR_KEY_DERIVATION_OK {
+            return Err(KeyDerivationError);
+        }
+
+        Ok(PassKey {
+            key,
+            salt: salt.to_vec(),
+        })
+    }
 
-            Ok(PassKey { key })
+    pub fn from_encrypted_slice(
+        passphrase: &str,
+        input: &[u8],
+    ) -> Result<PassKey, KeyDerivationError> {
+        let salt = get_salt(input)?;
+        Self::new_with_salt(passphrase, &salt)
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    /// Checks whether the given data looks like it was encrypted with a
+    /// [`PassKey`], e.g. a password protected tox save
+    pub fn is_data_encrypted(data: &[u8]) -> bool {
+        if data.len() < TOX_PASS_ENCRYPTION_EXTRA_LENGTH as usize {
+            return false;
         }
+
+        unsafe { sys::tox_is_data_encrypted(data.as_ptr()) }
     }
 
     pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, EncryptionError> {
@@ -80,6 +110,10 @@ impl PassKey {
     }
 
     pub fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        if ciphertext.len() < TOX_PASS_ENCRYPTION_EXTRA_LENGTH as usize {
+            return Err(DecryptionError);
+        }
+
         unsafe {
             let output_len = ciphertext.len() - TOX_PASS_ENCRYPTION_EXTRA_LENGTH as usize;
             let mut output = Vec::with_capacity(output_len);
@@ -112,7 +146,248 @@ impl Drop for PassKey {
     }
 }
 
+fn get_salt(input: &[u8]) -> Result<Vec<u8>, KeyDerivationError> {
+    if input.len() < TOX_PASS_ENCRYPTION_EXTRA_LENGTH as usize {
+        return Err(KeyDerivationError);
+    }
+
+    unsafe {
+        let mut err = TOX_ERR_GET_SALT_OK;
+        let mut salt = Vec::with_capacity(TOX_PASS_SALT_LENGTH as usize);
+        sys::tox_get_salt(input.as_ptr(), salt.as_mut_ptr(), &mut err);
+        salt.set_len(TOX_PASS_SALT_LENGTH as usize);
+
+        if err != TOX_ERR_GET_SALT_OK {
+            return Err(KeyDerivationError);
+        }
+
+        Ok(salt)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    // FIXME
+    use super::*;
+
+    const SALT_LEN: usize = TOX_PASS_SALT_LENGTH as usize;
+    const EXTRA_LEN: usize = TOX_PASS_ENCRYPTION_EXTRA_LENGTH as usize;
+
+    rusty_fork::rusty_fork_test! {
+        #[test]
+        fn test_key_derivation() -> Result<(), Box<dyn std::error::Error>> {
+            let derive_ctx = sys::tox_pass_key_derive_context();
+            derive_ctx
+                .expect()
+                .returning_st(|passphrase, len, _| unsafe {
+                    assert_eq!(std::slice::from_raw_parts(passphrase, len as usize), b"password");
+                    std::ptr::NonNull::dangling().as_ptr()
+                })
+                .times(1);
+
+            let free_ctx = sys::tox_pass_key_free_context();
+            free_ctx.expect().return_const(()).times(1);
+
+            // The salt is recovered from a throwaway encryption. Like real
+            // toxcore, empty input is refused
+            let encrypt_ctx = sys::tox_pass_key_encrypt_context();
+            encrypt_ctx
+                .expect()
+                .returning_st(|_, _, len, ciphertext, err| unsafe {
+                    if len == 0 {
+                        *err = TOX_ERR_ENCRYPTION_NULL;
+                        return false;
+                    }
+                    std::ptr::write_bytes(ciphertext, 0x42, EXTRA_LEN + len as usize);
+                    true
+                })
+                .times(1);
+
+            let get_salt_ctx = sys::tox_get_salt_context();
+            get_salt_ctx
+                .expect()
+                .returning_st(|ciphertext, salt, _| unsafe {
+                    std::ptr::copy_nonoverlapping(ciphertext, salt, SALT_LEN);
+                    true
+                })
+                .times(1);
+
+            let key = PassKey::new("password")?;
+            assert_eq!(key.salt(), &[0x42; SALT_LEN][..]);
+
+            Ok(())
+        }
+
+        #[test]
+        fn test_key_derivation_failure() {
+            let derive_ctx = sys::tox_pass_key_derive_context();
+            derive_ctx
+                .expect()
+                .returning_st(|_, _, err| unsafe {
+                    *err = TOX_ERR_KEY_DERIVATION_FAILED;
+                    std::ptr::null_mut()
+                });
+
+            // Nothing was allocated, so nothing should be freed
+            let free_ctx = sys::tox_pass_key_free_context();
+            free_ctx.expect().return_const(()).times(0);
+
+            assert!(PassKey::new("password").is_err());
+        }
+
+        #[test]
+        fn test_salt_extraction_failure() {
+            let get_salt_ctx = sys::tox_get_salt_context();
+            get_salt_ctx
+                .expect()
+                .returning_st(|_, _, err| unsafe {
+                    *err = TOX_ERR_GET_SALT_BAD_FORMAT;
+                    false
+                });
+
+            let derive_ctx = sys::tox_pass_key_derive_with_salt_context();
+            derive_ctx.expect().times(0);
+
+            let input = vec![0; EXTRA_LEN];
+            assert!(PassKey::from_encrypted_slice("password", &input).is_err());
+        }
+
+        #[test]
+        fn test_short_input() {
+            // Too short to contain a salt, toxcore should never see it
+            let get_salt_ctx = sys::tox_get_salt_context();
+            get_salt_ctx.expect().times(0);
+
+            let input = vec![0; EXTRA_LEN - 1];
+            assert!(PassKey::from_encrypted_slice("password", &input).is_err());
+        }
+
+        #[test]
+        fn test_short_ciphertext() -> Result<(), Box<dyn std::error::Error>> {
+            let derive_ctx = sys::tox_pass_key_derive_with_salt_context();
+            derive_ctx
+                .expect()
+                .returning_st(|_, _, _, _| std::ptr::NonNull::dangling().as_ptr());
+
+            let free_ctx = sys::tox_pass_key_free_context();
+            free_ctx.expect().return_const(());
+
+            let decrypt_ctx = sys::tox_pass_key_decrypt_context();
+            decrypt_ctx.expect().times(0);
+
+            let key = PassKey::new_with_salt("password", &[0; SALT_LEN])?;
+            assert!(key.decrypt(&[0; 4]).is_err());
+
+            Ok(())
+        }
+
+        #[test]
+        fn test_is_data_encrypted() {
+            let is_data_encrypted_ctx = sys::tox_is_data_encrypted_context();
+            is_data_encrypted_ctx.expect().return_const(true).times(1);
+
+            // Data too short to contain the encryption header should not be
+            // handed to toxcore
+            assert!(!PassKey::is_data_encrypted(&[0u8; 4]));
+
+            let data = vec![0u8; TOX_PASS_ENCRYPTION_EXTRA_LENGTH as usize];
+            assert!(PassKey::is_data_encrypted(&data));
+        }
+
+        #[test]
+        fn test_drop_frees_key() -> Result<(), Box<dyn std::error::Error>> {
+            let key_ptr = std::ptr::NonNull::<Tox_Pass_Key>::dangling().as_ptr();
+
+            let derive_ctx = sys::tox_pass_key_derive_with_salt_context();
+            derive_ctx
+                .expect()
+                .returning_st(move |_, _, _, _| key_ptr);
+
+            let key_addr = key_ptr as usize;
+            let free_ctx = sys::tox_pass_key_free_context();
+            free_ctx
+                .expect()
+                .withf(move |key| *key as usize == key_addr)
+                .return_const(())
+                .times(1);
+
+            let key = PassKey::new_with_salt("password", &[0; SALT_LEN])?;
+            drop(key);
+
+            free_ctx.checkpoint();
+
+            Ok(())
+        }
+
+        #[test]
+        fn test_round_trip_with_salt() -> Result<(), Box<dyn std::error::Error>> {
+            let salt = vec![0x5a; SALT_LEN];
+
+            // Fake encryption scheme, salt at the front of the header followed
+            // by the plaintext
+            let derive_salt = salt.clone();
+            let derive_ctx = sys::tox_pass_key_derive_with_salt_context();
+            derive_ctx
+                .expect()
+                .returning_st(move |_, _, salt, _| unsafe {
+                    assert_eq!(std::slice::from_raw_parts(salt, SALT_LEN), &derive_salt[..]);
+                    std::ptr::NonNull::dangling().as_ptr()
+                })
+                .times(2);
+
+            let free_ctx = sys::tox_pass_key_free_context();
+            free_ctx.expect().return_const(()).times(2);
+
+            let encrypt_salt = salt.clone();
+            let encrypt_ctx = sys::tox_pass_key_encrypt_context();
+            encrypt_ctx
+                .expect()
+                .returning_st(move |_, plaintext, len, ciphertext, _| unsafe {
+                    std::ptr::copy_nonoverlapping(encrypt_salt.as_ptr(), ciphertext, SALT_LEN);
+                    std::ptr::write_bytes(ciphertext.add(SALT_LEN), 0, EXTRA_LEN - SALT_LEN);
+                    std::ptr::copy_nonoverlapping(
+                        plaintext,
+                        ciphertext.add(EXTRA_LEN),
+                        len as usize,
+                    );
+                    true
+                });
+
+            let decrypt_ctx = sys::tox_pass_key_decrypt_context();
+            decrypt_ctx
+                .expect()
+                .returning_st(move |_, ciphertext, len, plaintext, _| unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        ciphertext.add(EXTRA_LEN),
+                        plaintext,
+                        len as usize - EXTRA_LEN,
+                    );
+                    true
+                });
+
+            let get_salt_ctx = sys::tox_get_salt_context();
+            get_salt_ctx
+                .expect()
+                .returning_st(|ciphertext, salt, _| unsafe {
+                    std::ptr::copy_nonoverlapping(ciphertext, salt, SALT_LEN);
+                    true
+                });
+
+            // Salts of the wrong size are rejected before reaching toxcore
+            assert!(PassKey::new_with_salt("password", &salt[1..]).is_err());
+
+            let key = PassKey::new_with_salt("password", &salt)?;
+            assert_eq!(key.salt(), &salt[..]);
+
+            let encrypted = key.encrypt(b"data")?;
+            assert_eq!(encrypted.len(), EXTRA_LEN + 4);
+            assert_eq!(key.decrypt(&encrypted)?, b"data");
+
+            // Re-deriving from an encrypted blob should land on the same salt
+            let restored = PassKey::from_encrypted_slice("password", &encrypted)?;
+            assert_eq!(restored.salt(), &salt[..]);
+            assert_eq!(restored.decrypt(&encrypted)?, b"data");
+
+            Ok(())
+        }
+    }
 }
@@ -153,6 +153,81 @@ impl From<u32> for ToxSendMessageError {
     }
 }
 
+#[derive(Error, Debug)]
+pub enum ToxCustomPacketError {
+    #[error("Invalid argument")]
+    InvalidArgument,
+    #[error("Invalid friend id")]
+    InvalidFriendId,
+    #[error("Not connected")]
+    NotConnected,
+    #[error("Packet id outside of the custom packet range")]
+    InvalidPacketId,
+    #[error("Packet empty")]
+    PacketEmpty,
+    #[error("Packet too long")]
+    PacketTooLong,
+    #[error("Send queue full")]
+    SendQueueFull,
+    #[error("Unknown")]
+    Unknown,
+}
+
+impl From<u32> for ToxCustomPacketError {
+    fn from(err: u32) -> ToxCustomPacketError {
+        match err {
+            TOX_ERR_FRIEND_CUSTOM_PACKET_NULL => ToxCustomPacketError::InvalidArgument,
+            TOX_ERR_FRIEND_CUSTOM_PACKET_FRIEND_NOT_FOUND => ToxCustomPacketError::InvalidFriendId,
+            TOX_ERR_FRIEND_CUSTOM_PACKET_FRIEND_NOT_CONNECTED => ToxCustomPacketError::NotConnected,
+            TOX_ERR_FRIEND_CUSTOM_PACKET_INVALID => ToxCustomPacketError::InvalidPacketId,
+            TOX_ERR_FRIEND_CUSTOM_PACKET_EMPTY => ToxCustomPacketError::PacketEmpty,
+            TOX_ERR_FRIEND_CUSTOM_PACKET_TOO_LONG => ToxCustomPacketError::PacketTooLong,
+            TOX_ERR_FRIEND_CUSTOM_PACKET_SENDQ => ToxCustomPacketError::SendQueueFull,
+            _ => ToxCustomPacketError::Unknown,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ToxBootstrapError {
+    #[error("Invalid argument")]
+    InvalidArgument,
+    #[error("Host could not be resolved")]
+    BadHost,
+    #[error("Invalid port")]
+    BadPort,
+    #[error("Unknown")]
+    Unknown,
+}
+
+impl From<u32> for ToxBootstrapError {
+    fn from(err: u32) -> ToxBootstrapError {
+        match err {
+            TOX_ERR_BOOTSTRAP_NULL => ToxBootstrapError::InvalidArgument,
+            TOX_ERR_BOOTSTRAP_BAD_HOST => ToxBootstrapError::BadHost,
+            TOX_ERR_BOOTSTRAP_BAD_PORT => ToxBootstrapError::BadPort,
+            _ => ToxBootstrapError::Unknown,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ToxSetTypingError {
+    #[error("Invalid friend id")]
+    InvalidFriendId,
+    #[error("Unknown")]
+    Unknown,
+}
+
+impl From<u32> for ToxSetTypingError {
+    fn from(err: u32) -> ToxSetTypingError {
+        match err {
+            TOX_ERR_SET_TYPING_FRIEND_NOT_FOUND => ToxSetTypingError::InvalidFriendId,
+            _ => ToxSetTypingError::Unknown,
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum KeyDecodeError {
     #[error("Invalid hex {0}")]
@@ -161,6 +236,14 @@ pub enum KeyDecodeError {
     InvalidKeyLength { actual: usize, expected: usize },
 }
 
+#[derive(Error, Debug)]
+pub enum ToxUriParseError {
+    #[error("Invalid tox id")]
+    InvalidId(#[from] KeyDecodeError),
+    #[error("Invalid percent encoding")]
+    InvalidEncoding,
+}
+
 #[derive(Error, Debug)]
 #[error("Tox instance no longer valid")]
 pub struct ToxDestructedError;
@@ -181,6 +264,10 @@ pub struct DecryptionError;
 #[error("Info too long")]
 pub struct SetInfoError;
 
+#[derive(Error, Debug)]
+#[error("Offline is not a user status")]
+pub struct InvalidStatusError;
+
 #[derive(Error, Debug)]
 #[error("Item has expired")]
 pub struct ExpiredError;
@@ -1,4 +1,4 @@
-use crate::{FriendData, PublicKey, Status};
+use crate::{ConnectionStatus, FriendData, PublicKey, Status};
 
 use std::{
     hash::{Hash, Hasher},
@@ -9,8 +9,8 @@ use std::{
 #[derive(Clone, Debug)]
 pub struct Friend {
     // FIXME: on friend removal outstanding friend handles are invalid. As of
-    // right now we only have one consumer who we know will handle it right, but
-    // we should validate that the friend is still valid when the ID is used
+    // right now we only have one consumer who we know will handle it right.
+    // Tox::friend_exists can check a handle before it's used
     pub(crate) id: u32,
     pub(crate) data: Arc<RwLock<FriendData>>,
 }
@@ -35,6 +35,13 @@ impl Friend {
         self.lock_data().public_key.clone()
     }
 
+    /// Hex encoded public key. Note that this is not a tox ID, the nospam and
+    /// checksum that make up the rest of a tox ID cannot be recovered from the
+    /// public key, so this can't be used to send a friend request
+    pub fn public_key_hex(&self) -> String {
+        self.lock_data().public_key.to_string()
+    }
+
     /// Retrieves the friend's advertised name
     pub fn name(&self) -> String {
         self.lock_data().name.clone()
@@ -44,6 +51,12 @@ impl Friend {
         self.lock_data().status
     }
 
+    /// Retrieves how the friend is connected. A friend can be connected while
+    /// still showing as offline until their status arrives
+    pub fn connection_status(&self) -> ConnectionStatus {
+        self.lock_data().connection_status
+    }
+
     fn lock_data(&self) -> RwLockReadGuard<'_, FriendData> {
         self.data.read().expect("Lock poisoned")
     }
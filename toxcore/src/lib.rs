@@ -9,10 +9,13 @@ pub mod error;
 mod builder;
 mod encryption;
 mod friend;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 mod sys;
 mod tox;
+mod uri;
 
-pub use crate::{builder::ToxBuilder, encryption::PassKey, friend::Friend, tox::Tox};
+pub use crate::{builder::ToxBuilder, encryption::PassKey, friend::Friend, tox::Tox, uri::ToxUri};
 use error::*;
 
 use toxcore_sys::{TOX_PUBLIC_KEY_SIZE, TOX_SECRET_KEY_SIZE};
@@ -96,6 +99,15 @@ impl_key_type!(PublicKey, Vec<u8>, TOX_PUBLIC_KEY_SIZE);
 impl_key_type!(SecretKey, Vec<u8>, TOX_SECRET_KEY_SIZE);
 impl_key_type!(ToxId, Vec<u8>, TOX_PUBLIC_KEY_SIZE + 4 + 2);
 
+impl ToxId {
+    /// The public key the address belongs to, without the nospam and checksum
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey {
+            key: self.key[..self.key.len() - 6].to_vec(),
+        }
+    }
+}
+
 /// Receipt for sent message
 #[derive(Hash, PartialEq, Eq)]
 pub struct Receipt {
@@ -131,6 +143,7 @@ pub(crate) struct FriendData {
     pub(crate) public_key: PublicKey,
     pub(crate) name: String,
     pub(crate) status: Status,
+    pub(crate) connection_status: ConnectionStatus,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -148,11 +161,22 @@ pub enum Status {
     Offline,
 }
 
+/// How we are currently connected to a friend. UDP is direct, TCP goes through
+/// a relay and is usually slower
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    None,
+    Tcp,
+    Udp,
+}
+
 pub enum Event {
     MessageReceived(Friend, Message),
     FriendRequest(FriendRequest),
     ReadReceipt(Receipt),
     StatusUpdated(Friend),
     NameUpdated(Friend),
+    /// Lossless custom packet, the first byte is the packet id
+    CustomPacketReceived(Friend, Vec<u8>),
     IncomingCall(av::IncomingCall),
 }
@@ -0,0 +1,243 @@
+//! Test helpers for crates building on toxcore. With the mock feature the
+//! toxcore API is replaced by mockall mocks, so tests can create a [`Tox`]
+//! without a network and set expectations on what it calls through [`sys`].
+//!
+//! Mock expectations are global, tests using these need to run in their own
+//! process
+
+use crate::{ConnectionStatus, Friend, PublicKey, Receipt, Status, Tox, ToxId};
+
+use toxcore_sys::*;
+
+/// The mocked toxcore API
+pub mod sys {
+    pub use crate::sys::*;
+}
+
+pub use toxcore_sys as ffi;
+
+/// Public key for a peer. Unlike [`PublicKey::from_bytes`] any length goes,
+/// so keys can match what the mocked tox_public_key_size reports
+pub fn public_key(key: &[u8]) -> PublicKey {
+    PublicKey { key: key.to_vec() }
+}
+
+/// Address of public_key, with zeroed nospam and checksum
+pub fn tox_id(public_key: &PublicKey) -> ToxId {
+    let mut key = public_key.key.clone();
+    key.extend_from_slice(&[0; 6]);
+    ToxId { key }
+}
+
+/// Receipt toxcore would have handed out for message id sent to friend
+pub fn receipt(friend: &Friend, id: u32) -> Receipt {
+    Receipt {
+        id,
+        friend: friend.clone(),
+    }
+}
+
+/// Updates friend as toxcore's status callbacks would have
+pub fn set_friend_status(friend: &Friend, status: Status, connection_status: ConnectionStatus) {
+    let mut data = friend.data.write().expect("Lock poisoned");
+    data.status = status;
+    data.connection_status = connection_status;
+}
+
+/// Expectations set up by [`ToxFixture`], they stop applying once dropped
+pub struct FixtureContexts {
+    _kill_ctx: sys::__tox_kill::Context,
+    _kill_av_ctx: sys::__toxav_kill::Context,
+    _public_key_size_ctx: sys::__tox_public_key_size::Context,
+    _toxav_callback_call_ctx: sys::__toxav_callback_call::Context,
+    _toxav_callback_call_state_ctx: sys::__toxav_callback_call_state::Context,
+    _toxav_callback_audio_receive_frame_ctx: sys::__toxav_callback_audio_receive_frame::Context,
+    _callback_friend_request_ctx: sys::__tox_callback_friend_request::Context,
+    _callback_friend_message_ctx: sys::__tox_callback_friend_message::Context,
+    _callback_friend_read_receipt_ctx: sys::__tox_callback_friend_read_receipt::Context,
+    _callback_friend_status_ctx: sys::__tox_callback_friend_status::Context,
+    _callback_friend_connection_status_ctx: sys::__tox_callback_friend_connection_status::Context,
+    _callback_friend_name_ctx: sys::__tox_callback_friend_name::Context,
+    _callback_friend_lossless_packet_ctx: sys::__tox_callback_friend_lossless_packet::Context,
+    _friend_get_public_key_ctx: sys::__tox_friend_get_public_key::Context,
+    _friend_get_name_size_ctx: sys::__tox_friend_get_name_size::Context,
+    _friend_get_name_ctx: sys::__tox_friend_get_name::Context,
+    _friend_get_status_ctx: sys::__tox_friend_get_status::Context,
+    _friend_get_connection_status_ctx: sys::__tox_friend_get_connection_status::Context,
+}
+
+/// A Tox backed by mocks, with one peer toxcore can answer queries about.
+/// Holding the fixture keeps its expectations alive
+pub struct ToxFixture {
+    pub tox: Tox,
+    contexts: FixtureContexts,
+    pub pk_len: usize,
+    pub default_peer_pk: PublicKey,
+    pub default_peer_id: u32,
+    pub default_peer_name: String,
+}
+
+impl ToxFixture {
+    pub fn new() -> ToxFixture {
+        let default_peer_pk = PublicKey {
+            key: "testkey1".to_string().into_bytes(),
+        };
+
+        let default_peer_id = 10u32;
+
+        let default_peer_name = "TestPeer";
+
+        let callback_friend_request_ctx = sys::tox_callback_friend_request_context();
+        callback_friend_request_ctx.expect().return_const(()).once();
+
+        let callback_friend_message_ctx = sys::tox_callback_friend_message_context();
+        callback_friend_message_ctx.expect().return_const(()).once();
+
+        let callback_friend_read_receipt_ctx = sys::tox_callback_friend_read_receipt_context();
+        callback_friend_read_receipt_ctx
+            .expect()
+            .return_const(())
+            .once();
+
+        let kill_ctx = sys::tox_kill_context();
+        kill_ctx.expect().return_const(()).once();
+
+        let kill_av_ctx = sys::toxav_kill_context();
+        kill_av_ctx.expect().return_const(()).once();
+
+        let public_key_size_ctx = sys::tox_public_key_size_context();
+        public_key_size_ctx
+            .expect()
+            .return_const(default_peer_pk.key.len() as u32);
+
+        let default_peer_pk_clone = default_peer_pk.clone();
+        let friend_get_public_key_ctx = sys::tox_friend_get_public_key_context();
+        friend_get_public_key_ctx
+            .expect()
+            .withf_st(move |_, id, _ptr, _err| *id == default_peer_id)
+            .returning_st(move |_, _id, ptr, _err| {
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        default_peer_pk.key.as_ptr(),
+                        ptr,
+                        default_peer_pk.key.len(),
+                    )
+                };
+                true
+            });
+
+        let friend_get_name_size_ctx = sys::tox_friend_get_name_size_context();
+        friend_get_name_size_ctx
+            .expect()
+            .withf_st(move |_, id, _err| *id == default_peer_id)
+            .return_const(default_peer_name.len() as u32);
+
+        let friend_get_name_ctx = sys::tox_friend_get_name_context();
+        friend_get_name_ctx
+            .expect()
+            .withf_st(move |_, id, _name, _err| *id == default_peer_id)
+            .returning_st(move |_, _id, name, _err| {
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        default_peer_name.as_ptr(),
+                        name,
+                        default_peer_name.len(),
+                    )
+                };
+                true
+            });
+
+        let friend_get_status_ctx = sys::tox_friend_get_status_context();
+        friend_get_status_ctx
+            .expect()
+            .withf_st(move |_, id, _err| *id == default_peer_id)
+            .returning_st(move |_, _id, _err| TOX_USER_STATUS_NONE);
+
+        let friend_get_connection_status_ctx = sys::tox_friend_get_connection_status_context();
+        friend_get_connection_status_ctx
+            .expect()
+            .withf_st(move |_, id, _err| *id == default_peer_id)
+            .returning_st(move |_, _id, _err| TOX_CONNECTION_UDP);
+
+        let callback_friend_status_ctx = sys::tox_callback_friend_status_context();
+        callback_friend_status_ctx
+            .expect()
+            .return_const(())
+            .times(1);
+
+        let callback_friend_connection_status_ctx =
+            sys::tox_callback_friend_connection_status_context();
+        callback_friend_connection_status_ctx
+            .expect()
+            .return_const(())
+            .times(1);
+
+        let callback_friend_name_ctx = sys::tox_callback_friend_name_context();
+        callback_friend_name_ctx.expect().return_const(()).times(1);
+
+        let callback_friend_lossless_packet_ctx =
+            sys::tox_callback_friend_lossless_packet_context();
+        callback_friend_lossless_packet_ctx
+            .expect()
+            .return_const(())
+            .times(1);
+
+        let toxav_callback_call_ctx = sys::toxav_callback_call_context();
+        toxav_callback_call_ctx.expect().return_const(()).times(1);
+
+        let toxav_callback_call_state_ctx = sys::toxav_callback_call_state_context();
+        toxav_callback_call_state_ctx
+            .expect()
+            .return_const(())
+            .times(1);
+
+        let toxav_callback_audio_receive_frame_ctx =
+            sys::toxav_callback_audio_receive_frame_context();
+        toxav_callback_audio_receive_frame_ctx
+            .expect()
+            .return_const(())
+            .times(1);
+
+        let tox = Tox::new(std::ptr::null_mut(), std::ptr::null_mut(), None);
+
+        ToxFixture {
+            tox,
+            contexts: FixtureContexts {
+                _kill_ctx: kill_ctx,
+                _kill_av_ctx: kill_av_ctx,
+                _public_key_size_ctx: public_key_size_ctx,
+                _toxav_callback_call_ctx: toxav_callback_call_ctx,
+                _toxav_callback_call_state_ctx: toxav_callback_call_state_ctx,
+                _toxav_callback_audio_receive_frame_ctx: toxav_callback_audio_receive_frame_ctx,
+                _callback_friend_request_ctx: callback_friend_request_ctx,
+                _callback_friend_message_ctx: callback_friend_message_ctx,
+                _callback_friend_read_receipt_ctx: callback_friend_read_receipt_ctx,
+                _callback_friend_status_ctx: callback_friend_status_ctx,
+                _callback_friend_connection_status_ctx: callback_friend_connection_status_ctx,
+                _callback_friend_name_ctx: callback_friend_name_ctx,
+                _callback_friend_lossless_packet_ctx: callback_friend_lossless_packet_ctx,
+                _friend_get_public_key_ctx: friend_get_public_key_ctx,
+                _friend_get_name_size_ctx: friend_get_name_size_ctx,
+                _friend_get_name_ctx: friend_get_name_ctx,
+                _friend_get_status_ctx: friend_get_status_ctx,
+                _friend_get_connection_status_ctx: friend_get_connection_status_ctx,
+            },
+            pk_len: default_peer_pk_clone.key.len(),
+            default_peer_pk: default_peer_pk_clone,
+            default_peer_id,
+            default_peer_name: default_peer_name.to_string(),
+        }
+    }
+
+    /// Hands the Tox over to whatever is under test. The returned contexts
+    /// keep the fixture's expectations alive and have to outlive the Tox
+    pub fn into_tox(self) -> (Tox, FixtureContexts) {
+        (self.tox, self.contexts)
+    }
+}
+
+impl Default for ToxFixture {
+    fn default() -> ToxFixture {
+        ToxFixture::new()
+    }
+}
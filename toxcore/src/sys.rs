@@ -1,10 +1,10 @@
 //! Wrapper trait/implementation for toxcore APIs. toxcore_sys APIs are wrapped
 //! as traits to allow for mocking/testing
 
-#[cfg_attr(test, mockall::automock)]
+#[cfg_attr(any(test, feature = "mock"), mockall::automock)]
 mod api_impl {
     extern "C" {
-        #![cfg_attr(test, allow(unused))]
+        #![cfg_attr(any(test, feature = "mock"), allow(unused))]
         pub fn tox_new(
             options: *const toxcore_sys::Tox_Options,
             error: *mut toxcore_sys::TOX_ERR_NEW,
@@ -16,6 +16,7 @@ mod api_impl {
         pub fn tox_get_savedata(tox: *const toxcore_sys::Tox, savedata: *mut u8);
         pub fn tox_public_key_size() -> u32;
         pub fn tox_self_get_public_key(tox: *const toxcore_sys::Tox, public_key: *mut u8);
+        pub fn tox_self_get_dht_id(tox: *const toxcore_sys::Tox, dht_id: *mut u8);
         pub fn tox_secret_key_size() -> u32;
         pub fn tox_self_get_secret_key(tox: *const toxcore_sys::Tox, secret_key: *mut u8);
         pub fn tox_address_size() -> u32;
@@ -28,6 +29,12 @@ mod api_impl {
             length: u64,
             error: *mut toxcore_sys::TOX_ERR_SET_INFO,
         ) -> bool;
+        pub fn tox_self_set_status_message(
+            tox: *mut toxcore_sys::Tox,
+            status_message: *const u8,
+            length: u64,
+            error: *mut toxcore_sys::TOX_ERR_SET_INFO,
+        ) -> bool;
         pub fn tox_self_get_friend_list_size(tox: *const toxcore_sys::Tox) -> u64;
         pub fn tox_self_get_friend_list(tox: *const toxcore_sys::Tox, friend_list: *mut u32);
         pub fn tox_friend_add(
@@ -65,6 +72,9 @@ mod api_impl {
             error: *mut toxcore_sys::TOX_ERR_FRIEND_QUERY,
         ) -> bool;
         pub fn tox_max_message_length() -> u32;
+        pub fn tox_max_name_length() -> u32;
+        pub fn tox_max_status_message_length() -> u32;
+        pub fn tox_max_friend_request_length() -> u32;
         pub fn tox_friend_send_message(
             tox: *mut toxcore_sys::Tox,
             friend_number: u32,
@@ -73,11 +83,44 @@ mod api_impl {
             length: u64,
             error: *mut toxcore_sys::TOX_ERR_FRIEND_SEND_MESSAGE,
         ) -> u32;
+        pub fn tox_friend_send_lossless_packet(
+            tox: *mut toxcore_sys::Tox,
+            friend_number: u32,
+            data: *const u8,
+            length: u64,
+            error: *mut toxcore_sys::TOX_ERR_FRIEND_CUSTOM_PACKET,
+        ) -> bool;
+        pub fn tox_bootstrap(
+            tox: *mut toxcore_sys::Tox,
+            host: *const ::std::os::raw::c_char,
+            port: u16,
+            public_key: *const u8,
+            error: *mut toxcore_sys::TOX_ERR_BOOTSTRAP,
+        ) -> bool;
+        pub fn tox_self_get_connection_status(
+            tox: *const toxcore_sys::Tox,
+        ) -> toxcore_sys::TOX_CONNECTION;
+        pub fn tox_self_set_status(
+            tox: *mut toxcore_sys::Tox,
+            status: toxcore_sys::TOX_USER_STATUS,
+        );
+        pub fn tox_self_get_status(tox: *const toxcore_sys::Tox) -> toxcore_sys::TOX_USER_STATUS;
+        pub fn tox_self_set_typing(
+            tox: *mut toxcore_sys::Tox,
+            friend_number: u32,
+            typing: bool,
+            error: *mut toxcore_sys::TOX_ERR_SET_TYPING,
+        ) -> bool;
         pub fn tox_friend_get_status(
             tox: *const toxcore_sys::Tox,
             friend_number: u32,
             error: *mut toxcore_sys::TOX_ERR_FRIEND_QUERY,
         ) -> toxcore_sys::TOX_USER_STATUS;
+        pub fn tox_friend_get_last_online(
+            tox: *const toxcore_sys::Tox,
+            friend_number: u32,
+            error: *mut toxcore_sys::TOX_ERR_FRIEND_GET_LAST_ONLINE,
+        ) -> u64;
         pub fn tox_friend_get_connection_status(
             tox: *const toxcore_sys::Tox,
             friend_number: u32,
@@ -107,6 +150,10 @@ mod api_impl {
             tox: *mut toxcore_sys::Tox,
             callback: toxcore_sys::tox_friend_name_cb,
         );
+        pub fn tox_callback_friend_lossless_packet(
+            tox: *mut toxcore_sys::Tox,
+            callback: toxcore_sys::tox_friend_lossless_packet_cb,
+        );
         pub fn toxav_new(
             tox: *mut toxcore_sys::Tox,
             err: *mut toxcore_sys::TOXAV_ERR_NEW,
@@ -212,6 +259,15 @@ mod api_impl {
             options: *mut toxcore_sys::Tox_Options,
             thread_safety: bool,
         );
+    }
+}
+
+// Encryption is kept apart from the rest of the API so crates using the mock
+// feature still get real save encryption
+#[cfg_attr(test, mockall::automock)]
+mod encryption_api_impl {
+    extern "C" {
+        #![cfg_attr(test, allow(unused))]
         pub fn tox_pass_key_free(key: *mut toxcore_sys::Tox_Pass_Key);
         pub fn tox_pass_key_derive(
             passphrase: *const u8,
@@ -243,10 +299,19 @@ mod api_impl {
             salt: *mut u8,
             err: *mut toxcore_sys::TOX_ERR_GET_SALT,
         ) -> bool;
+        pub fn tox_is_data_encrypted(data: *const u8) -> bool;
     }
 }
 
-#[mockall_double::double]
+// Not mockall_double, that only knows about cfg(test) and dependent crates
+// using the mock feature need the mocks too
+#[cfg(not(any(test, feature = "mock")))]
 use api_impl as api;
+#[cfg(any(test, feature = "mock"))]
+use mock_api_impl as api;
+
+#[mockall_double::double]
+use encryption_api_impl as encryption_api;
 
 pub use api::*;
+pub use encryption_api::*;
@@ -2,8 +2,8 @@ use crate::{
     av::{ActiveCall, AudioFrame, CallControl, CallData, CallEvent, CallState, IncomingCall},
     builder::ToxBuilder,
     error::*,
-    sys, Event, Friend, FriendData, FriendRequest, Message, PublicKey, Receipt, SecretKey, Status,
-    ToxId,
+    sys, ConnectionStatus, Event, Friend, FriendData, FriendRequest, Message, PublicKey, Receipt,
+    SecretKey, Status, ToxId,
 };
 
 use toxcore_sys::*;
@@ -20,8 +20,10 @@ use futures::{
 
 use std::{
     collections::HashMap,
+    ffi::CString,
     sync::{Arc, RwLock},
     pin::Pin,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 macro_rules! impl_self_key_getter {
@@ -117,6 +119,10 @@ impl Tox {
                 Some(tox_friend_connection_status_callback),
             );
             sys::tox_callback_friend_name(sys_tox, Some(tox_friend_name_callback));
+            sys::tox_callback_friend_lossless_packet(
+                sys_tox,
+                Some(tox_friend_lossless_packet_callback),
+            );
 
             sys::toxav_callback_call(
                 av,
@@ -163,6 +169,19 @@ impl Tox {
     impl_self_key_getter!(secret_key, SecretKey);
     impl_self_key_getter!(address, ToxId);
 
+    /// Temporary key other DHT nodes know us by. Unlike our public key this
+    /// changes every time tox starts, so it's only useful for debugging
+    pub fn self_dht_id(&self) -> PublicKey {
+        unsafe {
+            let size = sys::tox_public_key_size() as usize;
+
+            let mut ret = Vec::with_capacity(size);
+            sys::tox_self_get_dht_id(self.sys_tox.get(), ret.as_mut_ptr());
+            ret.set_len(size);
+            PublicKey { key: ret }
+        }
+    }
+
     pub fn self_name(&self) -> String {
         unsafe {
             let length = sys::tox_self_get_name_size(self.sys_tox.get()) as usize;
@@ -176,6 +195,10 @@ impl Tox {
     }
 
     pub fn self_set_name(&mut self, name: &str) -> Result<(), SetInfoError> {
+        if name.len() > self.max_name_length() {
+            return Err(SetInfoError);
+        }
+
         unsafe {
             let mut err = TOX_ERR_SET_INFO_OK;
             sys::tox_self_set_name(
@@ -193,6 +216,28 @@ impl Tox {
         }
     }
 
+    pub fn self_set_status_message(&mut self, status_message: &str) -> Result<(), SetInfoError> {
+        if status_message.len() > self.max_status_message_length() {
+            return Err(SetInfoError);
+        }
+
+        unsafe {
+            let mut err = TOX_ERR_SET_INFO_OK;
+            sys::tox_self_set_status_message(
+                self.sys_tox.get_mut(),
+                status_message.as_ptr(),
+                status_message.len() as u64,
+                &mut err,
+            );
+
+            if err != TOX_ERR_SET_INFO_OK {
+                return Err(SetInfoError);
+            }
+
+            Ok(())
+        }
+    }
+
     /// Retrieves all added toxcore friends
     pub fn friends(&mut self) -> Result<Vec<Friend>, ToxAddFriendError> {
         unsafe {
@@ -220,6 +265,10 @@ impl Tox {
         address: ToxId,
         message: String,
     ) -> Result<Friend, ToxAddFriendError> {
+        if message.len() > self.max_friend_request_length() {
+            return Err(ToxAddFriendError::MessageTooLong);
+        }
+
         unsafe {
             let mut err = TOX_ERR_FRIEND_ADD_OK;
             let friend_num = sys::tox_friend_add(
@@ -268,6 +317,36 @@ impl Tox {
         }
     }
 
+    /// Whether toxcore still knows friend. Handles outlive the friend they
+    /// refer to, and toxcore hands out deleted friend numbers again, so the
+    /// number being in use isn't enough. It has to still belong to the same
+    /// key
+    pub fn friend_exists(&self, friend: &Friend) -> bool {
+        self.public_key_from_id(friend.id)
+            .map_or(false, |public_key| public_key == friend.public_key())
+    }
+
+    /// When friend was last connected to us, None if they never were.
+    /// toxcore keeps this in the savedata, so it covers time we weren't
+    /// running
+    pub fn friend_last_online(
+        &self,
+        friend: &Friend,
+    ) -> Result<Option<SystemTime>, ToxFriendQueryError> {
+        let mut err = TOX_ERR_FRIEND_GET_LAST_ONLINE_OK;
+        let timestamp =
+            unsafe { sys::tox_friend_get_last_online(self.sys_tox.get(), friend.id, &mut err) };
+
+        if err != TOX_ERR_FRIEND_GET_LAST_ONLINE_OK {
+            return Err(match err {
+                TOX_ERR_FRIEND_GET_LAST_ONLINE_FRIEND_NOT_FOUND => ToxFriendQueryError::NotFound,
+                _ => ToxFriendQueryError::Unknown,
+            });
+        }
+
+        Ok(last_online_time(timestamp))
+    }
+
     pub fn remove_friend(&mut self, friend: &Friend) -> Result<(), ToxFriendRemoveError> {
         unsafe {
             let mut err = TOX_ERR_FRIEND_DELETE_OK;
@@ -316,6 +395,110 @@ impl Tox {
         })
     }
 
+    /// Sends a lossless custom packet to the given friend. The first byte of
+    /// data is the packet id and must be in the range 160-191
+    pub fn send_lossless_packet(
+        &mut self,
+        friend: &Friend,
+        data: &[u8],
+    ) -> Result<(), ToxCustomPacketError> {
+        let mut err = TOX_ERR_FRIEND_CUSTOM_PACKET_OK;
+
+        unsafe {
+            sys::tox_friend_send_lossless_packet(
+                self.sys_tox.get_mut(),
+                friend.id,
+                data.as_ptr(),
+                data.len() as u64,
+                &mut err,
+            );
+        }
+
+        if err != TOX_ERR_FRIEND_CUSTOM_PACKET_OK {
+            return Err(ToxCustomPacketError::from(err));
+        }
+
+        Ok(())
+    }
+
+    /// Connects to the DHT through the node at host:port. Only fails on
+    /// invalid input, whether the node is actually reachable shows up later in
+    /// [`Tox::self_connection_status`]
+    pub fn bootstrap(
+        &mut self,
+        host: &str,
+        port: u16,
+        public_key: &PublicKey,
+    ) -> Result<(), ToxBootstrapError> {
+        let host = CString::new(host).map_err(|_| ToxBootstrapError::BadHost)?;
+
+        let mut err = TOX_ERR_BOOTSTRAP_OK;
+
+        unsafe {
+            sys::tox_bootstrap(
+                self.sys_tox.get_mut(),
+                host.as_ptr(),
+                port,
+                public_key.key.as_ptr(),
+                &mut err,
+            );
+        }
+
+        if err != TOX_ERR_BOOTSTRAP_OK {
+            return Err(ToxBootstrapError::from(err));
+        }
+
+        Ok(())
+    }
+
+    /// How we are connected to the tox network, if at all
+    pub fn self_connection_status(&self) -> ConnectionStatus {
+        let connection = unsafe { sys::tox_self_get_connection_status(self.sys_tox.get()) };
+        convert_connection_status(connection).unwrap_or_else(|_| {
+            warn!("Unknown self connection status {}", connection);
+            ConnectionStatus::None
+        })
+    }
+
+    /// The status friends see while we're connected
+    pub fn self_status(&self) -> Status {
+        let status = unsafe { sys::tox_self_get_status(self.sys_tox.get()) };
+        convert_status(status).unwrap_or_else(|_| {
+            warn!("Unknown self status {}", status);
+            Status::Online
+        })
+    }
+
+    /// Offline can't be set, friends only see us as offline when we aren't
+    /// connected
+    pub fn self_set_status(&mut self, status: Status) -> Result<(), InvalidStatusError> {
+        let status = match status {
+            Status::Online => TOX_USER_STATUS_NONE,
+            Status::Away => TOX_USER_STATUS_AWAY,
+            Status::Busy => TOX_USER_STATUS_BUSY,
+            Status::Offline => return Err(InvalidStatusError),
+        };
+
+        unsafe { sys::tox_self_set_status(self.sys_tox.get_mut(), status) };
+
+        Ok(())
+    }
+
+    /// Tells friend whether we are currently typing a message to them
+    pub fn set_typing(&mut self, friend: &Friend, typing: bool) -> Result<(), ToxSetTypingError> {
+        let mut err = TOX_ERR_SET_TYPING_OK;
+
+        unsafe {
+            sys::tox_self_set_typing(self.sys_tox.get_mut(), friend.id, typing, &mut err);
+        }
+
+        if err != TOX_ERR_SET_TYPING_OK {
+            return Err(ToxSetTypingError::from(err));
+        }
+
+        Ok(())
+    }
+
     pub fn get_savedata(&self) -> Vec<u8> {
         unsafe {
             let data_size = sys::tox_get_savedata_size(self.sys_tox.get()) as usize;
@@ -331,10 +514,27 @@ impl Tox {
         unsafe { sys::tox_max_message_length() as usize }
     }
 
-    pub fn call_friend(&mut self, friend: &Friend) -> Result<ActiveCall, ToxCallError> {
+    pub fn max_name_length(&self) -> usize {
+        unsafe { sys::tox_max_name_length() as usize }
+    }
+
+    pub fn max_status_message_length(&self) -> usize {
+        unsafe { sys::tox_max_status_message_length() as usize }
+    }
+
+    pub fn max_friend_request_length(&self) -> usize {
+        unsafe { sys::tox_max_friend_request_length() as usize }
+    }
+
+    /// Calls friend with audio only, audio_bit_rate is in kbit/s
+    pub fn call_friend(
+        &mut self,
+        friend: &Friend,
+        audio_bit_rate: u32,
+    ) -> Result<ActiveCall, ToxCallError> {
         unsafe {
             let mut err = TOXAV_ERR_CALL_OK;
-            sys::toxav_call(self.av.get_mut(), friend.id, 64u32, 0u32, &mut err);
+            sys::toxav_call(self.av.get_mut(), friend.id, audio_bit_rate, 0u32, &mut err);
             if err != TOXAV_ERR_CALL_OK {
                 return Err(err.into());
             }
@@ -425,7 +625,7 @@ impl Tox {
         }
     }
 
-    fn status_from_id(&self, id: u32) -> Result<Status, ToxFriendQueryError> {
+    fn connection_status_from_id(&self, id: u32) -> Result<ConnectionStatus, ToxFriendQueryError> {
         let mut err = TOX_ERR_FRIEND_QUERY_OK;
 
         let connection_status = unsafe {
@@ -437,13 +637,27 @@ impl Tox {
         };
 
         if connection_status == TOX_CONNECTION_NONE {
-            return Ok(Status::Offline);
+            return Ok(ConnectionStatus::None);
         }
 
         if err != TOX_ERR_FRIEND_QUERY_OK {
             return Err(ToxFriendQueryError::from(err));
         }
 
+        convert_connection_status(connection_status)
+    }
+
+    fn status_from_id(
+        &self,
+        id: u32,
+        connection_status: ConnectionStatus,
+    ) -> Result<Status, ToxFriendQueryError> {
+        if connection_status == ConnectionStatus::None {
+            return Ok(Status::Offline);
+        }
+
+        let mut err = TOX_ERR_FRIEND_QUERY_OK;
+
         let status = unsafe {
             sys::tox_friend_get_status(
                 self.sys_tox.get(),
@@ -473,12 +687,14 @@ impl Tox {
         } else {
             let public_key = self.public_key_from_id(id)?;
             let name = self.name_from_id(id)?;
-            let status = self.status_from_id(id)?;
+            let connection_status = self.connection_status_from_id(id)?;
+            let status = self.status_from_id(id, connection_status)?;
 
             let friend_data = FriendData {
                 public_key,
                 name,
                 status,
+                connection_status,
             };
 
             let friend_data = Arc::new(RwLock::new(friend_data));
@@ -557,10 +773,16 @@ impl Tox {
                     error!("Failed to reject call: {}", CallControlError::from(err));
                 }
             }
-            CallControl::Accepted => {
+            CallControl::Accepted(audio_bit_rate) => {
                 let mut err = TOXAV_ERR_ANSWER_OK;
                 unsafe {
-                    sys::toxav_answer(self.av.get_mut(), friend_number, 64u32, 0, &mut err);
+                    sys::toxav_answer(
+                        self.av.get_mut(),
+                        friend_number,
+                        audio_bit_rate,
+                        0,
+                        &mut err,
+                    );
                 }
 
                 if err != TOXAV_ERR_ANSWER_OK {
@@ -577,6 +799,24 @@ impl Tox {
                     None => error!("Call data missing"),
                 }
             }
+            CallControl::Pause | CallControl::Resume => {
+                let control = match event {
+                    CallControl::Pause => TOXAV_CALL_CONTROL_PAUSE,
+                    _ => TOXAV_CALL_CONTROL_RESUME,
+                };
+
+                let mut err = TOXAV_ERR_CALL_CONTROL_OK;
+                unsafe {
+                    sys::toxav_call_control(self.av.get_mut(), friend_number, control, &mut err);
+                }
+
+                if err != TOXAV_ERR_CALL_CONTROL_OK {
+                    error!(
+                        "Failed to pause/resume call: {}",
+                        CallControlError::from(err)
+                    );
+                }
+            }
             CallControl::SendAudio(frame) => {
                 let active_call_friends =
                     self.data
@@ -717,17 +957,10 @@ pub(crate) unsafe extern "C" fn tox_friend_request_callback(
         key: public_key_storage,
     };
 
-    let mut message = Vec::with_capacity(length as usize);
-    std::ptr::copy_nonoverlapping(input_message, message.as_mut_ptr(), length as usize);
-    message.set_len(length as usize);
-
-    let message = match String::from_utf8(message) {
-        Ok(s) => s,
-        Err(_) => {
-            error!("Failed to parse friend request message");
-            return;
-        }
-    };
+    // A mangled message is no reason to lose the request itself
+    let message =
+        String::from_utf8_lossy(std::slice::from_raw_parts(input_message, length as usize))
+            .to_string();
 
     let request = FriendRequest {
         public_key,
@@ -864,8 +1097,18 @@ unsafe extern "C" fn tox_friend_connection_status_callback(
         }
     };
 
-    // We only care about the offline callback, We determine a friend has gone "online" via the friend status callback
-    if connection != TOX_CONNECTION_NONE {
+    let connection_status = match convert_connection_status(connection) {
+        Ok(s) => s,
+        Err(_) => {
+            error!("Unknown connection status {}", connection);
+            return;
+        }
+    };
+
+    friend_data.write().unwrap().connection_status = connection_status;
+
+    // We only send an event for the offline callback, We determine a friend has gone "online" via the friend status callback
+    if connection_status != ConnectionStatus::None {
         return;
     }
 
@@ -892,6 +1135,30 @@ fn convert_status(status: TOX_USER_STATUS) -> Result<Status, ToxFriendQueryError
     Ok(status)
 }
 
+fn convert_connection_status(
+    connection: TOX_CONNECTION,
+) -> Result<ConnectionStatus, ToxFriendQueryError> {
+    let connection_status = match connection {
+        TOX_CONNECTION_NONE => ConnectionStatus::None,
+        TOX_CONNECTION_TCP => ConnectionStatus::Tcp,
+        TOX_CONNECTION_UDP => ConnectionStatus::Udp,
+        _ => return Err(ToxFriendQueryError::Unknown),
+    };
+
+    Ok(connection_status)
+}
+
+/// Converts the unix timestamp toxcore reports for a friend's last
+/// connection. UINT64_MAX is its "never" sentinel, and friends that were
+/// never online are left at 0
+fn last_online_time(timestamp: u64) -> Option<SystemTime> {
+    if timestamp == u64::MAX || timestamp == 0 {
+        return None;
+    }
+
+    Some(UNIX_EPOCH + Duration::from_secs(timestamp))
+}
+
 unsafe extern "C" fn tox_friend_name_callback(
     _tox: *mut toxcore_sys::Tox,
     friend_number: u32,
@@ -923,6 +1190,35 @@ unsafe extern "C" fn tox_friend_name_callback(
     }
 }
 
+unsafe extern "C" fn tox_friend_lossless_packet_callback(
+    _tox: *mut toxcore_sys::Tox,
+    friend_number: u32,
+    data: *const u8,
+    len: u64,
+    user_data: *mut std::os::raw::c_void,
+) {
+    let tox_data = &mut *(user_data as *mut ToxData);
+
+    let friend_data = match tox_data.friend_data.get(&friend_number) {
+        Some(d) => d,
+        None => {
+            error!("Friend data is not initialized");
+            return;
+        }
+    };
+
+    let data = std::slice::from_raw_parts(data, len as usize).to_vec();
+
+    let f = Friend {
+        id: friend_number,
+        data: Arc::clone(&friend_data),
+    };
+
+    if let Some(callback) = &mut tox_data.event_callback {
+        (*callback)(Event::CustomPacketReceived(f, data));
+    }
+}
+
 unsafe extern "C" fn toxav_call_callback(
     _av: *mut toxcore_sys::ToxAV,
     friend_number: u32,
@@ -1042,169 +1338,22 @@ unsafe extern "C" fn toxav_receive_audio(
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use crate::mock::ToxFixture;
     use futures::FutureExt;
-    use std::sync::atomic::{AtomicBool, AtomicU64};
-
-    pub(crate) struct ToxFixture {
-        tox: Tox,
-        _kill_ctx: sys::__tox_kill::Context,
-        _kill_av_ctx: sys::__toxav_kill::Context,
-        _public_key_size_ctx: sys::__tox_public_key_size::Context,
-        _toxav_callback_call_ctx: sys::__toxav_callback_call::Context,
-        _toxav_callback_call_state_ctx: sys::__toxav_callback_call_state::Context,
-        _toxav_callback_audio_receive_frame_ctx: sys::__toxav_callback_audio_receive_frame::Context,
-        _callback_friend_request_ctx: sys::__tox_callback_friend_request::Context,
-        _callback_friend_message_ctx: sys::__tox_callback_friend_message::Context,
-        _callback_friend_read_receipt_ctx: sys::__tox_callback_friend_read_receipt::Context,
-        _callback_friend_status_ctx: sys::__tox_callback_friend_status::Context,
-        _callback_friend_connection_status_ctx:
-            sys::__tox_callback_friend_connection_status::Context,
-        _callback_friend_name_ctx: sys::__tox_callback_friend_name::Context,
-        _friend_get_public_key_ctx: sys::__tox_friend_get_public_key::Context,
-        _friend_get_name_size_ctx: sys::__tox_friend_get_name_size::Context,
-        _friend_get_name_ctx: sys::__tox_friend_get_name::Context,
-        _friend_get_status_ctx: sys::__tox_friend_get_status::Context,
-        _friend_get_connection_status_ctx: sys::__tox_friend_get_connection_status::Context,
-        pk_len: usize,
-        default_peer_pk: PublicKey,
-        default_peer_id: u32,
-        default_peer_name: String,
-    }
-
-    impl ToxFixture {
-        pub(crate) fn new() -> ToxFixture {
-            let default_peer_pk = PublicKey {
-                key: "testkey1".to_string().into_bytes(),
-            };
-
-            let default_peer_id = 10u32;
-
-            let default_peer_name = "TestPeer";
-
-            let callback_friend_request_ctx = sys::tox_callback_friend_request_context();
-            callback_friend_request_ctx.expect().return_const(()).once();
-
-            let callback_friend_message_ctx = sys::tox_callback_friend_message_context();
-            callback_friend_message_ctx.expect().return_const(()).once();
-
-            let callback_friend_read_receipt_ctx = sys::tox_callback_friend_read_receipt_context();
-            callback_friend_read_receipt_ctx
-                .expect()
-                .return_const(())
-                .once();
-
-            let kill_ctx = sys::tox_kill_context();
-            kill_ctx.expect().return_const(()).once();
-
-            let kill_av_ctx = sys::toxav_kill_context();
-            kill_av_ctx.expect().return_const(()).once();
-
-            let public_key_size_ctx = sys::tox_public_key_size_context();
-            public_key_size_ctx
-                .expect()
-                .return_const(default_peer_pk.key.len() as u32);
-
-            let default_peer_pk_clone = default_peer_pk.clone();
-            let friend_get_public_key_ctx = sys::tox_friend_get_public_key_context();
-            friend_get_public_key_ctx
-                .expect()
-                .withf_st(move |_, id, _ptr, _err| *id == default_peer_id)
-                .returning_st(move |_, _id, ptr, _err| {
-                    unsafe {
-                        std::ptr::copy_nonoverlapping(
-                            default_peer_pk.key.as_ptr(),
-                            ptr,
-                            default_peer_pk.key.len(),
-                        )
-                    };
-                    true
-                });
-
-            let friend_get_name_size_ctx = sys::tox_friend_get_name_size_context();
-            friend_get_name_size_ctx
-                .expect()
-                .withf_st(move |_, id, _err| *id == default_peer_id)
-                .return_const(default_peer_name.len() as u32);
-
-            let friend_get_name_ctx = sys::tox_friend_get_name_context();
-            friend_get_name_ctx
-                .expect()
-                .withf_st(move |_, id, _name, _err| *id == default_peer_id)
-                .returning_st(move |_, _id, name, _err| {
-                    unsafe {
-                        std::ptr::copy_nonoverlapping(
-                            default_peer_name.as_ptr(),
-                            name,
-                            default_peer_name.len(),
-                        )
-                    };
-                    true
-                });
-
-            let friend_get_status_ctx = sys::tox_friend_get_status_context();
-            friend_get_status_ctx
-                .expect()
-                .withf_st(move |_, id, _err| *id == default_peer_id)
-                .returning_st(move |_, _id, _err| TOX_USER_STATUS_NONE);
-
-            let friend_get_connection_status_ctx = sys::tox_friend_get_connection_status_context();
-            friend_get_connection_status_ctx
-                .expect()
-                .withf_st(move |_, id, _err| *id == default_peer_id)
-                .returning_st(move |_, _id, _err| TOX_CONNECTION_UDP);
-
-            let callback_friend_status_ctx = sys::tox_callback_friend_status_context();
-            callback_friend_status_ctx
-                .expect()
-                .return_const(())
-                .times(1);
+    use std::{
+        cell::RefCell,
+        rc::Rc,
+        sync::atomic::{AtomicBool, AtomicU64},
+    };
 
-            let callback_friend_connection_status_ctx =
-                sys::tox_callback_friend_connection_status_context();
-            callback_friend_connection_status_ctx
-                .expect()
-                .return_const(())
-                .times(1);
-
-            let callback_friend_name_ctx = sys::tox_callback_friend_name_context();
-            callback_friend_name_ctx.expect().return_const(()).times(1);
-
-            let toxav_callback_call_ctx = sys::toxav_callback_call_context();
-            toxav_callback_call_ctx.expect().return_const(()).times(1);
-
-            let toxav_callback_call_state_ctx = sys::toxav_callback_call_state_context();
-            toxav_callback_call_state_ctx.expect().return_const(()).times(1);
-
-            let toxav_callback_audio_receive_frame_ctx = sys::toxav_callback_audio_receive_frame_context();
-            toxav_callback_audio_receive_frame_ctx.expect().return_const(()).times(1);
-
-            let tox = Tox::new(std::ptr::null_mut(), std::ptr::null_mut(), None);
-
-            ToxFixture {
-                tox,
-                _kill_ctx: kill_ctx,
-                _kill_av_ctx: kill_av_ctx,
-                _public_key_size_ctx: public_key_size_ctx,
-                _toxav_callback_call_ctx: toxav_callback_call_ctx,
-                _toxav_callback_call_state_ctx: toxav_callback_call_state_ctx,
-                _toxav_callback_audio_receive_frame_ctx: toxav_callback_audio_receive_frame_ctx,
-                _callback_friend_request_ctx: callback_friend_request_ctx,
-                _callback_friend_message_ctx: callback_friend_message_ctx,
-                _callback_friend_read_receipt_ctx: callback_friend_read_receipt_ctx,
-                _callback_friend_status_ctx: callback_friend_status_ctx,
-                _callback_friend_connection_status_ctx: callback_friend_connection_status_ctx,
-                _callback_friend_name_ctx: callback_friend_name_ctx,
-                _friend_get_public_key_ctx: friend_get_public_key_ctx,
-                _friend_get_name_size_ctx: friend_get_name_size_ctx,
-                _friend_get_name_ctx: friend_get_name_ctx,
-                _friend_get_status_ctx: friend_get_status_ctx,
-                _friend_get_connection_status_ctx: friend_get_connection_status_ctx,
-                pk_len: default_peer_pk_clone.key.len(),
-                default_peer_pk: default_peer_pk_clone,
-                default_peer_id,
-                default_peer_name: default_peer_name.to_string(),
-            }
-        }
+    #[test]
+    fn test_last_online_time() {
+        assert_eq!(
+            last_online_time(1_600_000_000),
+            Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+        );
+        assert_eq!(last_online_time(u64::MAX), None);
+        assert_eq!(last_online_time(0), None);
     }
 
     rusty_fork::rusty_fork_test! {
@@ -1319,6 +1468,48 @@ pub(crate) mod tests {
             Ok(())
         }
 
+        #[test]
+        fn test_friend_request_invalid_utf8() -> Result<(), Box<dyn std::error::Error>> {
+            let mut fixture = ToxFixture::new();
+
+            // Truncated in the middle of a multi byte character
+            let mut message = "hi \u{1F600}".to_string().into_bytes();
+            message.pop();
+
+            let default_peer_pk = fixture.default_peer_pk.clone();
+
+            let callback_called = Arc::new(AtomicBool::new(false));
+            let callback_called_clone = Arc::clone(&callback_called);
+
+            use std::sync::atomic::Ordering;
+
+            fixture.tox.data.event_callback = Some(Box::new(move |event| {
+                callback_called_clone.store(true, Ordering::Relaxed);
+                match event {
+                    Event::FriendRequest(friend_request) => {
+                        assert_eq!(friend_request.message, "hi \u{FFFD}");
+                        assert_eq!(friend_request.public_key, default_peer_pk);
+                    }
+                    _ => assert!(false),
+                }
+            }));
+
+            unsafe {
+                tox_friend_request_callback(
+                    std::ptr::null_mut(),
+                    fixture.default_peer_pk.key.as_ptr(),
+                    message.as_ptr(),
+                    message.len() as u64,
+                    (&mut *fixture.tox.data as *mut ToxData)
+                        as *mut std::os::raw::c_void,
+                );
+            }
+
+            assert!(callback_called.load(Ordering::Relaxed));
+
+            Ok(())
+        }
+
         #[test]
         fn test_friend_status_dispatch() -> Result<(), Box<dyn std::error::Error>> {
             // Initialize our default friend
@@ -1372,15 +1563,180 @@ pub(crate) mod tests {
         }
 
         #[test]
-        fn test_get_self_name() {
-            let self_name = "TestName";
+        fn test_friend_name_dispatch() -> Result<(), Box<dyn std::error::Error>> {
+            let mut fixture = ToxFixture::new();
 
-            let self_get_name_size_ctx = sys::tox_self_get_name_size_context();
-            self_get_name_size_ctx.expect()
-                .return_const(self_name.len() as u64);
+            let name = "new name".to_string().into_bytes();
 
-            let self_get_name_ctx = sys::tox_self_get_name_context();
-            self_get_name_ctx.expect()
+            let callback_called = Arc::new(AtomicBool::new(false));
+            let callback_called_clone = Arc::clone(&callback_called);
+
+            use std::sync::atomic::Ordering;
+
+            fixture.tox.data.event_callback = Some(Box::new(move |event| {
+                callback_called_clone.store(true, Ordering::Relaxed);
+                match event {
+                    Event::NameUpdated(friend) => {
+                        assert_eq!(friend.name(), "new name");
+                    }
+                    _ => assert!(false),
+                }
+            }));
+
+            let peer_pk = fixture.default_peer_pk.clone();
+            let pk_len = peer_pk.key.len();
+            let friend_add_norequest_ctx = sys::tox_friend_add_norequest_context();
+            friend_add_norequest_ctx
+                .expect()
+                .withf_st(move |_, input_public_key, _err| {
+                    let slice = unsafe { std::slice::from_raw_parts(*input_public_key, pk_len) };
+                    slice == peer_pk.key
+                })
+                .return_const(fixture.default_peer_id)
+                .once();
+
+            let friend = fixture.tox.add_friend_norequest(&fixture.default_peer_pk)?;
+
+            unsafe {
+                tox_friend_name_callback(
+                    std::ptr::null_mut(),
+                    fixture.default_peer_id,
+                    name.as_ptr(),
+                    name.len() as u64,
+                    (&mut *fixture.tox.data as *mut ToxData)
+                        as *mut std::os::raw::c_void,
+                );
+            }
+
+            assert!(callback_called.load(Ordering::Relaxed));
+            // Friend handles share their data, so ones we already had see
+            // the new name too
+            assert_eq!(friend.name(), "new name");
+
+            Ok(())
+        }
+
+        #[test]
+        fn test_friend_connection_status_tracked() -> Result<(), Box<dyn std::error::Error>> {
+            let mut fixture = ToxFixture::new();
+
+            let status_events = Arc::new(AtomicU64::new(0));
+            let status_events_clone = Arc::clone(&status_events);
+
+            use std::sync::atomic::Ordering;
+
+            fixture.tox.data.event_callback = Some(Box::new(move |event| {
+                match event {
+                    Event::StatusUpdated(friend) => {
+                        status_events_clone.fetch_add(1, Ordering::Relaxed);
+                        assert_eq!(friend.status(), Status::Offline);
+                    }
+                    _ => assert!(false),
+                }
+            }));
+
+            let peer_pk = fixture.default_peer_pk.clone();
+            let pk_len = peer_pk.key.len();
+            let friend_add_norequest_ctx = sys::tox_friend_add_norequest_context();
+            friend_add_norequest_ctx
+                .expect()
+                .withf_st(move |_, input_public_key, _err| {
+                    let slice = unsafe { std::slice::from_raw_parts(*input_public_key, pk_len) };
+                    slice == peer_pk.key
+                })
+                .return_const(fixture.default_peer_id)
+                .once();
+
+            let friend = fixture.tox.add_friend_norequest(&fixture.default_peer_pk)?;
+
+            let connection_changed = |fixture: &mut ToxFixture, connection| unsafe {
+                tox_friend_connection_status_callback(
+                    std::ptr::null_mut(),
+                    fixture.default_peer_id,
+                    connection,
+                    (&mut *fixture.tox.data as *mut ToxData)
+                        as *mut std::os::raw::c_void,
+                );
+            };
+
+            // Falling back to a relay is not a status change
+            connection_changed(&mut fixture, TOX_CONNECTION_TCP);
+            assert_eq!(friend.connection_status(), ConnectionStatus::Tcp);
+            assert_eq!(status_events.load(Ordering::Relaxed), 0);
+
+            connection_changed(&mut fixture, TOX_CONNECTION_UDP);
+            assert_eq!(friend.connection_status(), ConnectionStatus::Udp);
+            assert_eq!(status_events.load(Ordering::Relaxed), 0);
+
+            connection_changed(&mut fixture, TOX_CONNECTION_NONE);
+            assert_eq!(friend.connection_status(), ConnectionStatus::None);
+            assert_eq!(status_events.load(Ordering::Relaxed), 1);
+
+            Ok(())
+        }
+
+        #[test]
+        fn test_friend_lossless_packet_dispatch() -> Result<(), Box<dyn std::error::Error>> {
+            let mut fixture = ToxFixture::new();
+
+            let packet = vec![172u8, 1, 2, 3];
+            let expected_packet = packet.clone();
+
+            let callback_called = Arc::new(AtomicBool::new(false));
+            let callback_called_clone = Arc::clone(&callback_called);
+
+            use std::sync::atomic::Ordering;
+
+            fixture.tox.data.event_callback = Some(Box::new(move |event| {
+                callback_called_clone.store(true, Ordering::Relaxed);
+                match event {
+                    Event::CustomPacketReceived(_, data) => {
+                        assert_eq!(data, expected_packet);
+                    }
+                    _ => assert!(false),
+                }
+            }));
+
+            let peer_pk = fixture.default_peer_pk.clone();
+            let pk_len = peer_pk.key.len();
+            let friend_add_norequest_ctx = sys::tox_friend_add_norequest_context();
+            friend_add_norequest_ctx
+                .expect()
+                .withf_st(move |_, input_public_key, _err| {
+                    let slice = unsafe { std::slice::from_raw_parts(*input_public_key, pk_len) };
+                    slice == peer_pk.key
+                })
+                .return_const(fixture.default_peer_id)
+                .once();
+
+            fixture.tox.add_friend_norequest(&fixture.default_peer_pk)?;
+
+            unsafe {
+                tox_friend_lossless_packet_callback(
+                    std::ptr::null_mut(),
+                    fixture.default_peer_id,
+                    packet.as_ptr(),
+                    packet.len() as u64,
+                    (&mut *fixture.tox.data as *mut ToxData)
+                        as *mut std::os::raw::c_void,
+                );
+            }
+
+            assert!(callback_called.load(Ordering::Relaxed));
+
+            Ok(())
+        }
+
+        #[test]
+        fn test_get_self_name() {
+            let self_name = "TestName";
+
+            let self_get_name_size_ctx = sys::tox_self_get_name_size_context();
+            self_get_name_size_ctx.expect()
+                .return_const(self_name.len() as u64);
+
+            let self_get_name_ctx = sys::tox_self_get_name_context();
+            self_get_name_ctx.expect()
                 .returning_st(move |_, name_out| unsafe {
                     std::ptr::copy_nonoverlapping(self_name.as_ptr(), name_out, self_name.len())
                 });
@@ -1505,6 +1861,8 @@ pub(crate) mod tests {
             assert_eq!(friend4.public_key().as_bytes(), "pk4".as_bytes());
             assert_eq!(friend4.name(), "name4");
             assert_eq!(friend4.status(), Status::Offline);
+            assert_eq!(friend4.connection_status(), ConnectionStatus::None);
+            assert_eq!(friend1.connection_status(), ConnectionStatus::Udp);
         }
 
         #[test]
@@ -1690,12 +2048,107 @@ pub(crate) mod tests {
                 .return_const_st(TOX_CONNECTION_NONE);
 
 
+            let max_friend_request_length_ctx = sys::tox_max_friend_request_length_context();
+            max_friend_request_length_ctx
+                .expect()
+                .return_const_st(1016u32);
+
             let _friend = fixture.tox.add_friend(ToxId::from_bytes(vec![0; 38]).unwrap(), "Message".into())?;
             let _friend2 = fixture.tox.add_friend(ToxId::from_bytes(vec![1; 38]).unwrap(), "Message".into())?;
 
             Ok(())
         }
 
+        #[test]
+        fn test_add_friend_request_too_long() {
+            let mut fixture = ToxFixture::new();
+
+            let max_friend_request_length_ctx = sys::tox_max_friend_request_length_context();
+            max_friend_request_length_ctx
+                .expect()
+                .times(1)
+                .return_const_st(4u32);
+
+            // No tox_friend_add expectation, the request must never reach toxcore
+            assert!(matches!(
+                fixture.tox.add_friend(ToxId::from_bytes(vec![0; 38]).unwrap(), "Message".into()),
+                Err(ToxAddFriendError::MessageTooLong)
+            ));
+        }
+
+        #[test]
+        fn test_max_lengths() {
+            let fixture = ToxFixture::new();
+
+            let max_name_length_ctx = sys::tox_max_name_length_context();
+            max_name_length_ctx
+                .expect()
+                .times(1)
+                .return_const_st(128u32);
+
+            let max_status_message_length_ctx = sys::tox_max_status_message_length_context();
+            max_status_message_length_ctx
+                .expect()
+                .times(1)
+                .return_const_st(1007u32);
+
+            let max_friend_request_length_ctx = sys::tox_max_friend_request_length_context();
+            max_friend_request_length_ctx
+                .expect()
+                .times(1)
+                .return_const_st(1016u32);
+
+            assert_eq!(fixture.tox.max_name_length(), 128);
+            assert_eq!(fixture.tox.max_status_message_length(), 1007);
+            assert_eq!(fixture.tox.max_friend_request_length(), 1016);
+        }
+
+        #[test]
+        fn test_set_name_length_validated() -> Result<(), Box<dyn std::error::Error>> {
+            let mut fixture = ToxFixture::new();
+
+            let max_name_length_ctx = sys::tox_max_name_length_context();
+            max_name_length_ctx
+                .expect()
+                .times(2)
+                .return_const_st(4u32);
+
+            let set_name_ctx = sys::tox_self_set_name_context();
+            set_name_ctx
+                .expect()
+                .times(1)
+                .withf_st(|_, _name, length, _err| *length == 4)
+                .return_const_st(true);
+
+            fixture.tox.self_set_name("name")?;
+            assert!(fixture.tox.self_set_name("longer").is_err());
+
+            Ok(())
+        }
+
+        #[test]
+        fn test_set_status_message_length_validated() -> Result<(), Box<dyn std::error::Error>> {
+            let mut fixture = ToxFixture::new();
+
+            let max_status_message_length_ctx = sys::tox_max_status_message_length_context();
+            max_status_message_length_ctx
+                .expect()
+                .times(2)
+                .return_const_st(4u32);
+
+            let set_status_message_ctx = sys::tox_self_set_status_message_context();
+            set_status_message_ctx
+                .expect()
+                .times(1)
+                .withf_st(|_, _status_message, length, _err| *length == 4)
+                .return_const_st(true);
+
+            fixture.tox.self_set_status_message("busy")?;
+            assert!(fixture.tox.self_set_status_message("very busy").is_err());
+
+            Ok(())
+        }
+
         #[test]
         fn test_remove_friend() -> Result<(), Box<dyn std::error::Error>> {
             let mut fixture = ToxFixture::new();
@@ -1724,9 +2177,351 @@ pub(crate) mod tests {
             Ok(())
 
         }
-    }
 
-    // FIXME: test friend name dispatch
+        #[test]
+        fn test_set_typing() -> Result<(), Box<dyn std::error::Error>> {
+            let mut fixture = ToxFixture::new();
+
+            let default_peer_id = fixture.default_peer_id;
+
+            let add_friend_norequest_ctx = sys::tox_friend_add_norequest_context();
+            add_friend_norequest_ctx
+                .expect()
+                .returning_st(move |_, _pk, _err| {
+                    default_peer_id
+                });
+
+            let set_typing_ctx = sys::tox_self_set_typing_context();
+            set_typing_ctx
+                .expect()
+                .times(1)
+                .withf_st(move |_, id, typing, _err| {
+                    *id == default_peer_id && !*typing
+                })
+                .return_const_st(true);
+
+            let friend = fixture.tox.add_friend_norequest(&fixture.default_peer_pk)?;
+            fixture.tox.set_typing(&friend, false)?;
+
+            Ok(())
+        }
+
+        #[test]
+        fn test_friend_last_online() -> Result<(), Box<dyn std::error::Error>> {
+            let mut fixture = ToxFixture::new();
+
+            let default_peer_id = fixture.default_peer_id;
+
+            let add_friend_norequest_ctx = sys::tox_friend_add_norequest_context();
+            add_friend_norequest_ctx
+                .expect()
+                .returning_st(move |_, _pk, _err| {
+                    default_peer_id
+                });
+
+            let last_online_ctx = sys::tox_friend_get_last_online_context();
+            last_online_ctx
+                .expect()
+                .times(1)
+                .withf_st(move |_, id, _err| *id == default_peer_id)
+                .return_const_st(1_600_000_000u64);
+            last_online_ctx
+                .expect()
+                .times(1)
+                .return_const_st(u64::MAX);
+            last_online_ctx
+                .expect()
+                .times(1)
+                .returning_st(|_, _, err| {
+                    unsafe { *err = TOX_ERR_FRIEND_GET_LAST_ONLINE_FRIEND_NOT_FOUND };
+                    u64::MAX
+                });
+
+            let friend = fixture.tox.add_friend_norequest(&fixture.default_peer_pk)?;
+            assert_eq!(
+                fixture.tox.friend_last_online(&friend)?,
+                Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+            );
+            assert_eq!(fixture.tox.friend_last_online(&friend)?, None);
+            assert!(matches!(
+                fixture.tox.friend_last_online(&friend),
+                Err(ToxFriendQueryError::NotFound)
+            ));
+
+            Ok(())
+        }
+
+        #[test]
+        fn test_friend_exists() -> Result<(), Box<dyn std::error::Error>> {
+            let mut fixture = ToxFixture::new();
+
+            let default_peer_id = fixture.default_peer_id;
+
+            let add_friend_norequest_ctx = sys::tox_friend_add_norequest_context();
+            add_friend_norequest_ctx
+                .expect()
+                .returning_st(move |_, _pk, _err| {
+                    default_peer_id
+                });
+
+            let friend = fixture.tox.add_friend_norequest(&fixture.default_peer_pk)?;
+            assert!(fixture.tox.friend_exists(&friend));
+
+            // Our handle is for a removed friend whose number toxcore has
+            // since given to the default peer
+            friend.data.write().unwrap().public_key = PublicKey {
+                key: "testkey2".to_string().into_bytes(),
+            };
+            assert!(!fixture.tox.friend_exists(&friend));
+
+            Ok(())
+        }
+
+        #[test]
+        fn test_cancel_outgoing_call() -> Result<(), Box<dyn std::error::Error>> {
+            let mut fixture = ToxFixture::new();
+
+            let default_peer_id = fixture.default_peer_id;
+
+            let add_friend_norequest_ctx = sys::tox_friend_add_norequest_context();
+            add_friend_norequest_ctx
+                .expect()
+                .returning_st(move |_, _pk, _err| {
+                    default_peer_id
+                });
+
+            let call_ctx = sys::toxav_call_context();
+            call_ctx
+                .expect()
+                .times(1)
+                .withf_st(move |_, id, audio_bit_rate, _video_bit_rate, _err| {
+                    *id == default_peer_id && *audio_bit_rate == 64
+                })
+                .return_const_st(true);
+
+            let call_control_ctx = sys::toxav_call_control_context();
+            call_control_ctx
+                .expect()
+                .times(1)
+                .withf_st(move |_, id, control, _err| {
+                    *id == default_peer_id && *control == TOXAV_CALL_CONTROL_CANCEL
+                })
+                .return_const_st(true);
+
+            let friend = fixture.tox.add_friend_norequest(&fixture.default_peer_pk)?;
+            let call = fixture.tox.call_friend(&friend, 64)?;
+            assert!(call.call_state() == CallState::WaitingForPeerAnswer);
+
+            call.cancel();
+
+            // The control is handled in Tox::run, pull it through by hand
+            let (friend_id, control) = wait_for_call_control(&mut fixture.tox.data.call_data)
+                .now_or_never()
+                .expect("Cancel not sent");
+            assert_eq!(friend_id, default_peer_id);
+            fixture.tox.handle_call_control(friend_id, control);
+
+            assert!(fixture.tox.data.call_data.is_empty());
+
+            Ok(())
+        }
+
+        #[test]
+        fn test_answer_with_bit_rate() -> Result<(), Box<dyn std::error::Error>> {
+            let mut fixture = ToxFixture::new();
+
+            let default_peer_id = fixture.default_peer_id;
+
+            let add_friend_norequest_ctx = sys::tox_friend_add_norequest_context();
+            add_friend_norequest_ctx
+                .expect()
+                .returning_st(move |_, _pk, _err| {
+                    default_peer_id
+                });
+
+            let answer_ctx = sys::toxav_answer_context();
+            answer_ctx
+                .expect()
+                .times(1)
+                .withf_st(move |_, id, audio_bit_rate, video_bit_rate, _err| {
+                    *id == default_peer_id && *audio_bit_rate == 32 && *video_bit_rate == 0
+                })
+                .return_const_st(true);
+
+            fixture.tox.add_friend_norequest(&fixture.default_peer_pk)?;
+
+            let incoming_call = Rc::new(RefCell::new(None));
+            let incoming_call_clone = Rc::clone(&incoming_call);
+            fixture.tox.data.event_callback = Some(Box::new(move |event| match event {
+                Event::IncomingCall(call) => *incoming_call_clone.borrow_mut() = Some(call),
+                _ => panic!("Unexpected event"),
+            }));
+
+            unsafe {
+                toxav_call_callback(
+                    std::ptr::null_mut(),
+                    default_peer_id,
+                    true,
+                    false,
+                    (&mut *fixture.tox.data as *mut ToxData) as *mut std::os::raw::c_void,
+                );
+            }
+
+            let incoming_call = incoming_call.borrow_mut().take().expect("No incoming call");
+            let call = incoming_call.accept(32)?;
+
+            // The control is handled in Tox::run, pull it through by hand
+            let (friend_id, control) = wait_for_call_control(&mut fixture.tox.data.call_data)
+                .now_or_never()
+                .expect("Answer not sent");
+            fixture.tox.handle_call_control(friend_id, control);
+
+            assert!(call.call_state() == CallState::Active);
+
+            Ok(())
+        }
+
+        #[test]
+        fn test_pause_resume_call() -> Result<(), Box<dyn std::error::Error>> {
+            let mut fixture = ToxFixture::new();
+
+            let default_peer_id = fixture.default_peer_id;
+
+            let add_friend_norequest_ctx = sys::tox_friend_add_norequest_context();
+            add_friend_norequest_ctx
+                .expect()
+                .returning_st(move |_, _pk, _err| {
+                    default_peer_id
+                });
+
+            let call_ctx = sys::toxav_call_context();
+            call_ctx.expect().return_const_st(true);
+
+            let controls = Arc::new(RwLock::new(Vec::new()));
+            let controls_clone = Arc::clone(&controls);
+            let call_control_ctx = sys::toxav_call_control_context();
+            call_control_ctx
+                .expect()
+                .withf_st(move |_, id, _control, _err| *id == default_peer_id)
+                .returning_st(move |_, _id, control, _err| {
+                    controls_clone.write().unwrap().push(control);
+                    true
+                });
+
+            let friend = fixture.tox.add_friend_norequest(&fixture.default_peer_pk)?;
+            let call = fixture.tox.call_friend(&friend, 64)?;
+
+            let pump_control = |fixture: &mut ToxFixture| {
+                let (friend_id, control) = wait_for_call_control(&mut fixture.tox.data.call_data)
+                    .now_or_never()
+                    .expect("Control not sent");
+                fixture.tox.handle_call_control(friend_id, control);
+            };
+
+            call.set_paused(true)?;
+            pump_control(&mut fixture);
+            call.set_paused(false)?;
+            pump_control(&mut fixture);
+
+            assert_eq!(
+                *controls.read().unwrap(),
+                vec![TOXAV_CALL_CONTROL_PAUSE, TOXAV_CALL_CONTROL_RESUME]
+            );
+
+            // Pausing doesn't end the call
+            assert!(call.call_state() == CallState::WaitingForPeerAnswer);
+
+            Ok(())
+        }
+
+        #[test]
+        fn test_bootstrap() -> Result<(), Box<dyn std::error::Error>> {
+            let mut fixture = ToxFixture::new();
+
+            let node_pk = fixture.default_peer_pk.clone();
+            let pk_len = node_pk.key.len();
+
+            let bootstrap_ctx = sys::tox_bootstrap_context();
+            bootstrap_ctx
+                .expect()
+                .times(1)
+                .withf_st(move |_, host, port, public_key, _err| {
+                    let host = unsafe { std::ffi::CStr::from_ptr(*host) };
+                    let public_key = unsafe { std::slice::from_raw_parts(*public_key, pk_len) };
+                    host.to_str() == Ok("node.example")
+                        && *port == 33445
+                        && public_key == node_pk.key
+                })
+                .return_const_st(true);
+
+            fixture.tox.bootstrap("node.example", 33445, &fixture.default_peer_pk)?;
+
+            // Interior nul bytes can't be passed on to toxcore
+            assert!(matches!(
+                fixture.tox.bootstrap("node\0example", 33445, &fixture.default_peer_pk),
+                Err(ToxBootstrapError::BadHost)
+            ));
+
+            Ok(())
+        }
+
+        #[test]
+        fn test_self_connection_status() {
+            let fixture = ToxFixture::new();
+
+            let connection_status_ctx = sys::tox_self_get_connection_status_context();
+            connection_status_ctx
+                .expect()
+                .times(1)
+                .return_const_st(TOX_CONNECTION_TCP);
+
+            assert_eq!(fixture.tox.self_connection_status(), ConnectionStatus::Tcp);
+        }
+
+        #[test]
+        fn test_self_status() -> Result<(), Box<dyn std::error::Error>> {
+            let mut fixture = ToxFixture::new();
+
+            let get_status_ctx = sys::tox_self_get_status_context();
+            get_status_ctx
+                .expect()
+                .times(1)
+                .return_const_st(TOX_USER_STATUS_AWAY);
+            assert_eq!(fixture.tox.self_status(), Status::Away);
+
+            let set_status_ctx = sys::tox_self_set_status_context();
+            set_status_ctx
+                .expect()
+                .times(1)
+                .withf_st(|_, status| *status == TOX_USER_STATUS_BUSY)
+                .return_const_st(());
+            fixture.tox.self_set_status(Status::Busy)?;
+
+            // Never reaches toxcore
+            assert!(fixture.tox.self_set_status(Status::Offline).is_err());
+
+            Ok(())
+        }
+
+        #[test]
+        fn test_self_dht_id() {
+            let fixture = ToxFixture::new();
+
+            // The fixture already answers the public key size
+            let dht_id = vec![7u8; fixture.pk_len];
+
+            let dht_id_clone = dht_id.clone();
+            let dht_id_ctx = sys::tox_self_get_dht_id_context();
+            dht_id_ctx
+                .expect()
+                .times(1)
+                .returning_st(move |_, output| unsafe {
+                    std::ptr::copy_nonoverlapping(dht_id_clone.as_ptr(), output, dht_id_clone.len());
+                });
+
+            assert_eq!(fixture.tox.self_dht_id().key, dht_id);
+        }
+    }
 
     macro_rules! test_array_getter {
         ($name:ident, $value:expr) => {
@@ -0,0 +1,190 @@
+use crate::{error::ToxUriParseError, ToxId};
+
+use std::{fmt, str::FromStr};
+
+const SCHEME: &str = "tox:";
+
+/// A tox ID in the tox: URI form other clients use for links and QR codes,
+/// e.g. tox:<hex tox id>?name=Alice
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ToxUri {
+    pub tox_id: ToxId,
+    /// Display name to suggest for the friend, if the sharer provided one
+    pub name: Option<String>,
+}
+
+impl ToxUri {
+    pub fn new(tox_id: ToxId, name: Option<String>) -> ToxUri {
+        ToxUri { tox_id, name }
+    }
+}
+
+impl fmt::Display for ToxUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", SCHEME, self.tox_id)?;
+
+        match &self.name {
+            Some(name) if !name.is_empty() => write!(f, "?name={}", percent_encode(name)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromStr for ToxUri {
+    type Err = ToxUriParseError;
+
+    /// Accepts tox:<id>, tox://<id> and a bare hex tox ID, so the same input
+    /// handles pasted links and IDs copied out of older clients
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        let has_scheme = s
+            .get(..SCHEME.len())
+            .map_or(false, |scheme| scheme.eq_ignore_ascii_case(SCHEME));
+
+        let rest = if has_scheme {
+            let rest = &s[SCHEME.len()..];
+            rest.strip_prefix("//").unwrap_or(rest)
+        } else {
+            s
+        };
+
+        let (id, query) = match rest.find('?') {
+            Some(pos) => (&rest[..pos], Some(&rest[pos + 1..])),
+            None => (rest, None),
+        };
+
+        let tox_id = ToxId::from_str(id)?;
+
+        let mut name = None;
+        for param in query.into_iter().flat_map(|query| query.split('&')) {
+            if let Some(value) = param.strip_prefix("name=") {
+                let value = percent_decode(value)?;
+                if !value.is_empty() {
+                    name = Some(value);
+                }
+            }
+        }
+
+        Ok(ToxUri { tox_id, name })
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                ret.push(b as char)
+            }
+            _ => ret.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    ret
+}
+
+fn percent_decode(s: &str) -> Result<String, ToxUriParseError> {
+    let bytes = s.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .ok_or(ToxUriParseError::InvalidEncoding)?;
+                let hex =
+                    std::str::from_utf8(hex).map_err(|_| ToxUriParseError::InvalidEncoding)?;
+                let b =
+                    u8::from_str_radix(hex, 16).map_err(|_| ToxUriParseError::InvalidEncoding)?;
+                ret.push(b);
+                i += 3;
+            }
+            b'+' => {
+                ret.push(b' ');
+                i += 1;
+            }
+            b => {
+                ret.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(ret).map_err(|_| ToxUriParseError::InvalidEncoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tox_id() -> ToxId {
+        ToxId::from_bytes((0..ToxId::SIZE as u8).collect()).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let uri = ToxUri::new(tox_id(), Some("Alice & Bob/ünïcode".to_string()));
+        let encoded = uri.to_string();
+
+        assert!(encoded.starts_with(&format!("tox:{}?name=", tox_id())));
+        assert!(!encoded[4..].contains(&['&', '/', ' ', ':'][..]));
+        assert_eq!(ToxUri::from_str(&encoded)?, uri);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_name() -> Result<(), Box<dyn std::error::Error>> {
+        let uri = ToxUri::new(tox_id(), None);
+        assert_eq!(uri.to_string(), format!("tox:{}", tox_id()));
+        assert_eq!(ToxUri::from_str(&uri.to_string())?, uri);
+
+        // An empty name is the same as no name
+        let empty = ToxUri::new(tox_id(), Some(String::new()));
+        assert_eq!(empty.to_string(), uri.to_string());
+        assert_eq!(ToxUri::from_str(&format!("tox:{}?name=", tox_id()))?, uri);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lenient_parsing() -> Result<(), Box<dyn std::error::Error>> {
+        let expected = ToxUri::new(tox_id(), Some("a b".to_string()));
+
+        for input in &[
+            format!("TOX:{}?name=a+b", tox_id()),
+            format!("tox://{}?other=1&name=a%20b", tox_id()),
+            format!("  tox:{}?name=a%20b\n", tox_id()),
+        ] {
+            assert_eq!(ToxUri::from_str(input)?, expected);
+        }
+
+        // Bare IDs are still accepted
+        assert_eq!(
+            ToxUri::from_str(&tox_id().to_string())?,
+            ToxUri::new(tox_id(), None)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(matches!(
+            ToxUri::from_str("tox:abcd"),
+            Err(ToxUriParseError::InvalidId(_))
+        ));
+        assert!(matches!(
+            ToxUri::from_str(&format!("tox:{}?name=%4", tox_id())),
+            Err(ToxUriParseError::InvalidEncoding)
+        ));
+        assert!(matches!(
+            ToxUri::from_str(&format!("tox:{}?name=%ff", tox_id())),
+            Err(ToxUriParseError::InvalidEncoding)
+        ));
+    }
+}
@@ -26,6 +26,12 @@ Item {
             selectByMouse: true
             Layout.fillWidth: true
             Layout.alignment: Qt.AlignRight | Qt.AlignVCenter
+
+            onEditingFinished: {
+                if (text !== account.name) {
+                    tocks.setSelfName(account.id, text)
+                }
+            }
         }
 
         Text {
@@ -41,6 +47,18 @@ Item {
             Layout.alignment: Qt.AlignRight | Qt.AlignVCenter
         }
 
+        Text {
+            Layout.preferredWidth: 50
+            text: "Online"
+            horizontalAlignment: Text.AlignLeft
+        }
+
+        // Messages sent while offline are queued until we reconnect
+        Switch {
+            checked: true
+            onToggled: tocks.setOnline(account.id, checked)
+        }
+
         Text {
             text: "Tox ID"
         }
@@ -75,6 +93,19 @@ Item {
             }
         }
 
+        Text {
+            text: "Share link"
+        }
+
+        // Includes our name, other clients can add us straight from it
+        TextEdit {
+            text: account.toxUri
+            Layout.maximumWidth: 300
+            readOnly: true
+            wrapMode: Text.WrapAnywhere
+            selectByMouse: true
+        }
+
         Text {
             Layout.columnSpan: 2
             text: "Blocked Users"
@@ -88,10 +119,14 @@ Item {
 
             model: account.blockedUsers
 
-            delegate: Text {
-                anchors.fill: parent
+            // The tox ID is what's needed to add them back, fall back to
+            // the public key for users who added us
+            delegate: TextEdit {
                 width: 300
-                text: modelData.publicKey
+                readOnly: true
+                selectByMouse: true
+                wrapMode: Text.WrapAnywhere
+                text: modelData.toxId !== "" ? modelData.toxId : modelData.publicKey
             }
         }
     }
@@ -5,16 +5,85 @@ import QtQuick.Layouts 1.11
 
 Rectangle {
     required property var friend
+    required property var account
 
     color: "white"
 
-    Row {
-        leftPadding: 10
+    RowLayout {
+        anchors.fill: parent
+        anchors.leftMargin: 10
+        anchors.rightMargin: 10
 
         Text {
-            anchors.verticalCenter: parent.verticalCenter
             text: friend !== undefined ? friend.name : ""
             font.bold: true
         }
+
+        Text {
+            visible: friend !== undefined && friend.verified
+            text: "Verified"
+            color: "green"
+            font.pointSize: 8
+        }
+
+        // Not seen in a long time, toxcore can't tell us more than that.
+        // Re-sending a friend request is the only way back if they really
+        // did remove us
+        Text {
+            visible: friend !== undefined && friend.possiblyRemoved
+            text: "May have removed you"
+            color: "orange"
+            font.pointSize: 8
+        }
+
+        Text {
+            visible: text !== ""
+            text: {
+                if (friend === undefined) {
+                    return ""
+                }
+
+                switch (friend.lastCallEnd) {
+                case "missed":
+                    return "Missed call"
+                case "declined":
+                    return "Call not answered"
+                default:
+                    return ""
+                }
+            }
+            color: "orange"
+            font.pointSize: 8
+        }
+
+        // Compare against the key your friend reads out to you through some
+        // other channel before marking them verified
+        TextEdit {
+            Layout.fillWidth: true
+
+            text: friend !== undefined ? friend.publicKey : ""
+            readOnly: true
+            selectByMouse: true
+            color: "grey"
+            font.family: "monospace"
+            font.pointSize: 8
+            clip: true
+        }
+
+        TocksButton {
+            visible: friend !== undefined
+            text: friend !== undefined && friend.muted ? "Unmute" : "Mute"
+            onClicked: {
+                tocks.setChatMuted(account.id, friend.chatId, !friend.muted)
+            }
+        }
+
+        TocksButton {
+            visible: friend !== undefined
+            text: friend !== undefined && friend.verified ? "Unverify" : "Mark verified"
+            onClicked: {
+                tocks.setFriendVerified(account.id, friend.userId, !friend.verified)
+            }
+        }
     }
 }
@@ -19,6 +19,90 @@ ListView {
 
     // Global chatModel defined in rust
     model: chatModel
+
+    signal replyRequested(int messageId, string snippet)
+
+    // Page in older history at the top, and drop it again once we're back
+    // at the newest messages
+    onAtYBeginningChanged: {
+        if (atYBeginning && count > 0) {
+            chatModel.fetchOlder()
+        }
+    }
+
+    onAtYEndChanged: {
+        if (atYEnd) {
+            chatModel.evictOldMessages()
+            markNewestRead()
+        }
+    }
+
+    onCountChanged: markNewestRead()
+
+    // Newest message we last told the peer we'd read
+    property int readMarkerMessage: -1
+
+    // Let the peer know once a new message of theirs is in view at the
+    // bottom. Our own messages and paged in history change count too, but
+    // there's nothing new to report then. Nobody is reading while the window
+    // is minimized or in the background
+    function markNewestRead() {
+        if (!atYEnd || Qt.application.state !== Qt.ApplicationActive) {
+            return
+        }
+
+        const newest = chatModel.newestPeerMessageId(account.userId)
+        if (newest <= readMarkerMessage) {
+            return
+        }
+
+        readMarkerMessage = newest
+        tocks.sendReadMarker(account.id, chatModel.chat)
+        tocks.markChatRead(account.id, chatModel.chat)
+    }
+
+    Connections {
+        target: chatModel
+
+        function onChatChanged() {
+            root.readMarkerMessage = -1
+        }
+
+        function onScrollRequested(row) {
+            root.positionViewAtIndex(row, ListView.Center)
+        }
+    }
+
+    // Whatever arrived while we were away is seen once the window is back
+    Connections {
+        target: Qt.application
+
+        function onStateChanged() {
+            root.markNewestRead()
+        }
+    }
+
+    // QML has no clipboard API of its own, copy through a hidden text edit
+    TextEdit {
+        id: clipboardHelper
+        visible: false
+    }
+
+    Menu {
+        id: messageMenu
+
+        property int row: -1
+
+        MenuItem {
+            text: "Copy"
+            onTriggered: {
+                clipboardHelper.text = chatModel.textForRow(messageMenu.row)
+                clipboardHelper.selectAll()
+                clipboardHelper.copy()
+            }
+        }
+    }
+
     delegate: Rectangle {
         property bool sentByMe: model.senderId == account.userId
 
@@ -32,23 +116,131 @@ ListView {
         anchors.leftMargin: bubbleHorizPadding
         anchors.rightMargin: bubbleHorizPadding
 
-        color: (sentByMe && model.complete) ? Colors.selfColor : Colors.friendColor
+        property bool darkTheme: ApplicationWindow.window.darkTheme
+
+        color: {
+            if (sentByMe && model.complete) {
+                return darkTheme ? Colors.darkSelfColor : Colors.selfColor
+            }
+            return darkTheme ? Colors.darkFriendColor : Colors.friendColor
+        }
 
-        height: messageText.height + bubbleTextVertPadding
-        width: messageText.paintedWidth + bubbleTextHorizPadding
+        height: bubbleContent.height + bubbleTextVertPadding
+        width: Math.max(messageText.paintedWidth, quoteText.visible ? quoteText.paintedWidth : 0) + bubbleTextHorizPadding
         radius: 5
 
-        Text {
-            id: messageText
+        MouseArea {
+            anchors.fill: parent
+            acceptedButtons: Qt.LeftButton | Qt.RightButton
+            onDoubleClicked: root.replyRequested(model.messageId, model.message)
+            onClicked: {
+                if (mouse.button === Qt.RightButton) {
+                    messageMenu.row = index
+                    messageMenu.popup()
+                }
+            }
+        }
+
+        Column {
+            id: bubbleContent
 
             anchors.left: parent.left
             anchors.leftMargin: bubbleTextHorizPadding / 2
             anchors.verticalCenter: parent.verticalCenter
 
-            width: 500
+            spacing: 5
+
+            Text {
+                id: quoteText
+
+                visible: model.replySnippet !== ""
+                width: 500
+
+                text: "> " + model.replySnippet
+                color: "grey"
+                font.italic: true
+                elide: Text.ElideRight
+
+                // Jump to the message being replied to
+                MouseArea {
+                    anchors.fill: parent
+                    cursorShape: Qt.PointingHandCursor
+                    onClicked: chatModel.scrollToMessage(model.replyTo)
+                }
+            }
+
+            Text {
+                id: messageText
+
+                width: 500
+
+                text: model.styledMessage
+                textFormat: Text.StyledText
+                color: darkTheme ? Colors.darkText : "black"
+                wrapMode: Text.Wrap
+            }
+        }
+
+        Text {
+            id: retryText
+
+            // Only once toxcore refused it or its receipt timed out, a
+            // message still waiting on its receipt may well arrive
+            visible: sentByMe && !model.complete && !model.queued && model.sendState === "failed"
+
+            anchors.right: parent.left
+            anchors.rightMargin: bubbleHorizPadding
+            anchors.verticalCenter: parent.verticalCenter
+
+            text: "Retry"
+            color: "grey"
+            font.underline: retryMouseArea.containsMouse
+
+            MouseArea {
+                id: retryMouseArea
+
+                anchors.fill: parent
+                hoverEnabled: true
+                cursorShape: Qt.PointingHandCursor
+
+                onClicked: {
+                    tocks.resendMessage(account.id, chatModel.chat, model.messageId)
+                }
+            }
+        }
+
+        Text {
+            visible: sentByMe && !model.complete && model.queued
+
+            anchors.right: parent.left
+            anchors.rightMargin: bubbleHorizPadding
+            anchors.verticalCenter: parent.verticalCenter
+
+            text: "Queued"
+            color: "grey"
+            font.pointSize: 8
+
+            ToolTip.visible: queuedMouseArea.containsMouse
+            ToolTip.text: "Will be sent when your friend comes online"
+
+            MouseArea {
+                id: queuedMouseArea
+
+                anchors.fill: parent
+                hoverEnabled: true
+            }
+        }
+
+        Text {
+            visible: sentByMe && model.complete && model.readByPeer
+
+            anchors.right: parent.left
+            anchors.rightMargin: bubbleHorizPadding
+            anchors.verticalCenter: parent.verticalCenter
 
-            text: model.message
-            wrapMode: Text.Wrap
+            text: "Read"
+            color: "grey"
+            font.pointSize: 8
         }
     }
 
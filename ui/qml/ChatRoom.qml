@@ -11,7 +11,11 @@ Rectangle {
     required property var account
     required property var friend
 
-    color: "white"
+    // Message the next message we send replies to, -1 for none
+    property int replyTo: -1
+    property string replySnippet: ""
+
+    color: ApplicationWindow.window.darkTheme ? Colors.darkBackground : "white"
 
     ColumnLayout {
         anchors.fill: parent
@@ -19,6 +23,7 @@ Rectangle {
 
         ChatHeader {
             friend: root.friend
+            account: root.account
 
             Layout.fillWidth: true
             Layout.minimumHeight: 40
@@ -36,12 +41,35 @@ Rectangle {
             Layout.fillHeight: true
             Layout.fillWidth: true
             id: chatLog
+
+            onReplyRequested: {
+                root.replyTo = messageId
+                root.replySnippet = snippet
+                messageText.forceActiveFocus()
+            }
         }
 
         TocksSpacer {
             Layout.fillWidth: true
         }
 
+        Text {
+            Layout.fillWidth: true
+            Layout.margins: 5
+
+            visible: root.replyTo >= 0
+
+            text: "Replying to: " + root.replySnippet + " (click to cancel)"
+            color: "grey"
+            elide: Text.ElideRight
+
+            MouseArea {
+                anchors.fill: parent
+                cursorShape: Qt.PointingHandCursor
+                onClicked: root.replyTo = -1
+            }
+        }
+
         Rectangle {
             Layout.fillWidth: true
             Layout.fillHeight: false
@@ -70,10 +98,22 @@ Rectangle {
                         event.accepted = false
                         return
                         }
-                        tocks.sendMessage(account.id, friend.chatId, text)
+                        if (root.replyTo >= 0) {
+                            tocks.sendReply(account.id, friend.chatId, root.replyTo, text)
+                            root.replyTo = -1
+                        } else {
+                            tocks.sendMessage(account.id, friend.chatId, text)
+                        }
                         text = ""
                     }
 
+                    // Skip the input being cleared after a send
+                    onTextChanged: {
+                        if (text.length > 0) {
+                            tocks.setTyping(account.id, friend.chatId)
+                        }
+                    }
+
                     Keys.onReturnPressed: {
                         handleReturn(event)
                     }
@@ -81,6 +121,47 @@ Rectangle {
                     Keys.onEnterPressed: {
                         handleReturn(event)
                     }
+
+                    Text {
+                        // Long messages are split on send, let the user know
+                        // before it happens. Toxcore limits are in UTF-8
+                        // bytes, not characters
+                        readonly property int maxLength: account.maxMessageLength
+                        readonly property int byteLength: utf8Length(messageText.text)
+                        readonly property bool willSplit: maxLength > 0 && byteLength > maxLength
+
+                        function utf8Length(str) {
+                            var bytes = 0
+                            for (const c of str) {
+                                const codePoint = c.codePointAt(0)
+                                if (codePoint < 0x80) {
+                                    bytes += 1
+                                } else if (codePoint < 0x800) {
+                                    bytes += 2
+                                } else if (codePoint < 0x10000) {
+                                    bytes += 3
+                                } else {
+                                    bytes += 4
+                                }
+                            }
+                            return bytes
+                        }
+
+                        anchors.right: parent.right
+                        anchors.bottom: parent.bottom
+                        anchors.margins: 5
+
+                        visible: messageText.length > 0 && maxLength > 0
+                        color: willSplit ? "red" : "grey"
+                        font.pointSize: 8
+                        text: {
+                            var counter = byteLength + "/" + maxLength
+                            if (willSplit) {
+                                counter += " (sent as " + Math.ceil(byteLength / maxLength) + " messages)"
+                            }
+                            return counter
+                        }
+                    }
                 }
 
                 TocksButton {
@@ -18,3 +18,8 @@ var busyStatus = "#d93736"
 var offlineStatus = "grey"
 
 var background = "#ffffff"
+
+var darkBackground = "#2b2a33"
+var darkSelfColor = "#5e5885"
+var darkFriendColor = "#44424f"
+var darkText = "#dddddd"
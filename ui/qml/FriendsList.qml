@@ -62,6 +62,14 @@ Rectangle {
                     color: Colors.sidebarText
                 }
 
+                Text {
+                    Layout.alignment: Qt.AlignVCenter
+                    visible: modelData.unreadCount > 0
+                    text: modelData.unreadCount
+                    color: Colors.sidebarText
+                    font.bold: true
+                }
+
                 StatusIcon {
                     Layout.fillHeight: true
                     Layout.margins: SidebarConstants.contentMargins
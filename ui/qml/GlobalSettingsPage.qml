@@ -1,6 +1,7 @@
 import QtQuick 2.0
 import QtQuick.Controls 2.15
 import QtQuick.Layouts 1.12
+import QtQuick.Dialogs 1.3
 
 Item {
     GridLayout {
@@ -21,9 +22,10 @@ Item {
 
         TocksComboBox {
             model: tocks.audioOutputs
+            currentIndex: tocks.audioOutputIndex
 
-            onCurrentIndexChanged: {
-                tocks.setAudioOutput(currentIndex)
+            onActivated: {
+                tocks.setAudioOutput(index)
             }
 
             Layout.preferredWidth: 400
@@ -42,6 +44,14 @@ Item {
                 }
             }
 
+            TocksButton {
+                Layout.fillWidth: true
+                text: "Test microphone"
+                onClicked: {
+                    tocks.startLoopbackTest()
+                }
+            }
+
             TocksButton {
                 Layout.fillWidth: true
                 text: "Stop audio test"
@@ -51,6 +61,141 @@ Item {
             }
         }
 
+        Text {
+            Layout.columnSpan: 2
+            font.bold: true
+            text: "Appearance"
+        }
+
+        Text {
+            Layout.preferredWidth: 150
+            text: "Theme"
+            horizontalAlignment: Text.AlignLeft
+        }
+
+        TocksComboBox {
+            model: ["system", "light", "dark"]
+            currentIndex: model.indexOf(tocks.theme)
+
+            onActivated: {
+                tocks.setTheme(model[index])
+            }
+
+            Layout.preferredWidth: 400
+            Layout.fillWidth: true
+            Layout.alignment: Qt.AlignRight | Qt.AlignVCenter
+        }
+
+        Text {
+            Layout.preferredWidth: 150
+            text: "Notification sounds"
+            horizontalAlignment: Text.AlignLeft
+        }
+
+        CheckBox {
+            checked: tocks.notificationSounds
+
+            onToggled: {
+                tocks.setNotificationSounds(checked)
+            }
+        }
+
+        Text {
+            Layout.preferredWidth: 150
+            text: "Do not disturb"
+            horizontalAlignment: Text.AlignLeft
+        }
+
+        CheckBox {
+            checked: tocks.doNotDisturb
+
+            onToggled: {
+                tocks.setDoNotDisturb(checked)
+            }
+        }
+
+        Text {
+            Layout.preferredWidth: 150
+            text: "Notification sound"
+            horizontalAlignment: Text.AlignLeft
+        }
+
+        RowLayout {
+            Text {
+                Layout.fillWidth: true
+                text: tocks.notificationSoundPath !== "" ? tocks.notificationSoundPath : "Default"
+                elide: Text.ElideLeft
+            }
+
+            TocksButton {
+                text: "Choose..."
+                onClicked: soundDialog.open()
+            }
+
+            TocksButton {
+                text: "Reset"
+                enabled: tocks.notificationSoundPath !== ""
+                onClicked: tocks.setNotificationSoundPath("")
+            }
+        }
+
+        FileDialog {
+            id: soundDialog
+            title: "Choose a notification sound"
+            nameFilters: ["Sound files (*.mp3 *.wav)", "All files (*)"]
+            onAccepted: tocks.setNotificationSoundPath(soundDialog.fileUrl.toString())
+        }
+
+        Text {
+            Layout.preferredWidth: 150
+            text: "Expand emoji shortcodes"
+            horizontalAlignment: Text.AlignLeft
+        }
+
+        CheckBox {
+            checked: tocks.expandShortcodes
+
+            onToggled: {
+                tocks.setExpandShortcodes(checked)
+            }
+        }
+
+        Text {
+            Layout.columnSpan: 2
+            font.bold: true
+            text: "Logs"
+        }
+
+        ListView {
+            id: logView
+
+            Layout.columnSpan: 2
+            Layout.fillWidth: true
+            Layout.preferredHeight: 200
+            clip: true
+
+            model: logModel
+            delegate: Text {
+                width: logView.width
+                text: timestamp + " " + level + " " + target + ": " + message
+                color: level === "ERROR" ? "red" : level === "WARN" ? "orange" : "black"
+                font.family: "monospace"
+                font.pointSize: 8
+                elide: Text.ElideRight
+            }
+
+            ScrollBar.vertical: ScrollBar {}
+        }
+
+        TocksButton {
+            Layout.columnSpan: 2
+            Layout.alignment: Qt.AlignHCenter
+            text: "Refresh logs"
+            onClicked: tocks.refreshLogs()
+        }
+
+        Component.onCompleted: tocks.refreshLogs()
+
         Text {
             Layout.columnSpan: 2
             font.bold: true
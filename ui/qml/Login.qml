@@ -10,7 +10,15 @@ RowLayout {
 
     width: 200
 
+    function showPasswordRequired(accountName) {
+        passwordRequiredText.text = qsTr("A password is required for %1").arg(accountName)
+        passwordRequiredText.visible = true
+        password.forceActiveFocus()
+    }
+
     function login() {
+        passwordRequiredText.visible = false
+
         if (comboBox.currentIndex != 0) {
             tocks.login(comboBox.currentText, password.text)
         } else {
@@ -69,6 +77,14 @@ RowLayout {
             onAccepted: login()
         }
 
+        Text {
+            id: passwordRequiredText
+            Layout.fillWidth: true
+            visible: false
+            color: "red"
+            wrapMode: Text.Wrap
+        }
+
         TocksButton {
             id: loginButton
             Layout.fillWidth: true
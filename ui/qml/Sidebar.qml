@@ -34,14 +34,19 @@ Rectangle {
                 anchors.right: parent.right
                 account: modelData
 
+                // Select before telling tocks, onActiveAccountChanged below
+                // would otherwise treat this like a new login and reset the
+                // selection
                 onAccountSelected: {
                     root.selectedAccount = account
                     root.selectedFriend = undefined
+                    tocks.setActiveAccount(account.id)
                 }
 
                 onSelectedFriendChanged: {
                     root.selectedAccount = account
                     root.selectedFriend = selectedFriend
+                    tocks.setActiveAccount(account.id)
                 }
 
                 MouseArea {
@@ -60,24 +65,67 @@ Rectangle {
             ScrollBar.vertical: ScrollBar {}
         }
 
-        //Rectangle {
-        //    id: newAccountBox
+        // A new login becomes the active account, follow it so the user
+        // lands on the account they just added. A stopped account hands over
+        // to whichever one is left
+        Connections {
+            target: tocks
 
-        //    Layout.fillWidth: true
-        //    Layout.minimumHeight: SidebarConstants.accountHeight
-        //    color: "white"
+            function onActiveAccountChanged() {
+                if (root.selectedAccount !== undefined && root.selectedAccount.id === tocks.activeAccount) {
+                    return
+                }
+
+                if (tocks.activeAccount === -1) {
+                    root.selectedFriend = undefined
+                    root.selectedAccount = undefined
+                    return
+                }
+
+                for (var i = 0; i < tocks.accounts.length; ++i) {
+                    if (tocks.accounts[i].id === tocks.activeAccount) {
+                        if (sidebarAccounts.currentItem !== null) {
+                            sidebarAccounts.currentItem.clearSelection()
+                        }
+                        sidebarAccounts.currentIndex = i
+                        root.selectedFriend = undefined
+                        root.selectedAccount = tocks.accounts[i]
+                        return
+                    }
+                }
+            }
+        }
+
+        Rectangle {
+            id: newAccountBox
+
+            Layout.fillWidth: true
+            Layout.minimumHeight: SidebarConstants.accountHeight
+            color: Colors.sidebarSettingsBackground
+
+            Text {
+                anchors.verticalCenter: parent.verticalCenter
+                anchors.left: parent.left
+                anchors.margins: SidebarConstants.contentMargins
+
+                text: "Add account..."
+            }
 
-        //    MouseArea {
-        //        id: newAccountArea
+            MouseArea {
+                id: newAccountArea
 
-        //        anchors.fill: parent
+                anchors.fill: parent
 
-        //        onClicked: {
-        //            root.newAccountClicked()
-        //            sidebarAccounts.currentItem.clearSelection()
-        //        }
-        //    }
-        //}
+                cursorShape: Qt.PointingHandCursor
+
+                onClicked: {
+                    root.newAccountClicked()
+                    if (sidebarAccounts.currentItem !== null) {
+                        sidebarAccounts.currentItem.clearSelection()
+                    }
+                }
+            }
+        }
 
         Rectangle {
             id: settingsBox
@@ -107,7 +155,9 @@ Rectangle {
 
                 onClicked: {
                     root.settingsClicked()
-                    sidebarAccounts.currentItem.clearSelection()
+                    if (sidebarAccounts.currentItem !== null) {
+                        sidebarAccounts.currentItem.clearSelection()
+                    }
                 }
             }
         }
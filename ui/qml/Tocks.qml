@@ -11,12 +11,41 @@ ApplicationWindow {
     width: 960
     height: 720
 
+    Component.onCompleted: {
+        // The screen we opened on goes first, it's where the window ends up
+        // if it no longer overlaps any screen
+        var screens = [screen]
+        for (var i = 0; i < Qt.application.screens.length; i++) {
+            screens.push(Qt.application.screens[i])
+        }
+        var geometries = screens.map(function (s) {
+            return { x: s.virtualX, y: s.virtualY, width: s.width, height: s.height }
+        })
+
+        var geometry = tocks.savedWindowGeometry(JSON.stringify(geometries))
+        if (geometry.length === 4) {
+            x = geometry[0]
+            y = geometry[1]
+            width = geometry[2]
+            height = geometry[3]
+        }
+    }
+
     onClosing: {
+        tocks.saveWindowGeometry(x, y, width, height)
         tocks.close()
         Qt.quit()
     }
 
-    color: Colors.background
+    // "system" follows the desktop palette
+    readonly property bool darkTheme: tocks.theme === "dark"
+        || (tocks.theme === "system" && systemPalette.window.hslLightness < 0.5)
+
+    color: darkTheme ? Colors.darkBackground : Colors.background
+
+    SystemPalette {
+        id: systemPalette
+    }
 
     onActiveFocusControlChanged: {
         tocks.visible = activeFocusControl !== null
@@ -27,14 +56,26 @@ ApplicationWindow {
         target: tocks
 
         function onAccountsChanged() {
-            if (tocks.accounts.length === 1) {
+            if (tocks.accounts.length === 1 && applicationStack.currentItem === login) {
                 applicationStack.replace(login, mainWindow)
+            } else if (tocks.accounts.length === 0 && applicationStack.currentItem === mainWindow) {
+                // Every account stopped, nothing left to show
+                applicationStack.replace(mainWindow, login)
             }
         }
 
         function onError(error) {
             console.log(error)
         }
+
+        function onPasswordRequired(accountName) {
+            login.showPasswordRequired(accountName)
+        }
+
+        function onQuitRequested() {
+            tocks.saveWindowGeometry(x, y, width, height)
+            Qt.quit()
+        }
     }
 
     Login {
@@ -1,10 +1,12 @@
 use crate::contacts::{Friend, User};
 
+use ::log::*;
+use chrono::{DateTime, Utc};
 use qmetaobject::*;
-use tocks::{AccountId, CallState, ChatHandle, Status, UserHandle};
-use toxcore::ToxId;
+use tocks::{AccountId, CallEndReason, CallState, ChatHandle, Status, UserHandle};
+use toxcore::{PublicKey, ToxId, ToxUri};
 
-use std::{cell::RefCell, collections::HashMap};
+use std::{cell::RefCell, collections::HashMap, str::FromStr};
 
 #[derive(QObject, Default)]
 #[allow(non_snake_case)]
@@ -15,19 +17,37 @@ pub struct Account {
     userIdChanged: qt_signal!(),
     toxId: qt_property!(QString; NOTIFY toxIdChanged),
     toxIdChanged: qt_signal!(),
+    // tox: link with our name, for sharing with other clients
+    toxUri: qt_property!(QString; NOTIFY toxUriChanged),
+    toxUriChanged: qt_signal!(),
     name: qt_property!(QString; NOTIFY nameChanged),
     nameChanged: qt_signal!(),
+    maxMessageLength: qt_property!(i32; NOTIFY maxMessageLengthChanged),
+    maxMessageLengthChanged: qt_signal!(),
     friends: qt_property!(QVariantList; READ get_friends NOTIFY friendsChanged),
     friendsChanged: qt_signal!(),
+    friendCount: qt_property!(i32; NOTIFY friendCountChanged),
+    friendCountChanged: qt_signal!(),
+    onlineFriendCount: qt_property!(i32; NOTIFY onlineFriendCountChanged),
+    onlineFriendCountChanged: qt_signal!(),
     blockedUsers: qt_property!(QVariantList; READ get_blocked_users NOTIFY blockedUsersChanged),
     blockedUsersChanged: qt_signal!(),
+    friendByPublicKey: qt_method!(fn(&self, public_key: QString) -> QVariant),
 
     friends_storage: HashMap<UserHandle, Box<RefCell<Friend>>>,
+    friend_keys: HashMap<PublicKey, UserHandle>,
     blocked_users_storage: HashMap<UserHandle, User>,
+    address: Option<ToxId>,
 }
 
 impl Account {
-    pub fn new(id: AccountId, user: UserHandle, address: ToxId, name: String) -> Account {
+    pub fn new(
+        id: AccountId,
+        user: UserHandle,
+        address: ToxId,
+        name: String,
+        max_message_length: usize,
+    ) -> Account {
         Account {
             base: Default::default(),
             id: id.id(),
@@ -35,31 +55,84 @@ impl Account {
             userIdChanged: Default::default(),
             toxId: address.to_string().into(),
             toxIdChanged: Default::default(),
+            toxUri: ToxUri::new(address.clone(), Some(name.clone()))
+                .to_string()
+                .into(),
+            toxUriChanged: Default::default(),
             name: name.into(),
             nameChanged: Default::default(),
+            maxMessageLength: max_message_length as i32,
+            maxMessageLengthChanged: Default::default(),
             friends: Default::default(),
             friendsChanged: Default::default(),
+            friendCount: Default::default(),
+            friendCountChanged: Default::default(),
+            onlineFriendCount: Default::default(),
+            onlineFriendCountChanged: Default::default(),
             blockedUsers: Default::default(),
             blockedUsersChanged: Default::default(),
+            friendByPublicKey: Default::default(),
 
             friends_storage: Default::default(),
+            friend_keys: Default::default(),
             blocked_users_storage: Default::default(),
+            address: Some(address),
         }
     }
 
     pub fn add_friend(&mut self, friend: &tocks::Friend) {
+        self.insert_friend(friend);
+        self.friendsChanged();
+        self.counts_changed()
+    }
+
+    /// Like add_friend, but QML only rebuilds the friend list once
+    pub fn add_friends(&mut self, friends: &[tocks::Friend]) {
+        for friend in friends {
+            self.insert_friend(friend);
+        }
+        self.friendsChanged();
+        self.counts_changed()
+    }
+
+    fn insert_friend(&mut self, friend: &tocks::Friend) {
         let id = *friend.id();
+        self.friend_keys.insert(friend.public_key().clone(), id);
         let friend = Box::new(RefCell::new(Friend::from(friend)));
         unsafe { QObject::cpp_construct(&friend) };
-        self.friends_storage.insert(id, friend);
-        self.friendsChanged()
+
+        self.friendCount += 1;
+        if friend.borrow().is_online() {
+            self.onlineFriendCount += 1;
+        }
+
+        if let Some(replaced) = self.friends_storage.insert(id, friend) {
+            self.uncount_friend(&replaced.borrow());
+        }
     }
 
     pub fn remove_friend(&mut self, user_id: UserHandle) {
         // Keep a reference to the removed friend so it does not go out of scope
         // until QML stops using it
-        let _friend = self.friends_storage.remove(&user_id);
-        self.friendsChanged()
+        let friend = self.friends_storage.remove(&user_id);
+        if let Some(friend) = &friend {
+            self.uncount_friend(&friend.borrow());
+        }
+        self.friend_keys.retain(|_, id| *id != user_id);
+        self.friendsChanged();
+        self.counts_changed()
+    }
+
+    fn uncount_friend(&mut self, friend: &Friend) {
+        self.friendCount -= 1;
+        if friend.is_online() {
+            self.onlineFriendCount -= 1;
+        }
+    }
+
+    fn counts_changed(&mut self) {
+        self.friendCountChanged();
+        self.onlineFriendCountChanged();
     }
 
     pub fn get_friends(&mut self) -> QVariantList {
@@ -69,14 +142,131 @@ impl Account {
             .collect()
     }
 
+    pub fn friend_from_public_key(&self, public_key: &PublicKey) -> Option<&RefCell<Friend>> {
+        let user_id = self.friend_keys.get(public_key)?;
+        self.friends_storage.get(user_id).map(|friend| &**friend)
+    }
+
+    /// Friend object for the hex encoded public_key, or null if we have no
+    /// such friend
+    #[allow(non_snake_case)]
+    fn friendByPublicKey(&self, public_key: QString) -> QVariant {
+        let public_key = match PublicKey::from_str(&public_key.to_string()) {
+            Ok(k) => k,
+            Err(_) => return QVariant::default(),
+        };
+
+        match self.friend_from_public_key(&public_key) {
+            Some(friend) => unsafe { (&*friend.borrow_mut() as &dyn QObject).as_qvariant() },
+            None => QVariant::default(),
+        }
+    }
+
     pub fn set_friend_status(&mut self, user_id: UserHandle, status: Status) {
-        self.friends_storage[&user_id]
-            .borrow_mut()
-            .set_status(status);
+        let friend = match self.friends_storage.get(&user_id) {
+            Some(friend) => friend,
+            None => {
+                warn!("Status update for unknown friend {}", user_id.id());
+                return;
+            }
+        };
+
+        let was_online = friend.borrow().is_online();
+        friend.borrow_mut().set_status(status);
+        let is_online = friend.borrow().is_online();
+
+        if was_online != is_online {
+            self.onlineFriendCount += if is_online { 1 } else { -1 };
+            self.onlineFriendCountChanged();
+        }
+    }
+
+    pub fn friend_chat(&self, user_id: UserHandle) -> Option<ChatHandle> {
+        self.friends_storage
+            .get(&user_id)
+            .map(|friend| ChatHandle::from(friend.borrow().chat_id()))
+    }
+
+    /// Whether the friend on the other end of chat is offline. Unknown chats
+    /// count as online so nothing is shown as queued by mistake
+    pub fn chat_peer_offline(&self, chat_id: ChatHandle) -> bool {
+        self.friends_storage
+            .values()
+            .find(|f| f.borrow().chat_id() == chat_id.id())
+            .map_or(false, |f| f.borrow().is_offline())
     }
 
     pub fn set_user_name(&mut self, user_id: UserHandle, name: &str) {
-        self.friends_storage[&user_id].borrow_mut().set_name(name);
+        match self.friends_storage.get(&user_id) {
+            Some(friend) => friend.borrow_mut().set_name(name),
+            None => warn!("Name update for unknown friend {}", user_id.id()),
+        }
+    }
+
+    pub fn set_friend_verified(&mut self, user_id: UserHandle, verified: bool) {
+        match self.friends_storage.get(&user_id) {
+            Some(friend) => friend.borrow_mut().set_verified(verified),
+            None => warn!("Verification update for unknown friend {}", user_id.id()),
+        }
+    }
+
+    pub fn set_friend_possibly_removed(&mut self, user_id: UserHandle, possibly_removed: bool) {
+        match self.friends_storage.get(&user_id) {
+            Some(friend) => friend.borrow_mut().set_possibly_removed(possibly_removed),
+            None => warn!("Removal hint for unknown friend {}", user_id.id()),
+        }
+    }
+
+    pub fn chat_muted(&self, chat_id: ChatHandle) -> bool {
+        self.friends_storage
+            .values()
+            .find(|f| f.borrow().chat_id() == chat_id.id())
+            .map_or(false, |f| f.borrow().is_muted())
+    }
+
+    pub fn set_chat_muted(&mut self, chat_id: ChatHandle, muted: bool) {
+        match self
+            .friends_storage
+            .values()
+            .find(|f| f.borrow().chat_id() == chat_id.id())
+        {
+            Some(friend) => friend.borrow_mut().set_muted(muted),
+            None => warn!("Mute update for unknown chat {}", chat_id.id()),
+        }
+    }
+
+    pub fn set_chat_unread_count(&mut self, chat_id: ChatHandle, unread_count: usize) {
+        match self
+            .friends_storage
+            .values()
+            .find(|f| f.borrow().chat_id() == chat_id.id())
+        {
+            Some(friend) => friend.borrow_mut().set_unread_count(unread_count),
+            None => warn!("Unread count update for unknown chat {}", chat_id.id()),
+        }
+    }
+
+    pub fn set_chat_read_time(&mut self, chat_id: ChatHandle, read_time: &DateTime<Utc>) {
+        match self
+            .friends_storage
+            .values()
+            .find(|f| f.borrow().chat_id() == chat_id.id())
+        {
+            Some(friend) => friend.borrow_mut().set_read_time(read_time),
+            None => warn!("Read time update for unknown chat {}", chat_id.id()),
+        }
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.into();
+        self.nameChanged();
+
+        if let Some(address) = &self.address {
+            self.toxUri = ToxUri::new(address.clone(), Some(name.to_string()))
+                .to_string()
+                .into();
+            self.toxUriChanged();
+        }
     }
 
     pub fn add_blocked_user(&mut self, user: &tocks::User) {
@@ -85,11 +275,34 @@ impl Account {
             id: user.id().id(),
             publicKey: user.public_key().to_string().into(),
             name: user.name().into(),
+            toxId: user
+                .tox_id()
+                .map(|tox_id| tox_id.to_string())
+                .unwrap_or_default()
+                .into(),
         };
         self.blocked_users_storage.insert(*user.id(), qt_user);
         self.blockedUsersChanged();
     }
 
+    /// Display names of everyone who can show up as a message sender,
+    /// including us
+    pub fn user_names(&self) -> HashMap<UserHandle, String> {
+        let friends = self
+            .friends_storage
+            .iter()
+            .map(|(id, friend)| (*id, friend.borrow().name()));
+
+        let blocked = self
+            .blocked_users_storage
+            .iter()
+            .map(|(id, user)| (*id, user.name.to_string()));
+
+        let me = std::iter::once((UserHandle::from(self.userId), self.name.to_string()));
+
+        friends.chain(blocked).chain(me).collect()
+    }
+
     pub fn self_id(&mut self) -> UserHandle {
         UserHandle::from(self.userId)
     }
@@ -105,6 +318,17 @@ impl Account {
         }
     }
 
+    pub fn set_call_ended(&mut self, chat_id: ChatHandle, reason: &CallEndReason) {
+        let item = self
+            .friends_storage
+            .values()
+            .find(|f| f.borrow().chat_id() == chat_id.id());
+
+        if let Some(friend) = item {
+            friend.borrow_mut().set_call_ended(reason)
+        }
+    }
+
     fn get_blocked_users(&mut self) -> QVariantList {
         self.blocked_users_storage
             .values()
@@ -112,3 +336,111 @@ impl Account {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn friend(id: i64, key: u8) -> tocks::Friend {
+        tocks::Friend::new(
+            UserHandle::from(id),
+            ChatHandle::from(id),
+            PublicKey::from_bytes(vec![key; PublicKey::SIZE]).unwrap(),
+            format!("friend{}", id),
+            Status::Offline,
+        )
+    }
+
+    #[test]
+    fn test_user_names() {
+        let mut account = Account::default();
+        account.userId = 10;
+        account.set_name("me");
+        account.add_friend(&friend(1, 1));
+        account.add_friend(&friend(2, 2));
+
+        account.set_user_name(UserHandle::from(2), "renamed");
+
+        let names = account.user_names();
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[&UserHandle::from(1)], "friend1");
+        assert_eq!(names[&UserHandle::from(2)], "renamed");
+        assert_eq!(names[&UserHandle::from(10)], "me");
+    }
+
+    #[test]
+    fn test_friend_from_public_key() {
+        let mut account = Account::default();
+        let friend1 = friend(1, 1);
+        let friend2 = friend(2, 2);
+        account.add_friend(&friend1);
+        account.add_friend(&friend2);
+
+        let found = account
+            .friend_from_public_key(friend2.public_key())
+            .unwrap();
+        assert_eq!(found.borrow().chat_id(), 2);
+
+        account.remove_friend(*friend2.id());
+        assert!(account
+            .friend_from_public_key(friend2.public_key())
+            .is_none());
+        assert!(account
+            .friend_from_public_key(friend1.public_key())
+            .is_some());
+    }
+
+    #[test]
+    fn test_friend_counts() {
+        let mut account = Account::default();
+        let friend1 = friend(1, 1);
+        let friend2 = friend(2, 2);
+        account.add_friends(&[friend1.clone(), friend2.clone()]);
+        assert_eq!((account.friendCount, account.onlineFriendCount), (2, 0));
+
+        account.set_friend_status(*friend1.id(), Status::Online);
+        assert_eq!((account.friendCount, account.onlineFriendCount), (2, 1));
+
+        // Moving between online states doesn't change the count
+        account.set_friend_status(*friend1.id(), Status::Away);
+        assert_eq!(account.onlineFriendCount, 1);
+
+        account.set_friend_status(*friend2.id(), Status::Busy);
+        assert_eq!(account.onlineFriendCount, 2);
+
+        account.set_friend_status(*friend2.id(), Status::Offline);
+        assert_eq!(account.onlineFriendCount, 1);
+
+        // Re-adding an existing friend replaces them
+        account.add_friend(&friend1);
+        assert_eq!((account.friendCount, account.onlineFriendCount), (2, 0));
+
+        account.set_friend_status(*friend1.id(), Status::Online);
+        account.remove_friend(*friend1.id());
+        assert_eq!((account.friendCount, account.onlineFriendCount), (1, 0));
+
+        account.remove_friend(*friend1.id());
+        assert_eq!((account.friendCount, account.onlineFriendCount), (1, 0));
+    }
+
+    #[test]
+    fn test_add_friends_bulk() {
+        let mut account = Account::default();
+        let friends: Vec<tocks::Friend> = (1..=50).map(|i| friend(i, i as u8)).collect();
+        account.add_friends(&friends);
+
+        assert_eq!(account.friends_storage.len(), friends.len());
+        for friend in &friends {
+            let found = account.friend_from_public_key(friend.public_key()).unwrap();
+            assert_eq!(found.borrow().chat_id(), friend.chat_handle().id());
+        }
+
+        // Incremental additions after a bulk load still land in the lookup
+        let late = friend(51, 51);
+        account.add_friend(&late);
+        assert!(account.friend_from_public_key(late.public_key()).is_some());
+        assert!(account
+            .friend_from_public_key(&PublicKey::from_bytes(vec![0; PublicKey::SIZE]).unwrap())
+            .is_none());
+    }
+}
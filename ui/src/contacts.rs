@@ -1,7 +1,8 @@
-use crate::{call_state_to_qtring, status_to_qstring};
+use crate::{call_end_reason_to_qstring, call_state_to_qtring, status_to_qstring};
 
+use chrono::{DateTime, Utc};
 use qmetaobject::*;
-use tocks::{CallState, Friend as TocksFriend, Status};
+use tocks::{CallEndReason, CallState, Friend as TocksFriend, Status};
 
 #[allow(non_snake_case)]
 #[derive(QObject, Default)]
@@ -19,6 +20,24 @@ pub struct Friend {
     statusChanged: qt_signal!(),
     callState: qt_property!(QString; NOTIFY callStateChanged),
     callStateChanged: qt_signal!(),
+    // How the last call ended, empty while a call is going or if there
+    // hasn't been one
+    lastCallEnd: qt_property!(QString; NOTIFY lastCallEndChanged),
+    lastCallEndChanged: qt_signal!(),
+    verified: qt_property!(bool; NOTIFY verifiedChanged),
+    verifiedChanged: qt_signal!(),
+    muted: qt_property!(bool; NOTIFY mutedChanged),
+    mutedChanged: qt_signal!(),
+    possiblyRemoved: qt_property!(bool; NOTIFY possiblyRemovedChanged),
+    possiblyRemovedChanged: qt_signal!(),
+    unreadCount: qt_property!(i64; NOTIFY unreadCountChanged),
+    unreadCountChanged: qt_signal!(),
+    // When we last read the chat in ms since the epoch, 0 if we haven't yet
+    readTime: qt_property!(i64; NOTIFY readTimeChanged),
+    readTimeChanged: qt_signal!(),
+
+    offline: bool,
+    online: bool,
 }
 
 impl Friend {
@@ -26,19 +45,73 @@ impl Friend {
         self.chatId
     }
 
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Pending friends are neither online nor offline
+    pub fn is_online(&self) -> bool {
+        self.online
+    }
+
     pub fn set_status(&mut self, status: Status) {
+        self.offline = status == Status::Offline;
+        self.online = status_is_online(&status);
         self.status = status_to_qstring(&status);
         self.statusChanged();
     }
 
+    pub fn name(&self) -> String {
+        self.name.to_string()
+    }
+
     pub fn set_name(&mut self, name: &str) {
         self.name = QString::from(name);
         self.nameChanged();
     }
 
+    pub fn set_verified(&mut self, verified: bool) {
+        self.verified = verified;
+        self.verifiedChanged();
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+        self.mutedChanged();
+    }
+
+    pub fn set_possibly_removed(&mut self, possibly_removed: bool) {
+        self.possiblyRemoved = possibly_removed;
+        self.possiblyRemovedChanged();
+    }
+
+    pub fn set_unread_count(&mut self, unread_count: usize) {
+        self.unreadCount = unread_count as i64;
+        self.unreadCountChanged();
+    }
+
+    pub fn set_read_time(&mut self, read_time: &DateTime<Utc>) {
+        self.readTime = read_time.timestamp_millis();
+        self.readTimeChanged();
+    }
+
     pub fn set_call_state(&mut self, state: &CallState) {
         self.callState = call_state_to_qtring(state);
-        self.callStateChanged()
+        self.callStateChanged();
+
+        if !matches!(state, CallState::Idle) {
+            self.lastCallEnd = QString::default();
+            self.lastCallEndChanged();
+        }
+    }
+
+    pub fn set_call_ended(&mut self, reason: &CallEndReason) {
+        self.lastCallEnd = call_end_reason_to_qstring(reason);
+        self.lastCallEndChanged();
     }
 }
 
@@ -58,14 +131,35 @@ impl From<&TocksFriend> for Friend {
             statusChanged: Default::default(),
             callState: call_state_to_qtring(&CallState::Idle),
             callStateChanged: Default::default(),
+            lastCallEnd: Default::default(),
+            lastCallEndChanged: Default::default(),
+            verified: friend.verified(),
+            verifiedChanged: Default::default(),
+            muted: friend.muted(),
+            mutedChanged: Default::default(),
+            possiblyRemoved: false,
+            possiblyRemovedChanged: Default::default(),
+            unreadCount: 0,
+            unreadCountChanged: Default::default(),
+            readTime: 0,
+            readTimeChanged: Default::default(),
+
+            offline: *friend.status() == Status::Offline,
+            online: status_is_online(friend.status()),
         }
     }
 }
 
+fn status_is_online(status: &Status) -> bool {
+    matches!(status, Status::Online | Status::Away | Status::Busy)
+}
+
 #[allow(non_snake_case)]
 #[derive(QGadget, Clone, Default)]
 pub struct User {
     pub id: qt_property!(i64),
     pub name: qt_property!(QString),
     pub publicKey: qt_property!(QString),
+    // Empty if we never sent them a friend request
+    pub toxId: qt_property!(QString),
 }
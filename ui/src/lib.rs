@@ -1,17 +1,25 @@
 mod account;
 mod contacts;
+mod logs;
+mod settings;
 
 use account::Account;
+use logs::LogModel;
+use settings::{Settings, Theme, WindowGeometry};
 
 use tocks::{
-    audio::{AudioFrame, AudioManager, FormattedAudio, OutputDevice, RepeatingAudioHandle},
-    AccountId, CallState, ChatHandle, ChatLogEntry, ChatMessageId, Status, TocksEvent,
-    TocksUiEvent, UserHandle,
+    audio::{
+        AudioFrame, AudioLoopback, AudioManager, CaptureFramer, CaptureReceiver, FormattedAudio,
+        OutputDevice, RepeatingAudioHandle,
+    },
+    AccountId, CallEndReason, CallState, ChatHandle, ChatLogEntry, ChatMessageId, SendState, Span,
+    SpanStyle, Status, TocksEvent, TocksUiEvent, UserHandle,
 };
 
-use toxcore::{Message, ToxId};
+use toxcore::ToxId;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, TimeZone, Utc};
 
 use futures::{
     channel::mpsc::{self, UnboundedSender},
@@ -20,11 +28,12 @@ use futures::{
 
 use std::{
     borrow::BorrowMut,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
     io::Read,
     path::{Path, PathBuf},
     thread::JoinHandle,
+    time::Duration,
 };
 
 use ::log::*;
@@ -33,12 +42,15 @@ use qmetaobject::*;
 
 const ATTRIBUTION: &'static str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/qml/res/attribution.txt"));
 
+/// How far behind the microphone the loopback test plays back
+const LOOPBACK_TEST_DELAY: Duration = Duration::from_millis(500);
+
 fn resource_path<P: AsRef<Path>>(relative_path: P) -> PathBuf {
     let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     path.join(relative_path.as_ref())
 }
 
-fn load_notification_sound() -> FormattedAudio {
+fn load_bundled_notification_sound() -> FormattedAudio {
     let mut notification_data = Vec::new();
     // FIXME: better error handling
     File::open(resource_path("qml/res/incoming_message.mp3"))
@@ -49,6 +61,66 @@ fn load_notification_sound() -> FormattedAudio {
     FormattedAudio::Mp3(notification_data)
 }
 
+/// Loads a user provided sound, only accepting it if it can be played
+fn load_sound_file(path: &Path) -> Result<FormattedAudio> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+
+    let sound = FormattedAudio::from_bytes(data);
+    sound
+        .validate()
+        .with_context(|| format!("{} is not a playable sound", path.to_string_lossy()))?;
+
+    Ok(sound)
+}
+
+/// The user's notification sound, or the bundled one if they haven't picked
+/// one or it can no longer be played
+fn load_notification_sound(custom_path: Option<&Path>) -> FormattedAudio {
+    if let Some(path) = custom_path {
+        match load_sound_file(path) {
+            Ok(sound) => return sound,
+            Err(e) => warn!("Falling back to bundled notification sound: {:?}", e),
+        }
+    }
+
+    load_bundled_notification_sound()
+}
+
+/// Turns what the settings page hands us into a path. FileDialog gives us a
+/// percent encoded file:// URL, anything else is taken as a plain path
+fn path_from_file_url(input: &str) -> PathBuf {
+    let encoded = match input.strip_prefix("file://") {
+        // An empty or localhost authority both mean this machine
+        Some(rest) => rest.strip_prefix("localhost").unwrap_or(rest),
+        None => return PathBuf::from(input),
+    };
+
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    PathBuf::from(String::from_utf8_lossy(&decoded).into_owned())
+}
+
 #[derive(QObject, Default)]
 #[allow(non_snake_case)]
 struct ChatModel {
@@ -57,14 +129,56 @@ struct ChatModel {
     accountChanged: qt_signal!(),
     chat: qt_property!(i64; NOTIFY chatChanged),
     chatChanged: qt_signal!(),
-
+    // Id of the newest of our messages the peer has read, 0 if unknown
+    peerReadMessage: qt_property!(i64; NOTIFY peerReadMessageChanged),
+    peerReadMessageChanged: qt_signal!(),
+    scrollToMessage: qt_method!(fn(&mut self, message_id: i64)),
+    scrollRequested: qt_signal!(row: i32),
+    jumpToDate: qt_method!(fn(&mut self, msecs_since_epoch: i64)),
+    fetchOlder: qt_method!(fn(&mut self)),
+    evictOldMessages: qt_method!(fn(&mut self)),
+    textForRow: qt_method!(fn(&self, row: i32) -> QString),
+    newestPeerMessageId: qt_method!(fn(&self, self_id: i64) -> i64),
+
+    // Newest messages of the chat, older ones are paged in on demand and
+    // dropped again once the user is back at the bottom
     chat_log: Vec<ChatLogEntry>,
+    has_older: bool,
+    fetch_pending: bool,
+    // Message scrollToMessage is paging in older history for, and how many
+    // more pages it may fetch before giving up
+    pending_scroll: Option<(ChatMessageId, usize)>,
+    // Time jumpToDate asked tocks for messages around
+    pending_jump: Option<DateTime<Utc>>,
+    // Outgoing messages written while the peer was offline. tocks sends
+    // them as soon as the peer comes back, until then they aren't "sending"
+    queued: HashSet<ChatMessageId>,
+    peer_offline: bool,
+    ui_requests_tx: Option<UnboundedSender<TocksUiEvent>>,
+    peer_read_messages: HashMap<(AccountId, ChatHandle), ChatMessageId>,
+    // Names of the users of the account the chat belongs to, QML would
+    // otherwise have to map sender ids itself
+    sender_names: HashMap<UserHandle, String>,
 }
 
 impl ChatModel {
     const MESSAGE_ROLE: i32 = USER_ROLE;
     const SENDER_ID_ROLE: i32 = USER_ROLE + 1;
     const COMPLETE_ROLE: i32 = USER_ROLE + 2;
+    const MESSAGE_ID_ROLE: i32 = USER_ROLE + 3;
+    const READ_BY_PEER_ROLE: i32 = USER_ROLE + 4;
+    const REPLY_TO_ROLE: i32 = USER_ROLE + 5;
+    const REPLY_SNIPPET_ROLE: i32 = USER_ROLE + 6;
+    const STYLED_MESSAGE_ROLE: i32 = USER_ROLE + 7;
+    const QUEUED_ROLE: i32 = USER_ROLE + 8;
+    const SENDER_NAME_ROLE: i32 = USER_ROLE + 9;
+    const SEND_STATE_ROLE: i32 = USER_ROLE + 10;
+
+    const MAX_SNIPPET_CHARS: usize = 100;
+
+    const MAX_LOADED_MESSAGES: usize = 500;
+    const PAGE_SIZE: usize = 100;
+    const MAX_SCROLL_PAGES: usize = 10;
 
     fn set_content(&mut self, account_id: AccountId, chat: ChatHandle, content: Vec<ChatLogEntry>) {
         self.account = account_id.id();
@@ -73,17 +187,69 @@ impl ChatModel {
         self.chat = chat.id();
         self.chatChanged();
 
+        self.peerReadMessage = self
+            .peer_read_messages
+            .get(&(account_id, chat))
+            .map_or(0, |id| id.id());
+        self.peerReadMessageChanged();
+
         (self as &dyn QAbstractItemModel).begin_reset_model();
 
         self.chat_log = content;
+        // Everything else here assumes the log is sorted by id without
+        // duplicates, don't trust whoever gave us the batch to get that right
+        self.chat_log.sort_by(|a, b| a.id().cmp(b.id()));
+        self.chat_log.dedup_by(|a, b| a.id() == b.id());
+
+        let excess = self
+            .chat_log
+            .len()
+            .saturating_sub(Self::MAX_LOADED_MESSAGES);
+        self.chat_log.drain(..excess);
+        // tocks only sends the newest messages, a full batch means there may
+        // be more in storage
+        self.has_older = self.chat_log.len() >= Self::MAX_LOADED_MESSAGES;
+        self.fetch_pending = false;
+        self.pending_scroll = None;
+        self.pending_jump = None;
+
+        self.queued.clear();
+        for entry in &self.chat_log {
+            self.mark_if_queued(entry);
+        }
 
         (self as &dyn QAbstractItemModel).end_reset_model();
     }
 
     fn push_message(&mut self, entry: ChatLogEntry) {
-        (self as &dyn QAbstractItemModel).begin_insert_rows(QModelIndex::default(), 0, 0);
+        // chat_log is kept sorted by id so resolve_message can binary search.
+        // New messages almost always land at the end, but don't rely on it
+        let idx = match self
+            .chat_log
+            .binary_search_by(|item| item.id().cmp(entry.id()))
+        {
+            Ok(idx) => {
+                warn!("Chatlog item {} inserted twice", entry.id());
+                self.chat_log[idx] = entry;
+
+                let qidx = (self as &dyn QAbstractItemModel).create_index(
+                    self.reversed_index(idx as i32) as i32,
+                    0,
+                    0,
+                );
+                (self as &dyn QAbstractItemModel).data_changed(qidx, qidx);
+                return;
+            }
+            Err(idx) => idx,
+        };
+
+        self.mark_if_queued(&entry);
+
+        // Rows are in reverse order, newest first
+        let row = (self.chat_log.len() - idx) as i32;
+        (self as &dyn QAbstractItemModel).begin_insert_rows(QModelIndex::default(), row, row);
 
-        self.chat_log.push(entry);
+        self.chat_log.insert(idx, entry);
 
         (self as &dyn QAbstractItemModel).end_insert_rows()
     }
@@ -107,9 +273,370 @@ impl ChatModel {
         (self as &dyn QAbstractItemModel).data_changed(qidx, qidx);
     }
 
+    fn set_send_state(&mut self, id: ChatMessageId, state: SendState) {
+        let idx = match self.chat_log.binary_search_by(|item| item.id().cmp(&id)) {
+            Ok(idx) => idx,
+            // Not loaded, it'll have the stored state once it is
+            Err(_) => return,
+        };
+
+        self.chat_log[idx].set_send_state(Some(state));
+
+        let qidx = (self as &dyn QAbstractItemModel).create_index(
+            self.reversed_index(idx as i32) as i32,
+            0,
+            0,
+        );
+        (self as &dyn QAbstractItemModel).data_changed(qidx, qidx);
+    }
+
+    fn mark_if_queued(&mut self, entry: &ChatLogEntry) {
+        if self.peer_offline && !entry.complete() {
+            self.queued.insert(*entry.id());
+        }
+    }
+
+    fn set_peer_offline(&mut self, account_id: AccountId, chat: ChatHandle, offline: bool) {
+        if self.account != account_id.id() || self.chat != chat.id() {
+            return;
+        }
+
+        self.peer_offline = offline;
+
+        if offline || self.queued.is_empty() {
+            return;
+        }
+
+        // Everything queued is resent when the peer comes online
+        self.queued.clear();
+
+        let model = self as &dyn QAbstractItemModel;
+        let first = model.create_index(0, 0, 0);
+        let last = model.create_index(self.chat_log.len() as i32 - 1, 0, 0);
+        model.data_changed(first, last);
+    }
+
+    fn set_peer_read_message(
+        &mut self,
+        account_id: AccountId,
+        chat: ChatHandle,
+        read: ChatMessageId,
+    ) {
+        self.peer_read_messages.insert((account_id, chat), read);
+
+        if self.account != account_id.id() || self.chat != chat.id() {
+            return;
+        }
+
+        self.peerReadMessage = read.id();
+        self.peerReadMessageChanged();
+
+        if self.chat_log.is_empty() {
+            return;
+        }
+
+        let model = self as &dyn QAbstractItemModel;
+        let first = model.create_index(0, 0, 0);
+        let last = model.create_index(self.chat_log.len() as i32 - 1, 0, 0);
+        model.data_changed(first, last);
+    }
+
+    fn set_sender_names(&mut self, sender_names: HashMap<UserHandle, String>) {
+        self.sender_names = sender_names;
+
+        if self.chat_log.is_empty() {
+            return;
+        }
+
+        let model = self as &dyn QAbstractItemModel;
+        let first = model.create_index(0, 0, 0);
+        let last = model.create_index(self.chat_log.len() as i32 - 1, 0, 0);
+        model.data_changed(first, last);
+    }
+
+    /// Empty if we don't know the sender, e.g. a friend that was removed
+    fn sender_name(&self, entry: &ChatLogEntry) -> &str {
+        self.sender_names
+            .get(entry.sender())
+            .map_or("", |name| name.as_str())
+    }
+
+    /// Rich text of entry, actions are shown IRC style as "* name waves"
+    fn styled_message(&self, entry: &ChatLogEntry) -> String {
+        let styled = spans_to_styled_text(&entry.styled_text());
+        if !entry.is_action() {
+            return styled;
+        }
+
+        format!(
+            "<i>* {} {}</i>",
+            escape_styled_text(self.sender_name(entry)),
+            styled
+        )
+    }
+
+    /// Shortened text of the message entry replies to, empty if it isn't a
+    /// reply
+    fn reply_snippet(&self, entry: &ChatLogEntry) -> String {
+        let replied = entry.reply_to().and_then(|id| {
+            self.chat_log
+                .binary_search_by(|item| item.id().cmp(id))
+                .ok()
+                .map(|idx| &self.chat_log[idx])
+        });
+
+        let text = match replied {
+            Some(replied) => replied.display_text(),
+            None => return String::new(),
+        };
+
+        let mut snippet: String = text.chars().take(Self::MAX_SNIPPET_CHARS).collect();
+        if snippet.len() < text.len() {
+            snippet.push('…');
+        }
+
+        snippet
+    }
+
+    /// Model row of the message with the given id, if it is loaded
+    fn row_for_message_id(&self, id: ChatMessageId) -> Option<i32> {
+        self.chat_log
+            .binary_search_by(|item| item.id().cmp(&id))
+            .ok()
+            .map(|idx| self.reversed_index(idx as i32) as i32)
+    }
+
+    #[allow(non_snake_case)]
+    fn scrollToMessage(&mut self, message_id: i64) {
+        self.pending_scroll = None;
+        self.scroll_to(message_id.into(), Self::MAX_SCROLL_PAGES);
+    }
+
+    /// Scrolls to id if it's loaded. Older history may not be in memory,
+    /// that is paged in until id shows up or pages_left runs out
+    fn scroll_to(&mut self, id: ChatMessageId, pages_left: usize) {
+        if let Some(row) = self.row_for_message_id(id) {
+            self.scrollRequested(row);
+            return;
+        }
+
+        let older_than_loaded = self
+            .chat_log
+            .first()
+            .map_or(false, |oldest| id < *oldest.id());
+        if !older_than_loaded || !self.has_older || pages_left == 0 {
+            warn!("Cannot scroll to unknown message {}", id);
+            return;
+        }
+
+        self.pending_scroll = Some((id, pages_left - 1));
+        self.fetchOlder();
+    }
+
+    fn resume_scroll(&mut self) {
+        if let Some((id, pages_left)) = self.pending_scroll.take() {
+            self.scroll_to(id, pages_left);
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn jumpToDate(&mut self, msecs_since_epoch: i64) {
+        let time = Utc.timestamp_millis(msecs_since_epoch);
+        let request = TocksUiEvent::LoadMessagesAround(
+            AccountId::from(self.account),
+            ChatHandle::from(self.chat),
+            time,
+            Self::PAGE_SIZE / 2,
+            Self::PAGE_SIZE / 2,
+        );
+
+        if let Some(tx) = &self.ui_requests_tx {
+            if let Err(e) = tx.unbounded_send(request) {
+                error!("tocks app not responding to UI requests: {}", e);
+                return;
+            }
+        }
+
+        self.pending_jump = Some(time);
+    }
+
+    /// Scrolls to the first message sent at or after the time jumpToDate
+    /// asked for
+    fn push_around(&mut self, account_id: AccountId, chat: ChatHandle, entries: Vec<ChatLogEntry>) {
+        if self.account != account_id.id() || self.chat != chat.id() {
+            return;
+        }
+
+        let time = match self.pending_jump.take() {
+            Some(time) => time,
+            None => return,
+        };
+
+        // Nothing from time on means the newest message is the closest
+        let target = entries
+            .iter()
+            .find(|entry| *entry.timestamp() >= time)
+            .or_else(|| entries.last())
+            .map(|entry| *entry.id());
+
+        let target = match target {
+            Some(target) => target,
+            None => {
+                warn!("No messages near {}", time);
+                return;
+            }
+        };
+
+        // Only history that reaches the loaded messages can be spliced in
+        // without leaving a gap, anything older is paged in by scroll_to
+        let reaches_loaded = match (entries.last(), self.chat_log.first()) {
+            (Some(newest), Some(oldest)) => newest.id() >= oldest.id(),
+            _ => false,
+        };
+        if reaches_loaded {
+            self.prepend_older(entries);
+        }
+
+        self.pending_scroll = None;
+        self.scroll_to(target, Self::MAX_SCROLL_PAGES);
+    }
+
+    #[allow(non_snake_case)]
+    fn fetchOlder(&mut self) {
+        if !self.has_older || self.fetch_pending {
+            return;
+        }
+
+        let oldest = match self.chat_log.first() {
+            Some(entry) => *entry.id(),
+            None => return,
+        };
+
+        let request = TocksUiEvent::LoadOlderMessages(
+            AccountId::from(self.account),
+            ChatHandle::from(self.chat),
+            oldest,
+            Self::PAGE_SIZE,
+        );
+
+        if let Some(tx) = &self.ui_requests_tx {
+            if let Err(e) = tx.unbounded_send(request) {
+                error!("tocks app not responding to UI requests: {}", e);
+                return;
+            }
+        }
+
+        self.fetch_pending = true;
+    }
+
+    /// Prepends a page of history requested by fetchOlder
+    fn push_older(&mut self, account_id: AccountId, chat: ChatHandle, entries: Vec<ChatLogEntry>) {
+        if self.account != account_id.id() || self.chat != chat.id() {
+            return;
+        }
+
+        self.fetch_pending = false;
+        self.has_older = entries.len() >= Self::PAGE_SIZE;
+
+        self.prepend_older(entries);
+        self.resume_scroll();
+    }
+
+    /// Inserts the entries older than everything loaded, the rest are
+    /// already in the log
+    fn prepend_older(&mut self, entries: Vec<ChatLogEntry>) {
+        let oldest = self.chat_log.first().map(|entry| *entry.id());
+        let mut entries: Vec<ChatLogEntry> = entries
+            .into_iter()
+            .filter(|entry| oldest.map_or(true, |oldest| *entry.id() < oldest))
+            .collect();
+        entries.sort_by(|a, b| a.id().cmp(b.id()));
+        entries.dedup_by(|a, b| a.id() == b.id());
+
+        if entries.is_empty() {
+            return;
+        }
+
+        // Older messages go at the end of the reversed rows
+        let first_row = self.chat_log.len() as i32;
+        let last_row = first_row + entries.len() as i32 - 1;
+        (self as &dyn QAbstractItemModel).begin_insert_rows(
+            QModelIndex::default(),
+            first_row,
+            last_row,
+        );
+
+        for entry in &entries {
+            self.mark_if_queued(entry);
+        }
+
+        self.chat_log.splice(0..0, entries);
+
+        (self as &dyn QAbstractItemModel).end_insert_rows();
+    }
+
+    /// Drops paged in history beyond the memory limit. Called when the user
+    /// is back at the newest messages, the history can be fetched again
+    #[allow(non_snake_case)]
+    fn evictOldMessages(&mut self) {
+        let excess = self
+            .chat_log
+            .len()
+            .saturating_sub(Self::MAX_LOADED_MESSAGES);
+        if excess == 0 {
+            return;
+        }
+
+        let first_row = (self.chat_log.len() - excess) as i32;
+        let last_row = self.chat_log.len() as i32 - 1;
+        (self as &dyn QAbstractItemModel).begin_remove_rows(
+            QModelIndex::default(),
+            first_row,
+            last_row,
+        );
+
+        self.chat_log.drain(..excess);
+        self.has_older = true;
+
+        (self as &dyn QAbstractItemModel).end_remove_rows();
+    }
+
     fn reversed_index(&self, idx: i32) -> usize {
         self.chat_log.len() - idx as usize - 1
     }
+
+    /// Entry shown at model row, rows count up from the newest message
+    fn entry_for_row(&self, row: i32) -> Option<&ChatLogEntry> {
+        if row < 0 || row as usize >= self.chat_log.len() {
+            return None;
+        }
+
+        self.chat_log.get(self.reversed_index(row))
+    }
+
+    /// Plain text of the message at row, empty if there is no such row
+    fn text_for_row(&self, row: i32) -> QString {
+        self.entry_for_row(row)
+            .map_or_else(QString::default, |entry| {
+                QString::from(entry.display_text())
+            })
+    }
+
+    #[allow(non_snake_case)]
+    fn textForRow(&self, row: i32) -> QString {
+        self.text_for_row(row)
+    }
+
+    /// Id of the newest loaded message not sent by self_id, -1 if there
+    /// isn't one
+    #[allow(non_snake_case)]
+    fn newestPeerMessageId(&self, self_id: i64) -> i64 {
+        self.chat_log
+            .iter()
+            .rev()
+            .find(|entry| entry.sender().id() != self_id)
+            .map_or(-1, |entry| entry.id().id())
+    }
 }
 
 impl QAbstractItemModel for ChatModel {
@@ -132,26 +659,28 @@ impl QAbstractItemModel for ChatModel {
     fn data(&self, index: QModelIndex, role: i32) -> QVariant {
         debug!("Returning line, {}", index.row());
 
-        let entry = self.chat_log.get(self.reversed_index(index.row()));
-
-        if entry.is_none() {
-            return QVariant::default();
-        }
-
-        let entry = entry.unwrap();
+        let entry = match self.entry_for_row(index.row()) {
+            Some(entry) => entry,
+            None => return QVariant::default(),
+        };
 
         match role {
-            Self::MESSAGE_ROLE => {
-                let message = entry.message();
-
-                if let Message::Normal(message) = message {
-                    QString::from(message.as_ref()).to_qvariant()
-                } else {
-                    QVariant::default()
-                }
-            }
+            Self::MESSAGE_ROLE => QString::from(entry.display_text()).to_qvariant(),
             Self::SENDER_ID_ROLE => entry.sender().id().to_qvariant(),
+            Self::SENDER_NAME_ROLE => QString::from(self.sender_name(entry)).to_qvariant(),
             Self::COMPLETE_ROLE => entry.complete().to_qvariant(),
+            Self::MESSAGE_ID_ROLE => entry.id().id().to_qvariant(),
+            Self::READ_BY_PEER_ROLE => (entry.id().id() <= self.peerReadMessage).to_qvariant(),
+            Self::REPLY_TO_ROLE => entry.reply_to().map_or(-1, |id| id.id()).to_qvariant(),
+            Self::REPLY_SNIPPET_ROLE => QString::from(self.reply_snippet(entry)).to_qvariant(),
+            Self::QUEUED_ROLE => {
+                (!entry.complete() && self.queued.contains(entry.id())).to_qvariant()
+            }
+            Self::STYLED_MESSAGE_ROLE => QString::from(self.styled_message(entry)).to_qvariant(),
+            Self::SEND_STATE_ROLE => entry
+                .send_state()
+                .map_or_else(QString::default, |state| send_state_to_qstring(&state))
+                .to_qvariant(),
             _ => QVariant::default(),
         }
     }
@@ -161,7 +690,15 @@ impl QAbstractItemModel for ChatModel {
 
         ret.insert(Self::MESSAGE_ROLE, "message".into());
         ret.insert(Self::SENDER_ID_ROLE, "senderId".into());
+        ret.insert(Self::SENDER_NAME_ROLE, "senderName".into());
         ret.insert(Self::COMPLETE_ROLE, "complete".into());
+        ret.insert(Self::MESSAGE_ID_ROLE, "messageId".into());
+        ret.insert(Self::READ_BY_PEER_ROLE, "readByPeer".into());
+        ret.insert(Self::REPLY_TO_ROLE, "replyTo".into());
+        ret.insert(Self::REPLY_SNIPPET_ROLE, "replySnippet".into());
+        ret.insert(Self::STYLED_MESSAGE_ROLE, "styledMessage".into());
+        ret.insert(Self::QUEUED_ROLE, "queued".into());
+        ret.insert(Self::SEND_STATE_ROLE, "sendState".into());
 
         ret
     }
@@ -171,8 +708,10 @@ impl QAbstractItemModel for ChatModel {
 // loop from within our class due to qmetaobject mutability issues
 enum QTocksEvent {
     SetAudioOutput(OutputDevice),
+    SetNotificationSound(FormattedAudio),
     PlayNotificationSound,
     StartAudioTest,
+    StartLoopbackTest,
     StopAudioTest,
 }
 
@@ -183,32 +722,78 @@ struct QTocks {
     attribution: qt_property!(QString; CONST READ get_attribution),
     accounts: qt_property!(QVariantList; READ get_accounts NOTIFY accountsChanged),
     accountsChanged: qt_signal!(),
+    // Which logged in account the sidebar is showing, -1 before any login
+    activeAccount: qt_property!(i64; READ get_active_account NOTIFY activeAccountChanged),
+    activeAccountChanged: qt_signal!(),
+    setActiveAccount: qt_method!(fn(&mut self, account: i64)),
     offlineAccounts: qt_property!(QVariantList; READ get_offline_accounts NOTIFY offlineAccountsChanged),
     offlineAccountsChanged: qt_signal!(),
     newAccount: qt_method!(fn(&mut self, name: QString, password: QString)),
     close: qt_method!(fn(&mut self)),
+    refreshLogs: qt_method!(fn(&mut self)),
+    // Tocks is shutting down without the window being closed, e.g. a
+    // tocks_cli client sent Close
+    quitRequested: qt_signal!(),
     addPendingFriend: qt_method!(fn(&mut self, account: i64, user: i64)),
     blockUser: qt_method!(fn(&mut self, account: i64, user: i64)),
+    setFriendVerified: qt_method!(fn(&mut self, account: i64, user: i64, verified: bool)),
+    setChatMuted: qt_method!(fn(&mut self, account: i64, chat: i64, muted: bool)),
+    setSelfName: qt_method!(fn(&mut self, account: i64, name: QString)),
+    setOnline: qt_method!(fn(&mut self, account: i64, online: bool)),
     login: qt_method!(fn(&mut self, account_name: QString, password: QString)),
     updateChatModel: qt_method!(fn(&mut self, account: i64, chat: i64)),
     sendMessage: qt_method!(fn(&mut self, account: i64, chat: i64, message: QString)),
+    resendMessage: qt_method!(fn(&mut self, account: i64, chat: i64, message: i64)),
+    sendReply: qt_method!(fn(&mut self, account: i64, chat: i64, reply_to: i64, message: QString)),
+    setAutoReply: qt_method!(fn(&mut self, account: i64, message: QString)),
+    sendReadMarker: qt_method!(fn(&mut self, account: i64, chat: i64)),
+    markChatRead: qt_method!(fn(&mut self, account: i64, chat: i64)),
+    setTyping: qt_method!(fn(&mut self, account: i64, chat: i64)),
     error: qt_signal!(error: QString),
+    passwordRequired: qt_signal!(account_name: QString),
     audioOutputs: qt_property!(QVariantList; READ get_audio_outputs NOTIFY audioOutputsChanged),
     audioOutputsChanged: qt_signal!(),
+    audioOutputIndex: qt_property!(i64; READ get_audio_output_index NOTIFY audioOutputsChanged),
+    audioAvailable: qt_property!(bool; READ get_audio_available NOTIFY audioAvailableChanged),
+    audioAvailableChanged: qt_signal!(),
     startCall: qt_method!(fn(&mut self, account: i64, chat: i64)),
     endCall: qt_method!(fn(&mut self, account: i64, chat: i64)),
+    setCallPaused: qt_method!(fn(&mut self, account: i64, chat: i64, paused: bool)),
     startAudioTest: qt_method!(fn(&mut self)),
+    startLoopbackTest: qt_method!(fn(&mut self)),
     stopAudioTest: qt_method!(fn(&mut self)),
     setAudioOutput: qt_method!(fn(&mut self, output_idx: i64)),
+    theme: qt_property!(QString; READ get_theme NOTIFY themeChanged),
+    themeChanged: qt_signal!(),
+    setTheme: qt_method!(fn(&mut self, theme: QString)),
+    notificationSounds: qt_property!(bool; READ get_notification_sounds NOTIFY notificationSoundsChanged),
+    notificationSoundsChanged: qt_signal!(),
+    setNotificationSounds: qt_method!(fn(&mut self, enabled: bool)),
+    doNotDisturb: qt_property!(bool; READ get_do_not_disturb NOTIFY doNotDisturbChanged),
+    doNotDisturbChanged: qt_signal!(),
+    setDoNotDisturb: qt_method!(fn(&mut self, enabled: bool)),
+    // Empty when using the bundled sound
+    notificationSoundPath: qt_property!(QString; READ get_notification_sound_path NOTIFY notificationSoundPathChanged),
+    notificationSoundPathChanged: qt_signal!(),
+    setNotificationSoundPath: qt_method!(fn(&mut self, path: QString)),
+    expandShortcodes: qt_property!(bool; READ get_expand_shortcodes NOTIFY expandShortcodesChanged),
+    expandShortcodesChanged: qt_signal!(),
+    setExpandShortcodes: qt_method!(fn(&mut self, enabled: bool)),
+    saveWindowGeometry: qt_method!(fn(&mut self, x: i32, y: i32, width: i32, height: i32)),
+    savedWindowGeometry: qt_method!(fn(&mut self, screens: QString) -> QVariantList),
     visible: qt_property!(bool; WRITE set_visible),
 
     ui_requests_tx: UnboundedSender<TocksUiEvent>,
     qtocks_event_tx: UnboundedSender<QTocksEvent>,
     chat_model: QObjectBox<ChatModel>,
+    log_model: QObjectBox<LogModel>,
     accounts_storage: HashMap<AccountId, QObjectBox<Account>>,
+    active_account: Option<AccountId>,
     offline_accounts: Vec<String>,
     audio_output_storage: Vec<OutputDevice>,
+    audio_available: bool,
     visible_storage: bool,
+    settings: Settings,
 }
 
 impl QTocks {
@@ -216,37 +801,83 @@ impl QTocks {
         ui_requests_tx: UnboundedSender<TocksUiEvent>,
         qtocks_event_tx: UnboundedSender<QTocksEvent>,
         audio_devices: Vec<OutputDevice>,
+        audio_available: bool,
+        settings: Settings,
     ) -> QTocks {
         QTocks {
             base: Default::default(),
             attribution: Default::default(),
             accounts: Default::default(),
             accountsChanged: Default::default(),
+            activeAccount: Default::default(),
+            activeAccountChanged: Default::default(),
+            setActiveAccount: Default::default(),
             offlineAccounts: Default::default(),
             offlineAccountsChanged: Default::default(),
             newAccount: Default::default(),
             close: Default::default(),
+            refreshLogs: Default::default(),
             addPendingFriend: Default::default(),
             blockUser: Default::default(),
+            setFriendVerified: Default::default(),
+            setChatMuted: Default::default(),
+            setSelfName: Default::default(),
+            setOnline: Default::default(),
             login: Default::default(),
             sendMessage: Default::default(),
+            resendMessage: Default::default(),
+            sendReply: Default::default(),
+            setAutoReply: Default::default(),
+            sendReadMarker: Default::default(),
+            markChatRead: Default::default(),
+            setTyping: Default::default(),
             updateChatModel: Default::default(),
             error: Default::default(),
+            passwordRequired: Default::default(),
             audioOutputs: Default::default(),
             audioOutputsChanged: Default::default(),
+            audioOutputIndex: Default::default(),
+            audioAvailable: Default::default(),
+            audioAvailableChanged: Default::default(),
             startCall: Default::default(),
             endCall: Default::default(),
+            setCallPaused: Default::default(),
             startAudioTest: Default::default(),
+            startLoopbackTest: Default::default(),
             stopAudioTest: Default::default(),
             setAudioOutput: Default::default(),
+            theme: Default::default(),
+            themeChanged: Default::default(),
+            setTheme: Default::default(),
+            notificationSounds: Default::default(),
+            notificationSoundsChanged: Default::default(),
+            setNotificationSounds: Default::default(),
+            doNotDisturb: Default::default(),
+            doNotDisturbChanged: Default::default(),
+            setDoNotDisturb: Default::default(),
+            notificationSoundPath: Default::default(),
+            notificationSoundPathChanged: Default::default(),
+            setNotificationSoundPath: Default::default(),
+            expandShortcodes: Default::default(),
+            expandShortcodesChanged: Default::default(),
+            setExpandShortcodes: Default::default(),
+            saveWindowGeometry: Default::default(),
+            savedWindowGeometry: Default::default(),
             visible: Default::default(),
             ui_requests_tx,
             qtocks_event_tx,
-            chat_model: QObjectBox::new(Default::default()),
+            chat_model: QObjectBox::new(ChatModel {
+                ui_requests_tx: Some(ui_requests_tx.clone()),
+                ..Default::default()
+            }),
+            log_model: Default::default(),
             accounts_storage: Default::default(),
+            active_account: None,
             offline_accounts: Default::default(),
             audio_output_storage: audio_devices,
+            audio_available,
             visible_storage: false,
+            settings,
         }
     }
 
@@ -254,20 +885,62 @@ impl QTocks {
         self.send_ui_request(TocksUiEvent::Close);
     }
 
+    #[allow(non_snake_case)]
+    fn refreshLogs(&mut self) {
+        self.send_ui_request(TocksUiEvent::QueryLogs);
+    }
+
     #[allow(non_snake_case)]
     fn addPendingFriend(&mut self, account: i64, friend: i64) {
-        self.send_ui_request(TocksUiEvent::AcceptPendingFriend(
-            AccountId::from(account),
-            UserHandle::from(friend),
-        ));
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::AcceptPendingFriend(
+                account,
+                UserHandle::from(friend),
+            ));
+        }
     }
 
     #[allow(non_snake_case)]
     fn blockUser(&mut self, account: i64, user: i64) {
-        self.send_ui_request(TocksUiEvent::BlockUser(
-            AccountId::from(account),
-            UserHandle::from(user),
-        ));
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::BlockUser(account, UserHandle::from(user)));
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn setSelfName(&mut self, account: i64, name: QString) {
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::SetSelfName(account, name.to_string()));
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn setOnline(&mut self, account: i64, online: bool) {
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::SetOnline(account, online));
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn setFriendVerified(&mut self, account: i64, user: i64, verified: bool) {
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::SetFriendVerified(
+                account,
+                UserHandle::from(user),
+                verified,
+            ));
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn setChatMuted(&mut self, account: i64, chat: i64, muted: bool) {
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::SetChatMuted(
+                account,
+                ChatHandle::from(chat),
+                muted,
+            ));
+        }
     }
 
     fn login(&mut self, account_name: QString, password: QString) {
@@ -286,21 +959,104 @@ impl QTocks {
 
     #[allow(non_snake_case)]
     fn updateChatModel(&mut self, account: i64, chat_handle: i64) {
-        self.send_ui_request(TocksUiEvent::LoadMessages(
-            AccountId::from(account),
-            ChatHandle::from(chat_handle),
-        ));
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::LoadMessages(
+                account,
+                ChatHandle::from(chat_handle),
+                ChatModel::MAX_LOADED_MESSAGES,
+            ));
+        }
     }
 
     #[allow(non_snake_case)]
     fn sendMessage(&mut self, account: i64, chat: i64, message: QString) {
         let message = message.to_string();
 
-        self.send_ui_request(TocksUiEvent::MessageSent(
-            AccountId::from(account),
-            ChatHandle::from(chat),
-            message,
-        ));
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::MessageSent(
+                account,
+                ChatHandle::from(chat),
+                message,
+            ));
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn sendReply(&mut self, account: i64, chat: i64, reply_to: i64, message: QString) {
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::ReplySent(
+                account,
+                ChatHandle::from(chat),
+                ChatMessageId::from(reply_to),
+                message.to_string(),
+            ));
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn resendMessage(&mut self, account: i64, chat: i64, message: i64) {
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::ResendMessage(
+                account,
+                ChatHandle::from(chat),
+                ChatMessageId::from(message),
+            ));
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn setAutoReply(&mut self, account: i64, message: QString) {
+        let message = message.to_string();
+        let message = if message.is_empty() {
+            None
+        } else {
+            Some(message)
+        };
+
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::SetAutoReply(account, message));
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn sendReadMarker(&mut self, account: i64, chat: i64) {
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::SendReadMarker(
+                account,
+                ChatHandle::from(chat),
+            ));
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn setTyping(&mut self, account: i64, chat: i64) {
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::UserTyping(account, ChatHandle::from(chat)));
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn markChatRead(&mut self, account: i64, chat: i64) {
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::MarkChatRead(
+                account,
+                ChatHandle::from(chat),
+                Utc::now(),
+            ));
+        }
+    }
+
+    /// Converts an account id handed to us by QML, surfacing an error to the
+    /// UI instead of sending tocks a request for an account we don't know
+    fn account_id(&mut self, account: i64) -> Option<AccountId> {
+        match validate_account_id(&self.accounts_storage, account) {
+            Ok(account) => Some(account),
+            Err(e) => {
+                error!("{}", e);
+                self.error(e.to_string().into());
+                None
+            }
+        }
     }
 
     fn get_offline_accounts(&mut self) -> QVariantList {
@@ -329,24 +1085,89 @@ impl QTocks {
         user: UserHandle,
         address: ToxId,
         name: String,
+        max_message_length: usize,
     ) {
-        let account = QObjectBox::new(Account::new(account_id, user, address, name));
+        let account = QObjectBox::new(Account::new(
+            account_id,
+            user,
+            address,
+            name,
+            max_message_length,
+        ));
         account.pinned().get_or_create_cpp_object();
         self.accounts_storage.insert(account_id, account);
         self.accountsChanged();
-    }
 
-    fn get_accounts(&mut self) -> QVariantList {
-        self.accounts_storage
-            .values()
-            .map(|item| unsafe { (&*item.pinned().borrow() as &dyn QObject).as_qvariant() })
-            .collect()
-    }
+        // Logging in from the sidebar means the user wants to look at the new
+        // account, not whichever one they were on before
+        self.set_active_account(account_id);
 
-    fn send_ui_request(&mut self, request: TocksUiEvent) {
-        if let Err(e) = self.ui_requests_tx.unbounded_send(request) {
-            error!("tocks app not responding to UI requests: {}", e);
-        }
+        self.send_ui_request(TocksUiEvent::SetShortcodeExpansion(
+            account_id,
+            self.settings.expand_shortcodes,
+        ));
+    }
+
+    fn account_removed(&mut self, account_id: AccountId, reason: String) {
+        if self.accounts_storage.remove(&account_id).is_none() {
+            return;
+        }
+        self.accountsChanged();
+
+        // Fall back to whichever account is left so the sidebar isn't
+        // pointing at one that's gone
+        if self.active_account == Some(account_id) {
+            self.active_account = self
+                .accounts_storage
+                .keys()
+                .min_by_key(|id| id.id())
+                .copied();
+            self.activeAccountChanged();
+        }
+
+        self.error(reason.into());
+    }
+
+    fn get_accounts(&mut self) -> QVariantList {
+        // Sorted so the sidebar doesn't shuffle every time an account is added
+        let mut account_ids: Vec<&AccountId> = self.accounts_storage.keys().collect();
+        account_ids.sort_by_key(|id| id.id());
+
+        account_ids
+            .into_iter()
+            .map(|id| unsafe {
+                (&*self.accounts_storage[id].pinned().borrow() as &dyn QObject).as_qvariant()
+            })
+            .collect()
+    }
+
+    fn get_active_account(&mut self) -> i64 {
+        self.active_account.map_or(-1, |account| account.id())
+    }
+
+    #[allow(non_snake_case)]
+    fn setActiveAccount(&mut self, account: i64) {
+        // Not worth an error popup, QML can race a selection against the
+        // account list changing
+        match validate_account_id(&self.accounts_storage, account) {
+            Ok(account) => self.set_active_account(account),
+            Err(e) => warn!("Ignoring account switch: {}", e),
+        }
+    }
+
+    fn set_active_account(&mut self, account: AccountId) {
+        if self.active_account == Some(account) {
+            return;
+        }
+
+        self.active_account = Some(account);
+        self.activeAccountChanged();
+    }
+
+    fn send_ui_request(&mut self, request: TocksUiEvent) {
+        if let Err(e) = self.ui_requests_tx.unbounded_send(request) {
+            error!("tocks app not responding to UI requests: {}", e);
+        }
     }
 
     fn send_qtocks_request(&mut self, request: QTocksEvent) {
@@ -370,17 +1191,45 @@ impl QTocks {
             .cloned()
             .expect("Invalid audio device id passed from qml");
 
+        self.update_settings(|settings| settings.audio_output = device.clone());
         self.send_qtocks_request(QTocksEvent::SetAudioOutput(device));
     }
 
+    fn get_audio_available(&mut self) -> bool {
+        self.audio_available
+    }
+
+    fn get_audio_output_index(&mut self) -> i64 {
+        self.audio_output_storage
+            .iter()
+            .position(|device| *device == self.settings.audio_output)
+            .unwrap_or(0) as i64
+    }
+
     #[allow(non_snake_case)]
     fn startCall(&mut self, account: i64, chat: i64) {
-        self.send_ui_request(TocksUiEvent::JoinCall(account.into(), chat.into()));
+        if !self.audio_available {
+            self.error("Calls are unavailable without an audio device".into());
+            return;
+        }
+
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::JoinCall(account, chat.into()));
+        }
     }
 
     #[allow(non_snake_case)]
     fn endCall(&mut self, account: i64, chat: i64) {
-        self.send_ui_request(TocksUiEvent::LeaveCall(account.into(), chat.into()));
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::LeaveCall(account, chat.into()));
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn setCallPaused(&mut self, account: i64, chat: i64, paused: bool) {
+        if let Some(account) = self.account_id(account) {
+            self.send_ui_request(TocksUiEvent::SetCallPaused(account, chat.into(), paused));
+        }
     }
 
     #[allow(non_snake_case)]
@@ -388,62 +1237,283 @@ impl QTocks {
         self.send_qtocks_request(QTocksEvent::StartAudioTest);
     }
 
+    #[allow(non_snake_case)]
+    fn startLoopbackTest(&mut self) {
+        self.send_qtocks_request(QTocksEvent::StartLoopbackTest);
+    }
+
     #[allow(non_snake_case)]
     fn stopAudioTest(&mut self) {
         self.send_qtocks_request(QTocksEvent::StopAudioTest);
     }
 
+    fn get_theme(&mut self) -> QString {
+        self.settings.theme.as_str().into()
+    }
+
+    #[allow(non_snake_case)]
+    fn setTheme(&mut self, theme: QString) {
+        let theme = match theme.to_string().parse::<Theme>() {
+            Ok(t) => t,
+            Err(e) => {
+                self.error(e.to_string().into());
+                return;
+            }
+        };
+
+        if theme == self.settings.theme {
+            return;
+        }
+
+        self.update_settings(|settings| settings.theme = theme);
+        self.themeChanged();
+    }
+
+    fn get_notification_sounds(&mut self) -> bool {
+        self.settings.notification_sounds
+    }
+
+    #[allow(non_snake_case)]
+    fn setNotificationSounds(&mut self, enabled: bool) {
+        if enabled == self.settings.notification_sounds {
+            return;
+        }
+
+        self.update_settings(|settings| settings.notification_sounds = enabled);
+        self.notificationSoundsChanged();
+    }
+
+    fn get_do_not_disturb(&mut self) -> bool {
+        self.settings.do_not_disturb
+    }
+
+    #[allow(non_snake_case)]
+    fn setDoNotDisturb(&mut self, enabled: bool) {
+        if enabled == self.settings.do_not_disturb {
+            return;
+        }
+
+        self.update_settings(|settings| settings.do_not_disturb = enabled);
+        self.doNotDisturbChanged();
+    }
+
+    fn get_notification_sound_path(&mut self) -> QString {
+        self.settings
+            .notification_sound_path
+            .as_ref()
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_default()
+            .into()
+    }
+
+    /// Path or file:// URL of the sound to play, empty to go back to the
+    /// bundled sound
+    #[allow(non_snake_case)]
+    fn setNotificationSoundPath(&mut self, path: QString) {
+        let path = path.to_string();
+
+        let (path, sound) = if path.is_empty() {
+            (None, load_bundled_notification_sound())
+        } else {
+            let path = path_from_file_url(&path);
+            match load_sound_file(&path) {
+                Ok(sound) => (Some(path), sound),
+                Err(e) => {
+                    self.error(format!("{:#}", e).into());
+                    return;
+                }
+            }
+        };
+
+        self.update_settings(|settings| settings.notification_sound_path = path);
+        self.send_qtocks_request(QTocksEvent::SetNotificationSound(sound));
+        self.notificationSoundPathChanged();
+    }
+
+    fn get_expand_shortcodes(&mut self) -> bool {
+        self.settings.expand_shortcodes
+    }
+
+    #[allow(non_snake_case)]
+    fn setExpandShortcodes(&mut self, enabled: bool) {
+        if enabled == self.settings.expand_shortcodes {
+            return;
+        }
+
+        self.update_settings(|settings| settings.expand_shortcodes = enabled);
+        self.expandShortcodesChanged();
+
+        let accounts: Vec<AccountId> = self.accounts_storage.keys().copied().collect();
+        for account in accounts {
+            self.send_ui_request(TocksUiEvent::SetShortcodeExpansion(account, enabled));
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn saveWindowGeometry(&mut self, x: i32, y: i32, width: i32, height: i32) {
+        let geometry = WindowGeometry {
+            x,
+            y,
+            width,
+            height,
+        };
+
+        self.update_settings(|settings| settings.window_geometry = Some(geometry));
+    }
+
+    /// Returns [x, y, width, height] of the last window geometry fit to the
+    /// given screens, or an empty list if we have nothing saved. screens is a
+    /// JSON list of screen geometries, fallback screen first
+    #[allow(non_snake_case)]
+    fn savedWindowGeometry(&mut self, screens: QString) -> QVariantList {
+        let geometry = match &self.settings.window_geometry {
+            Some(g) => g,
+            None => return QVariantList::default(),
+        };
+
+        let screens: Vec<WindowGeometry> = match serde_json::from_str(&screens.to_string()) {
+            Ok(screens) => screens,
+            Err(e) => {
+                warn!("Invalid screen list, using default window geometry: {}", e);
+                return QVariantList::default();
+            }
+        };
+
+        let geometry = match geometry.fitted_to(&screens) {
+            Some(g) => g,
+            None => return QVariantList::default(),
+        };
+
+        [geometry.x, geometry.y, geometry.width, geometry.height]
+            .iter()
+            .map(|v| v.to_qvariant())
+            .collect()
+    }
+
+    /// Applies f to our settings and writes them out. Failing to persist is
+    /// reported but the change still applies for this session
+    fn update_settings<F: FnOnce(&mut Settings)>(&mut self, f: F) {
+        f(&mut self.settings);
+
+        if let Err(e) = self.settings.save(&settings::settings_path()) {
+            error!("Failed to save settings: {:?}", e);
+            self.error(format!("Failed to save settings: {}", e).into());
+        }
+    }
+
     fn set_visible(&mut self, visible: bool) {
         self.visible_storage = visible
     }
 
+    /// Events arrive asynchronously, so an event may reference an account we
+    /// have not seen (or no longer have). Those are logged and dropped
+    fn with_account<F: FnOnce(&mut Account)>(&mut self, account_id: &AccountId, f: F) {
+        match self.accounts_storage.get(account_id) {
+            Some(account) => f(&mut *account.pinned().borrow_mut()),
+            None => warn!("Received event for unknown account {}", account_id),
+        }
+    }
+
+    /// Re-reads sender names if the chat model is showing a chat of
+    /// account_id
+    fn refresh_sender_names(&mut self, account_id: &AccountId) {
+        let chat_model_pinned = self.chat_model.pinned();
+        let mut chat_model = chat_model_pinned.borrow_mut();
+        if chat_model.account != account_id.id() {
+            return;
+        }
+
+        if let Some(account) = self.accounts_storage.get(account_id) {
+            chat_model.set_sender_names(account.pinned().borrow().user_names());
+        }
+    }
+
     fn handle_ui_callback(&mut self, event: TocksEvent) {
         match event {
             TocksEvent::AccountListLoaded(list) => self.set_account_list(list),
             TocksEvent::Error(e) => self.error(e.into()),
-            TocksEvent::AccountLoggedIn(account_id, user_handle, address, name) => {
-                self.account_login(account_id, user_handle, address, name)
+            TocksEvent::AccountInUse(account_name) => self.error(
+                format!(
+                    "{} is already open in another tocks instance, close it and try again",
+                    account_name
+                )
+                .into(),
+            ),
+            TocksEvent::PasswordRequired(account_name) => {
+                self.passwordRequired(account_name.into())
             }
+            TocksEvent::AccountRemoved(account_id, reason) => {
+                self.account_removed(account_id, reason)
+            }
+            TocksEvent::AccountLoggedIn(
+                account_id,
+                user_handle,
+                address,
+                name,
+                max_message_length,
+            ) => self.account_login(account_id, user_handle, address, name, max_message_length),
             TocksEvent::FriendAdded(account, friend) => {
-                self.accounts_storage
-                    .get(&account)
-                    .unwrap()
-                    .pinned()
-                    .borrow_mut()
-                    .add_friend(&friend);
+                self.with_account(&account, |account| account.add_friend(&friend));
+                self.refresh_sender_names(&account);
+            }
+            TocksEvent::FriendsLoaded(account, friends) => {
+                self.with_account(&account, |account| account.add_friends(&friends));
+                self.refresh_sender_names(&account);
             }
             TocksEvent::BlockedUserAdded(account, user) => {
-                self.accounts_storage
-                    .get(&account)
-                    .unwrap()
-                    .pinned()
-                    .borrow_mut()
-                    .add_blocked_user(&user);
+                self.with_account(&account, |account| account.add_blocked_user(&user));
+                self.refresh_sender_names(&account);
             }
             TocksEvent::FriendRemoved(account, user_id) => {
-                self.accounts_storage
-                    .get(&account)
-                    .unwrap()
+                self.with_account(&account, |account| account.remove_friend(user_id));
+            }
+            TocksEvent::OlderMessagesLoaded(account, chat, messages) => {
+                self.chat_model
                     .pinned()
                     .borrow_mut()
-                    .remove_friend(user_id);
+                    .push_older(account, chat, messages);
             }
-            TocksEvent::MessagesLoaded(account, chat, messages) => {
+            TocksEvent::MessagesAroundLoaded(account, chat, messages) => {
                 self.chat_model
                     .pinned()
                     .borrow_mut()
-                    .set_content(account, chat, messages);
+                    .push_around(account, chat, messages);
             }
-            TocksEvent::MessageInserted(account, chat, entry) => {
-                let self_id = self
+            TocksEvent::MessagesLoaded(account, chat, messages) => {
+                let peer_offline = self
                     .accounts_storage
                     .get(&account)
-                    .unwrap()
+                    .map_or(false, |a| a.pinned().borrow().chat_peer_offline(chat));
+                self.chat_model.pinned().borrow_mut().peer_offline = peer_offline;
+
+                self.chat_model
                     .pinned()
                     .borrow_mut()
-                    .self_id();
+                    .set_content(account, chat, messages);
+                self.refresh_sender_names(&account);
+            }
+            TocksEvent::MessageInserted(account, chat, entry) => {
+                let account_info = self.accounts_storage.get(&account).map(|account| {
+                    let pinned = account.pinned();
+                    let mut account = pinned.borrow_mut();
+                    (account.self_id(), account.chat_muted(chat))
+                });
+
+                if account_info.is_none() {
+                    warn!("Received message for unknown account {}", account);
+                }
 
-                if *entry.sender() != self_id && !self.visible_storage {
+                let notify = account_info.map_or(false, |(self_id, chat_muted)| {
+                    plays_notification(
+                        entry.sender(),
+                        &self_id,
+                        chat_muted,
+                        self.visible_storage,
+                        &self.settings,
+                    )
+                });
+
+                if notify {
                     self.send_qtocks_request(QTocksEvent::PlayNotificationSound);
                 }
 
@@ -461,30 +1531,96 @@ impl QTocks {
                     chat_model_ref.resolve_message(id);
                 }
             }
+            TocksEvent::MessageSendStateChanged(account, chat, id, state) => {
+                let chat_model_pinned = self.chat_model.pinned();
+                let mut chat_model_ref = chat_model_pinned.borrow_mut();
+                if chat_model_ref.account == account.id() && chat_model_ref.chat == chat.id() {
+                    chat_model_ref.set_send_state(id, state);
+                }
+            }
             TocksEvent::FriendStatusChanged(account_id, user_id, status) => {
-                self.accounts_storage
-                    .get(&account_id)
-                    .unwrap()
-                    .pinned()
-                    .borrow_mut()
-                    .set_friend_status(user_id, status);
+                let mut chat = None;
+                self.with_account(&account_id, |account| {
+                    account.set_friend_status(user_id, status);
+                    chat = account.friend_chat(user_id);
+                });
+
+                if let Some(chat) = chat {
+                    self.chat_model.pinned().borrow_mut().set_peer_offline(
+                        account_id,
+                        chat,
+                        status == Status::Offline,
+                    );
+                }
             }
             TocksEvent::UserNameChanged(account_id, user_id, name) => {
-                self.accounts_storage
-                    .get(&account_id)
-                    .unwrap()
-                    .pinned()
-                    .borrow_mut()
-                    .set_user_name(user_id, &name);
+                self.with_account(&account_id, |account| account.set_user_name(user_id, &name));
+                self.refresh_sender_names(&account_id);
+            }
+            TocksEvent::SelfNameChanged(account_id, name) => {
+                self.with_account(&account_id, |account| account.set_name(&name));
+                self.refresh_sender_names(&account_id);
+            }
+            TocksEvent::FriendVerifiedChanged(account_id, user_id, verified) => {
+                self.with_account(&account_id, |account| {
+                    account.set_friend_verified(user_id, verified)
+                });
+            }
+            TocksEvent::FriendPossiblyRemoved(account_id, user_id, possibly_removed) => {
+                self.with_account(&account_id, |account| {
+                    account.set_friend_possibly_removed(user_id, possibly_removed)
+                });
+            }
+            TocksEvent::ChatMutedChanged(account_id, chat_handle, muted) => {
+                self.with_account(&account_id, |account| {
+                    account.set_chat_muted(chat_handle, muted)
+                });
             }
             TocksEvent::ChatCallStateChanged(account_id, chat_handle, state) => {
-                // Do nothing
-                self.accounts_storage
-                    .get(&account_id)
-                    .unwrap()
+                self.with_account(&account_id, |account| {
+                    account.set_call_state(chat_handle, &state)
+                });
+            }
+            TocksEvent::CallEnded(account_id, chat_handle, reason) => {
+                self.with_account(&account_id, |account| {
+                    account.set_call_ended(chat_handle, &reason)
+                });
+            }
+            TocksEvent::PeerReadUpTo(account, chat, message_id) => {
+                self.chat_model
                     .pinned()
                     .borrow_mut()
-                    .set_call_state(chat_handle, &state);
+                    .set_peer_read_message(account, chat, message_id);
+            }
+            TocksEvent::UnableToConnect(_) => self.error(
+                "Unable to connect to the tox network, check your connection and bootstrap nodes"
+                    .into(),
+            ),
+            TocksEvent::TypedError(e) => self.error(e.to_string().into()),
+            TocksEvent::ChatReadTimeUpdated(account_id, chat_handle, read_time) => {
+                self.with_account(&account_id, |account| {
+                    account.set_chat_read_time(chat_handle, &read_time)
+                });
+            }
+            TocksEvent::UnreadCountChanged(account_id, chat_handle, unread_count) => {
+                self.with_account(&account_id, |account| {
+                    account.set_chat_unread_count(chat_handle, unread_count)
+                });
+            }
+            TocksEvent::Metrics(metrics) => {
+                debug!("Metrics: {:?}", metrics);
+            }
+            TocksEvent::Logs(entries) => {
+                self.log_model.pinned().borrow_mut().set_entries(entries);
+            }
+            TocksEvent::ActiveCalls(_) => {
+                // Call state is already tracked through ChatCallStateChanged
+            }
+            TocksEvent::Diagnostics(_, diagnostics) => {
+                debug!("Diagnostics: {:?}", diagnostics);
+            }
+            TocksEvent::FriendList(_, _) => {
+                // Friends are tracked via FriendsLoaded/FriendAdded/FriendRemoved
             }
             TocksEvent::AudioDataReceived(_, _, _) => {
                 // This should be handled by the above layer
@@ -496,14 +1632,23 @@ impl QTocks {
 
 pub struct QmlUi {
     ui_handle: Option<JoinHandle<()>>,
-    audio_manager: AudioManager,
+    // None when there is no usable audio device. Messaging still works but
+    // calls are refused
+    audio_manager: Option<AudioManager>,
     audio_handles: HashMap<(AccountId, ChatHandle), mpsc::UnboundedSender<AudioFrame>>,
     repeating_audio_handle: Option<RepeatingAudioHandle>,
-    capture_channel: Option<mpsc::UnboundedReceiver<AudioFrame>>,
+    audio_loopback: Option<AudioLoopback>,
+    notification_sound: FormattedAudio,
+    capture_channel: Option<CaptureReceiver>,
+    // toxav only takes frames of certain lengths, captured audio is cut to
+    // size before it's sent to calls
+    capture_framer: CaptureFramer,
     tocks_event_rx: mpsc::UnboundedReceiver<TocksEvent>,
     ui_event_tx: mpsc::UnboundedSender<TocksUiEvent>,
     qtocks_event_rx: mpsc::UnboundedReceiver<QTocksEvent>,
     handle_ui_callback: Box<dyn Fn(TocksEvent) + Send + Sync>,
+    // Asks the QML engine to exit its event loop
+    quit_callback: Box<dyn Fn(()) + Send + Sync>,
 }
 
 impl QmlUi {
@@ -514,13 +1659,13 @@ impl QmlUi {
         let (handle_callback_tx, handle_callback_rx) = std::sync::mpsc::channel();
         let (qtocks_event_tx, qtocks_event_rx) = mpsc::unbounded();
 
-        let mut audio_manager = AudioManager::new().context("Failed to start audio manager")?;
-        // Ideally we would trigger something in QTocks when the devices are
-        // updated, but at the time of writing we already didn't support it.
-        // We'll fix it later.
-        let audio_devices = audio_manager
-            .output_devices()
-            .context("Failed to initialize audio devices")?;
+        let settings = Settings::load(&settings::settings_path());
+        let (audio_manager, audio_devices, audio_warning) =
+            Self::init_audio(AudioManager::new(), &settings);
+        let audio_available = audio_manager.is_some();
+        let notification_sound =
+            load_notification_sound(settings.notification_sound_path.as_deref());
+        let capture_framer = CaptureFramer::new(settings.call_frame_period);
 
         let ui_event_tx_clone = ui_event_tx.clone();
         // Spawn the QML engine into it's own thread. Our implementation will
@@ -532,6 +1677,8 @@ impl QmlUi {
                 ui_event_tx_clone,
                 qtocks_event_tx,
                 audio_devices,
+                audio_available,
+                settings,
             ));
             let qtocks_pinned = qtocks.pinned();
 
@@ -542,6 +1689,8 @@ impl QmlUi {
                 "chatModel".into(),
                 qtocks_pinned.borrow().chat_model.pinned(),
             );
+            engine
+                .set_object_property("logModel".into(), qtocks_pinned.borrow().log_model.pinned());
 
             // FIXME: bundle with qrc on release builds
             engine.load_file(concat!(env!("CARGO_MANIFEST_DIR"), "/qml/Tocks.qml").into());
@@ -552,42 +1701,126 @@ impl QmlUi {
                 pinned.borrow_mut().handle_ui_callback(event);
             });
 
+            let qtocks_clone = QPointer::from(&**qtocks_pinned.borrow_mut());
+            let quit_callback = queued_callback(move |()| {
+                if let Some(pinned) = qtocks_clone.as_pinned() {
+                    pinned.borrow().quitRequested();
+                }
+            });
+
             handle_callback_tx
-                .send(handle_ui_callback)
+                .send((handle_ui_callback, quit_callback))
                 .expect("Failed to hand off ui callback");
 
             engine.exec();
         });
 
-        let handle_ui_callback = Box::new(handle_callback_rx.recv().unwrap());
+        let (handle_ui_callback, quit_callback) = handle_callback_rx.recv().unwrap();
+        let handle_ui_callback = Box::new(handle_ui_callback);
+
+        if let Some(warning) = audio_warning {
+            warn!("{}", warning);
+            (*handle_ui_callback)(TocksEvent::Error(warning));
+        }
 
         Ok(QmlUi {
             ui_handle: Some(ui_handle),
             audio_manager,
             audio_handles: Default::default(),
             repeating_audio_handle: None,
+            audio_loopback: None,
+            notification_sound,
             capture_channel: None,
+            capture_framer,
             tocks_event_rx,
             ui_event_tx,
             qtocks_event_rx,
             handle_ui_callback,
+            quit_callback: Box::new(quit_callback),
         })
     }
 
+    /// Sets up audio from the result of creating the audio manager. Failing to
+    /// initialize audio (e.g. a machine without a sound card) is not fatal,
+    /// we just run without it. Returns a message for the user if something
+    /// went wrong
+    fn init_audio(
+        audio_manager: Result<AudioManager>,
+        settings: &Settings,
+    ) -> (Option<AudioManager>, Vec<OutputDevice>, Option<String>) {
+        // Ideally we would trigger something in QTocks when the devices are
+        // updated, but at the time of writing we already didn't support it.
+        // We'll fix it later.
+        let res = audio_manager
+            .context("Failed to start audio manager")
+            .and_then(|mut audio_manager| {
+                let audio_devices = audio_manager
+                    .output_devices()
+                    .context("Failed to initialize audio devices")?;
+                Ok((audio_manager, audio_devices))
+            });
+
+        match res {
+            Ok((mut audio_manager, audio_devices)) => {
+                let warning =
+                    Self::restore_audio_output(&mut audio_manager, &audio_devices, settings);
+                (Some(audio_manager), audio_devices, warning)
+            }
+            Err(e) => {
+                let warning = format!("Audio is unavailable, calls are disabled: {:#}", e);
+                (None, Vec::new(), Some(warning))
+            }
+        }
+    }
+
+    /// Switches to the output device the user picked last time. Returns a
+    /// message for the user if we had to fall back to the default device
+    fn restore_audio_output(
+        audio_manager: &mut AudioManager,
+        audio_devices: &[OutputDevice],
+        settings: &Settings,
+    ) -> Option<String> {
+        let saved = &settings.audio_output;
+
+        match settings::find_output_device(audio_devices, saved) {
+            Some(OutputDevice::Default) => None,
+            Some(device) => audio_manager
+                .set_output_device(device.clone())
+                .err()
+                .map(|e| {
+                    format!(
+                        "Failed to restore audio output {}, using the default device: {}",
+                        saved.to_string(),
+                        e
+                    )
+                }),
+            None => Some(format!(
+                "Audio output {} is no longer available, using the default device",
+                saved.to_string()
+            )),
+        }
+    }
+
     pub async fn run(&mut self) {
         loop {
             futures::select! {
-                _ = self.audio_manager.run().fuse() => {
+                _ = Self::run_audio(&mut self.audio_manager).fuse() => {
 
+                }
+                _ = Self::run_audio_loopback(&mut self.audio_loopback).fuse() => {
+                    self.audio_loopback = None;
                 }
                 frame = Self::wait_for_capture_frame(&mut self.capture_channel).fuse() => {
                     // Someone else will catch this failure
                     match frame {
                         Some(frame) => {
-                            let _ = self.ui_event_tx.unbounded_send(TocksUiEvent::IncomingAudioFrame(frame));
+                            for frame in self.capture_framer.push(frame) {
+                                let _ = self.ui_event_tx.unbounded_send(TocksUiEvent::IncomingAudioFrame(frame));
+                            }
                         },
                         None => {
                             self.capture_channel = None;
+                            self.capture_framer.reset();
                         }
                     }
                 }
@@ -606,9 +1839,23 @@ impl QmlUi {
         }
     }
 
-    async fn wait_for_capture_frame(
-        channel: &mut Option<mpsc::UnboundedReceiver<AudioFrame>>,
-    ) -> Option<AudioFrame> {
+    async fn run_audio(audio_manager: &mut Option<AudioManager>) {
+        if let Some(audio_manager) = audio_manager.as_mut() {
+            audio_manager.run().await
+        } else {
+            futures::future::pending().await
+        }
+    }
+
+    async fn run_audio_loopback(loopback: &mut Option<AudioLoopback>) {
+        if let Some(loopback) = loopback.as_mut() {
+            loopback.run().await
+        } else {
+            futures::future::pending().await
+        }
+    }
+
+    async fn wait_for_capture_frame(channel: &mut Option<CaptureReceiver>) -> Option<AudioFrame> {
         if let Some(channel) = channel.as_mut() {
             channel.next().await
         } else {
@@ -619,8 +1866,10 @@ impl QmlUi {
     fn handle_qtocks_event(&mut self, event: Option<QTocksEvent>) {
         match event {
             Some(QTocksEvent::SetAudioOutput(device)) => self.set_audio_output(device),
+            Some(QTocksEvent::SetNotificationSound(sound)) => self.notification_sound = sound,
             Some(QTocksEvent::PlayNotificationSound) => self.play_notification_sound(),
             Some(QTocksEvent::StartAudioTest) => self.start_audio_test(),
+            Some(QTocksEvent::StartLoopbackTest) => self.start_loopback_test(),
             Some(QTocksEvent::StopAudioTest) => self.stop_audio_test(),
             None => {
                 warn!("No QTocks event received");
@@ -636,22 +1885,43 @@ impl QmlUi {
             TocksEvent::ChatCallStateChanged(account, chat, state) => {
                 match state {
                     CallState::Active => {
+                        let audio_manager = match self.audio_manager.as_mut() {
+                            Some(audio_manager) => audio_manager,
+                            None => {
+                                // The call may have been accepted by someone
+                                // other than the UI, e.g. tocks_cli
+                                let _ = self
+                                    .ui_event_tx
+                                    .unbounded_send(TocksUiEvent::LeaveCall(account, chat));
+                                (*self.handle_ui_callback)(TocksEvent::Error(
+                                    "Calls are unavailable without an audio device".to_string(),
+                                ));
+                                return;
+                            }
+                        };
+
                         // FIXME: error handling
                         if self.audio_handles.get(&(account, chat)).is_none() {
                             let playback_channel =
-                                self.audio_manager.create_playback_channel(50).unwrap();
+                                audio_manager.create_playback_channel(50).unwrap();
                             self.audio_handles.insert((account, chat), playback_channel);
                         }
 
                         if self.capture_channel.is_none() {
                             self.capture_channel =
-                                Some(self.audio_manager.create_capture_channel().unwrap());
+                                Some(audio_manager.create_capture_channel().unwrap());
                         }
                     }
-                    CallState::Idle | CallState::Incoming | CallState::Outgoing => {
+                    // Playback and capture are recreated when the call
+                    // becomes active again
+                    CallState::Held
+                    | CallState::Idle
+                    | CallState::Incoming
+                    | CallState::Outgoing => {
                         self.audio_handles.remove(&(account, chat));
                         if self.audio_handles.is_empty() {
                             self.capture_channel = None;
+                            self.capture_framer.reset();
                         }
                     }
                 }
@@ -671,8 +1941,12 @@ impl QmlUi {
     }
 
     fn set_audio_output(&mut self, device: OutputDevice) {
-        let res = self
-            .audio_manager
+        let audio_manager = match self.audio_manager.as_mut() {
+            Some(audio_manager) => audio_manager,
+            None => return,
+        };
+
+        let res = audio_manager
             .set_output_device(device)
             .context("Failed to set output device");
 
@@ -683,18 +1957,32 @@ impl QmlUi {
 
     fn stop_audio_test(&mut self) {
         self.repeating_audio_handle = None;
+        self.audio_loopback = None;
+    }
+
+    fn start_loopback_test(&mut self) {
+        let audio_manager = match self.audio_manager.as_mut() {
+            Some(audio_manager) => audio_manager,
+            None => return,
+        };
+
+        match audio_manager.create_loopback(LOOPBACK_TEST_DELAY) {
+            Ok(loopback) => self.audio_loopback = Some(loopback),
+            Err(e) => (*self.handle_ui_callback)(TocksEvent::Error(format!("{:#}", e))),
+        }
     }
 
     fn start_audio_test(&mut self) {
-        self.repeating_audio_handle = Some(
-            self.audio_manager
-                .play_repeating_formatted_audio(load_notification_sound()),
-        );
+        if let Some(audio_manager) = self.audio_manager.as_mut() {
+            self.repeating_audio_handle =
+                Some(audio_manager.play_repeating_formatted_audio(self.notification_sound.clone()));
+        }
     }
 
     fn play_notification_sound(&mut self) {
-        self.audio_manager
-            .play_formatted_audio(load_notification_sound());
+        if let Some(audio_manager) = self.audio_manager.as_mut() {
+            audio_manager.play_formatted_audio(self.notification_sound.clone());
+        }
     }
 }
 
@@ -703,11 +1991,126 @@ impl Drop for QmlUi {
         let mut handle = None;
         std::mem::swap(&mut handle, &mut self.ui_handle);
         if let Some(handle) = handle {
+            // The engine only stops on its own when the window is closed. If
+            // tocks is going away for any other reason we'd wait forever
+            (*self.quit_callback)(());
             handle.join().unwrap();
         }
     }
 }
 
+/// Whether an incoming message should play the notification sound. Nothing
+/// plays for our own messages, muted chats, while the window is showing, or
+/// in do not disturb mode
+fn plays_notification(
+    sender: &UserHandle,
+    self_id: &UserHandle,
+    chat_muted: bool,
+    window_visible: bool,
+    settings: &Settings,
+) -> bool {
+    sender != self_id
+        && !chat_muted
+        && !window_visible
+        && settings.notification_sounds
+        && !settings.do_not_disturb
+}
+
+fn validate_account_id<T>(accounts: &HashMap<AccountId, T>, account: i64) -> Result<AccountId> {
+    let account_id = AccountId::from(account);
+
+    if !accounts.contains_key(&account_id) {
+        bail!("Unknown account {}", account);
+    }
+
+    Ok(account_id)
+}
+
+fn escape_styled_text(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+/// StyledText collapses whitespace like html does and has no white-space
+/// style to stop it. Newlines become breaks and every space after the first
+/// in a run becomes a non breaking space, so lines can still wrap. after_space
+/// carries over between spans so runs split across spans are kept too
+fn preserve_whitespace(text: &str, after_space: &mut bool) -> String {
+    let mut ret = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\n' => {
+                ret.push_str("<br>");
+                *after_space = true;
+            }
+            '\t' => {
+                ret.push_str("&nbsp;&nbsp;&nbsp;&nbsp;");
+                *after_space = true;
+            }
+            ' ' if *after_space => ret.push_str("&nbsp;"),
+            ' ' => {
+                ret.push(' ');
+                *after_space = true;
+            }
+            c => {
+                ret.push(c);
+                *after_space = false;
+            }
+        }
+    }
+    ret
+}
+
+/// Converts styled spans to the html subset understood by QML's
+/// Text.StyledText. The message itself is stored as typed
+fn spans_to_styled_text(spans: &[Span]) -> String {
+    let mut ret = String::new();
+    // Leading whitespace would be dropped as well
+    let mut after_space = true;
+
+    for span in spans {
+        let text = escape_styled_text(span.text);
+        let mut preserved = || preserve_whitespace(&text, &mut after_space);
+
+        match span.style {
+            SpanStyle::Plain => ret.push_str(&preserved()),
+            SpanStyle::Bold => {
+                ret.push_str("<b>");
+                ret.push_str(&preserved());
+                ret.push_str("</b>");
+            }
+            SpanStyle::Italic => {
+                ret.push_str("<i>");
+                ret.push_str(&preserved());
+                ret.push_str("</i>");
+            }
+            SpanStyle::Code => {
+                ret.push_str("<font face=\"monospace\">");
+                ret.push_str(&preserved());
+                ret.push_str("</font>");
+            }
+            SpanStyle::CodeBlock => {
+                // pre already keeps whitespace, and ends the line
+                ret.push_str("<pre>");
+                ret.push_str(&text);
+                ret.push_str("</pre>");
+                after_space = true;
+            }
+        }
+    }
+
+    ret
+}
+
 pub(crate) fn status_to_qstring(status: &Status) -> QString {
     match status {
         Status::Online => "online".into(),
@@ -718,11 +2121,845 @@ pub(crate) fn status_to_qstring(status: &Status) -> QString {
     }
 }
 
+fn send_state_to_qstring(state: &SendState) -> QString {
+    match state {
+        SendState::Unsent => "unsent".into(),
+        SendState::Sent => "sent".into(),
+        SendState::Failed => "failed".into(),
+    }
+}
+
+pub(crate) fn call_end_reason_to_qstring(reason: &CallEndReason) -> QString {
+    match reason {
+        CallEndReason::Cancelled => "cancelled".into(),
+        CallEndReason::Rejected => "rejected".into(),
+        CallEndReason::Declined => "declined".into(),
+        CallEndReason::Missed => "missed".into(),
+        CallEndReason::HungUp => "hungup".into(),
+    }
+}
+
 pub(crate) fn call_state_to_qtring(state: &CallState) -> QString {
     match state {
         CallState::Active => "active".into(),
         CallState::Incoming => "incoming".into(),
         CallState::Idle => "idle".into(),
         CallState::Outgoing => "outgoing".into(),
+        CallState::Held => "held".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::anyhow;
+    use toxcore::Message;
+
+    #[test]
+    fn test_validate_account_id() {
+        let mut accounts = HashMap::new();
+        accounts.insert(AccountId::from(1), ());
+
+        assert_eq!(
+            validate_account_id(&accounts, 1).unwrap(),
+            AccountId::from(1)
+        );
+        assert!(validate_account_id(&accounts, 2).is_err());
+        assert!(validate_account_id(&HashMap::<AccountId, ()>::new(), 1).is_err());
+    }
+
+    #[test]
+    fn test_path_from_file_url() {
+        assert_eq!(
+            path_from_file_url("file:///home/user/My%20Sounds/ding%25.wav"),
+            PathBuf::from("/home/user/My Sounds/ding%.wav")
+        );
+        assert_eq!(
+            path_from_file_url("file://localhost/tmp/%C3%A9t%C3%A9.ogg"),
+            PathBuf::from("/tmp/été.ogg")
+        );
+        // Plain paths are not URL decoded
+        assert_eq!(
+            path_from_file_url("/tmp/100%20.wav"),
+            PathBuf::from("/tmp/100%20.wav")
+        );
+    }
+
+    #[test]
+    fn test_muted_chat_does_not_notify() {
+        let me = UserHandle::from(0);
+        let peer = UserHandle::from(1);
+        let settings = Settings::default();
+
+        assert!(plays_notification(&peer, &me, false, false, &settings));
+        assert!(!plays_notification(&peer, &me, true, false, &settings));
+
+        assert!(!plays_notification(&me, &me, false, false, &settings));
+        assert!(!plays_notification(&peer, &me, false, true, &settings));
+
+        let settings = Settings {
+            notification_sounds: false,
+            ..Default::default()
+        };
+        assert!(!plays_notification(&peer, &me, false, false, &settings));
+    }
+
+    #[test]
+    fn test_do_not_disturb_overrides_chat_settings() {
+        let me = UserHandle::from(0);
+        let peer = UserHandle::from(1);
+        let settings = Settings {
+            do_not_disturb: true,
+            ..Default::default()
+        };
+
+        for &muted in &[false, true] {
+            assert!(!plays_notification(&peer, &me, muted, false, &settings));
+        }
+    }
+
+    #[test]
+    fn test_init_audio_without_device() {
+        let (audio_manager, audio_devices, warning) = QmlUi::init_audio(
+            Err(anyhow!("Failed to open audio device")),
+            &Settings::default(),
+        );
+
+        assert!(audio_manager.is_none());
+        assert!(audio_devices.is_empty());
+
+        let warning = warning.unwrap();
+        assert!(warning.contains("calls are disabled"));
+        assert!(warning.contains("Failed to open audio device"));
+    }
+
+    #[test]
+    fn test_start_call_without_audio() {
+        let (ui_requests_tx, mut ui_requests_rx) = mpsc::unbounded();
+        let (qtocks_event_tx, _qtocks_event_rx) = mpsc::unbounded();
+        // Boxed since the error signal needs a backing QObject
+        let qtocks = QObjectBox::new(QTocks::new(
+            ui_requests_tx,
+            qtocks_event_tx,
+            Vec::new(),
+            false,
+            Settings::default(),
+        ));
+        let qtocks_pinned = qtocks.pinned();
+        let mut qtocks = qtocks_pinned.borrow_mut();
+
+        let account = AccountId::from(1);
+        qtocks
+            .accounts_storage
+            .insert(account, QObjectBox::new(Account::default()));
+
+        qtocks.startCall(1, 1);
+        assert!(ui_requests_rx.try_next().is_err());
+    }
+
+    fn chat_log_entry(id: i64, timestamp_secs: i64) -> ChatLogEntry {
+        chat_log_reply(id, timestamp_secs, None, &format!("message {}", id))
+    }
+
+    fn chat_log_reply(
+        id: i64,
+        timestamp_secs: i64,
+        reply_to: Option<i64>,
+        text: &str,
+    ) -> ChatLogEntry {
+        let message = Message::Normal(text.to_string());
+        chat_log_message(id, timestamp_secs, reply_to, message)
+    }
+
+    fn chat_log_message(
+        id: i64,
+        timestamp_secs: i64,
+        reply_to: Option<i64>,
+        message: Message,
+    ) -> ChatLogEntry {
+        let mut entry = ChatLogEntry::new(
+            id.into(),
+            UserHandle::from(1),
+            message,
+            Utc.timestamp(timestamp_secs, 0),
+            reply_to.map(ChatMessageId::from),
+        );
+        entry.set_complete(false);
+        entry
+    }
+
+    fn chat_log_ids(model: &ChatModel) -> Vec<i64> {
+        model.chat_log.iter().map(|entry| entry.id().id()).collect()
+    }
+
+    #[test]
+    fn test_chat_model_push_message_order() {
+        let model = QObjectBox::new(ChatModel::default());
+        let model_pinned = model.pinned();
+        let mut model = model_pinned.borrow_mut();
+
+        model.set_content(
+            AccountId::from(1),
+            ChatHandle::from(1),
+            vec![chat_log_entry(1, 1), chat_log_entry(3, 3)],
+        );
+
+        model.push_message(chat_log_entry(4, 4));
+        assert_eq!(chat_log_ids(&model), vec![1, 3, 4]);
+
+        // Late arrivals still end up sorted so they can be resolved
+        model.push_message(chat_log_entry(2, 2));
+        assert_eq!(chat_log_ids(&model), vec![1, 2, 3, 4]);
+
+        // Duplicates replace instead of adding a second row
+        model.push_message(chat_log_entry(3, 3));
+        assert_eq!(chat_log_ids(&model), vec![1, 2, 3, 4]);
+
+        model.resolve_message(ChatMessageId::from(2));
+        assert!(model.chat_log[1].complete());
+        assert!(!model.chat_log[2].complete());
+
+        // Newest message is the first row
+        assert_eq!(model.chat_log[model.reversed_index(0)].id().id(), 4);
+    }
+
+    #[test]
+    fn test_chat_model_send_state() {
+        let model = QObjectBox::new(ChatModel::default());
+        let model_pinned = model.pinned();
+        let mut model = model_pinned.borrow_mut();
+
+        model.set_content(
+            AccountId::from(1),
+            ChatHandle::from(1),
+            vec![chat_log_entry(1, 1), chat_log_entry(2, 2)],
+        );
+
+        model.set_send_state(ChatMessageId::from(2), SendState::Failed);
+        assert_eq!(model.chat_log[1].send_state(), Some(SendState::Failed));
+        assert_eq!(model.chat_log[0].send_state(), None);
+
+        model.set_send_state(ChatMessageId::from(2), SendState::Sent);
+        assert_eq!(model.chat_log[1].send_state(), Some(SendState::Sent));
+
+        // Messages that aren't loaded are left alone
+        model.set_send_state(ChatMessageId::from(3), SendState::Failed);
+        assert_eq!(chat_log_ids(&model), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_chat_model_newest_peer_message() {
+        let model = QObjectBox::new(ChatModel::default());
+        let model_pinned = model.pinned();
+        let mut model = model_pinned.borrow_mut();
+
+        let self_id = 2;
+        assert_eq!(model.newestPeerMessageId(self_id), -1);
+
+        let own_message = ChatLogEntry::new(
+            ChatMessageId::from(3),
+            UserHandle::from(self_id),
+            Message::Normal("mine".to_string()),
+            Utc.timestamp(3, 0),
+            None,
+        );
+        model.set_content(
+            AccountId::from(1),
+            ChatHandle::from(1),
+            vec![chat_log_entry(1, 1), chat_log_entry(2, 2), own_message],
+        );
+
+        // Our own newer message doesn't count
+        assert_eq!(model.newestPeerMessageId(self_id), 2);
+    }
+
+    #[test]
+    fn test_chat_model_row_for_message_id() {
+        let model = QObjectBox::new(ChatModel::default());
+        let model_pinned = model.pinned();
+        let mut model = model_pinned.borrow_mut();
+
+        assert_eq!(model.row_for_message_id(ChatMessageId::from(1)), None);
+
+        model.set_content(
+            AccountId::from(1),
+            ChatHandle::from(1),
+            vec![
+                chat_log_entry(1, 1),
+                chat_log_entry(2, 2),
+                chat_log_entry(4, 4),
+            ],
+        );
+
+        // Rows are newest first
+        assert_eq!(model.row_for_message_id(ChatMessageId::from(4)), Some(0));
+        assert_eq!(model.row_for_message_id(ChatMessageId::from(2)), Some(1));
+        assert_eq!(model.row_for_message_id(ChatMessageId::from(1)), Some(2));
+        assert_eq!(model.row_for_message_id(ChatMessageId::from(3)), None);
+    }
+
+    #[test]
+    fn test_scroll_to_message_pages_in_history() {
+        let (ui_requests_tx, mut ui_requests_rx) = mpsc::unbounded();
+        let model = QObjectBox::new(ChatModel {
+            ui_requests_tx: Some(ui_requests_tx),
+            ..Default::default()
+        });
+        let model_pinned = model.pinned();
+        let mut model = model_pinned.borrow_mut();
+
+        let account = AccountId::from(1);
+        let chat = ChatHandle::from(1);
+        let history = |ids: std::ops::RangeInclusive<i64>| -> Vec<ChatLogEntry> {
+            ids.map(|id| chat_log_entry(id, 0)).collect()
+        };
+
+        // Serves every page request, returns how many there were
+        let serve_pages = |model: &mut ChatModel,
+                           rx: &mut mpsc::UnboundedReceiver<TocksUiEvent>|
+         -> usize {
+            let mut pages = 0;
+            while let Ok(Some(TocksUiEvent::LoadOlderMessages(_, _, before, limit))) = rx.try_next()
+            {
+                pages += 1;
+                let oldest = before.id() - limit as i64;
+                model.push_older(account, chat, history(oldest.max(1)..=before.id() - 1));
+            }
+            pages
+        };
+
+        let newest = (ChatModel::PAGE_SIZE * (ChatModel::MAX_SCROLL_PAGES + 1)
+            + ChatModel::MAX_LOADED_MESSAGES) as i64;
+        let first_loaded = newest - ChatModel::MAX_LOADED_MESSAGES as i64 + 1;
+        model.set_content(account, chat, history(first_loaded..=newest));
+
+        // One page back is enough
+        model.scrollToMessage(first_loaded - 50);
+        assert_eq!(serve_pages(&mut *model, &mut ui_requests_rx), 1);
+        assert!(model.pending_scroll.is_none());
+
+        // Too far back, paging stops at the limit
+        model.set_content(account, chat, history(first_loaded..=newest));
+        model.scrollToMessage(1);
+        assert_eq!(
+            serve_pages(&mut *model, &mut ui_requests_rx),
+            ChatModel::MAX_SCROLL_PAGES
+        );
+        assert!(model.pending_scroll.is_none());
+        assert!(model.row_for_message_id(ChatMessageId::from(1)).is_none());
+    }
+
+    #[test]
+    fn test_jump_to_date() {
+        let (ui_requests_tx, mut ui_requests_rx) = mpsc::unbounded();
+        let model = QObjectBox::new(ChatModel {
+            ui_requests_tx: Some(ui_requests_tx),
+            ..Default::default()
+        });
+        let model_pinned = model.pinned();
+        let mut model = model_pinned.borrow_mut();
+
+        let account = AccountId::from(1);
+        let chat = ChatHandle::from(1);
+        // Message id n was sent at n seconds
+        let history = |ids: std::ops::RangeInclusive<i64>| -> Vec<ChatLogEntry> {
+            ids.map(|id| chat_log_entry(id, id)).collect()
+        };
+
+        model.set_content(account, chat, history(100..=200));
+
+        // Answers to requests that weren't made are ignored
+        model.push_around(account, chat, history(50..=60));
+        assert_eq!(model.chat_log.len(), 101);
+
+        model.jumpToDate(90 * 1000);
+        match ui_requests_rx.try_next() {
+            Ok(Some(TocksUiEvent::LoadMessagesAround(_, _, time, _, _))) => {
+                assert_eq!(time, Utc.timestamp(90, 0))
+            }
+            _ => panic!("Expected messages around to be requested"),
+        }
+
+        // Reaches the loaded messages, spliced in without paging
+        model.push_around(account, chat, history(40..=140));
+        assert_eq!(chat_log_ids(&model)[0], 40);
+        assert_eq!(model.chat_log.len(), 161);
+        assert!(ui_requests_rx.try_next().is_err());
+
+        // Nothing near the time
+        model.jumpToDate(0);
+        model.push_around(account, chat, Vec::new());
+        assert!(model.pending_jump.is_none());
+        assert!(model.pending_scroll.is_none());
+    }
+
+    #[test]
+    fn test_chat_model_eviction_and_reload() {
+        let (ui_requests_tx, mut ui_requests_rx) = mpsc::unbounded();
+        let model = QObjectBox::new(ChatModel {
+            ui_requests_tx: Some(ui_requests_tx),
+            ..Default::default()
+        });
+        let model_pinned = model.pinned();
+        let mut model = model_pinned.borrow_mut();
+
+        let account = AccountId::from(1);
+        let chat = ChatHandle::from(1);
+        let num_messages = (ChatModel::MAX_LOADED_MESSAGES + ChatModel::PAGE_SIZE) as i64;
+        let history = |ids: std::ops::RangeInclusive<i64>| -> Vec<ChatLogEntry> {
+            ids.map(|id| chat_log_entry(id, 0)).collect()
+        };
+
+        // Only the newest messages stay loaded
+        model.set_content(account, chat, history(1..=num_messages));
+        assert_eq!(model.chat_log.len(), ChatModel::MAX_LOADED_MESSAGES);
+        let oldest_loaded = *model.chat_log[0].id();
+        assert_eq!(oldest_loaded.id(), ChatModel::PAGE_SIZE as i64 + 1);
+
+        // Scrolling to the top asks for the page right before what we have
+        model.fetchOlder();
+        match ui_requests_rx.try_next() {
+            Ok(Some(TocksUiEvent::LoadOlderMessages(a, c, before, limit))) => {
+                assert_eq!(a, account);
+                assert_eq!(c, chat);
+                assert_eq!(before, oldest_loaded);
+                assert_eq!(limit, ChatModel::PAGE_SIZE);
+            }
+            _ => panic!("Expected a request for older messages"),
+        }
+
+        // Only one request in flight at a time
+        model.fetchOlder();
+        assert!(ui_requests_rx.try_next().is_err());
+
+        model.push_older(account, chat, history(1..=ChatModel::PAGE_SIZE as i64));
+
+        let ids = chat_log_ids(&model);
+        assert_eq!(ids.len(), num_messages as usize);
+        assert!(ids.windows(2).all(|w| w[0] + 1 == w[1]));
+        assert_eq!(*ids.last().unwrap(), num_messages);
+
+        // Back at the bottom the extra history is dropped again, and can be
+        // fetched again from the same point
+        model.evictOldMessages();
+        assert_eq!(model.chat_log.len(), ChatModel::MAX_LOADED_MESSAGES);
+        assert_eq!(*model.chat_log[0].id(), oldest_loaded);
+
+        model.fetchOlder();
+        assert!(matches!(
+            ui_requests_rx.try_next(),
+            Ok(Some(TocksUiEvent::LoadOlderMessages(_, _, before, _))) if before == oldest_loaded
+        ));
+    }
+
+    #[test]
+    fn test_chat_model_reply_snippet() {
+        let model = QObjectBox::new(ChatModel::default());
+        let model_pinned = model.pinned();
+        let mut model = model_pinned.borrow_mut();
+
+        let long_text = "a".repeat(ChatModel::MAX_SNIPPET_CHARS * 2);
+        model.set_content(
+            AccountId::from(1),
+            ChatHandle::from(1),
+            vec![
+                chat_log_reply(1, 1, None, "short"),
+                chat_log_reply(2, 2, None, &long_text),
+                chat_log_reply(3, 3, Some(1), "reply"),
+                chat_log_reply(4, 4, Some(2), "reply"),
+                // Not in the model
+                chat_log_reply(5, 5, Some(10), "reply"),
+            ],
+        );
+
+        assert_eq!(model.reply_snippet(&model.chat_log[0]), "");
+        assert_eq!(model.reply_snippet(&model.chat_log[2]), "short");
+        assert_eq!(
+            model.reply_snippet(&model.chat_log[3]),
+            format!("{}…", "a".repeat(ChatModel::MAX_SNIPPET_CHARS))
+        );
+        assert_eq!(model.reply_snippet(&model.chat_log[4]), "");
+    }
+
+    #[test]
+    fn test_chat_model_sender_name() {
+        let model = QObjectBox::new(ChatModel::default());
+        let model_pinned = model.pinned();
+        let mut model = model_pinned.borrow_mut();
+
+        // chat_log_reply messages are all sent by user 1
+        model.set_content(
+            AccountId::from(1),
+            ChatHandle::from(1),
+            vec![chat_log_reply(1, 1, None, "hello")],
+        );
+        assert_eq!(model.sender_name(&model.chat_log[0]), "");
+
+        let mut names = HashMap::new();
+        names.insert(UserHandle::from(0), "me".to_string());
+        names.insert(UserHandle::from(1), "friend".to_string());
+        model.set_sender_names(names);
+
+        assert_eq!(model.sender_name(&model.chat_log[0]), "friend");
+    }
+
+    #[test]
+    fn test_chat_model_styled_actions() {
+        let model = QObjectBox::new(ChatModel::default());
+        let model_pinned = model.pinned();
+        let mut model = model_pinned.borrow_mut();
+
+        let action = chat_log_message(2, 2, None, Message::Action("waves".into()));
+
+        model.set_content(
+            AccountId::from(1),
+            ChatHandle::from(1),
+            vec![chat_log_reply(1, 1, None, "/me waves"), action],
+        );
+
+        let mut names = HashMap::new();
+        names.insert(UserHandle::from(1), "<friend>".to_string());
+        model.set_sender_names(names);
+
+        assert_eq!(model.styled_message(&model.chat_log[0]), "/me waves");
+        assert_eq!(
+            model.styled_message(&model.chat_log[1]),
+            "<i>* &lt;friend&gt; waves</i>"
+        );
+    }
+
+    #[test]
+    fn test_chat_model_text_for_row() {
+        let model = QObjectBox::new(ChatModel::default());
+        let model_pinned = model.pinned();
+        let mut model = model_pinned.borrow_mut();
+
+        model.set_content(
+            AccountId::from(1),
+            ChatHandle::from(1),
+            vec![
+                chat_log_reply(1, 1, None, "first"),
+                chat_log_reply(2, 2, None, "second"),
+                chat_log_reply(3, 3, None, "third"),
+            ],
+        );
+
+        // Row 0 is the newest message
+        assert_eq!(model.text_for_row(0).to_string(), "third");
+        assert_eq!(model.text_for_row(1).to_string(), "second");
+        assert_eq!(model.text_for_row(2).to_string(), "first");
+
+        assert_eq!(model.text_for_row(3).to_string(), "");
+        assert_eq!(model.text_for_row(-1).to_string(), "");
+    }
+
+    #[test]
+    fn test_chat_model_unsorted_content() {
+        let model = QObjectBox::new(ChatModel::default());
+        let model_pinned = model.pinned();
+        let mut model = model_pinned.borrow_mut();
+
+        model.set_content(
+            AccountId::from(1),
+            ChatHandle::from(1),
+            vec![
+                chat_log_entry(3, 3),
+                chat_log_entry(1, 1),
+                chat_log_entry(3, 3),
+                chat_log_entry(2, 2),
+                chat_log_entry(1, 1),
+            ],
+        );
+        assert_eq!(chat_log_ids(&model), vec![1, 2, 3]);
+
+        model.resolve_message(ChatMessageId::from(3));
+        assert!(model.chat_log[2].complete());
+
+        model.push_message(chat_log_entry(2, 2));
+        model.push_message(chat_log_entry(0, 0));
+        assert_eq!(chat_log_ids(&model), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_chat_model_peer_read_message_per_chat() {
+        let model = QObjectBox::new(ChatModel::default());
+        let model_pinned = model.pinned();
+        let mut model = model_pinned.borrow_mut();
+
+        let account = AccountId::from(1);
+        let chat = ChatHandle::from(1);
+        let other_chat = ChatHandle::from(2);
+
+        model.set_content(account, chat, vec![chat_log_entry(1, 1)]);
+
+        // Read markers for other chats are remembered but don't touch the
+        // visible one
+        model.set_peer_read_message(account, other_chat, ChatMessageId::from(2));
+        assert_eq!(model.peerReadMessage, 0);
+
+        model.set_peer_read_message(account, chat, ChatMessageId::from(1));
+        assert_eq!(model.peerReadMessage, 1);
+
+        model.set_content(account, other_chat, vec![chat_log_entry(2, 2)]);
+        assert_eq!(model.peerReadMessage, 2);
+
+        model.set_content(account, chat, vec![chat_log_entry(1, 1)]);
+        assert_eq!(model.peerReadMessage, 1);
+    }
+
+    #[test]
+    fn test_spans_to_styled_text() {
+        let spans = vec![
+            Span {
+                text: "a <b> & \"c\"\n",
+                style: SpanStyle::Plain,
+            },
+            Span {
+                text: "bold",
+                style: SpanStyle::Bold,
+            },
+            Span {
+                text: "it",
+                style: SpanStyle::Italic,
+            },
+            Span {
+                text: "x < y",
+                style: SpanStyle::Code,
+            },
+            Span {
+                text: "line 1\nline 2",
+                style: SpanStyle::CodeBlock,
+            },
+        ];
+
+        assert_eq!(
+            spans_to_styled_text(&spans),
+            "a &lt;b&gt; &amp; &quot;c&quot;<br><b>bold</b><i>it</i>\
+             <font face=\"monospace\">x &lt; y</font><pre>line 1\nline 2</pre>"
+        );
+    }
+
+    #[test]
+    fn test_styled_text_keeps_whitespace() {
+        let spans = [
+            Span {
+                text: "  indented\na   b ",
+                style: SpanStyle::Plain,
+            },
+            Span {
+                text: " c\td",
+                style: SpanStyle::Bold,
+            },
+        ];
+
+        assert_eq!(
+            spans_to_styled_text(&spans),
+            "&nbsp;&nbsp;indented<br>a &nbsp;&nbsp;b \
+             <b>&nbsp;c&nbsp;&nbsp;&nbsp;&nbsp;d</b>"
+        );
+    }
+
+    #[test]
+    fn test_offline_message_queued() {
+        let (ui_requests_tx, _ui_requests_rx) = mpsc::unbounded();
+        let (qtocks_event_tx, _qtocks_event_rx) = mpsc::unbounded();
+        let mut qtocks = QTocks::new(
+            ui_requests_tx,
+            qtocks_event_tx,
+            Vec::new(),
+            true,
+            Settings::default(),
+        );
+
+        let account_id = AccountId::from(1);
+        let friend_id = UserHandle::from(1);
+        let chat = ChatHandle::from(2);
+
+        let mut account = Account::default();
+        account.add_friend(&tocks::Friend::new(
+            friend_id,
+            chat,
+            toxcore::PublicKey::from_bytes(vec![0; toxcore::PublicKey::SIZE]).unwrap(),
+            "friend".to_string(),
+            Status::Offline,
+        ));
+        qtocks
+            .accounts_storage
+            .insert(account_id, QObjectBox::new(account));
+
+        qtocks.handle_ui_callback(TocksEvent::MessagesLoaded(account_id, chat, Vec::new()));
+        qtocks.handle_ui_callback(TocksEvent::MessageInserted(
+            account_id,
+            chat,
+            chat_log_entry(1, 1),
+        ));
+
+        let row_state = |qtocks: &QTocks| {
+            let model_pinned = qtocks.chat_model.pinned();
+            let model = model_pinned.borrow();
+            let role = |role| {
+                let index = (&*model as &dyn QAbstractItemModel).create_index(0, 0, 0);
+                bool::from_qvariant(model.data(index, role)).unwrap()
+            };
+            (role(ChatModel::COMPLETE_ROLE), role(ChatModel::QUEUED_ROLE))
+        };
+
+        assert_eq!(row_state(&qtocks), (false, true));
+
+        // Resent by tocks once the friend is back, now waiting on the receipt
+        qtocks.handle_ui_callback(TocksEvent::FriendStatusChanged(
+            account_id,
+            friend_id,
+            Status::Online,
+        ));
+        assert_eq!(row_state(&qtocks), (false, false));
+
+        qtocks.handle_ui_callback(TocksEvent::MessageCompleted(
+            account_id,
+            chat,
+            ChatMessageId::from(1),
+        ));
+        assert_eq!(row_state(&qtocks), (true, false));
+
+        // Messages sent while the friend is online are never queued
+        qtocks.handle_ui_callback(TocksEvent::MessageInserted(
+            account_id,
+            chat,
+            chat_log_entry(2, 2),
+        ));
+        assert_eq!(row_state(&qtocks), (false, false));
+    }
+
+    #[test]
+    fn test_switch_active_account() {
+        let (ui_requests_tx, _ui_requests_rx) = mpsc::unbounded();
+        let (qtocks_event_tx, _qtocks_event_rx) = mpsc::unbounded();
+        let mut qtocks = QTocks::new(
+            ui_requests_tx,
+            qtocks_event_tx,
+            Vec::new(),
+            true,
+            Settings::default(),
+        );
+
+        assert_eq!(qtocks.get_active_account(), -1);
+
+        let address = |byte| ToxId::from_bytes(vec![byte; ToxId::SIZE]).unwrap();
+        for id in &[2, 1] {
+            qtocks.account_login(
+                AccountId::from(*id),
+                UserHandle::from(*id),
+                address(*id as u8),
+                format!("account {}", id),
+                1372,
+            );
+        }
+
+        // Most recent login is shown, but both stay logged in
+        assert_eq!(qtocks.get_active_account(), 1);
+        assert_eq!(qtocks.get_accounts().len(), 2);
+
+        qtocks.setActiveAccount(2);
+        assert_eq!(qtocks.get_active_account(), 2);
+
+        // Accounts we never logged in to can't be shown
+        qtocks.setActiveAccount(3);
+        assert_eq!(qtocks.get_active_account(), 2);
+
+        // A stopped account leaves the sidebar, and the view moves on
+        qtocks.account_removed(AccountId::from(2), "Account 2 stopped".to_string());
+        assert_eq!(qtocks.get_active_account(), 1);
+        assert_eq!(qtocks.get_accounts().len(), 1);
+
+        qtocks.account_removed(AccountId::from(1), "Account 1 stopped".to_string());
+        assert_eq!(qtocks.get_active_account(), -1);
+        assert!(qtocks.get_accounts().is_empty());
+    }
+
+    #[test]
+    fn test_stale_events_do_not_panic() {
+        let (ui_requests_tx, _ui_requests_rx) = mpsc::unbounded();
+        let (qtocks_event_tx, _qtocks_event_rx) = mpsc::unbounded();
+        let mut qtocks = QTocks::new(
+            ui_requests_tx,
+            qtocks_event_tx,
+            Vec::new(),
+            true,
+            Settings::default(),
+        );
+
+        let known_account = AccountId::from(1);
+        let unknown_account = AccountId::from(2);
+        let unknown_user = UserHandle::from(1);
+
+        qtocks
+            .accounts_storage
+            .insert(known_account, QObjectBox::new(Account::default()));
+
+        for account in &[known_account, unknown_account] {
+            qtocks.handle_ui_callback(TocksEvent::FriendStatusChanged(
+                *account,
+                unknown_user,
+                Status::Online,
+            ));
+            qtocks.handle_ui_callback(TocksEvent::UserNameChanged(
+                *account,
+                unknown_user,
+                "name".to_string(),
+            ));
+            qtocks.handle_ui_callback(TocksEvent::ChatCallStateChanged(
+                *account,
+                ChatHandle::from(1),
+                CallState::Idle,
+            ));
+        }
+
+        qtocks.handle_ui_callback(TocksEvent::MessageCompleted(
+            unknown_account,
+            ChatHandle::from(1),
+            ChatMessageId::from(1),
+        ));
+    }
+
+    #[test]
+    fn test_messages_for_other_chats_not_shown() {
+        let (ui_requests_tx, _ui_requests_rx) = mpsc::unbounded();
+        let (qtocks_event_tx, _qtocks_event_rx) = mpsc::unbounded();
+        let mut qtocks = QTocks::new(
+            ui_requests_tx,
+            qtocks_event_tx,
+            Vec::new(),
+            true,
+            Settings::default(),
+        );
+
+        let known_account = AccountId::from(1);
+        let unknown_account = AccountId::from(2);
+        let shown_chat = ChatHandle::from(1);
+
+        qtocks
+            .accounts_storage
+            .insert(known_account, QObjectBox::new(Account::default()));
+        qtocks.chat_model.pinned().borrow_mut().set_content(
+            known_account,
+            shown_chat,
+            vec![chat_log_entry(1, 1)],
+        );
+
+        // Same chat id on an account we don't know, and another chat of the
+        // account being shown
+        qtocks.handle_ui_callback(TocksEvent::MessageInserted(
+            unknown_account,
+            shown_chat,
+            chat_log_entry(2, 2),
+        ));
+        qtocks.handle_ui_callback(TocksEvent::MessageInserted(
+            known_account,
+            ChatHandle::from(2),
+            chat_log_entry(3, 3),
+        ));
+
+        assert_eq!(chat_log_ids(&qtocks.chat_model.pinned().borrow()), vec![1]);
     }
 }
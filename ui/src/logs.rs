@@ -0,0 +1,73 @@
+use qmetaobject::*;
+use tocks::LogEntry;
+
+use std::collections::HashMap;
+
+/// Recent log lines for users without a console, newest first
+#[derive(QObject, Default)]
+pub struct LogModel {
+    base: qt_base_class!(trait QAbstractItemModel),
+
+    entries: Vec<LogEntry>,
+}
+
+impl LogModel {
+    const TIMESTAMP_ROLE: i32 = USER_ROLE;
+    const LEVEL_ROLE: i32 = USER_ROLE + 1;
+    const TARGET_ROLE: i32 = USER_ROLE + 2;
+    const MESSAGE_ROLE: i32 = USER_ROLE + 3;
+
+    /// entries comes oldest first, as tocks hands them out
+    pub fn set_entries(&mut self, mut entries: Vec<LogEntry>) {
+        (self as &dyn QAbstractItemModel).begin_reset_model();
+        entries.reverse();
+        self.entries = entries;
+        (self as &dyn QAbstractItemModel).end_reset_model();
+    }
+}
+
+impl QAbstractItemModel for LogModel {
+    fn index(&self, row: i32, _column: i32, _parent: QModelIndex) -> QModelIndex {
+        (self as &dyn QAbstractItemModel).create_index(row, 0, 0)
+    }
+
+    fn parent(&self, _index: QModelIndex) -> QModelIndex {
+        QModelIndex::default()
+    }
+
+    fn row_count(&self, _parent: QModelIndex) -> i32 {
+        self.entries.len() as i32
+    }
+
+    fn column_count(&self, _parent: QModelIndex) -> i32 {
+        1
+    }
+
+    fn data(&self, index: QModelIndex, role: i32) -> QVariant {
+        let entry = match self.entries.get(index.row() as usize) {
+            Some(entry) => entry,
+            None => return QVariant::default(),
+        };
+
+        match role {
+            Self::TIMESTAMP_ROLE => {
+                QString::from(entry.timestamp.format("%Y-%m-%d %H:%M:%S").to_string()).to_qvariant()
+            }
+            Self::LEVEL_ROLE => QString::from(entry.level.as_str()).to_qvariant(),
+            Self::TARGET_ROLE => QString::from(entry.target.as_str()).to_qvariant(),
+            Self::MESSAGE_ROLE => QString::from(entry.message.as_str()).to_qvariant(),
+            _ => QVariant::default(),
+        }
+    }
+
+    fn role_names(&self) -> HashMap<i32, QByteArray> {
+        let mut ret = HashMap::new();
+
+        ret.insert(Self::TIMESTAMP_ROLE, "timestamp".into());
+        ret.insert(Self::LEVEL_ROLE, "level".into());
+        ret.insert(Self::TARGET_ROLE, "target".into());
+        ret.insert(Self::MESSAGE_ROLE, "message".into());
+
+        ret
+    }
+}
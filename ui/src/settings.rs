@@ -0,0 +1,410 @@
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use tocks::{
+    audio::{FramePeriod, OutputDevice},
+    APP_DIRS,
+};
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Theme {
+    // Follow the desktop, QML decides what that means
+    System,
+    Light,
+    Dark,
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme::System
+    }
+}
+
+impl Theme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Theme::System => "system",
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+        }
+    }
+}
+
+impl FromStr for Theme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Theme> {
+        match s {
+            "system" => Ok(Theme::System),
+            "light" => Ok(Theme::Light),
+            "dark" => Ok(Theme::Dark),
+            _ => Err(anyhow!("Unknown theme {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct WindowGeometry {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl WindowGeometry {
+    /// Moves and shrinks the window as little as possible so that it fits
+    /// within screen. Saved geometry can point off screen after a monitor is
+    /// unplugged or the resolution changes
+    pub fn clamped_to(&self, screen: &WindowGeometry) -> WindowGeometry {
+        let width = self.width.min(screen.width).max(1);
+        let height = self.height.min(screen.height).max(1);
+
+        let x = self.x.min(screen.x + screen.width - width).max(screen.x);
+        let y = self.y.min(screen.y + screen.height - height).max(screen.y);
+
+        WindowGeometry {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Fits the window onto the screen it overlaps the most, so a window
+    /// left on a secondary monitor stays there. Falls back to the first
+    /// screen if it's on none of them. None if there are no screens
+    pub fn fitted_to(&self, screens: &[WindowGeometry]) -> Option<WindowGeometry> {
+        let best = screens
+            .iter()
+            .map(|screen| (self.overlap(screen), screen))
+            .filter(|(overlap, _)| *overlap > 0)
+            .max_by_key(|(overlap, _)| *overlap)
+            .map(|(_, screen)| screen);
+
+        best.or_else(|| screens.first())
+            .map(|screen| self.clamped_to(screen))
+    }
+
+    /// Area shared by the two rectangles
+    fn overlap(&self, other: &WindowGeometry) -> i64 {
+        let span = |start: i32, len: i32, other_start: i32, other_len: i32| {
+            let end = (start as i64 + len as i64).min(other_start as i64 + other_len as i64);
+            (end - start.max(other_start) as i64).max(0)
+        };
+
+        span(self.x, self.width, other.x, other.width)
+            * span(self.y, self.height, other.y, other.height)
+    }
+}
+
+/// User preferences that outlive a single run of the UI. Fields missing from
+/// the file (e.g. written by an older version) take their default value
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub(crate) struct Settings {
+    pub theme: Theme,
+    pub audio_output: OutputDevice,
+    pub notification_sounds: bool,
+    // None plays the bundled sound
+    pub notification_sound_path: Option<PathBuf>,
+    // Silences every notification, overriding per-chat settings
+    pub do_not_disturb: bool,
+    pub expand_shortcodes: bool,
+    pub window_geometry: Option<WindowGeometry>,
+    // Length of the audio frames sent in calls
+    pub call_frame_period: FramePeriod,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            theme: Default::default(),
+            audio_output: OutputDevice::Default,
+            notification_sounds: true,
+            notification_sound_path: None,
+            do_not_disturb: false,
+            expand_shortcodes: true,
+            window_geometry: None,
+            call_frame_period: Default::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Loads settings from path. A missing file gives the defaults, so does a
+    /// corrupt one, but the corrupt file is kept next to the original so the
+    /// user's settings are not silently thrown away
+    pub fn load(path: &Path) -> Settings {
+        let data = match fs::read(path) {
+            Ok(d) => d,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Default::default(),
+            Err(e) => {
+                error!("Failed to read settings, using defaults: {}", e);
+                return Default::default();
+            }
+        };
+
+        match serde_json::from_slice(&data) {
+            Ok(settings) => settings,
+            Err(e) => {
+                error!("Failed to parse settings, using defaults: {}", e);
+
+                let backup_path = path.with_extension("json.corrupt");
+                if let Err(e) = fs::rename(path, &backup_path) {
+                    error!("Failed to back up corrupt settings: {}", e);
+                }
+
+                Default::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context("Failed to create settings directory")?;
+        }
+
+        let data = serde_json::to_vec_pretty(self).context("Failed to serialize settings")?;
+
+        // Write then rename so a crash mid-write can't leave a truncated file
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, data).context("Failed to write settings")?;
+        fs::rename(&tmp_path, path).context("Failed to replace settings")
+    }
+}
+
+pub(crate) fn settings_path() -> PathBuf {
+    APP_DIRS.config_dir.join("settings.json")
+}
+
+/// Finds the saved output device in the currently available devices. Devices
+/// are matched by name since their order changes as they come and go. Returns
+/// None if the saved device is no longer available
+pub(crate) fn find_output_device<'a>(
+    available: &'a [OutputDevice],
+    saved: &OutputDevice,
+) -> Option<&'a OutputDevice> {
+    available.iter().find(|device| *device == saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_output_device() {
+        let available = vec![
+            OutputDevice::Default,
+            OutputDevice::Named("headphones".to_string()),
+            OutputDevice::Named("speakers".to_string()),
+        ];
+
+        assert_eq!(
+            find_output_device(&available, &OutputDevice::Named("speakers".to_string())),
+            Some(&available[2])
+        );
+        assert_eq!(
+            find_output_device(&available, &OutputDevice::Default),
+            Some(&available[0])
+        );
+        assert!(find_output_device(&available, &OutputDevice::Named("gone".to_string())).is_none());
+
+        // Index shouldn't matter, only the name
+        let reordered = vec![
+            OutputDevice::Named("speakers".to_string()),
+            OutputDevice::Default,
+        ];
+        assert_eq!(
+            find_output_device(&reordered, &OutputDevice::Named("speakers".to_string())),
+            Some(&reordered[0])
+        );
+    }
+
+    #[test]
+    fn test_window_geometry_clamped() {
+        let screen = WindowGeometry {
+            x: 0,
+            y: 0,
+            width: 1920,
+            height: 1080,
+        };
+
+        let on_screen = WindowGeometry {
+            x: 100,
+            y: 100,
+            width: 960,
+            height: 720,
+        };
+        assert_eq!(on_screen.clamped_to(&screen), on_screen);
+
+        // Was on a monitor to the right that is now gone
+        let off_screen = WindowGeometry {
+            x: 2500,
+            y: -300,
+            width: 960,
+            height: 720,
+        };
+        assert_eq!(
+            off_screen.clamped_to(&screen),
+            WindowGeometry {
+                x: 960,
+                y: 0,
+                width: 960,
+                height: 720,
+            }
+        );
+
+        // Bigger than the screen shrinks to fit
+        let too_big = WindowGeometry {
+            x: -10,
+            y: 50,
+            width: 3000,
+            height: 2000,
+        };
+        assert_eq!(too_big.clamped_to(&screen), screen);
+
+        // Screens don't have to start at the origin
+        let second_screen = WindowGeometry {
+            x: 1920,
+            y: 0,
+            width: 1280,
+            height: 1024,
+        };
+        assert_eq!(
+            on_screen.clamped_to(&second_screen),
+            WindowGeometry {
+                x: 1920,
+                y: 100,
+                width: 960,
+                height: 720,
+            }
+        );
+    }
+
+    #[test]
+    fn test_window_geometry_fitted_to_screens() {
+        let primary = WindowGeometry {
+            x: 0,
+            y: 0,
+            width: 1920,
+            height: 1080,
+        };
+        let secondary = WindowGeometry {
+            x: 1920,
+            y: 0,
+            width: 1280,
+            height: 1024,
+        };
+        let screens = [primary, secondary];
+
+        // Left on the secondary monitor, and still fully on it
+        let on_secondary = WindowGeometry {
+            x: 2000,
+            y: 100,
+            width: 960,
+            height: 720,
+        };
+        assert_eq!(on_secondary.fitted_to(&screens), Some(on_secondary));
+
+        // Straddling both, stays on the one it mostly covers
+        let straddling = WindowGeometry {
+            x: 1800,
+            y: 100,
+            width: 960,
+            height: 720,
+        };
+        assert_eq!(
+            straddling.fitted_to(&screens),
+            Some(WindowGeometry {
+                x: 1920,
+                ..straddling
+            })
+        );
+
+        // Secondary monitor unplugged, ends up on the first screen
+        assert_eq!(
+            on_secondary.fitted_to(&[primary]),
+            Some(on_secondary.clamped_to(&primary))
+        );
+
+        let off_everything = WindowGeometry {
+            x: -5000,
+            ..on_secondary
+        };
+        assert_eq!(
+            off_everything.fitted_to(&screens),
+            Some(off_everything.clamped_to(&primary))
+        );
+
+        assert_eq!(on_secondary.fitted_to(&[]), None);
+    }
+
+    #[test]
+    fn test_missing_file_is_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings::load(&dir.path().join("settings.json"));
+        assert_eq!(settings, Settings::default());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config").join("settings.json");
+
+        let settings = Settings {
+            theme: Theme::Dark,
+            audio_output: OutputDevice::Named("speakers".to_string()),
+            notification_sounds: false,
+            notification_sound_path: Some(PathBuf::from("/sounds/ding.wav")),
+            do_not_disturb: true,
+            expand_shortcodes: false,
+            window_geometry: Some(WindowGeometry {
+                x: 10,
+                y: 20,
+                width: 800,
+                height: 600,
+            }),
+            call_frame_period: FramePeriod::Ms40,
+        };
+        settings.save(&path).unwrap();
+
+        assert_eq!(Settings::load(&path), settings);
+        assert!(!path.with_extension("json.tmp").exists());
+    }
+
+    #[test]
+    fn test_corrupt_file_is_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.json");
+        fs::write(&path, b"{ not json").unwrap();
+
+        assert_eq!(Settings::load(&path), Settings::default());
+
+        let backup = fs::read(path.with_extension("json.corrupt")).unwrap();
+        assert_eq!(backup, b"{ not json");
+    }
+
+    #[test]
+    fn test_missing_fields_are_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.json");
+        fs::write(&path, br#"{ "theme": "light" }"#).unwrap();
+
+        let settings = Settings::load(&path);
+        assert_eq!(settings.theme, Theme::Light);
+        assert_eq!(settings.audio_output, OutputDevice::Default);
+        assert!(settings.notification_sounds);
+        assert!(settings.notification_sound_path.is_none());
+        assert!(!settings.do_not_disturb);
+        assert!(settings.expand_shortcodes);
+        assert!(settings.window_geometry.is_none());
+    }
+}